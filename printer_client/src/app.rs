@@ -1,15 +1,53 @@
 use std::{
-    net::IpAddr,
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, TryRecvError},
+        Arc,
+    },
     time::{Duration, Instant},
 };
+use tracing::warn;
 use url::Url;
+use uuid::Uuid;
 
 use egui::{
     ahash::{HashMap, HashMapExt},
-    Color32, Context, RichText, Widget,
+    Color32, Context, Key, Modifiers, RichText, Visuals, Widget,
 };
 
-use crate::{get_settings, save_settings, update, Printer};
+use crate::{
+    get_settings, pdf, save_settings, update, update::UpdateStatus, ConnectOptions, ConnectionPool,
+    Document, JobState, PrintError, PrintOptions, PrintOutcome, Printer, PrinterOptions, Session,
+    UnknownCertificate, DEFAULT_CONNECT_TIMEOUT, DEFAULT_PORT, MAX_COPIES, MAX_SCALING, POSITIONS,
+};
+
+/// How often the state of submitted jobs is asked for
+const JOB_POLL_INTERVAL: Duration = Duration::from_secs(3);
+/// Jobs unfinished after this long are no longer followed
+const JOB_POLL_LIMIT: Duration = Duration::from_secs(10 * 60);
+/// Printed files remembered for reprinting
+const MAX_RECENT_FILES: usize = 8;
+/// Range of the UI scale slider, in pixels per point
+const UI_SCALE_RANGE: std::ops::RangeInclusive<f32> = 0.8..=2.0;
+/// Printed by the "Print test page" button in settings
+const TEST_PAGE: &[u8] = include_bytes!("../assets/test_page.txt");
+/// Documents with more pages than this need confirming, unless changed in settings
+const DEFAULT_CONFIRM_PAGES: u32 = 50;
+/// Documents bigger than this many MB need confirming, unless changed in settings
+const DEFAULT_CONFIRM_MB: u64 = 20;
+/// Upload limit suggested when turning it on in settings, in KiB per second
+const DEFAULT_RATE_LIMIT_KIB: u64 = 1024;
+/// Sessions are renewed this long before they expire, so prints don't wait on logging in
+const SESSION_RENEW_BEFORE: Duration = Duration::from_secs(5 * 60);
+/// Unused connections are kept open this long for the next print, unless changed in settings
+const DEFAULT_IDLE_CONNECTION: Duration = Duration::from_secs(60);
+/// How often connections are checked for having been unused too long
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// How long Scan network listens for servers announcing themselves
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
 
 #[derive(serde::Deserialize, serde::Serialize)]
 pub enum Page {
@@ -22,6 +60,203 @@ pub enum Page {
 pub enum Crud {
     Remove,
     Add,
+    /// Replaces the printer at this address, moving it if the key is another
+    Edit(IpAddr),
+}
+
+/// Finished settings export or import from the worker thread
+enum Backup {
+    Exported(PathBuf),
+    Imported(Box<Settings>),
+}
+
+/// Messages from the worker thread sending files
+enum SendEvent {
+    /// Bytes sent and total bytes of the current file
+    Progress(u64, u64),
+    /// The server's certificate needs to be trusted first
+    UntrustedCertificate(UnknownCertificate),
+    /// Sending finished
+    Done {
+        /// Printer settings, with the session the worker may have fetched
+        ip: IpAddr,
+        printer: Printer,
+        /// A result line per file
+        results: Vec<(String, ResultKind)>,
+        /// Files worth sending again
+        retry: Vec<Document>,
+        /// Files on disk that printed
+        printed: Vec<PathBuf>,
+    },
+}
+
+/// How sending a file ended
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ResultKind {
+    Success,
+    /// Failed, but sending again might work
+    Retryable,
+    Failure,
+}
+
+/// Label of `printer`, or its address if it has none
+fn printer_label(ip: IpAddr, printer: &Printer) -> String {
+    match printer.label.trim() {
+        "" => ip.to_string(),
+        label => label.to_string(),
+    }
+}
+
+/// URL of the server at `ip`, bracketing IPv6 addresses
+fn printer_url(ip: IpAddr, port: u16) -> Url {
+    Url::parse(&format!("https://{}", SocketAddr::new(ip, port))).unwrap()
+}
+
+/// When `printer`'s session expires, or that the next print logs in again.
+/// Adds why `renewal` failed if it was renewing the current session
+fn session_status(printer: &Printer, renewal: Option<&SessionRenewal>) -> String {
+    let status = match &printer.session {
+        Some(session) if session.expiration > chrono::Utc::now() => format!(
+            "Session valid until {}",
+            session
+                .expiration
+                .with_timezone(&chrono::Local)
+                .format("%H:%M")
+        ),
+        _ => "Will re-authenticate".to_string(),
+    };
+    let failure = renewal.and_then(|renewal| {
+        let current = printer.session.as_ref()?.id == renewal.replacing;
+        renewal.failure.as_ref().filter(|_| current)
+    });
+    match failure {
+        Some(failure) => format!("{}, renewing failed: {}", status, failure),
+        None => status,
+    }
+}
+
+/// Green that stays readable on the light theme
+fn success_color(visuals: &Visuals) -> Color32 {
+    if visuals.dark_mode {
+        Color32::GREEN
+    } else {
+        Color32::DARK_GREEN
+    }
+}
+
+/// The document of a dropped file, `None` if it came with neither a path nor its contents.
+/// Files dropped from a browser only come with their contents
+fn dropped_document(file: &egui::DroppedFile) -> Option<Document> {
+    match (&file.path, &file.bytes) {
+        (Some(path), _) => Some(path.clone().into()),
+        (None, Some(bytes)) => Some(Document::Bytes {
+            name: if file.name.is_empty() {
+                "Dropped file".to_string()
+            } else {
+                file.name.clone()
+            },
+            bytes: bytes.clone(),
+        }),
+        (None, None) => None,
+    }
+}
+
+/// Files to send, whether some dropped files couldn't be read, and which files are large
+type LargeDocuments = (Vec<Document>, bool, Vec<String>);
+
+/// Servers found on the local network, by name and address
+type DiscoveredServers = Vec<(String, SocketAddr)>;
+
+/// Describes `document` if it's over `confirm_pages` pages or `confirm_mb` MB,
+/// e.g. `"report.pdf" is 312 pages / 40.0 MB`. Only PDFs have a page count,
+/// anything else is judged by size. Reads whole PDFs, so not for the UI thread
+fn large_document(document: &Document, confirm_pages: u32, confirm_mb: u64) -> Option<String> {
+    let name = document.name().unwrap_or_default();
+    let is_pdf = Path::new(&name)
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("pdf"));
+    let (size, pages) = match document {
+        Document::Path(path) => (
+            fs::metadata(path).map(|meta| meta.len()).unwrap_or(0),
+            is_pdf
+                .then(|| fs::read(path).ok())
+                .flatten()
+                .and_then(|pdf| pdf::page_count(&pdf)),
+        ),
+        Document::Bytes { bytes, .. } => (
+            bytes.len() as u64,
+            is_pdf.then(|| pdf::page_count(bytes)).flatten(),
+        ),
+    };
+
+    let too_long = pages.is_some_and(|pages| pages > confirm_pages);
+    let too_big = size > confirm_mb * 1_000_000;
+    let mb = size as f64 / 1_000_000.0;
+    (too_long || too_big).then(|| match pages {
+        Some(pages) => format!("{:?} is {} pages / {:.1} MB", name, pages, mb),
+        None => format!("{:?} is {:.1} MB", name, mb),
+    })
+}
+
+fn job_state_color(state: JobState, visuals: &Visuals) -> Color32 {
+    match state {
+        JobState::Completed => success_color(visuals),
+        JobState::Failed => Color32::RED,
+        JobState::Queued | JobState::Printing | JobState::Unknown => Color32::GRAY,
+    }
+}
+
+impl ResultKind {
+    fn color(self, visuals: &Visuals) -> Color32 {
+        match self {
+            ResultKind::Success => success_color(visuals),
+            ResultKind::Retryable => visuals.warn_fg_color,
+            ResultKind::Failure => Color32::RED,
+        }
+    }
+}
+
+/// Light or dark visuals
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Theme {
+    /// Follows the OS, dark if it can't tell
+    #[default]
+    System,
+    Light,
+    Dark,
+}
+
+/// State of a "Test" on the settings page
+enum ConnectionTest {
+    Running(Receiver<Result<Duration, PrintError>>),
+    Passed(Duration),
+    Failed(String),
+}
+
+/// Test page sent from the settings page, see [`TEST_PAGE`]
+enum TestPrint {
+    /// Waiting for the outcome, and the session the send may have fetched
+    Running(Receiver<(Result<PrintOutcome, PrintError>, Option<Session>)>),
+    /// What the server said, or why it failed
+    Done(Result<String, String>),
+}
+
+/// Renewal of a printer's session, kept after finishing so a failed one isn't retried
+struct SessionRenewal {
+    /// The session being replaced
+    replacing: Uuid,
+    /// The new session, `None` once collected
+    result: Option<Receiver<Result<Session, PrintError>>>,
+    /// Why renewing failed, shown with the session's status
+    failure: Option<String>,
+}
+
+/// Messages from the worker thread running the updater
+enum UpdateEvent {
+    /// Bytes downloaded and total bytes of the installer
+    Progress(u64, u64),
+    Done(Result<UpdateStatus, String>),
 }
 
 /// Current version status
@@ -33,54 +268,244 @@ pub enum VersionStatus {
 pub struct Interface {
     picked_path: Option<String>,
     dropped_files: Vec<egui::DroppedFile>,
+    /// Selection last checked for images and whether it has one, sniffing reads the files
+    image_check: (Vec<String>, bool),
+    /// Recent files last checked and whether each exists, checking is filesystem I/O
+    recent_check: (Vec<PathBuf>, Vec<bool>),
+    /// Printing `text` instead of files
+    text_mode: bool,
+    /// Typed or pasted text to print
+    text: String,
     current_page: Page,
     settings: Settings,
 
     carry: String, // Insturctions to carry to next iteration
     string: String,
+    port: String,
+    user: String,
+    /// Label entered on the Add Printer page
+    label: String,
+    /// Certificate hostname entered on the Add Printer page
+    cert_host: String,
+    /// Printer being changed on the Add Printer page, `None` when adding one
+    editing: Option<IpAddr>,
+    /// Passphrase settings are exported and imported with, none if empty
+    backup_passphrase: String,
+    /// Whether importing replaces the current settings rather than adding to them
+    backup_replace: bool,
+    /// Result of the last export or import
+    backup_notice: String,
+    /// Export or import running, its key derivation takes a while
+    backup: Option<Receiver<anyhow::Result<Backup>>>,
+    available_printers: Vec<String>,
+    /// Printers being listed by the server entered on the Add Printer page,
+    /// see [`Interface::poll_printer_fetch`]
+    printer_fetch: Option<Receiver<Result<Vec<String>, PrintError>>>,
+    printer_name: Option<String>,
+    discovered_servers: DiscoveredServers,
+    /// Network scan running, see [`Interface::poll_discovery`]
+    discovery: Option<Receiver<anyhow::Result<DiscoveredServers>>>,
     pub error: String,
 
     selected_printer: IpAddr,
-    submit_result: Option<(String, Instant)>,
+    print_options: PrintOptions,
+    page_range: String,
+    /// Media reported by the selected printer, see [`Interface::fetch_media`]
+    available_media: Vec<String>,
+    /// Whether the selected printer applies landscape and fit-to-page, assumed until fetched
+    layout_supported: (bool, bool),
+    /// Options being fetched from a printer, see [`Interface::poll_media_fetch`]
+    media_fetch: Option<(IpAddr, Receiver<Result<PrinterOptions, PrintError>>)>,
+    submit_result: Option<(Vec<(String, ResultKind)>, Instant)>,
+    retry_files: Vec<Document>,
+    sending: Option<Receiver<SendEvent>>,
+    /// Set to stop the in-flight send
+    cancel_send: Arc<AtomicBool>,
+    /// Runs every send, kept for the whole session so pooled connections stay open between them
+    runtime: Arc<tokio::runtime::Runtime>,
+    /// Connections to printers reused by the next send
+    pool: Arc<ConnectionPool>,
+    /// Latest state of each submitted job, labelled by file and job ID
+    jobs: Vec<(String, JobState)>,
+    job_updates: Option<Receiver<(String, JobState)>>,
+    connection_tests: HashMap<IpAddr, ConnectionTest>,
+    test_prints: HashMap<IpAddr, TestPrint>,
+    session_renewals: HashMap<IpAddr, SessionRenewal>,
+    /// Certificate waiting for the user to trust it
+    untrusted_cert: Option<UnknownCertificate>,
+    /// UI scale being dragged on the settings page, applied once let go
+    ui_scale: Option<f32>,
+    /// Send waiting for the user to confirm large documents
+    large_documents: Option<LargeDocuments>,
+    /// Send waiting for the large document check
+    checking_large: Option<Receiver<LargeDocuments>>,
+    send_progress: Option<(u64, u64)>,
 
     update_status: VersionStatus,
+    /// Background check for a newer version, `None` once done
+    update_check: Option<Receiver<anyhow::Result<VersionStatus>>>,
+    updating: Option<Receiver<UpdateEvent>>,
+    update_progress: Option<(u64, u64)>,
 }
 
-#[derive(serde::Deserialize, serde::Serialize)]
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
 pub struct Settings {
     printers: HashMap<IpAddr, Printer>, // Settings intended to be handled securely
+    #[serde(default)]
+    last_selected: Option<IpAddr>,
+    /// Printer selected on startup and used by the CLI without a URL, picked by the user
+    #[serde(default)]
+    default_printer: Option<IpAddr>,
+    /// Seconds to wait for a server to answer
+    #[serde(default = "default_connect_timeout")]
+    connect_timeout: u64,
+    /// Certificate fingerprints trusted on first use, by `host:port`
+    #[serde(default)]
+    pinned_certs: std::collections::HashMap<String, String>,
+    /// Last printed files, newest first
+    #[serde(default)]
+    recent_files: Vec<PathBuf>,
+    /// Ask before printing documents over `confirm_pages` pages or `confirm_mb` MB
+    #[serde(default = "default_confirm_large")]
+    confirm_large: bool,
+    #[serde(default = "default_confirm_pages")]
+    confirm_pages: u32,
+    #[serde(default = "default_confirm_mb")]
+    confirm_mb: u64,
+    /// Pixels per point, the system's scaling if `None`
+    #[serde(default)]
+    ui_scale: Option<f32>,
+    #[serde(default)]
+    theme: Theme,
+    /// Most KiB per second files are uploaded at, unlimited if `None`
+    #[serde(default)]
+    rate_limit_kib: Option<u64>,
+    /// Seconds a connection to a printer is kept open unused for the next print
+    #[serde(default = "default_idle_connection")]
+    idle_connection: u64,
+    /// Set when the settings file couldn't be read, so it isn't overwritten
+    #[serde(skip)]
+    unsaved: bool,
+}
+
+fn default_connect_timeout() -> u64 {
+    DEFAULT_CONNECT_TIMEOUT.as_secs()
+}
+
+fn default_idle_connection() -> u64 {
+    DEFAULT_IDLE_CONNECTION.as_secs()
+}
+
+fn default_confirm_large() -> bool {
+    true
+}
+
+fn default_confirm_pages() -> u32 {
+    DEFAULT_CONFIRM_PAGES
+}
+
+fn default_confirm_mb() -> u64 {
+    DEFAULT_CONFIRM_MB
 }
 
 impl Default for Interface {
     fn default() -> Self {
-        let mut build_error = String::new();
-        let settings = get_settings().unwrap();
-        let update_status = match update::check_oudated() {
-            Ok(val) => val,
+        // Without saved settings the window still opens, saying why. Nothing is saved,
+        // that would overwrite the file that couldn't be read
+        let (settings, error) = match get_settings() {
+            Ok(settings) => (settings, String::new()),
             Err(e) => {
-                build_error = e.to_string();
-                VersionStatus::UpToDate
+                let mut settings = Settings::new();
+                settings.unsaved = true;
+                let error = format!("Failed to load settings, changes won't be saved: {:#}", e);
+                (settings, error)
             }
         };
 
+        // Also drives pooled connections while no send runs, keeping them alive
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .expect("Failed building the Runtime");
+        let pool = Arc::new(ConnectionPool::new(Duration::from_secs(
+            settings.idle_connection,
+        )));
+        let idle = pool.clone();
+        runtime.spawn(async move {
+            let mut interval = tokio::time::interval(IDLE_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                idle.close_idle().await;
+            }
+        });
+
+        // Checked in the background so a slow network doesn't hold up the window
+        let (tx, update_check) = mpsc::channel();
+        std::thread::spawn(move || {
+            let _ = tx.send(update::check_outdated());
+        });
+
         Self {
             picked_path: None,
             dropped_files: Vec::new(),
+            image_check: (Vec::new(), false),
+            recent_check: (Vec::new(), Vec::new()),
+            text_mode: false,
+            text: String::new(),
             current_page: Page::Home,
 
             carry: String::new(),
             string: String::new(),
-            error: build_error,
-
-            selected_printer: *settings
-                .printers
-                .clone()
-                .keys()
-                .next()
-                .unwrap_or(&"0.0.0.0".parse::<IpAddr>().unwrap()),
+            port: DEFAULT_PORT.to_string(),
+            user: String::new(),
+            label: String::new(),
+            cert_host: String::new(),
+            editing: None,
+            backup_passphrase: String::new(),
+            backup_replace: false,
+            backup_notice: String::new(),
+            backup: None,
+            available_printers: Vec::new(),
+            printer_name: None,
+            discovered_servers: Vec::new(),
+            discovery: None,
+            printer_fetch: None,
+            error,
+
+            // Start on the default printer, or restore the last selection if that printer still exists
+            selected_printer: settings
+                .default_printer
+                .or(settings.last_selected)
+                .filter(|ip| settings.printers.contains_key(ip))
+                .or_else(|| settings.printers.keys().next().copied())
+                .unwrap_or("0.0.0.0".parse::<IpAddr>().unwrap()),
+            print_options: PrintOptions::default(),
+            page_range: String::new(),
+            available_media: Vec::new(),
+            layout_supported: (true, true),
+            media_fetch: None,
             submit_result: None,
+            retry_files: Vec::new(),
+            sending: None,
+            jobs: Vec::new(),
+            job_updates: None,
+            connection_tests: HashMap::new(),
+            test_prints: HashMap::new(),
+            session_renewals: HashMap::new(),
+            untrusted_cert: None,
+            large_documents: None,
+            checking_large: None,
+            ui_scale: None,
+            send_progress: None,
+            cancel_send: Arc::new(AtomicBool::new(false)),
+            runtime: Arc::new(runtime),
+            pool,
             settings,
-            update_status: update_status,
+            update_status: VersionStatus::UpToDate,
+            update_check: Some(update_check),
+            updating: None,
+            update_progress: None,
         }
     }
 }
@@ -100,39 +525,363 @@ impl Interface {
     }
 }
 
+impl Default for Settings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Settings {
     pub fn new() -> Self {
         let printers: HashMap<IpAddr, Printer> = HashMap::new();
 
-        Settings { printers }
+        Settings {
+            printers,
+            last_selected: None,
+            default_printer: None,
+            connect_timeout: default_connect_timeout(),
+            pinned_certs: Default::default(),
+            recent_files: Vec::new(),
+            confirm_large: default_confirm_large(),
+            confirm_pages: DEFAULT_CONFIRM_PAGES,
+            confirm_mb: DEFAULT_CONFIRM_MB,
+            ui_scale: None,
+            theme: Theme::System,
+            rate_limit_kib: None,
+            idle_connection: default_idle_connection(),
+            unsaved: false,
+        }
+    }
+
+    /// Brings values edited by hand back in range, for every settings file read
+    pub(crate) fn clamp(&mut self) {
+        // Outside the slider's range the window can become unusable
+        self.ui_scale = self
+            .ui_scale
+            .filter(|scale| scale.is_finite())
+            .map(|scale| scale.clamp(*UI_SCALE_RANGE.start(), *UI_SCALE_RANGE.end()));
+    }
+
+    /// Certificate fingerprints trusted on first use, by [`crate::host_key`]
+    pub fn pinned_certs(&self) -> &std::collections::HashMap<String, String> {
+        &self.pinned_certs
+    }
+
+    /// Printer added in the GUI with `name` as its label (any case) or IP, and its URL
+    pub fn find_printer(&self, name: &str) -> anyhow::Result<(Url, &Printer)> {
+        let found = match name.parse::<IpAddr>() {
+            Ok(ip) => self.printers.get_key_value(&ip),
+            Err(_) => self
+                .printers
+                .iter()
+                .find(|(_, printer)| printer.label.eq_ignore_ascii_case(name)),
+        };
+        if let Some((ip, printer)) = found {
+            return Ok((printer_url(*ip, printer.port), printer));
+        }
+
+        let mut known: Vec<String> = self
+            .printers
+            .iter()
+            .map(|(ip, printer)| match printer.label.as_str() {
+                "" => ip.to_string(),
+                label => format!("{} ({})", label, ip),
+            })
+            .collect();
+        if known.is_empty() {
+            anyhow::bail!(
+                "no printer {:?}, no printers are set up yet, add them in the GUI",
+                name
+            );
+        }
+        known.sort();
+        anyhow::bail!(
+            "no printer {:?}, set up printers: {}",
+            name,
+            known.join(", ")
+        )
+    }
+
+    /// The default printer and its URL, if one is set
+    pub fn default_printer(&self) -> Option<(Url, &Printer)> {
+        let ip = self.default_printer?;
+        let printer = self.printers.get(&ip)?;
+        Some((printer_url(ip, printer.port), printer))
+    }
+
+    /// Writes the settings to disk, unless the settings file couldn't be loaded
+    fn save(&self) -> anyhow::Result<()> {
+        if self.unsaved {
+            anyhow::bail!("the settings file couldn't be loaded");
+        }
+        save_settings(self)
+    }
+
+    /// Makes `key` the default printer, or unsets it if it already is
+    fn toggle_default(&mut self, key: IpAddr) {
+        self.default_printer = match self.default_printer {
+            Some(ip) if ip == key => None,
+            _ => Some(key),
+        };
+
+        if let Err(e) = self.save() {
+            eprintln!("[Failed to update settings]: {}", e);
+        }
+    }
+
+    /// Trusts `fingerprint` for `host` from now on
+    pub fn pin_cert(&mut self, host: String, fingerprint: String) {
+        self.pinned_certs.insert(host, fingerprint);
+
+        if let Err(e) = self.save() {
+            eprintln!("[Failed to update settings]: {}", e);
+        }
+    }
+
+    /// Options every request from the GUI connects with
+    fn connect_options(&self) -> ConnectOptions {
+        ConnectOptions {
+            timeout: Duration::from_secs(self.connect_timeout),
+            pinned_certs: self.pinned_certs.clone(),
+            rate_limit: self.rate_limit_kib.map(|kib| kib.saturating_mul(1024)),
+            ..Default::default()
+        }
+    }
+
+    /// [`Settings::connect_options`] for `printer`, checking its certificate hostname
+    fn printer_connect_options(&self, printer: &Printer) -> ConnectOptions {
+        ConnectOptions {
+            host: printer.cert_host.clone(),
+            ..self.connect_options()
+        }
+    }
+
+    fn set_connect_timeout(&mut self, secs: u64) {
+        self.connect_timeout = secs;
+
+        if let Err(e) = self.save() {
+            eprintln!("[Failed to update settings]: {}", e);
+        }
+    }
+
+    fn set_theme(&mut self, theme: Theme) {
+        self.theme = theme;
+
+        if let Err(e) = self.save() {
+            eprintln!("[Failed to update settings]: {}", e);
+        }
+    }
+
+    fn set_ui_scale(&mut self, scale: Option<f32>) {
+        self.ui_scale = scale;
+
+        if let Err(e) = self.save() {
+            eprintln!("[Failed to update settings]: {}", e);
+        }
+    }
+
+    fn set_idle_connection(&mut self, secs: u64) {
+        self.idle_connection = secs;
+
+        if let Err(e) = self.save() {
+            eprintln!("[Failed to update settings]: {}", e);
+        }
+    }
+
+    fn set_rate_limit(&mut self, kib: Option<u64>) {
+        self.rate_limit_kib = kib;
+
+        if let Err(e) = self.save() {
+            eprintln!("[Failed to update settings]: {}", e);
+        }
+    }
+
+    fn set_confirm_large(&mut self, enabled: bool, pages: u32, mb: u64) {
+        self.confirm_large = enabled;
+        self.confirm_pages = pages;
+        self.confirm_mb = mb;
+
+        if let Err(e) = self.save() {
+            eprintln!("[Failed to update settings]: {}", e);
+        }
+    }
+
+    /// Moves `files` to the top of the recent files, dropping the oldest past [`MAX_RECENT_FILES`]
+    fn add_recent_files(&mut self, files: &[PathBuf]) {
+        for file in files {
+            self.recent_files.retain(|recent| recent != file);
+            self.recent_files.insert(0, file.clone());
+        }
+        self.recent_files.truncate(MAX_RECENT_FILES);
+
+        if let Err(e) = self.save() {
+            eprintln!("[Failed to update settings]: {}", e);
+        }
+    }
+
+    /// Forgets recent files that no longer exist
+    fn prune_recent_files(&mut self) {
+        self.recent_files.retain(|file| file.exists());
+
+        if let Err(e) = self.save() {
+            eprintln!("[Failed to update settings]: {}", e);
+        }
+    }
+
+    /// Replaces the session of printer `key`, if it's still there
+    fn set_session(&mut self, key: IpAddr, session: Session) {
+        let Some(printer) = self.printers.get_mut(&key) else {
+            return;
+        };
+        printer.session = Some(session);
+
+        if let Err(e) = self.save() {
+            eprintln!("[Failed to update settings]: {}", e);
+        }
+    }
+
+    /// Forgets every printer's session, they're only good on this machine's login
+    pub(crate) fn clear_sessions(&mut self) {
+        for printer in self.printers.values_mut() {
+            printer.session = None;
+        }
+    }
+
+    /// Adds the printers and trusted certificates of `imported`, replacing printers with
+    /// the same address. With `replace`, `imported` replaces all settings instead
+    fn import(&mut self, imported: Settings, replace: bool) {
+        if replace {
+            *self = Settings {
+                unsaved: self.unsaved,
+                ..imported
+            };
+        } else {
+            self.printers.extend(imported.printers);
+            self.pinned_certs.extend(imported.pinned_certs);
+        }
+
+        if let Err(e) = self.save() {
+            eprintln!("[Failed to update settings]: {}", e);
+        }
+    }
+
+    /// Remembers `key` as the selected printer
+    fn select(&mut self, key: IpAddr) {
+        self.last_selected = Some(key);
+
+        if let Err(e) = self.save() {
+            eprintln!("[Failed to update settings]: {}", e);
+        }
+    }
+
+    /// Adds `value` as the printer at `key`, or removes it, and saves.
+    /// `key` is the IP address as typed
+    fn update(&mut self, crud: Crud, key: String, value: Option<Printer>) -> anyhow::Result<()> {
+        self.change_printers(crud, &key, value)?;
+
+        if let Err(e) = self.save() {
+            eprintln!("[Failed to update settings]: {}", e);
+        }
+        Ok(())
     }
 
-    fn update(&mut self, crud: Crud, key: String, value: Option<String>) {
+    /// [`Settings::update`] without saving
+    fn change_printers(
+        &mut self,
+        crud: Crud,
+        key: &str,
+        value: Option<Printer>,
+    ) -> anyhow::Result<()> {
         match crud {
             Crud::Remove => {
-                self.printers.remove(&key.parse().unwrap());
+                // Nothing is stored under a key that doesn't parse, so it's already gone
+                if let Ok(ip) = key.trim().parse::<IpAddr>() {
+                    self.printers.remove(&ip);
+                    if self.default_printer == Some(ip) {
+                        self.default_printer = None;
+                    }
+                }
             }
             Crud::Add => {
-                if let Some(val) = value {
-                    let key = key.parse().unwrap();
-                    let printer = Printer::new(val);
-                    self.printers.insert(key, printer);
-                } else {
-                    panic!("Attempted to add to settings with no value");
+                let Some(printer) = value else {
+                    anyhow::bail!("No printer to add");
+                };
+                let ip = key
+                    .trim()
+                    .parse::<IpAddr>()
+                    .map_err(|_| anyhow::anyhow!("Invalid IP Address"))?;
+                self.printers.insert(ip, printer);
+            }
+            Crud::Edit(old) => {
+                let Some(mut printer) = value else {
+                    anyhow::bail!("No printer to add");
+                };
+                let ip = key
+                    .trim()
+                    .parse::<IpAddr>()
+                    .map_err(|_| anyhow::anyhow!("Invalid IP Address"))?;
+                if ip != old && self.printers.contains_key(&ip) {
+                    anyhow::bail!("Printer already added");
+                }
+                let Some(previous) = self.printers.remove(&old) else {
+                    anyhow::bail!("Printer was removed");
+                };
+                // The session only holds for the server and account it was made with
+                if ip == old
+                    && previous.port == printer.port
+                    && previous.user == printer.user
+                    && previous.pass == printer.pass
+                {
+                    printer.session = previous.session;
+                }
+                self.printers.insert(ip, printer);
+                if self.default_printer == Some(old) {
+                    self.default_printer = Some(ip);
+                }
+                if self.last_selected == Some(old) {
+                    self.last_selected = Some(ip);
                 }
             }
         }
-
-        // Save settings
-        if let Err(e) = save_settings(&self) {
-            eprintln!("[Failed to update settings]: {}", e);
-        }
+        Ok(())
     }
 }
 
 impl eframe::App for Interface {
-    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-        ctx.set_pixels_per_point(1.2);
+    fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
+        let dark = match self.settings.theme {
+            Theme::System => frame.info().system_theme != Some(eframe::Theme::Light),
+            Theme::Light => false,
+            Theme::Dark => true,
+        };
+        if ctx.style().visuals.dark_mode != dark {
+            ctx.set_visuals(if dark {
+                Visuals::dark()
+            } else {
+                Visuals::light()
+            });
+        }
+
+        // The system's scaling unless the user picked one
+        if let Some(scale) = self
+            .settings
+            .ui_scale
+            .or_else(|| ctx.native_pixels_per_point())
+        {
+            ctx.set_pixels_per_point(scale);
+        }
+        self.poll_send();
+        self.poll_jobs();
+        self.poll_connection_tests();
+        self.poll_media_fetch();
+        self.poll_printer_fetch();
+        self.poll_discovery();
+        self.poll_test_prints();
+        self.renew_sessions(ctx);
+        self.poll_update_check(ctx);
+        self.poll_update();
+        self.poll_large_check(ctx);
+        self.poll_backup();
 
         egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
             egui::menu::bar(ui, |ui| {
@@ -151,20 +900,51 @@ impl eframe::App for Interface {
             });
         });
 
-        self.render_page(&ctx);
+        // Escape leaves sub-pages, a focused text field gets it first to drop focus
+        if matches!(self.current_page, Page::NewPrinter | Page::RemovePrinter)
+            && ctx.memory(|m| m.focus().is_none())
+            && ctx.input_mut(|i| i.consume_key(Modifiers::NONE, Key::Escape))
+        {
+            self.back_to_settings();
+        }
+
+        self.render_page(ctx);
+        self.certificate_prompt(ctx);
+        self.large_document_prompt(ctx);
+    }
+    /// Tells printers their pooled connections are done, rather than leaving them to time out
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        self.runtime.block_on(self.pool.close_all());
     }
 }
 
 impl Interface {
     fn home_page(&mut self, ctx: &Context) {
+        // Ctrl+P prints, so does Enter once a file is picked unless a text field has focus.
+        // Focus is checked before any widget runs, fields drop it on Enter
+        let has_file =
+            !self.text_mode && (self.picked_path.is_some() || !self.dropped_files.is_empty());
+        let shortcut = ctx.input_mut(|i| i.consume_key(Modifiers::COMMAND, Key::P))
+            || (has_file
+                && ctx.memory(|m| m.focus().is_none())
+                && ctx.input(|i| i.key_pressed(Key::Enter)));
+
         egui::CentralPanel::default().show(ctx, |ui| {
-            ui.label(
-                egui::RichText::new("Printing")
-                    .heading()
-                    .color(egui::Color32::from_rgb(255, 255, 255)),
-            );
+            ui.label(RichText::new("Printing").heading().strong());
+
+            ui.horizontal(|ui| {
+                ui.selectable_value(&mut self.text_mode, false, "File");
+                ui.selectable_value(&mut self.text_mode, true, "Print text");
+            });
 
-            if !self.dropped_files.is_empty() {
+            if self.text_mode {
+                ui.add(
+                    egui::TextEdit::multiline(&mut self.text)
+                        .hint_text("Type or paste text to print")
+                        .desired_rows(8)
+                        .desired_width(f32::INFINITY),
+                );
+            } else if !self.dropped_files.is_empty() {
                 if ui.button("Clear dropped files").clicked() {
                     self.dropped_files.clear();
                 }
@@ -213,6 +993,10 @@ impl Interface {
                         ui.monospace(picked_path);
                     });
                 }
+
+                if !self.settings.recent_files.is_empty() {
+                    self.recent_files(ui);
+                }
             }
 
             preview_files_being_dropped(ctx);
@@ -220,21 +1004,45 @@ impl Interface {
             ctx.input(|i| {
                 if !i.raw.dropped_files.is_empty() {
                     self.dropped_files = i.raw.dropped_files.clone();
+                    self.text_mode = false;
                 }
             });
 
             ui.add_space(8.0);
-            if &self.settings.printers.len() > &0 {
-                let selected = &self.selected_printer;
+            if !self.settings.printers.is_empty() {
+                let mut printers: Vec<(IpAddr, String)> = self
+                    .settings
+                    .printers
+                    .iter()
+                    .map(|(ip, printer)| (*ip, printer_label(*ip, printer)))
+                    .collect();
+                printers.sort_by(|a, b| a.1.cmp(&b.1));
+                let selected = printers
+                    .iter()
+                    .find(|(ip, _)| *ip == self.selected_printer)
+                    .map_or_else(
+                        || self.selected_printer.to_string(),
+                        |(_, label)| label.clone(),
+                    );
 
                 egui::ComboBox::from_label("Selected Printer")
-                    .selected_text(format!("{selected:?}"))
+                    .selected_text(selected)
                     .show_ui(ui, |ui| {
                         ui.style_mut().wrap = Some(false);
                         ui.set_min_width(60.0);
 
-                        for key in self.settings.printers.keys() {
-                            ui.selectable_value(&mut self.selected_printer, *key, key.to_string());
+                        for (key, label) in printers {
+                            if ui
+                                .selectable_value(&mut self.selected_printer, key, label)
+                                .on_hover_text(key.to_string())
+                                .changed()
+                            {
+                                self.settings.select(key);
+                                // Media and layout support differ between printers
+                                self.available_media.clear();
+                                self.print_options.media = None;
+                                self.layout_supported = (true, true);
+                            }
                         }
                     });
             } else {
@@ -242,14 +1050,155 @@ impl Interface {
             }
 
             ui.add_space(8.0);
-            self.send_button(ui);
+            ui.horizontal(|ui| {
+                ui.add(
+                    egui::DragValue::new(&mut self.print_options.copies)
+                        .clamp_range(1..=MAX_COPIES)
+                        .speed(0.1),
+                );
+                ui.label("Copies");
+                ui.add_space(16.0);
+                ui.checkbox(&mut self.print_options.duplex, "Double-sided");
+                ui.checkbox(&mut self.print_options.compress, "Compress")
+                    .on_hover_text("Speeds up slow connections; not useful for PNG or PDF");
+                ui.checkbox(&mut self.print_options.dry_run, "Dry run")
+                    .on_hover_text("Check the server accepts the file without printing it");
+            });
+
+            // Page numbers only make sense for a single document
+            let single_file = self.text_mode
+                || self.dropped_files.len() == 1
+                || (self.dropped_files.is_empty() && self.picked_path.is_some());
+            ui.horizontal(|ui| {
+                ui.add_enabled(
+                    single_file,
+                    egui::TextEdit::singleline(&mut self.page_range)
+                        .hint_text("All")
+                        .desired_width(100.0),
+                );
+                ui.label("Pages").on_hover_text("e.g. 3-7,10");
+            });
+
+            if !self.settings.printers.is_empty() {
+                ui.horizontal(|ui| {
+                    egui::ComboBox::from_label("Media")
+                        .selected_text(
+                            self.print_options
+                                .media
+                                .clone()
+                                .unwrap_or_else(|| "Printer default".to_string()),
+                        )
+                        .show_ui(ui, |ui| {
+                            ui.style_mut().wrap = Some(false);
+                            ui.selectable_value(
+                                &mut self.print_options.media,
+                                None,
+                                "Printer default",
+                            );
+
+                            for media in &self.available_media {
+                                ui.selectable_value(
+                                    &mut self.print_options.media,
+                                    Some(media.clone()),
+                                    media.as_str(),
+                                );
+                            }
+                        });
+
+                    if ui
+                        .add_enabled(self.media_fetch.is_none(), egui::Button::new("Fetch media"))
+                        .clicked()
+                    {
+                        self.fetch_media(ctx);
+                    }
+                });
+            }
+
+            let (landscape, fit_to_page) = self.layout_supported;
+            ui.horizontal(|ui| {
+                ui.add_enabled(
+                    landscape,
+                    egui::Checkbox::new(&mut self.print_options.landscape, "Landscape"),
+                )
+                .on_disabled_hover_text("Not supported by this printer");
+                ui.add_enabled(
+                    fit_to_page,
+                    egui::Checkbox::new(&mut self.print_options.fit_to_page, "Fit to page"),
+                )
+                .on_disabled_hover_text("Not supported by this printer");
+            });
+
+            if self.images_selected() {
+                ui.horizontal(|ui| {
+                    let mut scale = self.print_options.scaling.is_some();
+                    if ui
+                        .checkbox(&mut scale, "Scale image")
+                        .on_hover_text("Percent of the page the image covers, over 100 enlarges it")
+                        .changed()
+                    {
+                        self.print_options.scaling = scale.then_some(100);
+                    }
+                    if let Some(scaling) = &mut self.print_options.scaling {
+                        ui.add(
+                            egui::DragValue::new(scaling)
+                                .clamp_range(1..=MAX_SCALING)
+                                .suffix("%"),
+                        );
+                    }
+                    ui.add_space(16.0);
+
+                    egui::ComboBox::from_label("Position")
+                        .selected_text(
+                            self.print_options
+                                .position
+                                .clone()
+                                .unwrap_or_else(|| "Printer default".to_string()),
+                        )
+                        .show_ui(ui, |ui| {
+                            ui.style_mut().wrap = Some(false);
+                            ui.selectable_value(
+                                &mut self.print_options.position,
+                                None,
+                                "Printer default",
+                            );
+                            for position in POSITIONS {
+                                ui.selectable_value(
+                                    &mut self.print_options.position,
+                                    Some(position.to_string()),
+                                    *position,
+                                );
+                            }
+                        });
+                });
+            }
+
+            ui.add_space(8.0);
+            self.send_button(ui, shortcut);
 
-            if let Some(value) = self.submit_result.clone() {
-                if value.1.elapsed() >= Duration::from_secs(10) {
+            if let Some((lines, time)) = self.submit_result.clone() {
+                if time.elapsed() >= Duration::from_secs(10) {
                     self.submit_result = None;
+                    self.retry_files.clear();
+                }
+
+                for (line, kind) in lines {
+                    ui.label(RichText::new(line).color(kind.color(ui.visuals())));
                 }
 
-                ui.label(value.0.clone());
+                if !self.retry_files.is_empty()
+                    && self.sending.is_none()
+                    && ui.button("Retry").clicked()
+                {
+                    let files = std::mem::take(&mut self.retry_files);
+                    self.start_send(ui.ctx(), files, false);
+                }
+            }
+
+            for (label, state) in &self.jobs {
+                ui.label(
+                    RichText::new(format!("{}: {}", label, state))
+                        .color(job_state_color(*state, ui.visuals())),
+                );
             }
 
             if !self.error.is_empty() {
@@ -273,21 +1222,93 @@ impl Interface {
 
     fn settings_page(&mut self, ctx: &Context) {
         egui::CentralPanel::default().show(ctx, |ui| {
-            ui.label(
-                egui::RichText::new("Available Printers")
-                    .heading()
-                    .color(egui::Color32::from_rgb(255, 255, 255)),
-            );
+            ui.label(RichText::new("Available Printers").heading().strong());
             ui.group(|ui| {
-                if self.settings.printers.len() != 0 {
-                    for printer in self.settings.printers.clone().keys() {
+                if !self.settings.printers.is_empty() {
+                    for (printer, config) in self.settings.printers.clone() {
                         ui.horizontal(|ui| {
-                            ui.label(printer.to_string());
+                            let is_default = self.settings.default_printer == Some(printer);
+                            let star = if is_default { "★" } else { "☆" };
+                            if ui
+                                .selectable_label(is_default, star)
+                                .on_hover_text(if is_default {
+                                    "Default printer, click to unset"
+                                } else {
+                                    "Make this the default printer"
+                                })
+                                .clicked()
+                            {
+                                self.settings.toggle_default(printer);
+                            }
+                            ui.label(printer_label(printer, &config))
+                                .on_hover_text(printer.to_string());
+                            if let Some(name) = &config.printer_name {
+                                ui.label(RichText::new(name).weak());
+                            }
+                            let renewal = self.session_renewals.get(&printer);
+                            ui.label(RichText::new(session_status(&config, renewal)).weak());
                             ui.add_space(3.0);
+                            if ui.button("Edit").clicked() {
+                                self.edit_printer(printer, &config);
+                            }
                             if ui.button("Remove").clicked() {
                                 self.carry = printer.to_string();
                                 self.current_page = Page::RemovePrinter;
                             }
+
+                            let running = matches!(
+                                self.connection_tests.get(&printer),
+                                Some(ConnectionTest::Running(_))
+                            );
+                            if ui
+                                .add_enabled(!running, egui::Button::new("Test"))
+                                .clicked()
+                            {
+                                self.test_connection(ui.ctx(), printer, &config);
+                            }
+
+                            match self.connection_tests.get(&printer) {
+                                Some(ConnectionTest::Running(_)) => {
+                                    ui.spinner();
+                                }
+                                Some(ConnectionTest::Passed(latency)) => {
+                                    ui.label(
+                                        RichText::new(format!("✔ {} ms", latency.as_millis()))
+                                            .color(success_color(ui.visuals())),
+                                    );
+                                }
+                                Some(ConnectionTest::Failed(e)) => {
+                                    ui.label(RichText::new(format!("✖ {}", e)).color(Color32::RED));
+                                }
+                                None => (),
+                            }
+
+                            let printing = matches!(
+                                self.test_prints.get(&printer),
+                                Some(TestPrint::Running(_))
+                            );
+                            if ui
+                                .add_enabled(!printing, egui::Button::new("Print test page"))
+                                .on_hover_text("Print a short page saying it's a test")
+                                .clicked()
+                            {
+                                self.print_test_page(ui.ctx(), printer, &config);
+                            }
+                            match self.test_prints.get(&printer) {
+                                Some(TestPrint::Running(_)) => {
+                                    ui.spinner();
+                                }
+                                Some(TestPrint::Done(Ok(outcome))) => {
+                                    ui.label(
+                                        RichText::new(format!("✔ {}", outcome))
+                                            .color(success_color(ui.visuals())),
+                                    );
+                                }
+                                Some(TestPrint::Done(Err(e))) => {
+                                    ui.label(RichText::new(format!("✖ {}", e)).color(Color32::RED));
+                                }
+                                None => (),
+                            }
                         });
                     }
                 } else {
@@ -299,10 +1320,176 @@ impl Interface {
                 .add(egui::Button::new(RichText::new("Add a Printer")))
                 .clicked()
             {
+                self.clear_printer_form();
                 self.current_page = Page::NewPrinter;
             }
 
-            ui.separator();
+            ui.add_space(8.0);
+            ui.horizontal(|ui| {
+                let mut secs = self.settings.connect_timeout;
+                if ui
+                    .add(
+                        egui::DragValue::new(&mut secs)
+                            .clamp_range(1..=120)
+                            .suffix(" s"),
+                    )
+                    .changed()
+                {
+                    self.settings.set_connect_timeout(secs);
+                }
+                ui.label("Connect timeout");
+            });
+
+            ui.horizontal(|ui| {
+                let mut secs = self.settings.idle_connection;
+                if ui
+                    .add(
+                        egui::DragValue::new(&mut secs)
+                            .clamp_range(0..=3600)
+                            .suffix(" s"),
+                    )
+                    .changed()
+                {
+                    self.settings.set_idle_connection(secs);
+                    self.pool.set_idle_timeout(Duration::from_secs(secs));
+                }
+                ui.label("Keep connections open")
+                    .on_hover_text("How long an unused connection to a printer is kept for the next print, 0 to close it after each print");
+            });
+
+            ui.horizontal(|ui| {
+                let mut limited = self.settings.rate_limit_kib.is_some();
+                let mut kib = self
+                    .settings
+                    .rate_limit_kib
+                    .unwrap_or(DEFAULT_RATE_LIMIT_KIB);
+                let toggled = ui.checkbox(&mut limited, "Limit upload speed").changed();
+                let dragged = ui
+                    .add_enabled(
+                        limited,
+                        egui::DragValue::new(&mut kib)
+                            .clamp_range(1..=1_000_000)
+                            .suffix(" KiB/s"),
+                    )
+                    .changed();
+                if toggled || dragged {
+                    self.settings.set_rate_limit(limited.then_some(kib));
+                }
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Theme");
+                let mut theme = self.settings.theme;
+                ui.selectable_value(&mut theme, Theme::System, "System");
+                ui.selectable_value(&mut theme, Theme::Light, "Light");
+                ui.selectable_value(&mut theme, Theme::Dark, "Dark");
+                if theme != self.settings.theme {
+                    self.settings.set_theme(theme);
+                }
+            });
+
+            ui.horizontal(|ui| {
+                let mut custom = self.settings.ui_scale.is_some();
+                if ui.checkbox(&mut custom, "Scale").changed() {
+                    let scale = custom.then(|| ctx.pixels_per_point());
+                    self.settings.set_ui_scale(scale);
+                }
+
+                // Applied when let go, rescaling mid-drag moves the slider under the cursor
+                let mut scale = self
+                    .ui_scale
+                    .or(self.settings.ui_scale)
+                    .unwrap_or_else(|| ctx.pixels_per_point());
+                let slider = ui.add_enabled(
+                    custom,
+                    egui::Slider::new(&mut scale, UI_SCALE_RANGE).step_by(0.1),
+                );
+                if slider.dragged() {
+                    self.ui_scale = Some(scale);
+                } else if slider.drag_released() || slider.changed() {
+                    self.ui_scale = None;
+                    self.settings.set_ui_scale(Some(scale));
+                }
+                if !custom {
+                    ui.label(RichText::new("System").weak());
+                }
+            });
+
+            ui.horizontal(|ui| {
+                let mut enabled = self.settings.confirm_large;
+                let mut pages = self.settings.confirm_pages;
+                let mut mb = self.settings.confirm_mb;
+                let mut changed = ui
+                    .checkbox(&mut enabled, "Confirm documents over")
+                    .changed();
+                ui.add_enabled_ui(enabled, |ui| {
+                    changed |= ui
+                        .add(
+                            egui::DragValue::new(&mut pages)
+                                .clamp_range(1..=10_000)
+                                .suffix(" pages"),
+                        )
+                        .changed();
+                    ui.label("or");
+                    changed |= ui
+                        .add(
+                            egui::DragValue::new(&mut mb)
+                                .clamp_range(1..=1_000)
+                                .suffix(" MB"),
+                        )
+                        .changed();
+                });
+                if changed {
+                    self.settings.set_confirm_large(enabled, pages, mb);
+                }
+            });
+
+            ui.add_space(8.0);
+            ui.label(RichText::new("Backup").strong());
+            ui.horizontal(|ui| {
+                ui.label("Passphrase");
+                ui.add(
+                    egui::TextEdit::singleline(&mut self.backup_passphrase)
+                        .password(true)
+                        .hint_text("Optional"),
+                );
+            });
+            if self.backup_passphrase.is_empty() {
+                ui.label(
+                    RichText::new(
+                        "Without a passphrase, exports hold printer passwords in plain text",
+                    )
+                    .weak(),
+                );
+            }
+            ui.checkbox(
+                &mut self.backup_replace,
+                "Importing replaces current settings",
+            );
+            ui.horizontal(|ui| {
+                let idle = self.backup.is_none();
+                if ui.add_enabled(idle, egui::Button::new("Export…")).clicked() {
+                    self.export_settings(ui.ctx());
+                }
+                if ui.add_enabled(idle, egui::Button::new("Import…")).clicked() {
+                    self.import_settings(ui.ctx());
+                }
+                if !idle {
+                    ui.spinner();
+                }
+            });
+            if !self.backup_notice.is_empty() {
+                ui.label(RichText::new(&self.backup_notice).color(success_color(ui.visuals())));
+            }
+            if !self.error.is_empty() {
+                ui.label(
+                    RichText::new(self.error.clone())
+                        .color(Color32::RED)
+                        .strong(),
+                );
+            }
+
+            ui.separator();
 
             ui.with_layout(egui::Layout::bottom_up(egui::Align::LEFT), |ui| {
                 footer(ui);
@@ -328,16 +1515,22 @@ impl Interface {
 
                 ui.horizontal(|ui| {
                     if ui.add_sized([80., 30.], egui::Button::new("Yes")).clicked() {
-                        self.settings.update(Crud::Remove, instruction, None);
-                        self.current_page = Page::Settings;
-                        self.carry = String::new();
+                        if let Ok(ip) = instruction.parse::<IpAddr>() {
+                            self.forget_printer(ip);
+                        }
+                        match self.settings.update(Crud::Remove, instruction, None) {
+                            Ok(()) => {
+                                self.current_page = Page::Settings;
+                                self.carry = String::new();
+                            }
+                            Err(e) => self.error = format!("{:#}", e),
+                        }
                     }
 
                     ui.add_space(20.);
 
                     if ui.add_sized([80., 30.], egui::Button::new("No")).clicked() {
-                        self.current_page = Page::Settings;
-                        self.carry = String::new();
+                        self.back_to_settings();
                     }
                 });
             });
@@ -347,48 +1540,150 @@ impl Interface {
     }
 
     fn new_printer(&mut self, ctx: &Context) {
+        // Enter confirms the form, the fields are all single line. Not while a dropdown is open
+        let submit =
+            ctx.input(|i| i.key_pressed(Key::Enter)) && !ctx.memory(|m| m.any_popup_open());
+
         egui::CentralPanel::default().show(ctx, |ui| {
-            ui.label(RichText::new("Add a Printer"));
+            ui.label(RichText::new(match self.editing {
+                Some(_) => "Edit Printer",
+                None => "Add a Printer",
+            }));
 
             ui.separator();
 
+            let scanning = self.discovery.is_some();
+            let scan = egui::Button::new(if scanning {
+                "Scanning..."
+            } else {
+                "Scan network"
+            });
+            if ui.add_enabled(!scanning, scan).clicked() {
+                self.discover_servers(ctx);
+            }
+
+            for (name, addr) in self.discovered_servers.clone() {
+                if ui.button(format!("{} ({})", name, addr)).clicked() {
+                    self.string = addr.ip().to_string();
+                    self.port = addr.port().to_string();
+                    // Generated certificates are valid for the `.local` name
+                    self.cert_host = name;
+                }
+            }
+
+            ui.add_space(8.);
+
             ui.horizontal(|ui| {
                 ui.add(egui::TextEdit::singleline(&mut self.string).hint_text("IP Address"));
                 ui.label("Remote IP");
             });
 
+            ui.horizontal(|ui| {
+                ui.add(egui::TextEdit::singleline(&mut self.port).hint_text("Port"));
+                ui.label("Port");
+            });
+
+            ui.horizontal(|ui| {
+                ui.add(
+                    egui::TextEdit::singleline(&mut self.label).hint_text("Optional, e.g. Kitchen"),
+                );
+                ui.label("Label");
+            });
+
+            ui.horizontal(|ui| {
+                ui.add(
+                    egui::TextEdit::singleline(&mut self.cert_host)
+                        .hint_text("Optional, the IP if blank"),
+                );
+                ui.label("Certificate hostname")
+                    .on_hover_text("Name the server's certificate must be valid for");
+            });
+
             ui.add_space(20.);
 
+            ui.horizontal(|ui| {
+                ui.add(egui::TextEdit::singleline(&mut self.user).hint_text("Optional"));
+                ui.label("Username");
+            });
+
             ui.horizontal(|ui| {
                 password_ui(ui, &mut self.carry);
                 ui.label("Password");
             });
 
-            ui.separator();
+            ui.add_space(8.);
 
             ui.horizontal(|ui| {
                 if ui
-                    .add_sized([80., 30.], egui::Button::new("Done"))
+                    .add_enabled(
+                        self.printer_fetch.is_none(),
+                        egui::Button::new("Fetch printers"),
+                    )
                     .clicked()
                 {
-                    let is_valid = &self.string.parse(); // Simply tests if valid address
-
-                    if !self.carry.is_empty() && !self.string.is_empty() && is_valid.is_ok() {
-                        if !self
-                            .settings
-                            .printers
-                            .contains_key(&is_valid.clone().unwrap())
-                        {
-                            self.settings.update(
-                                Crud::Add,
-                                self.string.clone(),
-                                Some(self.carry.clone()),
+                    self.fetch_printers(ctx);
+                }
+
+                egui::ComboBox::from_label("Printer")
+                    .selected_text(
+                        self.printer_name
+                            .clone()
+                            .unwrap_or_else(|| "Server default".to_string()),
+                    )
+                    .show_ui(ui, |ui| {
+                        ui.style_mut().wrap = Some(false);
+                        ui.selectable_value(&mut self.printer_name, None, "Server default");
+
+                        for name in &self.available_printers {
+                            ui.selectable_value(
+                                &mut self.printer_name,
+                                Some(name.clone()),
+                                name.as_str(),
                             );
+                        }
+                    });
+            });
 
-                            self.current_page = Page::Settings;
-                            self.carry = String::new();
-                            self.string = String::new();
-                            self.error = String::new();
+            ui.separator();
+
+            ui.horizontal(|ui| {
+                if ui
+                    .add_sized([80., 30.], egui::Button::new("Done"))
+                    .clicked()
+                    || submit
+                {
+                    let is_valid = self.string.trim().parse::<IpAddr>();
+                    let port = self.port.trim().parse::<u16>().unwrap_or(0);
+
+                    if port == 0 {
+                        self.error = String::from("Invalid Port");
+                    } else if let (false, Ok(ip)) = (self.carry.is_empty(), &is_valid) {
+                        if self.editing == Some(*ip) || !self.settings.printers.contains_key(ip) {
+                            let mut printer = Printer::new(self.carry.clone(), port);
+                            printer.printer_name = self.printer_name.clone();
+                            printer.user = self.optional_user();
+                            printer.label = self.label.trim().to_string();
+                            printer.cert_host = self.optional_cert_host();
+                            let crud = match self.editing {
+                                Some(old) => Crud::Edit(old),
+                                None => Crud::Add,
+                            };
+                            match self
+                                .settings
+                                .update(crud, self.string.clone(), Some(printer))
+                            {
+                                Ok(()) => {
+                                    if let Some(old) = self.editing {
+                                        self.forget_printer(old);
+                                        if self.selected_printer == old {
+                                            self.selected_printer = *ip;
+                                        }
+                                    }
+                                    self.back_to_settings();
+                                    self.error = String::new();
+                                }
+                                Err(e) => self.error = format!("{:#}", e),
+                            }
                         } else {
                             self.error = String::from("Printer already added");
                         }
@@ -405,9 +1700,7 @@ impl Interface {
                     .add_sized([80., 30.], egui::Button::new("Cancel"))
                     .clicked()
                 {
-                    self.current_page = Page::Settings;
-                    self.carry = String::new();
-                    self.string = String::new();
+                    self.back_to_settings();
                 }
             });
 
@@ -421,76 +1714,973 @@ impl Interface {
         });
     }
 
-    fn send_button(&mut self, ui: &mut egui::Ui) {
-        if ui
-            .add_sized([80., 30.], egui::Button::new("Print File"))
+    /// Passphrase entered on the settings page, `None` if left blank
+    fn backup_passphrase(&self) -> Option<&str> {
+        (!self.backup_passphrase.is_empty()).then_some(self.backup_passphrase.as_str())
+    }
+
+    /// Asks where to save the settings and exports them there on a worker thread
+    fn export_settings(&mut self, ctx: &Context) {
+        self.backup_notice.clear();
+        self.error.clear();
+        let Some(path) = rfd::FileDialog::new()
+            .set_file_name("remote_print_settings.json")
+            .add_filter("JSON", &["json"])
+            .save_file()
+        else {
+            return;
+        };
+
+        let settings = self.settings.clone();
+        let passphrase = self.backup_passphrase().map(str::to_string);
+        self.spawn_backup(ctx, move || {
+            crate::export_settings(&settings, &path, passphrase.as_deref())
+                .map_err(|e| e.context("Failed to export settings"))?;
+            Ok(Backup::Exported(path))
+        });
+    }
+
+    /// Asks for an export and imports it on a worker thread, see [`Interface::poll_backup`]
+    fn import_settings(&mut self, ctx: &Context) {
+        self.backup_notice.clear();
+        self.error.clear();
+        let Some(path) = rfd::FileDialog::new()
+            .add_filter("JSON", &["json"])
+            .pick_file()
+        else {
+            return;
+        };
+
+        let passphrase = self.backup_passphrase().map(str::to_string);
+        self.spawn_backup(ctx, move || {
+            let imported = crate::import_settings(&path, passphrase.as_deref())
+                .map_err(|e| e.context("Failed to import settings"))?;
+            Ok(Backup::Imported(Box::new(imported)))
+        });
+    }
+
+    /// Runs `backup` on a worker thread, the key derivation would stall the UI
+    fn spawn_backup(
+        &mut self,
+        ctx: &Context,
+        backup: impl FnOnce() -> anyhow::Result<Backup> + Send + 'static,
+    ) {
+        let (tx, rx) = mpsc::channel();
+        let ctx = ctx.clone();
+        std::thread::spawn(move || {
+            let _ = tx.send(backup());
+            ctx.request_repaint();
+        });
+        self.backup = Some(rx);
+    }
+
+    /// Shows the result of a finished export, or adds or swaps in imported settings
+    fn poll_backup(&mut self) {
+        let Some(rx) = &self.backup else {
+            return;
+        };
+
+        match rx.try_recv() {
+            Ok(Ok(Backup::Imported(imported))) => self.apply_import(*imported),
+            Ok(Ok(Backup::Exported(path))) => {
+                self.backup_notice = format!("Exported to {}", path.display())
+            }
+            Ok(Err(e)) => self.error = format!("{:#}", e),
+            Err(TryRecvError::Empty) => return,
+            Err(TryRecvError::Disconnected) => {}
+        }
+        self.backup = None;
+    }
+
+    /// Adds `imported` to or swaps it in for the current settings
+    fn apply_import(&mut self, imported: Settings) {
+        let count = imported.printers.len();
+        self.settings.import(imported, self.backup_replace);
+
+        // Anything tracked for the old printers may no longer apply
+        self.connection_tests.clear();
+        self.test_prints.clear();
+        self.session_renewals.clear();
+        if !self.settings.printers.contains_key(&self.selected_printer) {
+            if let Some(key) = self.settings.printers.keys().next() {
+                self.selected_printer = *key;
+            }
+        }
+        self.backup_notice = format!("Imported {} printers", count);
+    }
+
+    /// Leaves a sub-page without saving, clearing anything entered on it
+    fn back_to_settings(&mut self) {
+        self.current_page = Page::Settings;
+        self.clear_printer_form();
+    }
+
+    /// Opens the Add Printer page filled in with `printer` at `ip`, to change it
+    fn edit_printer(&mut self, ip: IpAddr, printer: &Printer) {
+        self.clear_printer_form();
+        self.editing = Some(ip);
+        self.string = ip.to_string();
+        self.port = printer.port.to_string();
+        self.label = printer.label.clone();
+        self.cert_host = printer.cert_host.clone().unwrap_or_default();
+        self.user = printer.user.clone().unwrap_or_default();
+        self.carry = printer.pass.clone();
+        self.printer_name = printer.printer_name.clone();
+        self.error = String::new();
+        self.current_page = Page::NewPrinter;
+    }
+
+    /// Drops what's tracked for the printer at `ip`, it's about one that's gone or changed
+    fn forget_printer(&mut self, ip: IpAddr) {
+        self.connection_tests.remove(&ip);
+        self.test_prints.remove(&ip);
+        self.session_renewals.remove(&ip);
+    }
+
+    /// Clears everything entered on the Add Printer page
+    fn clear_printer_form(&mut self) {
+        self.editing = None;
+        self.carry = String::new();
+        self.user = String::new();
+        self.label = String::new();
+        self.cert_host = String::new();
+        self.string = String::new();
+        self.port = DEFAULT_PORT.to_string();
+        self.available_printers.clear();
+        self.printer_fetch = None;
+        self.discovered_servers.clear();
+        self.discovery = None;
+        self.printer_name = None;
+    }
+
+    /// Username entered on the Add Printer page, `None` if left blank
+    fn optional_user(&self) -> Option<String> {
+        let user = self.user.trim();
+        (!user.is_empty()).then(|| user.to_string())
+    }
+
+    /// Certificate hostname entered on the Add Printer page, `None` if left blank
+    fn optional_cert_host(&self) -> Option<String> {
+        let host = self.cert_host.trim();
+        (!host.is_empty()).then(|| host.to_string())
+    }
+
+    /// Looks for servers on the local network on a worker thread, see [`Interface::poll_discovery`]
+    fn discover_servers(&mut self, ctx: &Context) {
+        let (tx, rx) = mpsc::channel();
+        let ctx = ctx.clone();
+
+        std::thread::spawn(move || {
+            let _ = tx.send(crate::discover_servers(DISCOVERY_TIMEOUT));
+            ctx.request_repaint();
+        });
+
+        self.discovery = Some(rx);
+    }
+
+    /// Lists the servers found once the scan is over
+    fn poll_discovery(&mut self) {
+        let Some(rx) = &self.discovery else {
+            return;
+        };
+
+        let result = match rx.try_recv() {
+            Ok(result) => result,
+            Err(TryRecvError::Empty) => return,
+            Err(TryRecvError::Disconnected) => {
+                self.discovery = None;
+                return;
+            }
+        };
+        self.discovery = None;
+
+        match result {
+            Ok(servers) if servers.is_empty() => self.error = String::from("No servers found"),
+            Ok(servers) => {
+                self.discovered_servers = servers;
+                self.error = String::new();
+            }
+            Err(e) => self.error = format!("Failed to scan network: {}", e),
+        }
+    }
+
+    /// Lists the printers of the server entered on the Add Printer page on a worker thread,
+    /// see [`Interface::poll_printer_fetch`]
+    fn fetch_printers(&mut self, ctx: &Context) {
+        let port = self.port.trim().parse::<u16>().unwrap_or(0);
+        let Ok(ip) = self.string.trim().parse::<IpAddr>() else {
+            self.error = String::from("Invalid IP Address");
+            return;
+        };
+        if port == 0 {
+            self.error = String::from("Invalid Port");
+            return;
+        } else if self.carry.is_empty() {
+            self.error = String::from("Missing Input");
+            return;
+        }
+
+        let url = printer_url(ip, port);
+        let connect = ConnectOptions {
+            host: self.optional_cert_host(),
+            ..self.settings.connect_options()
+        };
+        let (tx, rx) = mpsc::channel();
+        let ctx = ctx.clone();
+        let user = self.optional_user();
+        let pass = self.carry.clone();
+
+        std::thread::spawn(move || {
+            let _ = tx.send(crate::list_printers(url, &connect, user, pass));
+            ctx.request_repaint();
+        });
+
+        self.printer_fetch = Some(rx);
+    }
+
+    /// Fills `available_printers` once listed, unless the form was cleared meanwhile
+    fn poll_printer_fetch(&mut self) {
+        let Some(rx) = &self.printer_fetch else {
+            return;
+        };
+
+        let result = match rx.try_recv() {
+            Ok(result) => result,
+            Err(TryRecvError::Empty) => return,
+            Err(TryRecvError::Disconnected) => {
+                self.printer_fetch = None;
+                return;
+            }
+        };
+        self.printer_fetch = None;
+
+        match result {
+            Ok(printers) => {
+                self.available_printers = printers;
+                self.error = String::new();
+            }
+            Err(e) => {
+                self.untrusted_cert = match e {
+                    PrintError::UntrustedCertificate(ref cert) => Some(cert.clone()),
+                    _ => None,
+                };
+                self.error = format!("Failed to fetch printers: {}", e);
+            }
+        }
+    }
+
+    /// Fetches the options of the selected printer on a worker thread,
+    /// see [`Interface::poll_media_fetch`]
+    fn fetch_media(&mut self, ctx: &Context) {
+        let Some(printer) = self.settings.printers.get(&self.selected_printer) else {
+            return;
+        };
+
+        let url = printer_url(self.selected_printer, printer.port);
+        let (tx, rx) = mpsc::channel();
+        let ctx = ctx.clone();
+        let connect = self.settings.printer_connect_options(printer);
+        let user = printer.user.clone();
+        let pass = printer.pass.clone();
+        let printer_name = printer.printer_name.clone();
+
+        std::thread::spawn(move || {
+            let result = crate::printer_options(url, &connect, user, pass, printer_name.as_deref());
+            let _ = tx.send(result);
+            ctx.request_repaint();
+        });
+
+        self.media_fetch = Some((self.selected_printer, rx));
+    }
+
+    /// Fills `available_media` once fetched, unless another printer was selected meanwhile
+    fn poll_media_fetch(&mut self) {
+        let Some((ip, rx)) = &self.media_fetch else {
+            return;
+        };
+
+        let result = match rx.try_recv() {
+            Ok(result) => result,
+            Err(TryRecvError::Empty) => return,
+            Err(TryRecvError::Disconnected) => {
+                self.media_fetch = None;
+                return;
+            }
+        };
+        let fetched_for = *ip;
+        self.media_fetch = None;
+        if fetched_for != self.selected_printer {
+            return;
+        }
+
+        match result {
+            Ok(options) => {
+                self.available_media = options.media;
+                self.layout_supported = (options.landscape, options.fit_to_page);
+                if !options.landscape {
+                    self.print_options.landscape = false;
+                }
+                if !options.fit_to_page {
+                    self.print_options.fit_to_page = false;
+                }
+                self.error = String::new();
+            }
+            Err(e) => {
+                self.untrusted_cert = match e {
+                    PrintError::UntrustedCertificate(ref cert) => Some(cert.clone()),
+                    _ => None,
+                };
+                self.error = format!("Failed to fetch media: {}", e);
+            }
+        }
+    }
+
+    /// Recently printed files, clicking one picks it again
+    fn recent_files(&mut self, ui: &mut egui::Ui) {
+        if self.recent_check.0 != self.settings.recent_files {
+            let exists = self.settings.recent_files.iter().map(|file| file.exists());
+            self.recent_check = (self.settings.recent_files.clone(), exists.collect());
+        }
+
+        ui.collapsing("Recent files", |ui| {
+            let (files, exists) = &self.recent_check;
+            let missing = exists.contains(&false);
+            for (file, &exists) in files.iter().zip(exists) {
+                let name = file.file_name().unwrap_or_default().to_string_lossy();
+                let response = ui
+                    .add_enabled(exists, egui::Button::new(name).frame(false))
+                    .on_hover_text(file.display().to_string())
+                    .on_disabled_hover_text("File no longer exists");
+                if response.clicked() {
+                    self.picked_path = Some(file.display().to_string());
+                }
+            }
+
+            if missing && ui.small_button("Remove missing files").clicked() {
+                self.settings.prune_recent_files();
+            }
+        });
+    }
+
+    /// Whether the selected files include an image, which image options apply to
+    fn images_selected(&mut self) -> bool {
+        let documents: Vec<Document> = if self.text_mode {
+            Vec::new()
+        } else if self.dropped_files.is_empty() {
+            self.picked_path
+                .iter()
+                .map(|path| PathBuf::from(path).into())
+                .collect()
+        } else {
+            self.dropped_files
+                .iter()
+                .filter_map(dropped_document)
+                .collect()
+        };
+        let selection: Vec<String> = documents.iter().map(Document::to_string).collect();
+        if self.image_check.0 != selection {
+            let images = documents.iter().any(Document::is_image);
+            self.image_check = (selection, images);
+        }
+        self.image_check.1
+    }
+
+    /// `shortcut` presses the button from the keyboard
+    fn send_button(&mut self, ui: &mut egui::Ui, shortcut: bool) {
+        let sending = self.sending.is_some() || self.checking_large.is_some();
+        let clicked = ui
+            .add_enabled_ui(!sending, |ui| {
+                let label = if self.text_mode {
+                    "Print Text"
+                } else {
+                    "Print File"
+                };
+                ui.add_sized([80., 30.], egui::Button::new(label))
+            })
+            .inner
             .clicked()
-        {
-            let parsed_url =
-                Url::parse(&format!("https://{}:4433", self.selected_printer)).unwrap();
-            let printer_settings = self
-                .settings
-                .printers
-                .get_mut(&self.selected_printer)
-                .expect("Failed to get settings for selected printer.");
+            || (shortcut && !sending);
+
+        if sending {
+            let (sent, total) = self.send_progress.unwrap_or((0, 1));
+            ui.add(
+                egui::ProgressBar::new(sent as f32 / total.max(1) as f32)
+                    .show_percentage()
+                    .animate(true),
+            );
+            let cancelling = self.cancel_send.load(Ordering::Relaxed);
+            if ui
+                .add_enabled(!cancelling, egui::Button::new("Cancel"))
+                .clicked()
+            {
+                self.cancel_send.store(true, Ordering::Relaxed);
+            }
+        }
 
-            if self.dropped_files.is_empty() {
+        if clicked {
+            // Collect files to send; Dropped files take priority
+            let mut files: Vec<Document> = Vec::new();
+            let mut missing = false;
+            if self.text_mode {
+                if self.text.trim().is_empty() {
+                    self.error = String::from("Nothing to print, type or paste some text");
+                    return;
+                }
+                // Sent from memory, no temp file needed
+                files.push(Document::Bytes {
+                    name: "Pasted text.txt".to_string(),
+                    bytes: Arc::from(self.text.as_bytes()),
+                });
+            } else if self.dropped_files.is_empty() {
                 if let Some(file) = &self.picked_path {
-                    // Handle result of sending file
-                    match crate::send_file(
-                        parsed_url,
-                        Some("localhost".to_string()),
-                        None,
-                        file.into(),
-                        Some(printer_settings),
-                    ) {
-                        Ok(_) => {
-                            self.submit_result =
-                                Some(("Successfully printed file".to_string(), Instant::now()))
-                        }
-                        Err(e) => {
-                            self.submit_result =
-                                Some((format!("Failed to print:\n {:?}", e), Instant::now()))
-                        }
-                    };
+                    files.push(PathBuf::from(file).into());
                 } else {
-                    self.error = String::from("No Send file specified")
+                    self.error = String::from("No Send file specified");
+                    return;
                 }
             } else {
-                let mut results = Vec::new();
                 for file in &self.dropped_files {
-                    let file = match &file.path {
-                        Some(v) => v,
-                        None => {
-                            results.push(
-                                "Failed to get one of the files; Do all the files exist?"
-                                    .to_string(),
-                            );
-                            break;
+                    match dropped_document(file) {
+                        Some(document) => files.push(document),
+                        None => missing = true,
+                    }
+                }
+            }
+
+            // Files deleted or made unreadable since they were picked stay selected
+            let unreadable: Vec<String> = files
+                .iter()
+                .filter_map(|file| file.check().err())
+                .map(|e| e.to_string())
+                .collect();
+            if !unreadable.is_empty() {
+                self.error = unreadable.join("\n");
+                return;
+            }
+
+            let range = self.page_range.trim();
+            self.print_options.page_range = if files.len() == 1 && !range.is_empty() {
+                if let Err(e) = crate::validate_page_range(range) {
+                    self.error = e.to_string();
+                    return;
+                }
+                Some(range.to_string())
+            } else {
+                None
+            };
+
+            // Counting pages reads whole PDFs, which would freeze the UI
+            if self.settings.confirm_large {
+                let (pages, mb) = (self.settings.confirm_pages, self.settings.confirm_mb);
+                let (tx, rx) = mpsc::channel();
+                let ctx = ui.ctx().clone();
+                std::thread::spawn(move || {
+                    let large = files
+                        .iter()
+                        .filter_map(|file| large_document(file, pages, mb))
+                        .collect();
+                    let _ = tx.send((files, missing, large));
+                    ctx.request_repaint();
+                });
+                self.checking_large = Some(rx);
+                return;
+            }
+
+            self.start_send(ui.ctx(), files, missing);
+        }
+    }
+
+    /// Sends `files` to the selected printer on a worker thread so the UI keeps repainting.
+    /// `missing` adds a note that some dropped files couldn't be read
+    fn start_send(&mut self, ctx: &Context, files: Vec<Document>, missing: bool) {
+        let printer_settings = self
+            .settings
+            .printers
+            .get(&self.selected_printer)
+            .expect("Failed to get settings for selected printer.");
+        let parsed_url = printer_url(self.selected_printer, printer_settings.port);
+
+        let single = files.len() == 1 && !missing;
+        let (tx, rx) = mpsc::channel();
+        let (job_tx, job_rx) = mpsc::channel();
+        let ctx = ctx.clone();
+        let ip = self.selected_printer;
+        let mut printer = printer_settings.clone();
+        let options = self.print_options.clone();
+        // Kept for the next print unless turned off
+        let connect = ConnectOptions {
+            pool: (self.settings.idle_connection > 0).then(|| self.pool.clone()),
+            ..self.settings.printer_connect_options(printer_settings)
+        };
+        let runtime = self.runtime.clone();
+        // A fresh flag, so a late cancel can't stop the next send
+        let cancel = Arc::new(AtomicBool::new(false));
+        self.cancel_send = cancel.clone();
+
+        std::thread::spawn(move || {
+            let mut results = Vec::new();
+            let mut retry = Vec::new();
+            let mut printed = Vec::new();
+            let mut jobs = Vec::new();
+            for file in files {
+                if cancel.load(Ordering::Relaxed) {
+                    results.push(("Cancelled".to_string(), ResultKind::Failure));
+                    break;
+                }
+                let progress_tx = tx.clone();
+                let progress_ctx = ctx.clone();
+                let progress = move |sent, total| {
+                    let _ = progress_tx.send(SendEvent::Progress(sent, total));
+                    progress_ctx.request_repaint();
+                };
+
+                // Handle result of sending file
+                let result = runtime.block_on(crate::send_file_async(
+                    parsed_url.clone(),
+                    &connect,
+                    file.clone(),
+                    Some(&mut printer),
+                    options.clone(),
+                    Some(&progress),
+                    Some(&cancel),
+                ));
+                // Connection problems are usually worth another try, the file is fine
+                let (message, kind) = match result {
+                    Ok(PrintOutcome::Printed(job_id, usage)) => {
+                        if let Some(id) = job_id {
+                            let label = if single {
+                                format!("Job {}", id)
+                            } else {
+                                format!("{:?} (job {})", file.name().unwrap_or_default(), id)
+                            };
+                            jobs.push((label, id));
                         }
-                    };
+                        printed.extend(file.path().map(PathBuf::from));
+                        (
+                            format!("Successfully printed{}", usage),
+                            ResultKind::Success,
+                        )
+                    }
+                    Ok(outcome) if outcome.is_success() => {
+                        (outcome.to_string(), ResultKind::Success)
+                    }
+                    Ok(outcome) if outcome.is_retryable() => {
+                        (outcome.to_string(), ResultKind::Retryable)
+                    }
+                    Ok(outcome) => (outcome.to_string(), ResultKind::Failure),
+                    Err(PrintError::Cancelled) => {
+                        results.push(("Cancelled".to_string(), ResultKind::Failure));
+                        break;
+                    }
+                    Err(e) => {
+                        if let PrintError::UntrustedCertificate(cert) = &e {
+                            let _ = tx.send(SendEvent::UntrustedCertificate(cert.clone()));
+                        }
+                        let kind = if e.is_retryable() {
+                            ResultKind::Retryable
+                        } else {
+                            ResultKind::Failure
+                        };
+                        (format!("Failed to send: {}", e), kind)
+                    }
+                };
+                if kind == ResultKind::Retryable {
+                    retry.push(file.clone());
+                }
+                results.push(if single {
+                    (message, kind)
+                } else {
+                    (
+                        format!("{:?}: {}", file.name().unwrap_or_default(), message),
+                        kind,
+                    )
+                });
+            }
+            if missing {
+                results.push((
+                    "Failed to get one of the files; Do all the files exist?".to_string(),
+                    ResultKind::Failure,
+                ));
+            }
+
+            let session = printer.session.clone();
+            let _ = tx.send(SendEvent::Done {
+                ip,
+                printer,
+                results,
+                retry,
+                printed,
+            });
+            ctx.request_repaint();
+
+            // Follow the jobs so the page shows when they actually printed
+            if let Some(session) = session {
+                track_jobs(
+                    &runtime,
+                    &parsed_url,
+                    &connect,
+                    &session,
+                    jobs,
+                    &job_tx,
+                    &ctx,
+                );
+            }
+        });
+
+        self.sending = Some(rx);
+        self.jobs.clear();
+        self.job_updates = Some(job_rx);
+        self.send_progress = None;
+        self.submit_result = None;
+        self.retry_files.clear();
+    }
+
+    /// Authenticates against `ip` on a worker thread, see [`crate::test_connection`]
+    fn test_connection(&mut self, ctx: &Context, ip: IpAddr, printer: &Printer) {
+        let url = printer_url(ip, printer.port);
+        let (tx, rx) = mpsc::channel();
+        let ctx = ctx.clone();
+        let connect = self.settings.printer_connect_options(printer);
+        let user = printer.user.clone();
+        let pass = printer.pass.clone();
+
+        std::thread::spawn(move || {
+            let result = crate::test_connection(url, &connect, user, pass);
+            let _ = tx.send(result);
+            ctx.request_repaint();
+        });
+
+        self.connection_tests
+            .insert(ip, ConnectionTest::Running(rx));
+    }
+
+    /// Prints [`TEST_PAGE`] on `printer` on a worker thread, see [`Interface::poll_test_prints`]
+    fn print_test_page(&mut self, ctx: &Context, ip: IpAddr, printer: &Printer) {
+        let url = printer_url(ip, printer.port);
+        let (tx, rx) = mpsc::channel();
+        let ctx = ctx.clone();
+        let connect = self.settings.printer_connect_options(printer);
+        let mut printer = printer.clone();
+
+        std::thread::spawn(move || {
+            let document = Document::Bytes {
+                name: "Remote Print test page.txt".to_string(),
+                bytes: Arc::from(TEST_PAGE),
+            };
+            let result = crate::send_file(
+                url,
+                &connect,
+                document,
+                Some(&mut printer),
+                PrintOptions::default(),
+                None,
+                None,
+            );
+            let _ = tx.send((result, printer.session));
+            ctx.request_repaint();
+        });
+
+        self.test_prints.insert(ip, TestPrint::Running(rx));
+    }
 
-                    match crate::send_file(
-                        parsed_url.clone(),
-                        Some("localhost".to_string()),
-                        None,
-                        file.into(),
-                        Some(printer_settings),
-                    ) {
-                        Ok(_) => results.push(format!(
-                            "Successfully printed: {:?}",
-                            file.file_name().unwrap_or_default()
-                        )),
-                        Err(e) => {
-                            results.push(format!(
-                                "Failed to print {:?}: {:?}",
-                                file.file_name().unwrap_or_default(),
-                                e
-                            ));
+    /// Collects the outcome of test pages, keeping sessions they fetched
+    fn poll_test_prints(&mut self) {
+        for (ip, test) in self.test_prints.iter_mut() {
+            let TestPrint::Running(rx) = test else {
+                continue;
+            };
+
+            match rx.try_recv() {
+                Ok((result, session)) => {
+                    let stored = self
+                        .settings
+                        .printers
+                        .get(ip)
+                        .and_then(|p| p.session.as_ref());
+                    match session {
+                        Some(session) if stored.map(|s| s.id) != Some(session.id) => {
+                            self.settings.set_session(*ip, session);
                         }
-                    };
+                        _ => (),
+                    }
+                    if let Err(PrintError::UntrustedCertificate(cert)) = &result {
+                        self.untrusted_cert = Some(cert.clone());
+                    }
+                    *test = TestPrint::Done(
+                        result
+                            .and_then(PrintOutcome::into_result)
+                            .map(|outcome| outcome.to_string())
+                            .map_err(|e| e.to_string()),
+                    );
+                }
+                Err(TryRecvError::Empty) => (),
+                Err(TryRecvError::Disconnected) => {
+                    *test = TestPrint::Done(Err("test page stopped unexpectedly".to_string()))
+                }
+            }
+        }
+    }
+
+    /// Renews sessions about to expire on a worker thread, and collects renewed ones.
+    /// Expired sessions are left for the next print to replace
+    fn renew_sessions(&mut self, ctx: &Context) {
+        for (ip, renewal) in self.session_renewals.iter_mut() {
+            let Some(rx) = &renewal.result else {
+                continue;
+            };
+            match rx.try_recv() {
+                Ok(Ok(session)) => {
+                    self.settings.set_session(*ip, session);
+                    renewal.result = None;
+                }
+                Ok(Err(e)) => {
+                    warn!("Failed to renew the session of {}: {:#}", ip, e);
+                    renewal.failure = Some(format!("{:#}", e));
+                    renewal.result = None;
+                }
+                Err(TryRecvError::Empty) => (),
+                Err(TryRecvError::Disconnected) => renewal.result = None,
+            }
+        }
+
+        // A send fetches its own session, and would overwrite a renewed one when done
+        if self.sending.is_some() {
+            return;
+        }
+
+        let now = chrono::Utc::now();
+        let before = chrono::Duration::from_std(SESSION_RENEW_BEFORE).unwrap();
+        let mut next_renewal: Option<Duration> = None;
+        for (ip, printer) in &self.settings.printers {
+            let Some(session) = &printer.session else {
+                continue;
+            };
+            if session.expiration <= now
+                || self
+                    .session_renewals
+                    .get(ip)
+                    .is_some_and(|renewal| renewal.replacing == session.id)
+            {
+                continue;
+            }
+
+            let renew_at = session.expiration - before;
+            if renew_at > now {
+                let wait = (renew_at - now).to_std().unwrap_or_default();
+                next_renewal = Some(next_renewal.map_or(wait, |next| next.min(wait)));
+                continue;
+            }
+
+            let url = printer_url(*ip, printer.port);
+            let (tx, rx) = mpsc::channel();
+            let ctx = ctx.clone();
+            let connect = self.settings.printer_connect_options(printer);
+            let user = printer.user.clone();
+            let pass = printer.pass.clone();
+            std::thread::spawn(move || {
+                let result = tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .map_err(PrintError::from)
+                    .and_then(|runtime| {
+                        runtime.block_on(crate::get_session(url, &connect, user, pass))
+                    });
+                let _ = tx.send(result);
+                ctx.request_repaint();
+            });
+            self.session_renewals.insert(
+                *ip,
+                SessionRenewal {
+                    replacing: session.id,
+                    result: Some(rx),
+                    failure: None,
+                },
+            );
+        }
+
+        // Nothing else may repaint until then
+        if let Some(wait) = next_renewal {
+            ctx.request_repaint_after(wait);
+        }
+    }
+
+    /// Collects results of finished connection tests
+    fn poll_connection_tests(&mut self) {
+        for test in self.connection_tests.values_mut() {
+            let ConnectionTest::Running(rx) = test else {
+                continue;
+            };
+
+            match rx.try_recv() {
+                Ok(Ok(latency)) => *test = ConnectionTest::Passed(latency),
+                Ok(Err(e)) => {
+                    if let PrintError::UntrustedCertificate(cert) = &e {
+                        self.untrusted_cert = Some(cert.clone());
+                    }
+                    *test = ConnectionTest::Failed(e.to_string())
+                }
+                Err(TryRecvError::Empty) => (),
+                Err(TryRecvError::Disconnected) => {
+                    *test = ConnectionTest::Failed("test stopped unexpectedly".to_string())
+                }
+            }
+        }
+    }
+
+    /// Asks whether to trust a server's certificate seen for the first time, pinning it if so
+    fn certificate_prompt(&mut self, ctx: &Context) {
+        let Some(cert) = &self.untrusted_cert else {
+            return;
+        };
+
+        let mut trust = None;
+        egui::Window::new("Untrusted certificate")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+            .show(ctx, |ui| {
+                ui.label(format!(
+                    "{} presented a certificate that isn't trusted yet.",
+                    cert.host
+                ));
+                ui.label(
+                    "Only trust it if this matches the fingerprint the server printed on startup:",
+                );
+                ui.monospace(&cert.fingerprint);
+
+                ui.horizontal(|ui| {
+                    if ui.button("Trust").clicked() {
+                        trust = Some(true);
+                    }
+                    if ui.button("Cancel").clicked() {
+                        trust = Some(false);
+                    }
+                });
+            });
+
+        match trust {
+            Some(true) => {
+                let cert = self.untrusted_cert.take().unwrap();
+                self.settings.pin_cert(cert.host, cert.fingerprint);
+                self.error = String::from("Certificate trusted, please try again");
+            }
+            Some(false) => self.untrusted_cert = None,
+            None => (),
+        }
+    }
+
+    /// Sends once the large document check is done, or asks first if it found any
+    fn poll_large_check(&mut self, ctx: &Context) {
+        let Some(rx) = &self.checking_large else {
+            return;
+        };
+
+        match rx.try_recv() {
+            Ok((files, missing, large)) if large.is_empty() => self.start_send(ctx, files, missing),
+            Ok(checked) => self.large_documents = Some(checked),
+            Err(TryRecvError::Empty) => return,
+            Err(TryRecvError::Disconnected) => {}
+        }
+        self.checking_large = None;
+    }
+
+    /// Asks whether to go ahead with a send that has large documents
+    fn large_document_prompt(&mut self, ctx: &Context) {
+        let Some((_, _, large)) = &self.large_documents else {
+            return;
+        };
+
+        let mut print = None;
+        egui::Window::new("Large document")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+            .show(ctx, |ui| {
+                for document in large {
+                    ui.label(document);
+                }
+                ui.label("Print anyway?");
+
+                ui.horizontal(|ui| {
+                    if ui.button("Print").clicked() {
+                        print = Some(true);
+                    }
+                    if ui.button("Cancel").clicked() {
+                        print = Some(false);
+                    }
+                });
+            });
+
+        match print {
+            Some(true) => {
+                let (files, missing, _) = self.large_documents.take().unwrap();
+                self.start_send(ctx, files, missing);
+            }
+            Some(false) => self.large_documents = None,
+            None => (),
+        }
+    }
+
+    /// Collects state changes of submitted jobs
+    fn poll_jobs(&mut self) {
+        let Some(rx) = &self.job_updates else {
+            return;
+        };
+
+        loop {
+            match rx.try_recv() {
+                Ok((label, state)) => match self.jobs.iter_mut().find(|(l, _)| *l == label) {
+                    Some(job) => job.1 = state,
+                    None => self.jobs.push((label, state)),
+                },
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => {
+                    self.job_updates = None;
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Handles events from an in-flight send
+    fn poll_send(&mut self) {
+        let Some(rx) = &self.sending else {
+            return;
+        };
+
+        loop {
+            match rx.try_recv() {
+                Ok(SendEvent::Progress(sent, total)) => self.send_progress = Some((sent, total)),
+                Ok(SendEvent::UntrustedCertificate(cert)) => self.untrusted_cert = Some(cert),
+                Ok(SendEvent::Done {
+                    ip,
+                    printer,
+                    results,
+                    retry,
+                    printed,
+                }) => {
+                    // Keep the session the worker may have fetched
+                    self.settings.printers.insert(ip, printer);
+                    if !printed.is_empty() {
+                        self.settings.add_recent_files(&printed);
+                    }
+                    self.submit_result = Some((results, Instant::now()));
+                    self.retry_files = retry;
+                    self.sending = None;
+                    return;
+                }
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => {
+                    self.submit_result = Some((
+                        vec![(
+                            "Failed to print: upload stopped unexpectedly".to_string(),
+                            ResultKind::Failure,
+                        )],
+                        Instant::now(),
+                    ));
+                    self.sending = None;
+                    return;
                 }
-                self.submit_result = Some((results.join("\n"), Instant::now()));
             }
         }
     }
@@ -505,23 +2695,90 @@ impl Interface {
                     ver
                 ))
                 .small()
-                .color(Color32::from_rgb(255, 140, 0)),
+                .color(ui.visuals().warn_fg_color),
             )
             .frame(false);
             let mut response = button.ui(ui);
 
-            if cfg!(target_os = "windows") {
-                response = response.on_hover_text("Click to launch updater");
-            } else if cfg!(target_os = "linux") {
+            if update::is_supported() {
                 response = response.on_hover_text("Click to launch updater");
             } else {
                 response = response.on_hover_text("Please visit releases to update app");
             }
 
-            if response.clicked() {
-                if let Err(e) = update::update() {
-                    self.error = e.to_string();
-                };
+            if let Some((done, total)) = self.update_progress {
+                ui.add(egui::ProgressBar::new(done as f32 / total.max(1) as f32).show_percentage());
+            }
+
+            if response.clicked() && update::is_supported() && self.updating.is_none() {
+                let (tx, rx) = mpsc::channel();
+                let ctx = ui.ctx().clone();
+
+                std::thread::spawn(move || {
+                    let progress_tx = tx.clone();
+                    let progress_ctx = ctx.clone();
+                    let progress = move |done, total| {
+                        let _ = progress_tx.send(UpdateEvent::Progress(done, total));
+                        progress_ctx.request_repaint();
+                    };
+
+                    let result = update::perform_update(Some(&progress)).map_err(|e| e.to_string());
+                    let _ = tx.send(UpdateEvent::Done(result));
+                    ctx.request_repaint();
+                });
+
+                self.updating = Some(rx);
+            }
+        }
+    }
+
+    /// Shows the result of the startup update check once it's done.
+    /// Failures are only logged, being offline shouldn't nag the user
+    fn poll_update_check(&mut self, ctx: &Context) {
+        let Some(rx) = &self.update_check else {
+            return;
+        };
+
+        match rx.try_recv() {
+            Ok(Ok(status)) => self.update_status = status,
+            Ok(Err(e)) => eprintln!("[Failed to check for updates]: {:#}", e),
+            Err(TryRecvError::Empty) => {
+                ctx.request_repaint_after(Duration::from_millis(250));
+                return;
+            }
+            Err(TryRecvError::Disconnected) => {}
+        }
+        self.update_check = None;
+    }
+
+    /// Handles events from the updater, exiting once the installer started
+    fn poll_update(&mut self) {
+        let Some(rx) = &self.updating else {
+            return;
+        };
+
+        loop {
+            match rx.try_recv() {
+                Ok(UpdateEvent::Progress(done, total)) => {
+                    self.update_progress = Some((done, total))
+                }
+                Ok(UpdateEvent::Done(result)) => {
+                    match result {
+                        Ok(UpdateStatus::Installing(_)) => std::process::exit(0),
+                        Ok(UpdateStatus::UpToDate) => self.update_status = VersionStatus::UpToDate,
+                        Err(e) => self.error = e,
+                    }
+                    self.updating = None;
+                    self.update_progress = None;
+                    return;
+                }
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => {
+                    self.error = String::from("Updater stopped unexpectedly");
+                    self.updating = None;
+                    self.update_progress = None;
+                    return;
+                }
             }
         }
     }
@@ -561,13 +2818,20 @@ fn preview_files_being_dropped(ctx: &egui::Context) {
             ctx.layer_painter(LayerId::new(Order::Foreground, Id::new("file_drop_target")));
 
         let screen_rect = ctx.screen_rect();
-        painter.rect_filled(screen_rect, 0.0, Color32::from_black_alpha(45));
+        // Dims the window on the dark theme, washes it out on the light one
+        let visuals = &ctx.style().visuals;
+        let overlay = if visuals.dark_mode {
+            Color32::from_black_alpha(45)
+        } else {
+            Color32::from_white_alpha(160)
+        };
+        painter.rect_filled(screen_rect, 0.0, overlay);
         painter.text(
             screen_rect.center(),
             Align2::CENTER_CENTER,
             text,
             TextStyle::Heading.resolve(&ctx.style()),
-            Color32::WHITE,
+            visuals.strong_text_color(),
         );
     }
 }
@@ -617,3 +2881,283 @@ pub fn password_ui(ui: &mut egui::Ui, password: &mut String) -> egui::Response {
 pub fn password(password: &mut String) -> impl egui::Widget + '_ {
     move |ui: &mut egui::Ui| password_ui(ui, password)
 }
+
+/// Polls `jobs` (label and job ID) every [`JOB_POLL_INTERVAL`] until they finish
+/// or [`JOB_POLL_LIMIT`] passes, sending each state change
+fn track_jobs(
+    runtime: &tokio::runtime::Runtime,
+    url: &Url,
+    connect: &ConnectOptions,
+    session: &Session,
+    mut jobs: Vec<(String, String)>,
+    tx: &mpsc::Sender<(String, JobState)>,
+    ctx: &Context,
+) {
+    let deadline = Instant::now() + JOB_POLL_LIMIT;
+    let mut states: HashMap<String, JobState> = HashMap::new();
+
+    while !jobs.is_empty() && Instant::now() < deadline {
+        jobs.retain(|(label, id)| {
+            // Older servers can't report jobs, stop following them
+            let Ok(state) =
+                runtime.block_on(crate::poll_job_async(url.clone(), connect, session, id))
+            else {
+                return false;
+            };
+            if states.insert(label.clone(), state) != Some(state) {
+                if tx.send((label.clone(), state)).is_err() {
+                    return false;
+                }
+                ctx.request_repaint();
+            }
+            !state.is_finished()
+        });
+
+        if !jobs.is_empty() {
+            std::thread::sleep(JOB_POLL_INTERVAL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn empty_settings() -> Settings {
+        serde_json::from_str(r#"{"printers": {}}"#).unwrap()
+    }
+
+    #[test]
+    fn clamps_ui_scale() {
+        for (saved, clamped) in [
+            (Some(10.0), Some(2.0)),
+            (Some(0.0), Some(0.8)),
+            (Some(1.5), Some(1.5)),
+            (Some(f32::NAN), None),
+            (None, None),
+        ] {
+            let mut settings = empty_settings();
+            settings.ui_scale = saved;
+            settings.clamp();
+            assert_eq!(settings.ui_scale, clamped, "saved {:?}", saved);
+        }
+    }
+
+    #[test]
+    fn huge_rate_limits_saturate() {
+        let mut settings = empty_settings();
+        settings.rate_limit_kib = Some(u64::MAX);
+        assert_eq!(settings.connect_options().rate_limit, Some(u64::MAX));
+    }
+
+    #[test]
+    fn adds_printers_by_ipv4_and_ipv6() {
+        let mut settings = empty_settings();
+        for key in ["192.168.1.20", " fe80::1 "] {
+            settings
+                .change_printers(Crud::Add, key, Some(Printer::new("pass".into(), 4433)))
+                .unwrap();
+        }
+        assert!(settings
+            .printers
+            .contains_key(&"192.168.1.20".parse::<IpAddr>().unwrap()));
+        assert!(settings
+            .printers
+            .contains_key(&"fe80::1".parse::<IpAddr>().unwrap()));
+
+        settings
+            .change_printers(Crud::Remove, "fe80::1", None)
+            .unwrap();
+        assert_eq!(settings.printers.len(), 1);
+    }
+
+    #[test]
+    fn rejects_malformed_addresses() {
+        let mut settings = empty_settings();
+        for key in [
+            "",
+            "192.168.1",
+            "192.168.1.256",
+            "printer.local",
+            "fe80::1::2",
+        ] {
+            let err = settings
+                .change_printers(Crud::Add, key, Some(Printer::new("pass".into(), 4433)))
+                .unwrap_err();
+            assert_eq!(err.to_string(), "Invalid IP Address", "{:?}", key);
+        }
+        assert!(settings.printers.is_empty());
+    }
+
+    #[test]
+    fn removing_a_malformed_address_is_a_no_op() {
+        let mut settings = empty_settings();
+        settings
+            .change_printers(
+                Crud::Add,
+                "10.0.0.1",
+                Some(Printer::new("pass".into(), 4433)),
+            )
+            .unwrap();
+        settings
+            .change_printers(Crud::Remove, "not an address", None)
+            .unwrap();
+        assert_eq!(settings.printers.len(), 1);
+    }
+
+    #[test]
+    fn removing_the_default_printer_unsets_it() {
+        let mut settings = empty_settings();
+        settings
+            .change_printers(
+                Crud::Add,
+                "10.0.0.1",
+                Some(Printer::new("pass".into(), 4433)),
+            )
+            .unwrap();
+        settings.default_printer = Some("10.0.0.1".parse().unwrap());
+        assert!(settings.default_printer().is_some());
+
+        settings
+            .change_printers(Crud::Remove, "10.0.0.1", None)
+            .unwrap();
+        assert_eq!(settings.default_printer, None);
+        assert!(settings.default_printer().is_none());
+    }
+
+    #[test]
+    fn adding_without_a_printer_fails() {
+        let mut settings = empty_settings();
+        assert!(settings
+            .change_printers(Crud::Add, "10.0.0.1", None)
+            .is_err());
+        assert!(settings.printers.is_empty());
+    }
+
+    #[test]
+    fn edits_keep_the_session_only_for_the_same_account() {
+        let mut settings = empty_settings();
+        let old: IpAddr = "10.0.0.1".parse().unwrap();
+        let mut printer = Printer::new("pass".into(), 4433);
+        printer.session = Some(Session {
+            id: Uuid::new_v4(),
+            expiration: chrono::Utc::now(),
+        });
+        settings
+            .change_printers(Crud::Add, "10.0.0.1", Some(printer))
+            .unwrap();
+        settings.default_printer = Some(old);
+
+        let mut edited = Printer::new("pass".into(), 4433);
+        edited.cert_host = Some("printer.local".into());
+        settings
+            .change_printers(Crud::Edit(old), "10.0.0.1", Some(edited.clone()))
+            .unwrap();
+        let printer = &settings.printers[&old];
+        assert_eq!(printer.cert_host.as_deref(), Some("printer.local"));
+        assert!(printer.session.is_some());
+
+        settings
+            .change_printers(Crud::Add, "10.0.0.2", Some(edited.clone()))
+            .unwrap();
+        let err = settings
+            .change_printers(Crud::Edit(old), "10.0.0.2", Some(edited.clone()))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Printer already added");
+
+        // Moved to another address, which the session wasn't made with
+        settings
+            .change_printers(Crud::Edit(old), "10.0.0.3", Some(edited))
+            .unwrap();
+        let new: IpAddr = "10.0.0.3".parse().unwrap();
+        assert!(!settings.printers.contains_key(&old));
+        assert!(settings.printers[&new].session.is_none());
+        assert_eq!(settings.default_printer, Some(new));
+    }
+
+    #[test]
+    fn exports_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = empty_settings();
+        let mut printer = Printer::new("pass".into(), 4433);
+        printer.label = "Kitchen".into();
+        settings
+            .printers
+            .insert("10.0.0.1".parse().unwrap(), printer);
+        settings
+            .pinned_certs
+            .insert("10.0.0.1:4433".into(), "ab:cd".into());
+        let expected = serde_json::to_value(&settings).unwrap();
+
+        let plain = dir.path().join("plain.json");
+        crate::export_settings(&settings, &plain, None).unwrap();
+        let imported = crate::import_settings(&plain, None).unwrap();
+        assert_eq!(serde_json::to_value(&imported).unwrap(), expected);
+
+        let encrypted = dir.path().join("encrypted.json");
+        crate::export_settings(&settings, &encrypted, Some("hunter2")).unwrap();
+        assert!(!fs::read_to_string(&encrypted).unwrap().contains("Kitchen"));
+        let imported = crate::import_settings(&encrypted, Some("hunter2")).unwrap();
+        assert_eq!(serde_json::to_value(&imported).unwrap(), expected);
+
+        let err = crate::import_settings(&encrypted, None).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "the export is encrypted, enter its passphrase"
+        );
+        let err = crate::import_settings(&encrypted, Some("wrong"))
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "wrong passphrase, or the export is damaged"
+        );
+    }
+
+    #[test]
+    fn imports_asking_for_too_many_iterations_fail() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("export.json");
+        let export = serde_json::json!({
+            "format": "encrypted",
+            "iterations": u32::MAX,
+            "memory_kib": 1 << 16,
+            "salt": vec![0; 16],
+            "sealed": [],
+        });
+        fs::write(&path, export.to_string()).unwrap();
+
+        let err = crate::import_settings(&path, Some("hunter2"))
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "the export asks for too many iterations to decrypt"
+        );
+    }
+
+    #[test]
+    fn large_documents_are_judged_by_pages_or_size() {
+        let pdf = |name: &str| Document::Bytes {
+            name: name.to_string(),
+            bytes: Arc::from(&b"%PDF-1.4\n2 0 obj << /Type /Pages /Count 40 >> endobj\n"[..]),
+        };
+        assert_eq!(
+            large_document(&pdf("report.pdf"), 30, 1).as_deref(),
+            Some("\"report.pdf\" is 40 pages / 0.0 MB")
+        );
+        assert_eq!(large_document(&pdf("report.pdf"), 40, 1), None);
+        // Only PDFs are counted
+        assert_eq!(large_document(&pdf("report.txt"), 30, 1), None);
+
+        let big = Document::Bytes {
+            name: "scan.png".to_string(),
+            bytes: Arc::from(vec![0; 2_000_000]),
+        };
+        assert_eq!(
+            large_document(&big, 30, 1).as_deref(),
+            Some("\"scan.png\" is 2.0 MB")
+        );
+        assert_eq!(large_document(&big, 30, 2), None);
+    }
+}
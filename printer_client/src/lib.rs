@@ -1,226 +1,1903 @@
+//! Client side of remote_print: finding servers, authenticating and sending files.
+//!
+//! Functions talking to a server come in two variants. The `_async` ones run on the
+//! caller's Tokio runtime; use them from async code, or from a worker thread that keeps
+//! a runtime for all its requests like the GUI does. The plain ones block on a runtime
+//! of their own for each call, which suits the CLI, and panic inside a runtime.
+//! They fail with a [`PrintError`], so callers can tell what went wrong.
+
 use std::{
-    ffi::OsStr,
+    collections::HashMap,
     fs, io,
     net::{SocketAddr, ToSocketAddrs},
-    path::PathBuf,
-    str::FromStr,
-    sync::Arc,
-    time::Duration,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
-use anyhow::{anyhow, bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use app::Settings;
 use chrono::prelude::*;
 use include_dir::{include_dir, Dir};
-use inquire;
-use quinn::{self, Connection, Endpoint};
-use rustls::Certificate;
-use tokio::{fs::File, io::AsyncReadExt, time::timeout};
-use tracing::{debug, error, info, info_span, Instrument};
-use url::Url;
+use mdns_sd::{ServiceDaemon, ServiceEvent};
+use orion::{aead, kdf};
+use printer_protocol::{
+    content_hash_reader, error_code, error_message, is_error_response, is_image,
+    is_valid_page_range, sniff_extension, AuthResponse, ContentEncoding, JobCode, JobStatus,
+    Request, UploadState, ALPN_PROTOCOL, CLOSE_CANCELLED, CLOSE_DONE, CLOSE_ERROR, ERROR_PREFIX,
+    LEGACY_ALPN, SNIFF_LEN, URL_SCHEME,
+};
+use quinn::{self, Connection, ConnectionError, Endpoint};
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+    Certificate, PrivateKey, ServerName,
+};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    task::JoinSet,
+    time::timeout,
+};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use url::{Host, Url};
 use uuid::Uuid;
 
 pub mod app;
 pub mod update;
+pub mod watch;
+
+pub use printer_protocol::{
+    fingerprint, pdf, JobState, PrinterOptions, DEFAULT_PORT, MAX_COPIES, MAX_SCALING, POSITIONS,
+    PROTOCOL_VERSION, SERVICE_TYPE,
+};
 
 static DEFAULT_ROOTS: Dir<'_> = include_dir!("$CARGO_MANIFEST_DIR/certs");
 
-const ALPN_QUIC_HTTP: &[&[u8]] = &[b"hq-29"];
+/// TLS alert sent when client and server share no ALPN protocol, as a QUIC error code
+const NO_APPLICATION_PROTOCOL: u64 = 0x100 | 120;
+
+/// Connect timeout used when none is configured
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connection retries used when none are configured
+pub const DEFAULT_RETRIES: u32 = 3;
+
+/// Wait before the first connection retry, doubled on each retry after
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// How long [`Transport::Auto`] waits for QUIC before trying TCP, without retrying.
+/// Networks that block UDP drop the packets, so QUIC never fails on its own
+const QUIC_FALLBACK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Head start each address of a server gets before the next is tried alongside it (RFC 8305)
+const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
+
+/// Largest response accepted from the server, in bytes
+pub const MAX_RESPONSE_SIZE: usize = 1024 * 1024;
+
+/// Size of the chunks requests are written in
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Uploads from this size on are resumed after a dropped connection, smaller ones are sent again
+const RESUMABLE_SIZE: u64 = 1024 * 1024;
+
+/// Longest wait for the server to acknowledge closing a connection
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How often pooled QUIC connections ping the server while unused
+const POOL_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// QUIC idle timeout of pooled connections, a few missed pings
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// How often an upload checks whether it was cancelled
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often a resumed upload asks about a job the server received but didn't finish yet
+const UPLOAD_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Most times a resumed upload asks, the outcome is unknown after that
+const MAX_UPLOAD_POLLS: u32 = 30;
+
+/// Default server limit on file size (50 MiB)
+pub const MAX_FILE_SIZE: u64 = 50 * 1024 * 1024;
 
 #[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
 pub struct Printer {
+    /// Name shown instead of the address, e.g. "Kitchen". Unset if empty
+    #[serde(default)]
+    pub label: String,
+    /// User to authenticate as, server default user if `None`
+    #[serde(default)]
+    pub user: Option<String>,
     pub pass: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// Printer on the server to use; server default if `None`
+    #[serde(default)]
+    pub printer_name: Option<String>,
+    /// Hostname the server's certificate is checked against, its address if `None`.
+    /// See [`ConnectOptions::host`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cert_host: Option<String>,
     pub session: Option<Session>,
 }
 
+/// Options sent along with a file
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq)]
+pub struct PrintOptions {
+    pub copies: u32,
+    /// Print on both sides (long edge binding)
+    pub duplex: bool,
+    /// Compress the file with zstd before sending.
+    /// Not worth it for already compressed formats (PNG, most PDFs)
+    #[serde(default)]
+    pub compress: bool,
+    /// Pages to print, e.g. `3-7,10`; all pages if `None`
+    #[serde(default)]
+    pub page_range: Option<String>,
+    /// Paper size or media, e.g. `A4`; printer default if `None`
+    #[serde(default)]
+    pub media: Option<String>,
+    /// Print in landscape orientation
+    #[serde(default)]
+    pub landscape: bool,
+    /// Scale the document to fit the page
+    #[serde(default)]
+    pub fit_to_page: bool,
+    /// Percent of the page an image covers, up to [`MAX_SCALING`]; printer default if `None`.
+    /// Only sent for images
+    #[serde(default)]
+    pub scaling: Option<u16>,
+    /// Where an image goes on the page, one of [`POSITIONS`]; printer default if `None`.
+    /// Only sent for images
+    #[serde(default)]
+    pub position: Option<String>,
+    /// Have the server validate the file without printing it
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+impl Default for PrintOptions {
+    fn default() -> Self {
+        PrintOptions {
+            copies: 1,
+            duplex: false,
+            compress: false,
+            page_range: None,
+            media: None,
+            landscape: false,
+            fit_to_page: false,
+            scaling: None,
+            position: None,
+            dry_run: false,
+        }
+    }
+}
+
+/// How to reach a server, shared by every request
+#[derive(Clone, Debug)]
+pub struct ConnectOptions {
+    /// Overrides the hostname used for certificate verification
+    pub host: Option<String>,
+    /// Certificate authorities to trust: DER or PEM files, or directories of them
+    pub ca: Vec<PathBuf>,
+    pub client_auth: Option<ClientAuth>,
+    /// How long to wait for the QUIC connection to be established
+    pub timeout: Duration,
+    /// How many times a timed out or reset connection attempt is retried,
+    /// and how many times a large upload is resumed after losing the connection
+    pub retries: u32,
+    /// Certificate fingerprints trusted per [`host_key`], on top of the roots.
+    /// Not used when `ca` isn't empty
+    pub pinned_certs: HashMap<String, String>,
+    pub transport: Transport,
+    /// Most bytes per second files are uploaded at, unlimited if `None`
+    pub rate_limit: Option<u64>,
+    /// Keeps connections open between requests to reuse them, each request connects if `None`
+    pub pool: Option<Arc<ConnectionPool>>,
+}
+
+/// How requests reach the server
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Transport {
+    /// QUIC, falling back to TCP if the server can't be reached over it
+    #[default]
+    Auto,
+    Quic,
+    /// TLS over TCP, for networks that block UDP. Servers accept it on the same port as QUIC
+    Tcp,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        ConnectOptions {
+            host: None,
+            ca: Vec::new(),
+            client_auth: None,
+            timeout: DEFAULT_CONNECT_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            pinned_certs: HashMap::new(),
+            transport: Transport::Auto,
+            rate_limit: None,
+            pool: None,
+        }
+    }
+}
+
+/// Paces writes to `rate` bytes per second (token bucket).
+/// After a pause it lets up to a second's worth through at once
+pub struct RateLimiter {
+    rate: u64,
+    /// Bytes that can be sent without waiting, negative while behind
+    tokens: f64,
+    last: tokio::time::Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        RateLimiter {
+            rate: rate.max(1),
+            tokens: 0.0,
+            last: tokio::time::Instant::now(),
+        }
+    }
+
+    /// Chunks to write in so the pace stays smooth, about a tenth of a second's worth
+    pub fn chunk_size(&self) -> usize {
+        (self.rate / 10).clamp(1024, CHUNK_SIZE as u64) as usize
+    }
+
+    /// Waits until `bytes` more can be sent
+    pub async fn wait(&mut self, bytes: usize) {
+        let rate = self.rate as f64;
+        let now = tokio::time::Instant::now();
+        self.tokens = (self.tokens + (now - self.last).as_secs_f64() * rate).min(rate);
+        self.last = now;
+
+        self.tokens -= bytes as f64;
+        if self.tokens < 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(-self.tokens / rate)).await;
+        }
+    }
+}
+
+/// A server presented a certificate that isn't signed by a trusted root
+/// and isn't pinned. Add it to [`ConnectOptions::pinned_certs`] to trust it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownCertificate {
+    /// [`host_key`] of the server
+    pub host: String,
+    pub fingerprint: String,
+}
+
+impl std::fmt::Display for UnknownCertificate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} presented an untrusted certificate, SHA-256 fingerprint {}",
+            self.host, self.fingerprint
+        )
+    }
+}
+
+impl std::error::Error for UnknownCertificate {}
+
+/// Why talking to a server failed, returned by [`send_file`], [`get_session`] and the like
+#[derive(Debug, thiserror::Error)]
+pub enum PrintError {
+    /// The server couldn't be reached, or the connection broke
+    #[error("{0:#}")]
+    Connect(anyhow::Error),
+    /// The server's certificate isn't trusted yet
+    #[error(transparent)]
+    UntrustedCertificate(UnknownCertificate),
+    /// The server rejected the login
+    #[error("Authentication failed: {0}")]
+    Auth(String),
+    /// The server didn't answer within [`ConnectOptions::timeout`]
+    #[error("{0}")]
+    Timeout(String),
+    /// The server answered with something this client doesn't understand
+    #[error("invalid response from the server: {0}")]
+    Protocol(String),
+    /// Reading a document failed
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// The server refused the request, e.g. its file type or too many jobs in flight
+    #[error("Rejected: {0}")]
+    Rejected(String),
+    /// The server has no printer by the requested name
+    #[error("Unknown printer: {0}")]
+    UnknownPrinter(String),
+    /// The file is over the server's size limit
+    #[error("Too large: {0}")]
+    TooLarge(String),
+    /// The server got the request but failed it, e.g. the file didn't print.
+    /// See [`PrintOutcome::into_result`]
+    #[error("{0}")]
+    PrinterError(String),
+    /// The upload was cancelled before it finished
+    #[error("Upload cancelled")]
+    Cancelled,
+    /// Anything else, like an invalid page range
+    #[error("{0:#}")]
+    Other(anyhow::Error),
+}
+
+impl PrintError {
+    /// Whether sending again might work, the server couldn't be reached or took too long
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, PrintError::Connect(_) | PrintError::Timeout(_))
+    }
+}
+
+impl From<anyhow::Error> for PrintError {
+    fn from(e: anyhow::Error) -> Self {
+        let e = match e.downcast::<PrintError>() {
+            Ok(e) => return e,
+            Err(e) => e,
+        };
+        match e.downcast::<UnknownCertificate>() {
+            Ok(cert) => PrintError::UntrustedCertificate(cert),
+            Err(e) => PrintError::Other(e),
+        }
+    }
+}
+
+/// The error a response that isn't the expected JSON stands for,
+/// a session the server refused is [`PrintError::Auth`]
+fn unexpected_response(resp: &[u8]) -> PrintError {
+    let text = String::from_utf8_lossy(resp).trim().to_string();
+    if !is_error_response(&text) {
+        return PrintError::Protocol(text);
+    }
+
+    let msg = error_message(&text).to_string();
+    match error_code(&text) {
+        Some(JobCode::AuthRequired | JobCode::SessionExpired) => PrintError::Auth(msg),
+        Some(JobCode::UnknownPrinter) => PrintError::UnknownPrinter(msg),
+        _ => PrintError::PrinterError(msg),
+    }
+}
+
+/// Verifies against the roots, falling back to the pinned fingerprint (trust on first use)
+struct TofuVerifier {
+    roots: WebPkiVerifier,
+    pin: Option<String>,
+    /// Fingerprint of an unpinned certificate the roots rejected
+    seen: Arc<Mutex<Option<String>>>,
+}
+
+impl ServerCertVerifier for TofuVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let result = self.roots.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        );
+        if result.is_ok() {
+            return result;
+        }
+
+        let fingerprint = fingerprint(&end_entity.0);
+        match &self.pin {
+            Some(pin) if *pin == fingerprint => Ok(ServerCertVerified::assertion()),
+            Some(pin) => Err(rustls::Error::General(format!(
+                "SERVER CERTIFICATE CHANGED: pinned {}, got {}. \
+                 The connection may be intercepted; only remove the pin if the server's certificate was replaced",
+                pin, fingerprint
+            ))),
+            None => {
+                *self.seen.lock().unwrap() = Some(fingerprint);
+                result
+            }
+        }
+    }
+}
+
+/// Server to connect to for `url`, and the certificate fingerprint it carries.
+/// A `remote-print://host:port?fingerprint=…` URL printed by the server (or scanned from its
+/// QR code) becomes `https://host:port`, any other URL is returned as is
+pub fn server_url(url: Url) -> Result<(Url, Option<String>)> {
+    if url.scheme() != URL_SCHEME {
+        return Ok((url, None));
+    }
+
+    let Some(host) = url.host_str() else {
+        bail!("{} has no host", url);
+    };
+    let mut server = Url::parse(&format!("https://{}", host))
+        .with_context(|| format!("{} has an invalid host", url))?;
+    server
+        .set_port(url.port())
+        .map_err(|_| anyhow!("{} has an invalid port", url))?;
+    let fingerprint = url
+        .query_pairs()
+        .find(|(key, _)| key == "fingerprint")
+        .map(|(_, value)| value.to_uppercase());
+    Ok((server, fingerprint))
+}
+
+/// Key certificates are pinned under, `host:port` of `url`
+pub fn host_key(url: &Url) -> String {
+    format!(
+        "{}:{}",
+        url.host_str().unwrap_or_default(),
+        url.port().unwrap_or(DEFAULT_PORT)
+    )
+}
+
+/// Certificate presented to servers started with `--client-ca`.
+/// Either file may be DER (`.der`) or PEM
+#[derive(Clone, Debug)]
+pub struct ClientAuth {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Pages a job prints and what they cost, as far as the server knows
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Usage {
+    /// Copies included, `None` for anything but PDFs
+    pub pages: Option<u32>,
+    /// Estimate from the server's per-page cost, if it has one
+    pub cost: Option<f64>,
+}
+
+impl std::fmt::Display for Usage {
+    /// `, 12 pages, cost 1.20`, empty if unknown
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(pages) = self.pages {
+            write!(f, ", {} page{}", pages, if pages == 1 { "" } else { "s" })?;
+        }
+        if let Some(cost) = self.cost {
+            write!(f, ", cost {:.2}", cost)?;
+        }
+        Ok(())
+    }
+}
+
+/// Result of a print job as reported by the server
+#[derive(Debug, Clone, PartialEq)]
+pub enum PrintOutcome {
+    /// Handed to the printer, with the CUPS job ID if the server reported one
+    Printed(Option<String>, Usage),
+    /// Dry run passed, nothing was printed
+    Validated(Usage),
+    /// Session missing or expired, retrying authenticates again
+    SessionExpired(String),
+    UnknownPrinter(String),
+    /// Server refused the request (file type, headers, too many jobs)
+    Rejected(String),
+    /// File is over the server's size limit
+    TooLarge(String),
+    /// The print command failed, may work later
+    PrinterError(String),
+    /// Any other failure
+    Failed(String),
+}
+
+impl PrintOutcome {
+    /// Parses a `POST` response. Older servers reply with a bare `done`
+    /// on success and plain text on failure
+    pub fn from_response(resp: &[u8]) -> Self {
+        let Ok(status) = serde_json::from_slice::<JobStatus>(resp) else {
+            let text = String::from_utf8_lossy(resp).trim().to_string();
+            return if text == "done" {
+                PrintOutcome::Printed(None, Usage::default())
+            } else {
+                PrintOutcome::Failed(text)
+            };
+        };
+        PrintOutcome::from_status(status)
+    }
+
+    fn from_status(status: JobStatus) -> Self {
+        if status.is_ok() {
+            let usage = Usage {
+                pages: status.pages,
+                cost: status.cost,
+            };
+            return match status.code {
+                JobCode::Validated => PrintOutcome::Validated(usage),
+                _ => PrintOutcome::Printed(status.job_id, usage),
+            };
+        }
+        match status.code {
+            JobCode::AuthRequired | JobCode::SessionExpired => {
+                PrintOutcome::SessionExpired(status.message)
+            }
+            JobCode::UnknownPrinter => PrintOutcome::UnknownPrinter(status.message),
+            JobCode::InvalidRequest | JobCode::TooManyJobs => {
+                PrintOutcome::Rejected(status.message)
+            }
+            JobCode::TooLarge => PrintOutcome::TooLarge(status.message),
+            JobCode::PrinterError => PrintOutcome::PrinterError(status.message),
+            _ => PrintOutcome::Failed(status.message),
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        matches!(self, PrintOutcome::Printed(..) | PrintOutcome::Validated(_))
+    }
+
+    /// `Err` unless the file printed (or passed a dry run), with the [`PrintError`] variant
+    /// of the outcome. A session the server refused counts as [`PrintError::Auth`]
+    pub fn into_result(self) -> Result<Self, PrintError> {
+        match self {
+            outcome if outcome.is_success() => Ok(outcome),
+            PrintOutcome::SessionExpired(msg) => Err(PrintError::Auth(msg)),
+            PrintOutcome::UnknownPrinter(msg) => Err(PrintError::UnknownPrinter(msg)),
+            PrintOutcome::Rejected(msg) => Err(PrintError::Rejected(msg)),
+            PrintOutcome::TooLarge(msg) => Err(PrintError::TooLarge(msg)),
+            outcome => Err(PrintError::PrinterError(outcome.to_string())),
+        }
+    }
+
+    /// Whether sending the same file again might succeed
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            PrintOutcome::SessionExpired(_) | PrintOutcome::PrinterError(_)
+        )
+    }
+}
+
+impl std::fmt::Display for PrintOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PrintOutcome::Printed(Some(id), usage) => write!(f, "Printed (job {}){}", id, usage),
+            PrintOutcome::Printed(None, usage) => write!(f, "Printed{}", usage),
+            PrintOutcome::Validated(usage) => {
+                write!(f, "Accepted by the server (dry run, not printed){}", usage)
+            }
+            PrintOutcome::SessionExpired(msg) => write!(f, "Session expired: {}", msg),
+            PrintOutcome::UnknownPrinter(msg) => write!(f, "Unknown printer: {}", msg),
+            PrintOutcome::Rejected(msg) => write!(f, "Rejected: {}", msg),
+            PrintOutcome::TooLarge(msg) => write!(f, "Too large: {}", msg),
+            PrintOutcome::PrinterError(msg) => write!(f, "Printer error: {}", msg),
+            PrintOutcome::Failed(msg) => write!(f, "Failed: {}", msg),
+        }
+    }
+}
+
+/// A file to print, on disk or already in memory (e.g. dropped from a browser)
+#[derive(Debug, Clone)]
+pub enum Document {
+    Path(PathBuf),
+    /// Contents and the file name they came with, which gives the extension
+    Bytes {
+        name: String,
+        bytes: Arc<[u8]>,
+    },
+}
+
+impl Document {
+    /// Name sent to the server. Non UTF-8 names are sent with replacement characters,
+    /// the name is only for display
+    pub fn name(&self) -> Result<String> {
+        match self {
+            Document::Path(path) => Ok(path
+                .file_name()
+                .ok_or_else(|| anyhow!("{} is not a file", path.display()))?
+                .to_string_lossy()
+                .into_owned()),
+            Document::Bytes { name, .. } => Ok(name.clone()),
+        }
+    }
+
+    /// Checks a file on disk still exists, is a regular file and can be read, so one deleted
+    /// since it was picked fails with its path instead of a bare OS error
+    pub fn check(&self) -> Result<(), PrintError> {
+        let Document::Path(path) = self else {
+            return Ok(());
+        };
+        let unreadable = |e: io::Error| {
+            let reason = match e.kind() {
+                io::ErrorKind::NotFound => "doesn't exist".to_string(),
+                io::ErrorKind::PermissionDenied => "can't be read, permission denied".to_string(),
+                _ => format!("can't be read: {}", e),
+            };
+            PrintError::Io(io::Error::new(
+                e.kind(),
+                format!("{} {}", path.display(), reason),
+            ))
+        };
+
+        let meta = fs::metadata(path).map_err(unreadable)?;
+        if !meta.is_file() {
+            let kind = if meta.is_dir() {
+                "a folder"
+            } else {
+                "not a regular file"
+            };
+            return Err(PrintError::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is {}, only files can be printed", path.display(), kind),
+            )));
+        }
+        fs::File::open(path).map_err(unreadable)?;
+        Ok(())
+    }
+
+    /// Whether the document is an image, by its extension or the start of a file without one
+    pub fn is_image(&self) -> bool {
+        use std::io::Read as _;
+
+        let name = self.name().unwrap_or_default();
+        if let Some(extension) = Path::new(&name).extension() {
+            return is_image(&extension.to_string_lossy());
+        }
+        let mut head = Vec::with_capacity(SNIFF_LEN);
+        match self {
+            Document::Path(path) => {
+                if let Ok(file) = fs::File::open(path) {
+                    let _ = file.take(SNIFF_LEN as u64).read_to_end(&mut head);
+                }
+            }
+            Document::Bytes { bytes, .. } => {
+                head.extend_from_slice(&bytes[..bytes.len().min(SNIFF_LEN)])
+            }
+        }
+        sniff_extension(&head).is_some_and(is_image)
+    }
+
+    /// Where the document is on disk, `None` for one in memory
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Document::Path(path) => Some(path),
+            Document::Bytes { .. } => None,
+        }
+    }
+}
+
+impl From<PathBuf> for Document {
+    fn from(path: PathBuf) -> Self {
+        Document::Path(path)
+    }
+}
+
+impl std::fmt::Display for Document {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Document::Path(path) => write!(f, "{}", path.display()),
+            Document::Bytes { name, .. } => write!(f, "{}", name),
+        }
+    }
+}
+
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
 pub struct Session {
     pub id: Uuid,
-    pub expiratrion: DateTime<Utc>,
+    /// Was misspelled `expiratrion` in older settings files
+    #[serde(alias = "expiratrion")]
+    pub expiration: DateTime<Utc>,
 }
 
 impl Printer {
-    pub fn new(pass: String) -> Self {
+    pub fn new(pass: String, port: u16) -> Self {
         Printer {
+            label: String::new(),
+            user: None,
             pass,
+            port,
+            printer_name: None,
+            cert_host: None,
             session: None,
         }
     }
 }
 
+fn default_port() -> u16 {
+    DEFAULT_PORT
+}
+
+/// Blocking [`send_file_async`]
 #[tokio::main]
 pub async fn send_file(
     url: Url,
-    host: Option<String>,
-    ca: Option<PathBuf>,
-    file: PathBuf,
+    connect: &ConnectOptions,
+    document: impl Into<Document>,
     printer: Option<&mut Printer>,
-) -> Result<()> {
-    let remote = (url.host_str().unwrap(), url.port().unwrap_or(4433))
-        .to_socket_addrs()?
-        .next()
-        .ok_or_else(|| anyhow!("Couldn't resolve to an address"))?;
+    options: PrintOptions,
+    progress: Option<&dyn Fn(u64, u64)>,
+    cancel: Option<&AtomicBool>,
+) -> Result<PrintOutcome, PrintError> {
+    send_file_async(url, connect, document, printer, options, progress, cancel).await
+}
 
-    // Parse for TLS Certs
-    let mut roots = rustls::RootCertStore::empty();
-    if let Some(ca_path) = ca.clone() {
-        roots.add(&rustls::Certificate(fs::read(ca_path)?))?;
-    } else {
-        let dirs = directories::ProjectDirs::from("com", "Coded Masonry", "Remote Print").unwrap();
-        match fs::read(dirs.data_local_dir().join("cert.der")) {
-            Ok(cert) => {
-                roots.add(&rustls::Certificate(cert))?;
+/// Sends `document` to be printed, see [`send_files_async`]
+pub async fn send_file_async(
+    url: Url,
+    connect: &ConnectOptions,
+    document: impl Into<Document>,
+    printer: Option<&mut Printer>,
+    options: PrintOptions,
+    progress: Option<&dyn Fn(u64, u64)>,
+    cancel: Option<&AtomicBool>,
+) -> Result<PrintOutcome, PrintError> {
+    let documents = [document.into()];
+    send_files_async(url, connect, &documents, printer, options, progress, cancel)
+        .await?
+        .pop()
+        .expect("a result per file")
+}
+
+/// Blocking [`send_files_async`]
+#[tokio::main]
+pub async fn send_files(
+    url: Url,
+    connect: &ConnectOptions,
+    documents: &[Document],
+    printer: Option<&mut Printer>,
+    options: PrintOptions,
+    progress: Option<&dyn Fn(u64, u64)>,
+    cancel: Option<&AtomicBool>,
+) -> Result<Vec<Result<PrintOutcome, PrintError>>, PrintError> {
+    send_files_async(url, connect, documents, printer, options, progress, cancel).await
+}
+
+/// Sends `documents` to be printed over a single connection and session.
+/// Fails as a whole if the server can't be reached or the login fails,
+/// otherwise returns a result per document, in order, so one bad file doesn't stop the rest.
+/// `progress` is called with the bytes of the current file sent so far and its total.
+/// Files not sent yet when `cancel` is set fail with [`PrintError::Cancelled`].
+/// If the server rejects `printer`'s session, e.g. after restarting, it logs in again once
+/// with the stored password and sends the file again
+pub async fn send_files_async(
+    url: Url,
+    connect: &ConnectOptions,
+    documents: &[Document],
+    mut printer: Option<&mut Printer>,
+    options: PrintOptions,
+    progress: Option<&dyn Fn(u64, u64)>,
+    cancel: Option<&AtomicBool>,
+) -> Result<Vec<Result<PrintOutcome, PrintError>>, PrintError> {
+    if let Some(range) = &options.page_range {
+        validate_page_range(range)?;
+    }
+    let printer_name = printer.as_ref().and_then(|p| p.printer_name.clone());
+
+    // Checked before connecting, there's no need to if none of the files can be sent
+    let checks: Vec<Result<(), PrintError>> = documents.iter().map(Document::check).collect();
+    if !checks.is_empty() && checks.iter().all(Result::is_err) {
+        return Ok(checks
+            .into_iter()
+            .map(|check| Err(check.unwrap_err()))
+            .collect());
+    }
+
+    // Stored session is reused while valid. Asked before connecting so the prompt can't time it out
+    let stored = printer
+        .as_ref()
+        .and_then(|p| p.session.clone())
+        .filter(|session| session.expiration > Utc::now());
+    let login = match (&stored, printer.as_deref()) {
+        (Some(_), _) => None,
+        (None, Some(p)) => Some((p.user.clone(), p.pass.clone())),
+        // No Printer passed, generate temp session
+        (None, None) => Some((None, request_for_pass())),
+    };
+
+    let (mut link, mut pooled) = checkout(&url, connect).await?;
+    let mut session = match (stored, login) {
+        (Some(session), _) => session,
+        (None, login) => {
+            let (user, pass) = login.unwrap_or_default();
+            let request = session_request(user, &pass);
+            let session =
+                exchange_checked_out(&mut link, pooled, &url, connect, &request, None, None)
+                    .instrument(info_span!("Fetch Session"))
+                    .await
+                    .and_then(parse_session);
+            let session = match session {
+                Ok(session) => session,
+                Err(e) => {
+                    link.close(CLOSE_ERROR, b"login failed").await;
+                    return Err(e.into());
+                }
+            };
+            if let Some(p) = printer.as_deref_mut() {
+                p.session = Some(session.clone()); // Update session
             }
-            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
-                info!("local server certificate not found");
+            // The connection carried a request, later ones are no more likely to fail
+            pooled = false;
+            session
+        }
+    };
+
+    // Shared by every file, the limit is on the connection
+    let mut limiter = connect.rate_limit.map(RateLimiter::new);
+    let mut reauthed = false;
+    let mut results = Vec::with_capacity(documents.len());
+    for (document, check) in documents.iter().zip(checks) {
+        if cancel.is_some_and(|cancel| cancel.load(Ordering::Relaxed)) {
+            results.push(Err(PrintError::Cancelled));
+            continue;
+        }
+        if let Err(e) = check {
+            results.push(Err(e));
+            continue;
+        }
+
+        let result = loop {
+            let result: Result<PrintOutcome> = async {
+                let (mut headers, body) =
+                    document_request(document, &session, printer_name.clone(), &options).await?;
+                if body.len() >= RESUMABLE_SIZE {
+                    headers.content_hash = Some(body.hash().await?);
+                }
+
+                let mut resumes = 0;
+                loop {
+                    let head = headers.serialize().into_bytes();
+                    let mut reader = body.reader(headers.offset).await?;
+                    let body_len = body.len() - headers.offset;
+                    // Progress counts the part sent before resuming
+                    let offset = headers.offset;
+                    let resumed = progress.map(|progress| {
+                        move |sent: u64, total: u64| progress(offset + sent, offset + total)
+                    });
+                    let resumed = resumed.as_ref().map(|p| p as &dyn Fn(u64, u64));
+
+                    let sent = exchange_body(
+                        &mut link,
+                        &head,
+                        (&mut reader, body_len),
+                        resumed,
+                        cancel,
+                        limiter.as_mut(),
+                    )
+                    .await;
+                    let err = match sent {
+                        Ok(resp) => {
+                            eprintln!("Successfully sent file");
+                            return Ok(PrintOutcome::from_response(&resp));
+                        }
+                        Err(e) => e,
+                    };
+                    if std::mem::take(&mut pooled) && not_sent(&err) {
+                        reconnect(&mut link, &url, connect).await?;
+                        continue;
+                    }
+                    let dropped = matches!(err.downcast_ref(), Some(PrintError::Connect(_)));
+                    if dropped {
+                        // Tells the server, in case it can still hear it
+                        link.close(CLOSE_ERROR, b"connection lost").await;
+                    }
+                    let Some(hash) = headers.content_hash.clone().filter(|_| dropped) else {
+                        return Err(err);
+                    };
+                    if resumes == connect.retries {
+                        return Err(err);
+                    }
+                    resumes += 1;
+
+                    // Older servers don't know `upload/`, the whole body is sent again
+                    info!("Connection lost, resuming upload: {:#}", err);
+                    link = open_connection(&url, connect).await?;
+                    let request = Request {
+                        session_id: Some(session.id),
+                        ..Request::get(format!("upload/{}", hash))
+                    }
+                    .serialize()
+                    .into_bytes();
+                    // The server may have had all of it, sending it again would print it twice
+                    let mut polls = 0;
+                    let received = loop {
+                        let resp = match exchange(&mut link, &request, None, cancel, None).await {
+                            Ok(resp) => resp,
+                            Err(e) => {
+                                link.close(CLOSE_ERROR, b"resume failed").await;
+                                return Err(e);
+                            }
+                        };
+                        // Servers that don't track finished uploads send the bytes they have
+                        let state = serde_json::from_slice(&resp)
+                            .or_else(|_| serde_json::from_slice(&resp).map(UploadState::Partial))
+                            .unwrap_or(UploadState::Partial(0));
+                        match state {
+                            UploadState::Partial(received) => break received,
+                            UploadState::Finished(status) => {
+                                info!("Server already finished the upload");
+                                return Ok(PrintOutcome::from_status(status));
+                            }
+                            UploadState::Received if polls == MAX_UPLOAD_POLLS => {
+                                bail!("the server received the file but didn't say whether it printed");
+                            }
+                            UploadState::Received => {
+                                polls += 1;
+                                tokio::time::sleep(UPLOAD_POLL_INTERVAL).await;
+                            }
+                        }
+                    };
+                    headers.offset = received.min(body.len());
+                    info!("Server has {} of {} bytes", headers.offset, body.len());
+                }
+            }
+            .await;
+            let result = result.map_err(PrintError::from);
+
+            // The stored session was rejected (e.g. the server restarted), log in again once
+            let Some(p) = printer.as_deref_mut().filter(|_| !reauthed) else {
+                break result;
+            };
+            if !matches!(result, Ok(PrintOutcome::SessionExpired(_))) {
+                break result;
+            }
+            reauthed = true;
+            info!("Session rejected, logging in again");
+            let renewed = exchange(
+                &mut link,
+                &session_request(p.user.clone(), &p.pass),
+                None,
+                None,
+                None,
+            )
+            .instrument(info_span!("Fetch Session"))
+            .await
+            .and_then(parse_session);
+            match renewed {
+                Ok(renewed) => {
+                    p.session = Some(renewed.clone());
+                    session = renewed;
+                }
+                Err(e) => break Err(PrintError::from(e)),
+            }
+        };
+        debug!("Outcome for {}: {:?}", document, result);
+
+        // Stored session is no good (e.g. server restarted), fetch a new one next time
+        if let (Ok(PrintOutcome::SessionExpired(_)), Some(p)) = (&result, printer.as_deref_mut()) {
+            p.session = None;
+        }
+        results.push(result);
+    }
+
+    // Broken links were closed with CLOSE_ERROR above, a refused print leaves it usable
+    let open = link.is_open();
+    check_in(link, &url, connect, open).await;
+
+    Ok(results)
+}
+
+/// Body of a print request
+enum Body {
+    /// Pasted documents, and compressed files since their size has to be known up front
+    Memory(Vec<u8>),
+    /// Read from disk while sending, so files don't have to fit in memory
+    File { path: PathBuf, len: u64 },
+}
+
+impl Body {
+    fn len(&self) -> u64 {
+        match self {
+            Body::Memory(bytes) => bytes.len() as u64,
+            Body::File { len, .. } => *len,
+        }
+    }
+
+    /// Reads the body from `offset` on
+    async fn reader(&self, offset: u64) -> Result<Box<dyn AsyncRead + Send + Unpin + '_>> {
+        Ok(match self {
+            Body::Memory(bytes) => Box::new(&bytes[offset as usize..]),
+            Body::File { path, .. } => {
+                let mut file = File::open(path).await.map_err(PrintError::Io)?;
+                file.seek(io::SeekFrom::Start(offset))
+                    .await
+                    .map_err(PrintError::Io)?;
+                Box::new(file)
+            }
+        })
+    }
+
+    /// [`printer_protocol::content_hash`] of the body
+    async fn hash(&self) -> Result<String> {
+        match self {
+            Body::Memory(bytes) => Ok(content_hash_reader(bytes.as_slice())?),
+            Body::File { path, .. } => {
+                let path = path.clone();
+                let hash =
+                    tokio::task::spawn_blocking(move || content_hash_reader(fs::File::open(path)?))
+                        .await?
+                        .map_err(PrintError::Io)?;
+                Ok(hash)
+            }
+        }
+    }
+}
+
+/// Builds the headers of the `POST` printing `document`, and its body
+async fn document_request(
+    document: &Document,
+    session: &Session,
+    printer_name: Option<String>,
+    options: &PrintOptions,
+) -> Result<(Request, Body)> {
+    let file_name = document.name()?;
+    let (size, start) = match document {
+        Document::Path(path) => {
+            let mut file = File::open(path).await.map_err(PrintError::Io)?;
+            let size = file.metadata().await.map_err(PrintError::Io)?.len();
+            let mut start = Vec::with_capacity(SNIFF_LEN);
+            (&mut file)
+                .take(SNIFF_LEN as u64)
+                .read_to_end(&mut start)
+                .await
+                .map_err(PrintError::Io)?;
+            (size, start)
+        }
+        Document::Bytes { bytes, .. } => (
+            bytes.len() as u64,
+            bytes[..bytes.len().min(SNIFF_LEN)].to_vec(),
+        ),
+    };
+
+    // Servers have their own limit, this only warns about likely rejections
+    if size > MAX_FILE_SIZE {
+        warn!(
+            "{} is {} bytes, most servers reject files over {} bytes",
+            document, size, MAX_FILE_SIZE
+        );
+    }
+
+    let extension = file_extension(Path::new(&file_name), &start);
+    debug!("Extension: {:?}", extension);
+    // Other files would have the server ignore them
+    let image = extension.as_deref().is_some_and(is_image);
+    let body = match document {
+        // Only the compressed file is held in memory
+        Document::Path(path) if options.compress => {
+            let path = path.clone();
+            let compressed =
+                tokio::task::spawn_blocking(move || zstd::encode_all(fs::File::open(path)?, 0))
+                    .await?
+                    .map_err(PrintError::Io)?;
+            Body::Memory(compressed)
+        }
+        Document::Path(path) => Body::File {
+            path: path.clone(),
+            len: size,
+        },
+        Document::Bytes { bytes, .. } if options.compress => {
+            Body::Memory(zstd::encode_all(bytes.as_ref(), 0)?)
+        }
+        Document::Bytes { bytes, .. } => Body::Memory(bytes.to_vec()),
+    };
+    if options.compress {
+        debug!("Compressed {} bytes to {}", size, body.len());
+    }
+
+    let headers = Request {
+        content_length: Some(body.len()),
+        extension,
+        session_id: Some(session.id),
+        printer: printer_name,
+        options: printer_protocol::PrintOptions {
+            copies: options.copies,
+            sides: Some(
+                if options.duplex {
+                    "two-sided-long-edge"
+                } else {
+                    "one-sided"
+                }
+                .to_string(),
+            ),
+            page_ranges: options.page_range.clone(),
+            media: options.media.clone(),
+            landscape: options.landscape,
+            fit_to_page: options.fit_to_page,
+            scaling: options.scaling.filter(|_| image),
+            position: options.position.clone().filter(|_| image),
+        },
+        encoding: if options.compress {
+            ContentEncoding::Zstd
+        } else {
+            ContentEncoding::Identity
+        },
+        dry_run: options.dry_run,
+        ..Request::post(&file_name)
+    };
+    debug!("Headers: {:?}", headers);
+
+    Ok((headers, body))
+}
+
+/// Blocking [`list_printers_async`]
+#[tokio::main]
+pub async fn list_printers(
+    url: Url,
+    connect: &ConnectOptions,
+    user: Option<String>,
+    pass: String,
+) -> Result<Vec<String>, PrintError> {
+    list_printers_async(url, connect, user, pass).await
+}
+
+/// Fetches the names of the printers available on the server
+pub async fn list_printers_async(
+    url: Url,
+    connect: &ConnectOptions,
+    user: Option<String>,
+    pass: String,
+) -> Result<Vec<String>, PrintError> {
+    let session = get_session(url.clone(), connect, user, pass)
+        .instrument(info_span!("Fetch Session"))
+        .await?;
+
+    let headers = Request {
+        session_id: Some(session.id),
+        ..Request::get("printers")
+    }
+    .serialize();
+
+    let resp = send_request(&url, connect, headers.as_bytes(), None, None).await?;
+    let resp = String::from_utf8(resp).map_err(|e| PrintError::Protocol(e.to_string()))?;
+    debug!(response = resp);
+
+    if is_error_response(&resp) {
+        return Err(unexpected_response(resp.as_bytes()));
+    }
+
+    Ok(resp
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// Blocking [`printer_options_async`]
+#[tokio::main]
+pub async fn printer_options(
+    url: Url,
+    connect: &ConnectOptions,
+    user: Option<String>,
+    pass: String,
+    printer: Option<&str>,
+) -> Result<PrinterOptions, PrintError> {
+    printer_options_async(url, connect, user, pass, printer).await
+}
+
+/// Fetches the options `printer` supports, the server's default printer if `None`
+pub async fn printer_options_async(
+    url: Url,
+    connect: &ConnectOptions,
+    user: Option<String>,
+    pass: String,
+    printer: Option<&str>,
+) -> Result<PrinterOptions, PrintError> {
+    let session = get_session(url.clone(), connect, user, pass)
+        .instrument(info_span!("Fetch Session"))
+        .await?;
+
+    let target = match printer {
+        Some(name) => format!("printer-options/{}", name),
+        None => "printer-options".to_string(),
+    };
+    let headers = Request {
+        session_id: Some(session.id),
+        ..Request::get(target)
+    }
+    .serialize();
+
+    let resp = send_request(&url, connect, headers.as_bytes(), None, None).await?;
+    debug!(response = %String::from_utf8_lossy(&resp));
+
+    serde_json::from_slice(&resp).map_err(|_| unexpected_response(&resp))
+}
+
+/// Blocking [`poll_job_async`]
+#[tokio::main]
+pub async fn poll_job(
+    url: Url,
+    connect: &ConnectOptions,
+    session: &Session,
+    job_id: &str,
+) -> Result<JobState, PrintError> {
+    poll_job_async(url, connect, session, job_id).await
+}
+
+/// Asks the server what state print job `job_id` is in
+pub async fn poll_job_async(
+    url: Url,
+    connect: &ConnectOptions,
+    session: &Session,
+    job_id: &str,
+) -> Result<JobState, PrintError> {
+    let headers = Request {
+        session_id: Some(session.id),
+        ..Request::get(format!("job/{}", job_id))
+    }
+    .serialize();
+
+    let resp = send_request(&url, connect, headers.as_bytes(), None, None).await?;
+    debug!(response = %String::from_utf8_lossy(&resp));
+
+    serde_json::from_slice(&resp).map_err(|_| unexpected_response(&resp))
+}
+
+/// Checks a page range like `3-7,10`: comma separated pages or
+/// `first-last` ranges, pages starting at 1
+pub fn validate_page_range(range: &str) -> Result<()> {
+    if !is_valid_page_range(range) {
+        bail!("invalid page range {:?}, expected e.g. 3-7,10", range);
+    }
+
+    Ok(())
+}
+
+/// Blocking [`test_connection_async`]
+#[tokio::main]
+pub async fn test_connection(
+    url: Url,
+    connect: &ConnectOptions,
+    user: Option<String>,
+    pass: String,
+) -> Result<Duration, PrintError> {
+    test_connection_async(url, connect, user, pass).await
+}
+
+/// Checks the server is reachable and accepts the credentials by authenticating.
+/// Returns how long the round-trip took
+pub async fn test_connection_async(
+    url: Url,
+    connect: &ConnectOptions,
+    user: Option<String>,
+    pass: String,
+) -> Result<Duration, PrintError> {
+    let start = Instant::now();
+    get_session(url, connect, user, pass)
+        .instrument(info_span!("Test Connection"))
+        .await?;
+
+    Ok(start.elapsed())
+}
+
+/// Browses the local network for advertised servers until `timeout` passes.
+/// Returns the hostname and address of each, deduplicated by address
+pub fn discover_servers(timeout: Duration) -> Result<Vec<(String, SocketAddr)>> {
+    let daemon = ServiceDaemon::new()?;
+    let events = daemon.browse(SERVICE_TYPE)?;
+    let deadline = Instant::now() + timeout;
+
+    let mut servers: Vec<(String, SocketAddr)> = Vec::new();
+    while let Ok(event) = events.recv_deadline(deadline) {
+        if let ServiceEvent::ServiceResolved(info) = event {
+            let name = info.get_hostname().trim_end_matches('.').to_string();
+            for ip in info.get_addresses() {
+                let addr = SocketAddr::new(*ip, info.get_port());
+                if !servers.iter().any(|(_, known)| *known == addr) {
+                    debug!("Discovered {} at {}", name, addr);
+                    servers.push((name.clone(), addr));
+                }
+            }
+        }
+    }
+
+    if let Err(e) = daemon.shutdown() {
+        error!("failed to shut down mDNS daemon: {}", e);
+    }
+
+    Ok(servers)
+}
+
+/// Connects to the server, sends `request` on a new stream and returns the response, see [`exchange`]
+async fn send_request(
+    url: &Url,
+    connect: &ConnectOptions,
+    request: &[u8],
+    progress: Option<&dyn Fn(u64, u64)>,
+    cancel: Option<&AtomicBool>,
+) -> Result<Vec<u8>> {
+    let (mut link, pooled) = checkout(url, connect).await?;
+    let resp =
+        exchange_checked_out(&mut link, pooled, url, connect, request, progress, cancel).await;
+    check_in(link, url, connect, resp.is_ok()).await;
+    resp
+}
+
+/// Connects like [`open_connection`], reusing an open connection from `connect.pool` if it has one.
+/// Also returns whether it was reused, see [`exchange_checked_out`]
+async fn checkout(url: &Url, connect: &ConnectOptions) -> Result<(Link, bool)> {
+    if let Some(pool) = &connect.pool {
+        if let Some(link) = pool.take(&pool_key(url, connect)).await {
+            debug!("Reusing the connection to {}", url);
+            return Ok((link, true));
+        }
+    }
+    Ok((open_connection(url, connect).await?, false))
+}
+
+/// [`exchange`] over `link` from [`checkout`]. If it was `pooled`, the server may have dropped it
+/// while it sat idle, unnoticed until it's used. A request lost that way is sent once more
+/// over a new connection
+async fn exchange_checked_out(
+    link: &mut Link,
+    pooled: bool,
+    url: &Url,
+    connect: &ConnectOptions,
+    request: &[u8],
+    progress: Option<&dyn Fn(u64, u64)>,
+    cancel: Option<&AtomicBool>,
+) -> Result<Vec<u8>> {
+    let resp = exchange(link, request, progress, cancel, None).await;
+    if !pooled || !resp.as_ref().is_err_and(not_sent) {
+        return resp;
+    }
+    reconnect(link, url, connect).await?;
+    exchange(link, request, progress, cancel, None).await
+}
+
+/// Replaces `link`, a pooled connection that was lost, by a new one
+async fn reconnect(link: &mut Link, url: &Url, connect: &ConnectOptions) -> Result<()> {
+    debug!("Pooled connection to {} was lost, connecting again", url);
+    link.close(CLOSE_ERROR, b"connection lost").await;
+    *link = open_connection(url, connect).await?;
+    Ok(())
+}
+
+/// Done with `link`: kept in `connect.pool` if there is one and the request went through,
+/// closed otherwise. Tells the server whether it's a normal end, rather than leaving it to time out
+async fn check_in(link: Link, url: &Url, connect: &ConnectOptions, ok: bool) {
+    match (&connect.pool, ok) {
+        (Some(pool), true) => pool.put(pool_key(url, connect), link).await,
+        (None, true) => link.close(CLOSE_DONE, b"done").await,
+        (_, false) => link.close(CLOSE_ERROR, b"request failed").await,
+    }
+}
+
+/// What pooled connections are kept under, they're only reused with the same certificate hostname,
+/// trust and client certificate, over the same transport
+fn pool_key(url: &Url, connect: &ConnectOptions) -> String {
+    format!(
+        "{} {:?} {:?} {:?} {:?} {:?}",
+        url,
+        connect.host,
+        connect.ca,
+        connect.client_auth,
+        connect.pinned_certs.get(&host_key(url)),
+        connect.transport
+    )
+}
+
+/// Connections kept open between requests, so repeat prints to a server skip connecting.
+/// Connections unused for the idle timeout are closed by [`ConnectionPool::close_idle`],
+/// which the owner calls regularly, and [`ConnectionPool::close_all`] closes the rest on exit.
+/// Connections run on the runtime that opened them, so a pool belongs to a single runtime
+/// that keeps running between requests
+pub struct ConnectionPool {
+    idle_timeout: Mutex<Duration>,
+    links: tokio::sync::Mutex<HashMap<String, (Link, Instant)>>,
+}
+
+impl ConnectionPool {
+    pub fn new(idle_timeout: Duration) -> Self {
+        ConnectionPool {
+            idle_timeout: Mutex::new(idle_timeout),
+            links: tokio::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Changes how long connections stay open unused, from the next [`ConnectionPool::close_idle`]
+    pub fn set_idle_timeout(&self, idle_timeout: Duration) {
+        *self.idle_timeout.lock().unwrap() = idle_timeout;
+    }
+
+    /// Takes the connection kept under `key`, `None` if there's none or the server closed it
+    async fn take(&self, key: &str) -> Option<Link> {
+        let (link, _) = self.links.lock().await.remove(key)?;
+        link.is_open().then_some(link)
+    }
+
+    /// Keeps `link` under `key`, closing a connection it replaces
+    async fn put(&self, key: String, link: Link) {
+        let replaced = self.links.lock().await.insert(key, (link, Instant::now()));
+        if let Some((replaced, _)) = replaced {
+            replaced.close(CLOSE_DONE, b"done").await;
+        }
+    }
+
+    /// Closes connections unused for longer than the idle timeout
+    pub async fn close_idle(&self) {
+        let idle_timeout = *self.idle_timeout.lock().unwrap();
+        let idle: Vec<Link> = {
+            let mut links = self.links.lock().await;
+            let keys: Vec<String> = links
+                .iter()
+                .filter(|(_, (link, used))| used.elapsed() >= idle_timeout || !link.is_open())
+                .map(|(key, _)| key.clone())
+                .collect();
+            keys.iter()
+                .filter_map(|key| links.remove(key))
+                .map(|(link, _)| link)
+                .collect()
+        };
+        if !idle.is_empty() {
+            debug!("Closing {} idle connections", idle.len());
+        }
+        for link in idle {
+            link.close(CLOSE_DONE, b"idle").await;
+        }
+    }
+
+    /// Closes every connection, e.g. when the app exits
+    pub async fn close_all(&self) {
+        let links: Vec<_> = self.links.lock().await.drain().collect();
+        for (_, (link, _)) in links {
+            link.close(CLOSE_DONE, b"done").await;
+        }
+    }
+}
+
+impl std::fmt::Debug for ConnectionPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnectionPool")
+            .field("idle_timeout", &*self.idle_timeout.lock().unwrap())
+            .finish_non_exhaustive()
+    }
+}
+
+/// QUIC settings of pooled connections: pinged so they stay open however long the pool keeps them,
+/// rather than timing out between requests. The pool closes them once unused for its idle timeout
+fn pooled_transport() -> Arc<quinn::TransportConfig> {
+    let mut transport = quinn::TransportConfig::default();
+    transport.keep_alive_interval(Some(POOL_KEEP_ALIVE_INTERVAL));
+    transport.max_idle_timeout(Some(POOL_IDLE_TIMEOUT.try_into().unwrap()));
+    Arc::new(transport)
+}
+
+/// An open connection to the server
+enum Link {
+    Quic(Endpoint, Connection),
+    Tcp(TcpLink),
+}
+
+/// TLS over TCP carries a single request per connection, later requests connect again
+struct TcpLink {
+    connector: TlsConnector,
+    remote: SocketAddr,
+    host: ServerName,
+    timeout: Duration,
+    /// Made when connecting, used by the first request
+    first: Option<Box<TlsStream<TcpStream>>>,
+}
+
+/// Sending half of a request's stream, finished by `shutdown`
+type SendHalf = Box<dyn AsyncWrite + Unpin + Send>;
+/// Receiving half of a request's stream
+type RecvHalf = Box<dyn AsyncRead + Unpin + Send>;
+
+impl Link {
+    /// Opens the stream for a new request
+    async fn open_stream(&mut self) -> Result<(SendHalf, RecvHalf)> {
+        match self {
+            Link::Quic(_, conn) => {
+                let (send, recv) = conn
+                    .open_bi()
+                    .await
+                    .map_err(|e| anyhow!("Failed to open stream: {}", e))?;
+                Ok((Box::new(send), Box::new(recv)))
+            }
+            Link::Tcp(tcp) => {
+                let stream = match tcp.first.take() {
+                    Some(stream) => *stream,
+                    None => tcp.connect().await?,
+                };
+                let (recv, send) = tokio::io::split(stream);
+                Ok((Box::new(send), Box::new(recv)))
+            }
+        }
+    }
+
+    /// Whether the connection can still carry requests, TLS over TCP connects for each one anyway
+    fn is_open(&self) -> bool {
+        match self {
+            Link::Quic(_, conn) => conn.close_reason().is_none(),
+            Link::Tcp(_) => true,
+        }
+    }
+
+    /// Closes the connection with `code`, waiting up to [`CLOSE_TIMEOUT`] for the server to be told.
+    /// Closing again keeps the first code
+    async fn close(&self, code: u32, reason: &[u8]) {
+        if let Link::Quic(endpoint, conn) = self {
+            conn.close(code.into(), reason);
+            if timeout(CLOSE_TIMEOUT, endpoint.wait_idle()).await.is_err() {
+                debug!(
+                    "Server didn't acknowledge closing the connection within {:?}",
+                    CLOSE_TIMEOUT
+                );
+            }
+        }
+    }
+}
+
+impl TcpLink {
+    /// Connects and does the TLS handshake, giving up after `timeout`
+    async fn connect(&self) -> Result<TlsStream<TcpStream>> {
+        let attempt = async {
+            let stream = TcpStream::connect(self.remote).await?;
+            let stream = self.connector.connect(self.host.clone(), stream).await?;
+            anyhow::Ok(stream)
+        };
+        let stream = timeout(self.timeout, attempt)
+            .await
+            .map_err(|_| {
+                PrintError::Timeout(format!(
+                    "timed out connecting to {} after {:?}",
+                    self.remote, self.timeout
+                ))
+            })?
+            .map_err(|e| anyhow!("Failed to connect over TCP: {}", e))?;
+        if stream.get_ref().1.alpn_protocol() == Some(LEGACY_ALPN) {
+            debug!("Server uses the legacy protocol");
+        }
+        Ok(stream)
+    }
+}
+
+/// Connects to the server over `connect.transport`.
+/// Failing to reach it returns [`PrintError::Connect`], or [`PrintError::Timeout`]
+async fn open_connection(url: &Url, connect: &ConnectOptions) -> Result<Link> {
+    let remotes = resolve(url).map_err(PrintError::Connect)?;
+    let key = host_key(url);
+    let seen = Arc::new(Mutex::new(None));
+    let pin = connect.pinned_certs.get(&key).cloned();
+    let config = client_config(connect, pin, seen.clone()).await?;
+
+    // Name the certificate is checked against, IPv6 addresses without brackets
+    let host = match (&connect.host, url.host()) {
+        (Some(host), _) => host.clone(),
+        (None, Some(Host::Ipv6(ip))) => ip.to_string(),
+        (None, Some(host)) => host.to_string(),
+        (None, None) => bail!("no hostname specified"),
+    };
+
+    // Establish connection
+    let quic = || {
+        let mut quic = quinn::ClientConfig::new(config.clone());
+        if connect.pool.is_some() {
+            quic.transport_config(pooled_transport());
+        }
+        quic
+    };
+    let quic_first = ConnectOptions {
+        timeout: connect.timeout.min(QUIC_FALLBACK_TIMEOUT),
+        retries: 0,
+        ..connect.clone()
+    };
+    let connected = match connect.transport {
+        Transport::Quic => connect_any(quic(), remotes, &host, connect).await,
+        Transport::Tcp => connect_tcp(config, remotes, &host, connect).await,
+        Transport::Auto => match connect_any(quic(), remotes.clone(), &host, &quic_first).await {
+            // An untrusted certificate fails the same way over TCP
+            Err(e) if seen.lock().unwrap().is_some() => Err(e),
+            Err(quic) => {
+                warn!(
+                    "Couldn't connect over QUIC ({:#}), falling back to TCP",
+                    quic
+                );
+                connect_tcp(config, remotes, &host, connect)
+                    .await
+                    .map_err(|tcp| anyhow!("{:#}, and over TCP: {:#}", quic, tcp))
+            }
+            connected => connected,
+        },
+    };
+    match connected {
+        Ok(connected) => Ok(connected),
+        Err(e) => {
+            // Let the caller ask whether to trust the certificate
+            if let Some(fingerprint) = seen.lock().unwrap().take() {
+                return Err(PrintError::UntrustedCertificate(UnknownCertificate {
+                    host: key,
+                    fingerprint,
+                })
+                .into());
             }
-            Err(e) => {
-                error!("failed to open local server certificate: {}", e);
+            match e.downcast::<PrintError>() {
+                Ok(e) => Err(e.into()),
+                Err(e) => Err(PrintError::Connect(e).into()),
             }
         }
-
-        for cert in parse_certs().await {
-            debug!("Cert Added: {:#?}", cert);
-            roots.add(&cert)?;
-        }
     }
+}
 
-    // TLS
-    let mut client_crypto = rustls::ClientConfig::builder()
-        .with_safe_defaults()
-        .with_root_certificates(roots)
-        .with_no_client_auth();
-    client_crypto.alpn_protocols = ALPN_QUIC_HTTP.iter().map(|&x| x.into()).collect();
-
-    // Establish config
-    let client_config = quinn::ClientConfig::new(Arc::new(client_crypto));
-    let mut endpoint = quinn::Endpoint::client("0.0.0.0:0".parse().unwrap())?;
-    endpoint.set_default_client_config(client_config);
-
-    // Parse session
-    let session = if let Some(temp) = printer {
-        if let Some(session) = &temp.session {
-            // Session exists
-            if session.expiratrion <= Utc::now() {
-                // Session expired
-                get_session(url.clone(), host.clone(), ca.clone(), temp.pass.clone())
-                    .instrument(info_span!("Fetch Session"))
-                    .await?
+/// Connects to the first of `remotes` to answer, happy eyeballs style: each address gets
+/// [`HAPPY_EYEBALLS_DELAY`] before the next one is tried too, or none if it fails sooner.
+/// Returns the last error if none answer
+async fn connect_any(
+    config: quinn::ClientConfig,
+    remotes: Vec<SocketAddr>,
+    host: &str,
+    connect: &ConnectOptions,
+) -> Result<Link> {
+    let mut remotes = remotes.into_iter().peekable();
+    let mut attempts = JoinSet::new();
+    let mut error = None;
+    loop {
+        if let Some(remote) = remotes.next() {
+            // Bound per address family, a dual stack socket isn't a given on every OS
+            let bind = if remote.is_ipv6() {
+                "[::]:0"
             } else {
-                // Session Valid
-                session.clone()
+                "0.0.0.0:0"
+            };
+            match Endpoint::client(bind.parse().unwrap()) {
+                Ok(mut endpoint) => {
+                    endpoint.set_default_client_config(config.clone());
+                    let host = host.to_string();
+                    let connect = connect.clone();
+                    eprintln!("Connecting to {host} at {remote}");
+                    attempts.spawn(async move {
+                        let conn = establish_conn(&endpoint, remote, &host, &connect).await?;
+                        anyhow::Ok((endpoint, conn))
+                    });
+                }
+                Err(e) => error = Some(anyhow!("Failed to connect to {}: {}", remote, e)),
             }
-        } else {
-            // No session exists
-            let session = get_session(url.clone(), host.clone(), ca.clone(), temp.pass.clone())
-                .instrument(info_span!("Fetch Session"))
-                .await?;
-
-            temp.session = Some(session.clone()); // Update session
-            session
+        } else if attempts.is_empty() {
+            return Err(error.unwrap_or_else(|| anyhow!("Couldn't resolve to an address")));
         }
-    } else {
-        // No Printer passed, generate temp session
-        let pass = request_for_pass().await;
-        get_session(url.clone(), host.clone(), ca.clone(), pass)
-            .instrument(info_span!("Fetch Session"))
-            .await?
-    };
-
-    // Parse headers and file
-    let file = file.clone();
-    let headers = Vec::from([
-        format!("POST {:?}", file.file_name().unwrap()),
-        format!("Content-Length: {}", file.metadata().unwrap().len()),
-        format!(
-            "Extension: {}",
-            file.extension().and_then(OsStr::to_str).unwrap()
-        ),
-        format!("Session: {}", session.id),
-        format!("\r\n"),
-    ])
-    .join("\r\n");
 
-    let mut buf = Vec::new();
-    debug!("Headers: {:?}", headers);
-    File::open(file).await?.read_to_end(&mut buf).await?;
-
-    // convert request to binary
-    let mut request = headers.into_bytes();
-    request.extend(buf);
+        let more = remotes.peek().is_some();
+        tokio::select! {
+            Some(result) = attempts.join_next() => match result? {
+                Ok((endpoint, conn)) => return Ok(Link::Quic(endpoint, conn)),
+                Err(e) => {
+                    debug!("Connection attempt failed: {:#}", e);
+                    error = Some(e);
+                }
+            },
+            _ = tokio::time::sleep(HAPPY_EYEBALLS_DELAY), if more => (),
+            else => (),
+        }
+    }
+}
 
-    // Resolve host name
-    let host = host
-        .as_ref()
-        .map_or_else(|| url.host_str(), |x| Some(x))
-        .ok_or_else(|| anyhow!("no hostname specified"))?;
+/// Connects over TLS on TCP to the first of `remotes` that answers, trying them in turn
+async fn connect_tcp(
+    config: Arc<rustls::ClientConfig>,
+    remotes: Vec<SocketAddr>,
+    host: &str,
+    connect: &ConnectOptions,
+) -> Result<Link> {
+    let server_name =
+        ServerName::try_from(host).map_err(|e| anyhow!("invalid host name {:?}: {}", host, e))?;
+    let connector = TlsConnector::from(config);
+    let mut error = None;
+    for remote in remotes {
+        eprintln!("Connecting to {host} at {remote} over TCP");
+        let mut link = TcpLink {
+            connector: connector.clone(),
+            remote,
+            host: server_name.clone(),
+            timeout: connect.timeout,
+            first: None,
+        };
+        match link.connect().await {
+            Ok(stream) => {
+                link.first = Some(Box::new(stream));
+                return Ok(Link::Tcp(link));
+            }
+            Err(e) => {
+                debug!("Connection attempt failed: {:#}", e);
+                error = Some(e);
+            }
+        }
+    }
+    Err(error.unwrap_or_else(|| anyhow!("Couldn't resolve to an address")))
+}
 
-    // Establish connection
-    eprintln!("Connecting to {host} at {remote}");
-    let conn = timeout(
-        Duration::from_secs(15),
-        establish_conn(endpoint.clone(), remote, host),
+/// Sends `request` on a new stream of `link` and returns the response.
+/// `progress` is called with the bytes sent so far and the total.
+/// Setting `cancel` while sending closes the connection and returns [`PrintError::Cancelled`].
+/// A broken connection returns [`PrintError::Connect`]. Sent no faster than `limiter` allows
+async fn exchange(
+    link: &mut Link,
+    request: &[u8],
+    progress: Option<&dyn Fn(u64, u64)>,
+    cancel: Option<&AtomicBool>,
+    limiter: Option<&mut RateLimiter>,
+) -> Result<Vec<u8>> {
+    exchange_body(
+        link,
+        request,
+        (&mut tokio::io::empty(), 0),
+        progress,
+        cancel,
+        limiter,
     )
-    .await??;
+    .await
+}
 
+/// [`exchange`] with a request of the headers `head` followed by `len` bytes read from `body`,
+/// copied a chunk at a time so memory use doesn't grow with the body
+async fn exchange_body(
+    link: &mut Link,
+    head: &[u8],
+    (body, len): (&mut (dyn AsyncRead + Send + Unpin), u64),
+    progress: Option<&dyn Fn(u64, u64)>,
+    cancel: Option<&AtomicBool>,
+    mut limiter: Option<&mut RateLimiter>,
+) -> Result<Vec<u8>> {
     // Parse Reader & Writer
-    let (mut send, mut recv) = conn
-        .open_bi()
-        .await
-        .map_err(|e| anyhow!("Failed to open stream: {}", e))?;
+    let (mut send, mut recv) = match link.open_stream().await {
+        Ok(stream) => stream,
+        Err(e) => return Err(lost_unsent(link, PrintError::Connect(e))),
+    };
 
-    // Send off request
-    send.write_all(&request)
-        .await
-        .map_err(|e| anyhow!("Failed to send request: {}", e))?;
+    // Send off request in chunks so progress can be reported
+    let total = head.len() as u64 + len;
+    let mut sent = 0;
+    let chunk_size = limiter.as_ref().map_or(CHUNK_SIZE, |l| l.chunk_size());
+    let mut buf = vec![0; chunk_size];
+    let mut head = head;
+    while sent < total {
+        // Reading the file may stall too, e.g. on a network drive
+        let step = async {
+            let chunk = if head.is_empty() {
+                let want = buf.len().min((total - sent) as usize);
+                let read = body.read(&mut buf[..want]).await?;
+                if read == 0 {
+                    return Err(PrintError::Other(anyhow!(
+                        "file got shorter while it was sent"
+                    )));
+                }
+                &buf[..read]
+            } else {
+                let (chunk, rest) = head.split_at(head.len().min(chunk_size));
+                head = rest;
+                chunk
+            };
+            if let Some(limiter) = limiter.as_deref_mut() {
+                limiter.wait(chunk.len()).await;
+            }
+            send.write_all(chunk)
+                .await
+                .map_err(|e| PrintError::Connect(anyhow!("Failed to send request: {}", e)))?;
+            Ok(chunk.len())
+        };
+        tokio::select! {
+            result = step => match result {
+                Ok(len) => sent += len as u64,
+                Err(e @ PrintError::Connect(_)) => return Err(lost_unsent(link, e)),
+                Err(e) => return Err(e.into()),
+            },
+            _ = cancelled(cancel) => {
+                link.close(CLOSE_CANCELLED, b"cancelled").await;
+                return Err(PrintError::Cancelled.into());
+            }
+        }
 
-    send.finish()
-        .await
-        .map_err(|e| anyhow!("failed to shut down stream: {}", e))?;
+        if let Some(progress) = progress {
+            progress(sent, total);
+        }
+    }
 
-    // Read response
-    let resp = recv
-        .read_to_end(usize::max_value())
-        .await
-        .map_err(|e| anyhow!("failed to read response: {}", e))?;
-    eprintln!("Successfully sent file");
-    println!("{}", String::from_utf8(resp).unwrap());
+    if let Err(e) = send.shutdown().await {
+        let e = PrintError::Connect(anyhow!("failed to shut down stream: {}", e));
+        return Err(lost_unsent(link, e));
+    }
 
-    conn.close(0u32.into(), b"done");
+    // Read response, a cancel stops waiting for it though the server may still print
+    tokio::select! {
+        resp = read_response(&mut recv) => Ok(resp.map_err(PrintError::Connect)?),
+        _ = cancelled(cancel) => {
+            link.close(CLOSE_CANCELLED, b"cancelled").await;
+            Err(PrintError::Cancelled.into())
+        }
+    }
+}
 
-    endpoint.wait_idle().await;
+/// Marks a request that failed as [`NotSent`] if `link` was lost meanwhile
+fn lost_unsent(link: &Link, e: PrintError) -> anyhow::Error {
+    let e = anyhow::Error::from(e);
+    match link.is_open() {
+        true => e,
+        false => e.context(NotSent),
+    }
+}
 
-    Ok(())
+/// Context of a request whose connection was lost before the server had all of it,
+/// so it can't have acted on it. Downcasting still finds the [`PrintError`] underneath
+#[derive(Debug)]
+struct NotSent;
+
+impl std::fmt::Display for NotSent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("connection lost before the request was sent")
+    }
 }
 
-async fn establish_conn(endpoint: Endpoint, remote: SocketAddr, host: &str) -> Result<Connection> {
-    let conn = endpoint
-        .connect(remote, host)?
-        .await
-        .map_err(|e| anyhow!("Failed to connect: {}", e))?;
-    debug!("Connected to server");
+/// Whether `err` is a request that never reached the server, see [`NotSent`]
+fn not_sent(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<NotSent>().is_some()
+}
 
-    Ok(conn)
+/// Resolves once `cancel` is set, never without one
+async fn cancelled(cancel: Option<&AtomicBool>) {
+    let Some(cancel) = cancel else {
+        return std::future::pending().await;
+    };
+    while !cancel.load(Ordering::Relaxed) {
+        tokio::time::sleep(CANCEL_POLL_INTERVAL).await;
+    }
 }
 
-pub async fn get_session(
-    url: Url,
-    host: Option<String>,
-    ca: Option<PathBuf>,
-    pass: String,
-) -> Result<Session> {
-    let remote = (url.host_str().unwrap(), url.port().unwrap_or(4433))
-        .to_socket_addrs()?
-        .next()
-        .ok_or_else(|| anyhow!("Couldn't resolve to an address"))?;
+/// Resolves every address of the server, alternating between IPv6 and IPv4
+/// starting with the family of the first, so an unreachable family is skipped early
+fn resolve(url: &Url) -> Result<Vec<SocketAddr>> {
+    let port = url.port().unwrap_or(DEFAULT_PORT);
+    let addrs: Vec<SocketAddr> = match url.host() {
+        Some(Host::Domain(domain)) => (domain, port).to_socket_addrs()?.collect(),
+        Some(Host::Ipv4(ip)) => vec![SocketAddr::new(ip.into(), port)],
+        Some(Host::Ipv6(ip)) => vec![SocketAddr::new(ip.into(), port)],
+        None => bail!("url has no host: {}", url),
+    };
+    if addrs.is_empty() {
+        bail!("Couldn't resolve to an address");
+    }
+    Ok(interleave_families(addrs))
+}
+
+/// `addrs` alternating between IPv6 and IPv4 starting with the family of the first,
+/// each family in its original order
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first_v6) = addrs.first().map(SocketAddr::is_ipv6) else {
+        return addrs;
+    };
+    let (first, second): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_v6);
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+    let mut ordered = Vec::new();
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
 
+/// Creates a TLS config trusting `connect.ca`,
+/// or the local server certificate, the bundled roots and `pin` if empty.
+/// The fingerprint of a certificate failing verification without a pin is put in `seen`.
+/// Presents `connect.client_auth` if set
+async fn client_config(
+    connect: &ConnectOptions,
+    pin: Option<String>,
+    seen: Arc<Mutex<Option<String>>>,
+) -> Result<Arc<rustls::ClientConfig>> {
     // Parse for TLS Certs
     let mut roots = rustls::RootCertStore::empty();
-    if let Some(ca_path) = ca {
-        roots.add(&rustls::Certificate(fs::read(ca_path)?))?;
+    if !connect.ca.is_empty() {
+        for path in &connect.ca {
+            add_ca(&mut roots, path)?;
+        }
+        if roots.is_empty() {
+            bail!("no CA certificates found in --ca");
+        }
     } else {
-        let dirs = directories::ProjectDirs::from("com", "Coded Masonry", "Remote Print").unwrap();
-        match fs::read(dirs.data_local_dir().join("cert.der")) {
+        match fs::read(data_dir()?.join("cert.der")) {
             Ok(cert) => {
                 roots.add(&rustls::Certificate(cert))?;
             }
@@ -239,85 +1916,243 @@ pub async fn get_session(
     }
 
     // TLS
-    let mut client_crypto = rustls::ClientConfig::builder()
-        .with_safe_defaults()
-        .with_root_certificates(roots)
-        .with_no_client_auth();
-    client_crypto.alpn_protocols = ALPN_QUIC_HTTP.iter().map(|&x| x.into()).collect();
+    let client_crypto = rustls::ClientConfig::builder().with_safe_defaults();
+    let verifier: Arc<dyn ServerCertVerifier> = if !connect.ca.is_empty() {
+        Arc::new(WebPkiVerifier::new(roots, None))
+    } else {
+        Arc::new(TofuVerifier {
+            roots: WebPkiVerifier::new(roots, None),
+            pin,
+            seen,
+        })
+    };
+    let client_crypto = client_crypto.with_custom_certificate_verifier(verifier);
+    let mut client_crypto = match &connect.client_auth {
+        Some(auth) => {
+            let (certs, key) = parse_client_auth(auth)?;
+            client_crypto.with_client_auth_cert(certs, key)?
+        }
+        None => client_crypto.with_no_client_auth(),
+    };
+    client_crypto.alpn_protocols = vec![ALPN_PROTOCOL.to_vec(), LEGACY_ALPN.to_vec()];
 
-    // Establish config
-    let client_config = quinn::ClientConfig::new(Arc::new(client_crypto));
-    let mut endpoint = quinn::Endpoint::client("0.0.0.0:0".parse().unwrap())?;
-    endpoint.set_default_client_config(client_config);
+    Ok(Arc::new(client_crypto))
+}
 
-    // Parse headers and file
-    let headers = Vec::from([format!("GET authenticate"), format!("\r\n")]).join("\r\n");
+/// Adds the CA certificates in `path` to `roots`, a DER or PEM file or a directory of them.
+/// Unreadable files in a directory are skipped with a warning, a bad file given directly fails
+fn add_ca(roots: &mut rustls::RootCertStore, path: &Path) -> Result<()> {
+    if !path.is_dir() {
+        return add_ca_file(roots, path)
+            .with_context(|| format!("failed to load CA {}", path.display()));
+    }
 
-    let mut request = headers.into_bytes();
-    request.extend(pass.as_bytes());
+    let entries = fs::read_dir(path)
+        .with_context(|| format!("failed to read CA directory {}", path.display()))?;
+    for entry in entries {
+        let file = entry?.path();
+        let is_cert = file
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| matches!(ext, "der" | "pem" | "crt" | "cer"));
+        if !is_cert || file.is_dir() {
+            continue;
+        }
+        if let Err(e) = add_ca_file(roots, &file) {
+            warn!("skipping CA {}: {:#}", file.display(), e);
+        }
+    }
+    Ok(())
+}
 
-    // Resolve host name
-    let host = host
-        .as_ref()
-        .map_or_else(|| url.host_str(), |x| Some(x))
-        .ok_or_else(|| anyhow!("no hostname specified"))?;
+/// Adds every certificate in a file, PEM if it has PEM headers, DER otherwise
+fn add_ca_file(roots: &mut rustls::RootCertStore, path: &Path) -> Result<()> {
+    let data = fs::read(path)?;
+    let certs = if data.windows(10).any(|w| w == b"-----BEGIN") {
+        rustls_pemfile::certs(&mut &*data).context("invalid PEM-encoded certificate")?
+    } else {
+        vec![data]
+    };
+    if certs.is_empty() {
+        bail!("no certificates in file");
+    }
+    for cert in certs {
+        roots.add(&Certificate(cert))?;
+        debug!("Trusting CA from {}", path.display());
+    }
+    Ok(())
+}
 
-    // Establish connection
-    eprintln!("Connecting to {host} at {remote}");
-    let conn = endpoint
-        .connect(remote, host)?
-        .await
-        .map_err(|e| anyhow!("Failed to connect: {}", e))?;
-    debug!("Connected to server");
+/// Reads the client certificate chain and private key
+fn parse_client_auth(auth: &ClientAuth) -> Result<(Vec<Certificate>, PrivateKey)> {
+    let cert = fs::read(&auth.cert).context("failed to read client certificate")?;
+    let certs = if auth.cert.extension().is_some_and(|x| x == "der") {
+        vec![Certificate(cert)]
+    } else {
+        rustls_pemfile::certs(&mut &*cert)
+            .context("invalid PEM-encoded client certificate")?
+            .into_iter()
+            .map(Certificate)
+            .collect()
+    };
 
-    // Parse Reader & Writer
-    let (mut send, mut recv) = conn
-        .open_bi()
-        .await
-        .map_err(|e| anyhow!("Failed to open stream: {}", e))?;
+    let key = fs::read(&auth.key).context("failed to read client key")?;
+    let key = if auth.key.extension().is_some_and(|x| x == "der") {
+        key
+    } else {
+        let mut keys = rustls_pemfile::pkcs8_private_keys(&mut &*key)
+            .context("malformed PKCS #8 private key")?;
+        if keys.is_empty() {
+            keys = rustls_pemfile::rsa_private_keys(&mut &*key)
+                .context("malformed PKCS #1 private key")?;
+        }
+        keys.into_iter().next().context("no private keys found")?
+    };
 
-    // Send off request
-    send.write_all(&request)
-        .await
-        .map_err(|e| anyhow!("Failed to send request: {}", e))?;
+    Ok((certs, PrivateKey(key)))
+}
 
-    send.finish()
-        .await
-        .map_err(|e| anyhow!("failed to shut down stream: {}", e))?;
+/// Connects to `remote`, retrying timeouts and resets up to `connect.retries` times
+/// with exponential backoff. Anything else (e.g. a bad certificate) fails right away
+async fn establish_conn(
+    endpoint: &Endpoint,
+    remote: SocketAddr,
+    host: &str,
+    connect: &ConnectOptions,
+) -> Result<Connection> {
+    let mut delay = RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        let error = match timeout(connect.timeout, endpoint.connect(remote, host)?).await {
+            Ok(Ok(conn)) => {
+                let protocol = conn
+                    .handshake_data()
+                    .and_then(|data| data.downcast::<quinn::crypto::rustls::HandshakeData>().ok())
+                    .and_then(|data| data.protocol);
+                if protocol.as_deref() == Some(LEGACY_ALPN) {
+                    debug!("Server uses the legacy protocol");
+                }
+                debug!("Connected to server");
+                return Ok(conn);
+            }
+            // No shared protocol, the server is too old or too new for this client
+            Ok(Err(ConnectionError::ConnectionClosed(close)))
+                if u64::from(close.error_code) == NO_APPLICATION_PROTOCOL =>
+            {
+                bail!(
+                    "server doesn't speak remote-print/{}, client and server versions are incompatible",
+                    PROTOCOL_VERSION
+                )
+            }
+            Ok(Err(e @ ConnectionError::TimedOut)) => {
+                PrintError::Timeout(format!("Failed to connect: {}", e)).into()
+            }
+            Ok(Err(e @ ConnectionError::Reset)) => anyhow!("Failed to connect: {}", e),
+            Ok(Err(e)) => bail!("Failed to connect: {}", e),
+            Err(_) => PrintError::Timeout(format!(
+                "timed out connecting to {} after {:?}",
+                remote, connect.timeout
+            ))
+            .into(),
+        };
+
+        if attempt > connect.retries {
+            return Err(error);
+        }
+        debug!(
+            "Connection attempt {} failed ({}), retrying in {:?}",
+            attempt, error, delay
+        );
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+        attempt += 1;
+    }
+}
 
-    // Read response
-    let resp = recv
-        .read_to_end(usize::max_value())
+/// Reads a response, failing if it exceeds [`MAX_RESPONSE_SIZE`]
+async fn read_response<R: AsyncRead + Unpin>(recv: &mut R) -> Result<Vec<u8>> {
+    let mut resp = Vec::new();
+    recv.take(MAX_RESPONSE_SIZE as u64 + 1)
+        .read_to_end(&mut resp)
         .await
         .map_err(|e| anyhow!("failed to read response: {}", e))?;
-    eprintln!("Successfully verified session");
+    if resp.len() > MAX_RESPONSE_SIZE {
+        bail!("response exceeds {} bytes", MAX_RESPONSE_SIZE);
+    }
+    Ok(resp)
+}
+
+pub async fn get_session(
+    url: Url,
+    connect: &ConnectOptions,
+    user: Option<String>,
+    pass: String,
+) -> Result<Session, PrintError> {
+    let resp = send_request(&url, connect, &session_request(user, &pass), None, None).await?;
+    Ok(parse_session(resp)?)
+}
 
-    conn.close(0u32.into(), b"done");
+/// Builds the request logging in as `user`
+fn session_request(user: Option<String>, pass: &str) -> Vec<u8> {
+    let headers = Request {
+        user,
+        protocol_version: Some(PROTOCOL_VERSION),
+        ..Request::get("authenticate")
+    }
+    .serialize();
 
-    endpoint.wait_idle().await;
+    let mut request = headers.into_bytes();
+    request.extend(pass.as_bytes());
+    request
+}
 
-    let resp = String::from_utf8(resp)?;
+/// Parses the response to [`session_request`], a refused login is [`PrintError::Auth`]
+fn parse_session(resp: Vec<u8>) -> Result<Session> {
+    let resp = String::from_utf8(resp).map_err(|e| PrintError::Protocol(e.to_string()))?;
     debug!(response = resp);
 
-    let resp: Vec<&str> = resp.split("&").collect();
-    if resp[0] == "success" {
-        let session = Session {
-            id: Uuid::parse_str(resp[1])?,
-            expiratrion: DateTime::from_str(resp[2])?,
-        };
+    if is_error_response(&resp) {
+        let msg = resp.trim().trim_start_matches(ERROR_PREFIX);
+        return Err(PrintError::Auth(msg.to_string()).into());
+    }
+    let auth = AuthResponse::parse(&resp).map_err(|e| PrintError::Protocol(format!("{:#}", e)))?;
+    eprintln!("Successfully verified session");
+    Ok(Session {
+        id: auth.session_id,
+        expiration: auth.expiration,
+    })
+}
 
-        return Ok(session);
-    } else {
-        bail!("Failed: {}", resp[0])
+/// Environment variable pointing the client and server at another data directory
+pub const DATA_DIR_ENV: &str = "REMOTE_PRINT_DATA_DIR";
+
+/// Where settings and certificates are kept: [`DATA_DIR_ENV`] if set, the platform's
+/// local data directory otherwise. Fails without a home directory, e.g. in some containers.
+/// The server finds the same directory, the client trusts the certificate it keeps there
+pub fn data_dir() -> Result<PathBuf> {
+    data_dir_from(std::env::var_os(DATA_DIR_ENV))
+}
+
+/// [`data_dir`] with `var` as the value of [`DATA_DIR_ENV`]
+fn data_dir_from(var: Option<std::ffi::OsString>) -> Result<PathBuf> {
+    if let Some(dir) = var.filter(|dir| !dir.is_empty()) {
+        return Ok(PathBuf::from(dir));
     }
+    let dirs = directories::ProjectDirs::from("com", "Coded Masonry", "Remote Print")
+        .with_context(|| {
+            format!(
+                "couldn't find a home directory to keep data in, set {} to a directory",
+                DATA_DIR_ENV
+            )
+        })?;
+    Ok(dirs.data_local_dir().to_path_buf())
 }
 
 pub fn get_settings() -> Result<Settings> {
-    let dirs = directories::ProjectDirs::from("com", "Coded Masonry", "Remote Print").unwrap();
-
-    let settings = match fs::read(dirs.data_local_dir().join("settings.json")) {
+    let settings = match fs::read(data_dir()?.join("settings.json")) {
         Ok(file) => {
-            let settings: Settings = serde_json::from_slice(&file)?;
+            let mut settings: Settings = serde_json::from_slice(&file)?;
+            settings.clamp();
             settings
         }
         Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
@@ -334,18 +2169,121 @@ pub fn get_settings() -> Result<Settings> {
 }
 
 pub fn save_settings(settings: &Settings) -> Result<()> {
-    let dirs = directories::ProjectDirs::from("com", "Coded Masonry", "Remote Print").unwrap();
+    let dir = data_dir()?;
     let json = serde_json::to_string(&settings)?;
-    debug!("Fetching dir: {:?}", dirs.data_local_dir());
+    debug!("Fetching dir: {:?}", dir);
 
     // Make sure directories exist
-    fs::create_dir_all(dirs.data_local_dir())?;
+    fs::create_dir_all(&dir)?;
     // Write the json
-    fs::write(dirs.data_local_dir().join("settings.json"), json)?;
+    fs::write(dir.join("settings.json"), json)?;
+
+    Ok(())
+}
+
+/// Argon2 passes deriving the key of an encrypted settings export
+const EXPORT_KDF_ITERATIONS: u32 = 3;
+/// Argon2 memory in KiB deriving the key of an encrypted settings export
+const EXPORT_KDF_MEMORY_KIB: u32 = 1 << 16;
+/// Most Argon2 memory an imported file can ask for, so a bad file can't exhaust memory
+const MAX_EXPORT_KDF_MEMORY_KIB: u32 = 1 << 20;
+/// Most Argon2 passes an imported file can ask for, so a bad file can't hang the import
+const MAX_EXPORT_KDF_ITERATIONS: u32 = 16;
+
+/// File written by [`export_settings`]
+#[derive(serde::Deserialize, serde::Serialize)]
+#[serde(tag = "format", rename_all = "lowercase")]
+enum SettingsExport {
+    Plain {
+        settings: Settings,
+    },
+    /// Settings JSON sealed (XChaCha20-Poly1305) with a key derived (Argon2) from a passphrase
+    Encrypted {
+        iterations: u32,
+        memory_kib: u32,
+        salt: Vec<u8>,
+        sealed: Vec<u8>,
+    },
+}
+
+/// Key sealing an encrypted settings export
+fn export_key(
+    passphrase: &str,
+    salt: &kdf::Salt,
+    iterations: u32,
+    memory_kib: u32,
+) -> Result<aead::SecretKey> {
+    let password = kdf::Password::from_slice(passphrase.as_bytes())?;
+    Ok(kdf::derive_key(
+        &password, salt, iterations, memory_kib, 32,
+    )?)
+}
 
+/// Writes `settings` to `path` for [`import_settings`] on another machine, without sessions.
+/// Encrypted with `passphrase` if given, otherwise printer passwords are in plain text
+pub fn export_settings(settings: &Settings, path: &Path, passphrase: Option<&str>) -> Result<()> {
+    let mut settings = settings.clone();
+    settings.clear_sessions();
+
+    let export = match passphrase {
+        None => SettingsExport::Plain { settings },
+        Some(passphrase) => {
+            let salt = kdf::Salt::default();
+            let key = export_key(
+                passphrase,
+                &salt,
+                EXPORT_KDF_ITERATIONS,
+                EXPORT_KDF_MEMORY_KIB,
+            )?;
+            SettingsExport::Encrypted {
+                iterations: EXPORT_KDF_ITERATIONS,
+                memory_kib: EXPORT_KDF_MEMORY_KIB,
+                salt: salt.as_ref().to_vec(),
+                sealed: aead::seal(&key, &serde_json::to_vec(&settings)?)?,
+            }
+        }
+    };
+
+    fs::write(path, serde_json::to_vec_pretty(&export)?)
+        .with_context(|| format!("failed to write {}", path.display()))?;
     Ok(())
 }
 
+/// Reads settings written by [`export_settings`], `passphrase` is needed if it was encrypted
+pub fn import_settings(path: &Path, passphrase: Option<&str>) -> Result<Settings> {
+    let data = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let export: SettingsExport = serde_json::from_slice(&data)
+        .with_context(|| format!("{} isn't a settings export", path.display()))?;
+
+    let mut settings = match export {
+        SettingsExport::Plain { settings } => settings,
+        SettingsExport::Encrypted {
+            iterations,
+            memory_kib,
+            salt,
+            sealed,
+        } => {
+            let Some(passphrase) = passphrase else {
+                bail!("the export is encrypted, enter its passphrase");
+            };
+            if memory_kib > MAX_EXPORT_KDF_MEMORY_KIB {
+                bail!("the export asks for too much memory to decrypt");
+            }
+            if iterations > MAX_EXPORT_KDF_ITERATIONS {
+                bail!("the export asks for too many iterations to decrypt");
+            }
+            let salt = kdf::Salt::from_slice(&salt).context("the export's salt is invalid")?;
+            let key = export_key(passphrase, &salt, iterations, memory_kib)
+                .context("the export's key parameters are invalid")?;
+            let json = aead::open(&key, &sealed)
+                .map_err(|_| anyhow!("wrong passphrase, or the export is damaged"))?;
+            serde_json::from_slice(&json).context("the export's settings are invalid")?
+        }
+    };
+    settings.clamp();
+    Ok(settings)
+}
+
 pub async fn parse_certs() -> Vec<Certificate> {
     let mut temp = Vec::new();
     for file in DEFAULT_ROOTS.files() {
@@ -358,7 +2296,7 @@ pub async fn parse_certs() -> Vec<Certificate> {
 }
 
 /// Ask the user for the password (CLI Only)
-pub async fn request_for_pass() -> String {
+pub fn request_for_pass() -> String {
     let pass = inquire::Password::new("Please enter a password:")
         .with_display_toggle_enabled()
         .with_display_mode(inquire::PasswordDisplayMode::Hidden)
@@ -367,3 +2305,494 @@ pub async fn request_for_pass() -> String {
 
     pass
 }
+
+/// Extension to send for `file`, guessed from `contents` when the name has none.
+/// The server checks the content too
+fn file_extension(file: &Path, contents: &[u8]) -> Option<String> {
+    match file.extension() {
+        Some(extension) => Some(extension.to_string_lossy().into_owned()),
+        None => sniff_extension(&contents[..contents.len().min(SNIFF_LEN)]).map(String::from),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use printer_protocol::{error_response, JobError};
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limiter_paces_writes() {
+        let mut limiter = RateLimiter::new(100_000);
+        let start = tokio::time::Instant::now();
+        for _ in 0..50 {
+            limiter.wait(10_000).await;
+        }
+        // 500 kB at 100 kB/s
+        let elapsed = start.elapsed().as_secs_f64();
+        assert!((4.9..=5.1).contains(&elapsed), "took {}s", elapsed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limiter_bursts_at_most_a_second() {
+        let mut limiter = RateLimiter::new(100_000);
+        tokio::time::sleep(Duration::from_secs(10)).await;
+
+        // A second's worth goes right away, the rest is paced
+        let start = tokio::time::Instant::now();
+        for _ in 0..30 {
+            limiter.wait(10_000).await;
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+        assert!((1.9..=2.1).contains(&elapsed), "took {}s", elapsed);
+    }
+
+    #[test]
+    fn pooled_connections_are_kept_apart_by_trust() {
+        let url = Url::parse("print://127.0.0.1:5000").unwrap();
+        let connect = ConnectOptions::default();
+        let key = pool_key(&url, &connect);
+
+        let mut ca = connect.clone();
+        ca.ca = vec![PathBuf::from("ca.pem")];
+        let mut client_auth = connect.clone();
+        client_auth.client_auth = Some(ClientAuth {
+            cert: PathBuf::from("cert.pem"),
+            key: PathBuf::from("key.pem"),
+        });
+        let mut pinned = connect.clone();
+        pinned.pinned_certs.insert(host_key(&url), "ab".repeat(32));
+        let mut tcp = connect.clone();
+        tcp.transport = Transport::Tcp;
+        for other in [ca, client_auth, pinned, tcp] {
+            assert_ne!(pool_key(&url, &other), key);
+        }
+
+        // Pins of other servers don't matter
+        let mut other_pin = connect.clone();
+        other_pin
+            .pinned_certs
+            .insert("example.com:5000".to_string(), "ab".repeat(32));
+        assert_eq!(pool_key(&url, &other_pin), key);
+    }
+
+    #[test]
+    fn resolved_addresses_alternate_families() {
+        let addrs = |list: &[&str]| -> Vec<SocketAddr> {
+            list.iter().map(|addr| addr.parse().unwrap()).collect()
+        };
+        let resolved = addrs(&[
+            "[2001:db8::1]:4433",
+            "[2001:db8::2]:4433",
+            "[2001:db8::3]:4433",
+            "192.0.2.1:4433",
+            "192.0.2.2:4433",
+        ]);
+        assert_eq!(
+            interleave_families(resolved),
+            addrs(&[
+                "[2001:db8::1]:4433",
+                "192.0.2.1:4433",
+                "[2001:db8::2]:4433",
+                "192.0.2.2:4433",
+                "[2001:db8::3]:4433",
+            ])
+        );
+
+        // Starts with whichever family came first
+        let resolved = addrs(&["192.0.2.1:4433", "192.0.2.2:4433", "[2001:db8::1]:4433"]);
+        assert_eq!(
+            interleave_families(resolved),
+            addrs(&["192.0.2.1:4433", "[2001:db8::1]:4433", "192.0.2.2:4433"])
+        );
+
+        let resolved = addrs(&["192.0.2.1:4433", "192.0.2.2:4433"]);
+        assert_eq!(interleave_families(resolved.clone()), resolved);
+        assert!(interleave_families(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn resolves_addresses_with_the_default_port() {
+        let url = Url::parse("https://[2001:db8::1]").unwrap();
+        assert_eq!(
+            resolve(&url).unwrap(),
+            [SocketAddr::new(
+                "2001:db8::1".parse().unwrap(),
+                DEFAULT_PORT
+            )]
+        );
+    }
+
+    /// [`TofuVerifier`] without roots, so every certificate falls back to the pin
+    fn tofu(pin: Option<String>) -> TofuVerifier {
+        TofuVerifier {
+            roots: WebPkiVerifier::new(rustls::RootCertStore::empty(), None),
+            pin,
+            seen: Default::default(),
+        }
+    }
+
+    fn verify(verifier: &TofuVerifier, cert: &[u8]) -> Result<ServerCertVerified, rustls::Error> {
+        verifier.verify_server_cert(
+            &Certificate(cert.to_vec()),
+            &[],
+            &ServerName::try_from("localhost").unwrap(),
+            &mut std::iter::empty(),
+            &[],
+            SystemTime::now(),
+        )
+    }
+
+    #[test]
+    fn pinned_certificates_are_trusted() {
+        let (cert, _) = printer_server::self_signed(vec!["localhost".into()]).unwrap();
+        let verifier = tofu(Some(fingerprint(&cert)));
+        assert!(verify(&verifier, &cert).is_ok());
+        assert!(verifier.seen.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn changed_certificates_are_rejected() {
+        let (pinned, _) = printer_server::self_signed(vec!["localhost".into()]).unwrap();
+        let (cert, _) = printer_server::self_signed(vec!["localhost".into()]).unwrap();
+        let verifier = tofu(Some(fingerprint(&pinned)));
+
+        let err = verify(&verifier, &cert).unwrap_err().to_string();
+        assert!(err.contains("SERVER CERTIFICATE CHANGED"), "{}", err);
+        assert!(err.contains(&fingerprint(&cert)), "{}", err);
+        // Not offered for trust on first use, the pin has to be removed by hand
+        assert!(verifier.seen.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn unpinned_certificates_are_seen() {
+        let (cert, _) = printer_server::self_signed(vec!["localhost".into()]).unwrap();
+        let verifier = tofu(None);
+        assert!(verify(&verifier, &cert).is_err());
+        assert_eq!(*verifier.seen.lock().unwrap(), Some(fingerprint(&cert)));
+    }
+
+    #[tokio::test]
+    async fn gives_up_connecting_after_the_timeout() {
+        // Takes packets but never answers, like a host that's gone
+        let udp = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("https://{}", udp.local_addr().unwrap())).unwrap();
+        let connect = ConnectOptions {
+            timeout: Duration::from_millis(300),
+            ..Default::default()
+        };
+
+        let start = std::time::Instant::now();
+        let err = get_session(url, &connect, None, "hunter22".to_string())
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().starts_with("timed out connecting"),
+            "{:#}",
+            err
+        );
+        assert!(matches!(err, PrintError::Connect(_)));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        drop(udp);
+    }
+
+    #[tokio::test]
+    async fn auto_falls_back_to_tcp_quickly() {
+        // QUIC packets go unanswered like on a network blocking UDP, TCP connects
+        let udp = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = udp.local_addr().unwrap();
+        let tcp = tokio::net::TcpListener::bind(addr).await.unwrap();
+        let url = Url::parse(&format!("https://{}", addr)).unwrap();
+        let connect = ConnectOptions {
+            timeout: Duration::from_secs(30),
+            ..Default::default()
+        };
+
+        let start = std::time::Instant::now();
+        let accept = async {
+            let (stream, _) = tcp.accept().await.unwrap();
+            let elapsed = start.elapsed();
+            drop(stream);
+            elapsed
+        };
+        let (login, elapsed) = tokio::join!(
+            get_session(url, &connect, None, "hunter22".to_string()),
+            accept
+        );
+        assert!(elapsed < QUIC_FALLBACK_TIMEOUT * 2, "took {:?}", elapsed);
+        assert!(login.is_err());
+        drop(udp);
+    }
+
+    #[tokio::test]
+    async fn bodies_read_from_an_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("body.txt");
+        fs::write(&path, b"0123456789").unwrap();
+
+        for body in [
+            Body::Memory(b"0123456789".to_vec()),
+            Body::File { path, len: 10 },
+        ] {
+            for (offset, rest) in [(0, &b"0123456789"[..]), (4, b"456789"), (10, b"")] {
+                let mut read = Vec::new();
+                body.reader(offset)
+                    .await
+                    .unwrap()
+                    .read_to_end(&mut read)
+                    .await
+                    .unwrap();
+                assert_eq!(read, rest, "offset {}", offset);
+            }
+        }
+    }
+
+    #[test]
+    fn outcomes_map_to_print_errors() {
+        assert!(PrintOutcome::Printed(None, Usage::default())
+            .into_result()
+            .is_ok());
+        assert!(PrintOutcome::Validated(Usage::default())
+            .into_result()
+            .is_ok());
+        assert!(matches!(
+            PrintOutcome::SessionExpired("expired".to_string()).into_result(),
+            Err(PrintError::Auth(_))
+        ));
+        assert!(matches!(
+            PrintOutcome::Rejected("bad extension".to_string()).into_result(),
+            Err(PrintError::Rejected(_))
+        ));
+        assert!(matches!(
+            PrintOutcome::UnknownPrinter("office".to_string()).into_result(),
+            Err(PrintError::UnknownPrinter(_))
+        ));
+        assert!(matches!(
+            PrintOutcome::TooLarge("over 50 MiB".to_string()).into_result(),
+            Err(PrintError::TooLarge(_))
+        ));
+        assert!(matches!(
+            PrintOutcome::PrinterError("out of paper".to_string()).into_result(),
+            Err(PrintError::PrinterError(_))
+        ));
+    }
+
+    #[test]
+    fn only_connection_problems_are_retryable() {
+        assert!(PrintError::Connect(anyhow!("refused")).is_retryable());
+        assert!(PrintError::Timeout("timed out".to_string()).is_retryable());
+        for e in [
+            PrintError::Io(io::Error::from(io::ErrorKind::NotFound)),
+            PrintError::Cancelled,
+            PrintError::Auth("Invalid username or password".to_string()),
+            PrintError::Protocol("not JSON".to_string()),
+            PrintError::PrinterError("out of paper".to_string()),
+            PrintError::Other(anyhow!("invalid page range")),
+        ] {
+            assert!(!e.is_retryable(), "{:?}", e);
+        }
+    }
+
+    #[test]
+    fn files_without_an_extension_are_sniffed() {
+        let pdf = file_extension(Path::new("scan"), b"%PDF-1.7\n%\xe2\xe3");
+        assert_eq!(pdf.as_deref(), Some("pdf"));
+        assert_eq!(file_extension(Path::new("blob"), &[0, 159, 146, 150]), None);
+        assert_eq!(file_extension(Path::new("empty"), &[]), None);
+
+        // Still a well-formed request for the server without an extension
+        let request = Request {
+            content_length: Some(4),
+            extension: file_extension(Path::new("blob"), &[0, 159, 146, 150]),
+            ..Request::post("blob")
+        };
+        let parsed = Request::parse(&request.serialize()).unwrap();
+        assert_eq!(parsed.extension, None);
+        assert_eq!(parsed.content_length, Some(4));
+    }
+
+    #[tokio::test]
+    async fn responses_over_the_limit_are_rejected() {
+        let mut recv = std::io::Cursor::new(vec![b'a'; MAX_RESPONSE_SIZE]);
+        assert_eq!(
+            read_response(&mut recv).await.unwrap().len(),
+            MAX_RESPONSE_SIZE
+        );
+
+        let mut recv = std::io::Cursor::new(vec![b'a'; MAX_RESPONSE_SIZE + 1]);
+        let err = read_response(&mut recv).await.unwrap_err();
+        assert!(err.to_string().contains("response exceeds"), "{:#}", err);
+    }
+
+    /// Not valid UTF-8: a lone continuation byte and a truncated sequence
+    const INVALID_UTF8: &[u8] = b"Failed to process request: \x80 bad \xe2\x82";
+
+    #[test]
+    fn invalid_utf8_upload_response_fails_cleanly() {
+        match PrintOutcome::from_response(INVALID_UTF8) {
+            PrintOutcome::Failed(text) => assert!(text.contains("bad"), "{:?}", text),
+            outcome => panic!("expected a failure, got {:?}", outcome),
+        }
+        assert!(matches!(
+            PrintOutcome::from_response(b"\xff\xfe"),
+            PrintOutcome::Failed(_)
+        ));
+    }
+
+    #[test]
+    fn invalid_utf8_session_response_is_a_protocol_error() {
+        let err = PrintError::from(parse_session(INVALID_UTF8.to_vec()).unwrap_err());
+        assert!(matches!(err, PrintError::Protocol(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn invalid_utf8_json_response_is_an_error() {
+        let err = unexpected_response(INVALID_UTF8);
+        assert!(matches!(err, PrintError::PrinterError(_)), "{:?}", err);
+        let err = unexpected_response(b"\xff\xfe");
+        assert!(matches!(err, PrintError::Protocol(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn connection_urls_become_https() {
+        let url = Url::parse("remote-print://192.168.1.20:4433?fingerprint=ab:cd").unwrap();
+        let (url, fingerprint) = server_url(url).unwrap();
+        assert_eq!(url.as_str(), "https://192.168.1.20:4433/");
+        assert_eq!(fingerprint.as_deref(), Some("AB:CD"));
+
+        let url = Url::parse("remote-print://[fe80::1]:4433").unwrap();
+        let (url, fingerprint) = server_url(url).unwrap();
+        assert_eq!(url.as_str(), "https://[fe80::1]:4433/");
+        assert_eq!(fingerprint, None);
+
+        let url = Url::parse("https://printer.local:8443").unwrap();
+        assert_eq!(server_url(url.clone()).unwrap(), (url, None));
+    }
+
+    #[test]
+    fn refused_sessions_are_auth_errors() {
+        for (code, msg) in [
+            (JobCode::AuthRequired, "Authentication Required"),
+            (JobCode::AuthRequired, "missing Session header"),
+            (JobCode::SessionExpired, "Expired Session"),
+        ] {
+            let resp = error_response(&JobError::new(code, msg).into());
+            match unexpected_response(&resp) {
+                PrintError::Auth(text) => assert_eq!(text, msg),
+                err => panic!("expected an auth error for {:?}, got {:?}", msg, err),
+            }
+        }
+
+        // Told apart by the code, not the wording
+        let resp = error_response(&anyhow!("Authentication Required"));
+        let err = unexpected_response(&resp);
+        assert!(matches!(err, PrintError::PrinterError(_)), "{:?}", err);
+        let resp = error_response(&JobError::new(JobCode::UnknownPrinter, "office").into());
+        let err = unexpected_response(&resp);
+        assert!(matches!(err, PrintError::UnknownPrinter(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn missing_files_and_folders_fail_the_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("gone.pdf");
+        let err = Document::from(missing.clone()).check().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("{} doesn't exist", missing.display())
+        );
+
+        let err = Document::from(dir.path().to_path_buf())
+            .check()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "{} is a folder, only files can be printed",
+                dir.path().display()
+            )
+        );
+
+        fs::write(dir.path().join("a.txt"), "hello").unwrap();
+        Document::from(dir.path().join("a.txt")).check().unwrap();
+    }
+
+    #[tokio::test]
+    async fn unreadable_files_fail_without_connecting() {
+        let dir = tempfile::tempdir().unwrap();
+        let documents = [
+            Document::from(dir.path().join("gone.pdf")),
+            Document::from(dir.path().to_path_buf()),
+        ];
+        // Nothing listens there, connecting would fail the whole send
+        let url = Url::parse("https://127.0.0.1:9").unwrap();
+        let results = send_files_async(
+            url,
+            &ConnectOptions::default(),
+            &documents,
+            None,
+            PrintOptions::default(),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|r| matches!(r, Err(PrintError::Io(_)))));
+    }
+
+    #[test]
+    fn data_dir_can_be_overridden() {
+        let dir = data_dir_from(Some("/var/lib/remote-print".into())).unwrap();
+        assert_eq!(dir, PathBuf::from("/var/lib/remote-print"));
+
+        // Set but empty is the same as unset
+        if let Ok(default) = data_dir_from(None) {
+            assert_eq!(data_dir_from(Some("".into())).unwrap(), default);
+        }
+    }
+
+    #[test]
+    fn ca_files_can_be_der_or_pem() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let der = dir.path().join("ca.der");
+        fs::write(&der, cert.serialize_der().unwrap()).unwrap();
+        let other = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let bundle = dir.path().join("bundle.pem");
+        let pem = cert.serialize_pem().unwrap() + &other.serialize_pem().unwrap();
+        fs::write(&bundle, pem).unwrap();
+
+        let mut roots = rustls::RootCertStore::empty();
+        add_ca(&mut roots, &der).unwrap();
+        assert_eq!(roots.len(), 1);
+
+        let mut roots = rustls::RootCertStore::empty();
+        add_ca(&mut roots, &bundle).unwrap();
+        assert_eq!(roots.len(), 2);
+    }
+
+    #[test]
+    fn ca_directories_skip_bad_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let der = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        fs::write(dir.path().join("a.der"), der.serialize_der().unwrap()).unwrap();
+        let pem = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        fs::write(dir.path().join("b.crt"), pem.serialize_pem().unwrap()).unwrap();
+        let broken = dir.path().join("broken.pem");
+        fs::write(&broken, b"not a certificate").unwrap();
+        fs::write(dir.path().join("notes.txt"), b"not a certificate").unwrap();
+        fs::create_dir(dir.path().join("old.pem")).unwrap();
+
+        let mut roots = rustls::RootCertStore::empty();
+        add_ca(&mut roots, dir.path()).unwrap();
+        assert_eq!(roots.len(), 2);
+
+        // Given directly, a bad file fails
+        let err = add_ca(&mut roots, &broken).unwrap_err();
+        assert!(
+            err.to_string().starts_with("failed to load CA"),
+            "{:#}",
+            err
+        );
+    }
+}
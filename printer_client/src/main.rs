@@ -1,64 +1,673 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
-use std::path::PathBuf;
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::Arc,
+    time::Duration,
+};
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use clap::{Parser, Subcommand};
-use printer_client::app::Interface;
+use printer_client::{
+    app::{Interface, Settings},
+    get_settings, host_key, request_for_pass, ClientAuth, ConnectOptions, Document, PrintError,
+    PrintOptions, PrintOutcome, Printer, Transport, UnknownCertificate, DEFAULT_CONNECT_TIMEOUT,
+    DEFAULT_PORT, DEFAULT_RETRIES, MAX_COPIES, MAX_SCALING, POSITIONS,
+};
 
+use printer_client::watch::{self, AfterPrint, Event};
 use tracing::error;
-use tracing_subscriber::{self};
+use tracing_subscriber::EnvFilter;
 use url::Url;
 
+/// Exit code of `upload` when the server can't be reached
+const EXIT_CONNECTION: u8 = 3;
+/// Exit code of `upload` when the server rejects the login
+const EXIT_AUTH: u8 = 4;
+/// Exit code of `upload` when the server doesn't print the file
+const EXIT_PRINT: u8 = 5;
+
+/// Password used by `upload` when neither --password-file nor --password-stdin is given,
+/// instead of prompting
+const PASSWORD_ENV: &str = "REMOTE_PRINT_PASSWORD";
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true)]
 struct Args {
     #[command(subcommand)]
     command: Option<Commands>,
+
+    /// Log more, repeat for even more (-vv)
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Only log errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
 }
 
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Upload to remote printer.
+    #[command(after_help = "Exit codes:
+  0  printed, or passed the dry run
+  1  any other error
+  2  invalid arguments
+  3  couldn't connect to the server
+  4  the server rejected the login
+  5  the server didn't print the file
+With several files, the code of the first one that failed")]
     Upload {
-        url: Url,
+        /// Server to send to, e.g. https://192.168.1.20:8443, or a remote-print:// URL the server
+        /// printed, trusting the certificate it names.
+        /// Without one, the default printer starred in the GUI. With --printer, the first file instead
+        #[arg(value_name = "URL")]
+        url: Option<String>,
 
-        /// Override hostname used for certificate verification
-        #[arg(long = "host")]
-        host: Option<String>,
+        /// Printer set up in the GUI to send to instead of URL, by label or IP.
+        /// Uses its port, certificate hostname, user, printer and saved password
+        #[arg(short = 'p', long)]
+        printer: Option<String>,
 
-        /// Custom certificate authority to trust, in DER format
-        #[arg(long = "ca")]
-        ca: Option<PathBuf>,
+        #[command(flatten)]
+        server: ServerArgs,
 
-        /// The File to send
+        /// The File to send, repeat to send several
         #[arg(short, long = "file")]
-        file: PathBuf,
+        file: Vec<PathBuf>,
+
+        /// More files to send. Patterns like *.pdf are expanded if the shell didn't
+        #[arg(required_unless_present_any = ["file", "stdin", "printer", "url"])]
+        files: Vec<PathBuf>,
+
+        /// Send what's piped to standard input instead of files, needs --ext
+        #[arg(long, conflicts_with_all = ["file", "files", "password_stdin"], requires = "ext")]
+        stdin: bool,
+
+        /// Extension of the --stdin document, e.g. pdf or txt
+        #[arg(long, requires = "stdin", value_parser = parse_extension)]
+        ext: Option<String>,
+
+        #[command(flatten)]
+        print: PrintArgs,
+    },
+    /// Print files dropped into a folder, e.g. for a shared printing folder.
+    /// Runs until stopped; files are sent once they stop changing
+    Watch {
+        /// Server to send to, e.g. https://192.168.1.20:8443, or a remote-print:// URL the server
+        /// printed, trusting the certificate it names.
+        /// Without one, the default printer starred in the GUI
+        #[arg(value_name = "URL")]
+        url: Option<String>,
+
+        /// Printer set up in the GUI to send to instead of URL, by label or IP
+        #[arg(short = 'p', long, conflicts_with = "url")]
+        printer: Option<String>,
+
+        /// Folder to watch
+        #[arg(long)]
+        dir: PathBuf,
+
+        /// Where printed files are moved; the `printed` folder inside --dir if not set
+        #[arg(long, value_name = "PATH", conflicts_with = "delete")]
+        printed_dir: Option<PathBuf>,
+
+        /// Delete printed files instead of moving them
+        #[arg(long)]
+        delete: bool,
+
+        /// Where files the server refused are moved; the `failed` folder inside --dir if not set
+        #[arg(long, value_name = "PATH")]
+        failed_dir: Option<PathBuf>,
+
+        #[command(flatten)]
+        server: ServerArgs,
+
+        #[command(flatten)]
+        print: PrintArgs,
     },
 }
 
-// Init tracing
-fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+/// How to reach and log in to the server, shared by `upload` and `watch`
+#[derive(clap::Args, Debug)]
+struct ServerArgs {
+    /// Override hostname used for certificate verification
+    #[arg(long = "host")]
+    host: Option<String>,
 
-    let args = Args::parse();
+    /// Certificate authority to trust (DER or PEM), or a directory of them.
+    /// Repeat to trust several, e.g. an intermediate and its root
+    #[arg(long = "ca")]
+    ca: Vec<PathBuf>,
+
+    /// Client certificate to present, for servers requiring one (DER or PEM)
+    #[arg(long = "client-cert", requires = "client_key")]
+    client_cert: Option<PathBuf>,
+
+    /// Private key for --client-cert (DER or PEM)
+    #[arg(long = "client-key", requires = "client_cert")]
+    client_key: Option<PathBuf>,
+
+    /// Seconds to wait for the server to answer
+    #[arg(long, default_value_t = DEFAULT_CONNECT_TIMEOUT.as_secs())]
+    timeout: u64,
+
+    /// Times to retry a connection that timed out or was reset, or to resume an upload that lost it
+    #[arg(long, default_value_t = DEFAULT_RETRIES)]
+    retries: u32,
+
+    /// How to reach the server; auto tries QUIC and falls back to TCP if UDP is blocked
+    #[arg(long, value_enum, default_value_t = Transport::Auto)]
+    transport: Transport,
+
+    /// Upload no faster than this many bytes per second; unlimited if not set
+    #[arg(long, value_name = "BYTES_PER_SEC", value_parser = clap::value_parser!(u64).range(1..))]
+    rate_limit: Option<u64>,
+
+    /// User to authenticate as; uses the server's default user if not set
+    #[arg(short, long)]
+    user: Option<String>,
+
+    /// Read the password from the first line of this file instead of prompting
+    #[arg(long)]
+    password_file: Option<PathBuf>,
+
+    /// Read the password from the first line of standard input instead of prompting
+    #[arg(long, conflicts_with = "password_file")]
+    password_stdin: bool,
+}
+
+/// How to print, shared by `upload` and `watch`
+#[derive(clap::Args, Debug)]
+struct PrintArgs {
+    /// Number of copies to print
+    #[arg(
+        short = 'n',
+        long,
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u32).range(1..=MAX_COPIES as i64)
+    )]
+    copies: u32,
+
+    /// Print on both sides of the page
+    #[arg(short, long)]
+    duplex: bool,
+
+    /// Compress the file before sending
+    #[arg(long)]
+    compress: bool,
 
-    if args.command.is_none() {
-        run_gui()?;
+    /// Pages to print, e.g. 3-7,10
+    #[arg(long, value_parser = parse_page_range)]
+    pages: Option<String>,
+
+    /// Paper size or media to print on, e.g. A4 or Letter
+    #[arg(long)]
+    media: Option<String>,
+
+    /// Print in landscape orientation
+    #[arg(long)]
+    landscape: bool,
+
+    /// Scale the document to fit the page
+    #[arg(long)]
+    fit_to_page: bool,
+
+    /// Percent of the page an image covers, over 100 enlarges it. Only applies to images
+    #[arg(
+        long,
+        value_name = "PERCENT",
+        value_parser = clap::value_parser!(u16).range(1..=MAX_SCALING as i64)
+    )]
+    scaling: Option<u16>,
+
+    /// Where an image goes on the page. Only applies to images
+    #[arg(long, value_parser = clap::builder::PossibleValuesParser::new(POSITIONS))]
+    position: Option<String>,
+
+    /// Check the server would accept the file without printing it
+    #[arg(long)]
+    dry_run: bool,
+}
+
+impl PrintArgs {
+    fn options(self) -> PrintOptions {
+        PrintOptions {
+            copies: self.copies,
+            duplex: self.duplex,
+            compress: self.compress,
+            page_range: self.pages,
+            media: self.media,
+            landscape: self.landscape,
+            fit_to_page: self.fit_to_page,
+            scaling: self.scaling,
+            position: self.position,
+            dry_run: self.dry_run,
+        }
+    }
+}
+
+/// Shows the fingerprint of a server's untrusted certificate and asks whether to pin it.
+/// Fails instead unless `interactive`, `watch` may run with nobody to answer
+fn confirm_certificate(cert: &UnknownCertificate, interactive: bool) -> Result<bool> {
+    if !interactive {
+        bail!("{}, trust it by sending a file with `upload` first", cert);
+    }
+    eprintln!(
+        "{} presented a certificate that isn't trusted yet.",
+        cert.host
+    );
+    eprintln!("SHA-256 fingerprint: {}", cert.fingerprint);
+    eprintln!("Only trust it if this matches the fingerprint the server printed on startup.");
+
+    Ok(inquire::Confirm::new("Trust this certificate?")
+        .with_default(false)
+        .prompt()?)
+}
+
+/// Level to log at: warnings and errors by default, more with each `-v`,
+/// only errors with `-q`. `RUST_LOG` overrides it
+fn log_level(verbose: u8, quiet: bool) -> &'static str {
+    match (quiet, verbose) {
+        (true, _) => "error",
+        (false, 0) => "warn",
+        (false, 1) => "info",
+        (false, 2) => "debug",
+        (false, _) => "trace",
+    }
+}
+
+/// Logs at [`log_level`]
+fn init_tracing(verbose: u8, quiet: bool) {
+    let level = log_level(verbose, quiet);
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(level));
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+}
+
+fn parse_page_range(range: &str) -> Result<String, String> {
+    printer_client::validate_page_range(range).map_err(|e| e.to_string())?;
+    Ok(range.to_string())
+}
+
+fn parse_extension(ext: &str) -> Result<String, String> {
+    let ext = ext.trim_start_matches('.');
+    if ext.is_empty() || !ext.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(format!("invalid extension {:?}, expected e.g. pdf", ext));
+    }
+    Ok(ext.to_string())
+}
+
+/// Reads the whole of standard input as a document called `stdin.<ext>`
+fn read_stdin(ext: &str) -> Result<Document> {
+    let mut bytes = Vec::new();
+    std::io::stdin().read_to_end(&mut bytes)?;
+    if bytes.is_empty() {
+        bail!("nothing to send, standard input is empty");
+    }
+    Ok(Document::Bytes {
+        name: format!("stdin.{}", ext),
+        bytes: Arc::from(bytes),
+    })
+}
+
+/// Password from --password-file, --password-stdin, [`PASSWORD_ENV`] or `stored`, in that order.
+/// Prompts if none of them is given
+fn read_password(file: Option<&Path>, stdin: bool, stored: Option<&str>) -> Result<String> {
+    let pass = if let Some(path) = file {
+        std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read password from {}", path.display()))?
+    } else if stdin {
+        let mut line = String::new();
+        std::io::stdin()
+            .read_line(&mut line)
+            .context("failed to read password from standard input")?;
+        line
+    } else if let Ok(pass) = std::env::var(PASSWORD_ENV) {
+        pass
+    } else if let Some(pass) = stored {
+        pass.to_string()
     } else {
-        match args.command.unwrap() {
-            Commands::Upload {
-                url,
-                host,
-                ca,
-                file,
-            } => {
-                printer_client::send_file(url, host, ca, file, None)?;
-                true
+        return Ok(request_for_pass());
+    };
+
+    // Only the first line, without the newline files and pipes end with
+    let pass = pass.lines().next().unwrap_or_default();
+    if pass.is_empty() {
+        bail!("the password is empty");
+    }
+    Ok(pass.to_string())
+}
+
+/// Exit code for a failed upload, see `upload --help`
+fn exit_code(e: &PrintError) -> u8 {
+    match e {
+        PrintError::Connect(_) | PrintError::Timeout(_) => EXIT_CONNECTION,
+        // Declined to trust the server
+        PrintError::UntrustedCertificate(_) => EXIT_CONNECTION,
+        PrintError::Auth(_) => EXIT_AUTH,
+        PrintError::Rejected(_)
+        | PrintError::UnknownPrinter(_)
+        | PrintError::TooLarge(_)
+        | PrintError::PrinterError(_) => EXIT_PRINT,
+        _ => 1,
+    }
+}
+
+/// Reports a failed upload, returning the exit code for it
+fn upload_failed(e: impl Into<anyhow::Error>) -> ExitCode {
+    let e = e.into();
+    eprintln!("Error: {:?}", e);
+    ExitCode::from(e.downcast_ref::<PrintError>().map_or(1, exit_code))
+}
+
+/// Expands the `paths` the shell left as patterns, like `*.pdf` on Windows.
+/// Patterns matching nothing are kept so they're reported as missing
+fn expand_globs(paths: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut expanded = Vec::new();
+    for path in paths {
+        let matches: Vec<PathBuf> = match path.to_str() {
+            Some(pattern) if !path.exists() && pattern.contains(['*', '?', '[']) => {
+                glob::glob(pattern)
+                    .map(|paths| paths.filter_map(Result::ok).collect())
+                    .unwrap_or_default()
             }
+            _ => Vec::new(),
         };
+        if matches.is_empty() {
+            expanded.push(path);
+        } else {
+            expanded.extend(matches);
+        }
+    }
+    expanded
+}
+
+// Init tracing
+fn main() -> Result<ExitCode> {
+    let args = Args::parse();
+    init_tracing(args.verbose, args.quiet);
+
+    match args.command {
+        None => run_gui()?,
+        Some(Commands::Upload {
+            url: url_arg,
+            printer: printer_name,
+            server,
+            file,
+            files,
+            stdin,
+            ext,
+            print,
+        }) => {
+            let mut settings = get_settings()?;
+
+            // A printer set up in the GUI stands in for the URL, which is then a file
+            let (url, configured, first) =
+                target(&mut settings, printer_name.as_deref(), url_arg, true)?;
+            let files: Vec<PathBuf> = first.into_iter().map(PathBuf::from).chain(files).collect();
+            if !stdin && file.is_empty() && files.is_empty() {
+                bail!("no files to send, give them after the options or with --file");
+            }
+
+            // Read before asking for the password, which goes through the terminal instead
+            let documents: Vec<Document> = match ext.filter(|_| stdin) {
+                Some(ext) => match read_stdin(&ext) {
+                    Ok(document) => vec![document],
+                    Err(e) => return Ok(upload_failed(e)),
+                },
+                None => expand_globs(file.into_iter().chain(files).collect())
+                    .into_iter()
+                    .map(Document::from)
+                    .collect(),
+            };
+
+            let (mut printer, mut connect) = match login(server, configured, &url, &settings) {
+                Ok(login) => login,
+                Err(e) => return Ok(upload_failed(e)),
+            };
+            let options = print.options();
+
+            let results = match send_trusting(
+                &mut settings,
+                &url,
+                &mut connect,
+                &documents,
+                &mut printer,
+                &options,
+                true,
+            ) {
+                Ok(results) => results,
+                Err(e) => return Ok(upload_failed(e)),
+            };
+
+            // A single file is reported as before, several get a line each and a summary
+            let single = documents.len() == 1;
+            let mut printed = 0;
+            let mut first_failure = None;
+            for (document, result) in documents.iter().zip(results) {
+                match result.and_then(PrintOutcome::into_result) {
+                    Ok(outcome) if single => println!("{}", outcome),
+                    Ok(outcome) => {
+                        printed += 1;
+                        println!("{}: {}", document, outcome);
+                    }
+                    Err(e) if single => return Ok(upload_failed(e)),
+                    Err(e) => {
+                        eprintln!("{}: {:#}", document, e);
+                        first_failure.get_or_insert(exit_code(&e));
+                    }
+                }
+            }
+            if !single {
+                println!("{} of {} files succeeded", printed, documents.len());
+            }
+            if let Some(code) = first_failure {
+                return Ok(ExitCode::from(code));
+            }
+        }
+        Some(Commands::Watch {
+            url: url_arg,
+            printer: printer_name,
+            dir,
+            printed_dir,
+            delete,
+            failed_dir,
+            server,
+            print,
+        }) => {
+            let mut settings = get_settings()?;
+            let (url, configured, extra) =
+                target(&mut settings, printer_name.as_deref(), url_arg, false)?;
+            if let Some(extra) = extra {
+                bail!("invalid URL {:?}", extra);
+            }
+            if !dir.is_dir() {
+                bail!("{} isn't a folder", dir.display());
+            }
+            let after = match printed_dir {
+                _ if delete => AfterPrint::Delete,
+                Some(printed) => AfterPrint::Move(printed),
+                None => AfterPrint::Move(dir.join("printed")),
+            };
+            let failed = failed_dir.unwrap_or_else(|| dir.join("failed"));
+
+            let (mut printer, mut connect) = login(server, configured, &url, &settings)?;
+            let options = print.options();
+            eprintln!("Watching {} for files to print", dir.display());
+            // The session is kept between batches, and renewed when it runs out
+            watch::watch(
+                &dir,
+                &after,
+                &failed,
+                |documents| {
+                    send_trusting(
+                        &mut settings,
+                        &url,
+                        &mut connect,
+                        documents,
+                        &mut printer,
+                        &options,
+                        false,
+                    )
+                },
+                report_watched,
+            )?;
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Prints what `watch` did with a file, like `upload` does
+fn report_watched(event: Event) {
+    match event {
+        Event::Unreachable(e) => {
+            eprintln!("{:#}, trying again in {}s", e, watch::RETRY_DELAY.as_secs())
+        }
+        Event::Printed { file, outcome } => println!("{}: {}", file.display(), outcome),
+        Event::Retrying { file, error } => {
+            eprintln!("{}: {:#}, trying again later", file.display(), error)
+        }
+        Event::Failed {
+            file,
+            error,
+            moved_to,
+        } => {
+            eprintln!("{}: {:#}", file.display(), error);
+            if let Some(to) = moved_to {
+                eprintln!("Moved to {}", to.display());
+            }
+        }
+    }
+}
+
+/// Server to send to: the GUI printer `name`, `url`, or else the default printer.
+/// Returns `url` as the last item if it isn't used as one, `upload` takes it as a file.
+/// Replacing a pinned certificate fails unless `interactive`, see [`confirm_certificate`]
+fn target(
+    settings: &mut Settings,
+    name: Option<&str>,
+    url: Option<String>,
+    interactive: bool,
+) -> Result<(Url, Option<Printer>, Option<String>)> {
+    let is_url = |arg: &str| Url::parse(arg).is_ok_and(|url| url.has_host());
+    match (name, url) {
+        (Some(name), url) => {
+            let (found, printer) = settings.find_printer(name)?;
+            Ok((found, Some(printer.clone()), url))
+        }
+        (None, Some(url)) if is_url(&url) => {
+            let (url, fingerprint) = printer_client::server_url(Url::parse(&url)?)?;
+            // Copied from the server itself, so its certificate is trusted like a confirmed one.
+            // Replacing a pinned certificate is asked first, any URL could carry a fingerprint
+            if let Some(fingerprint) = fingerprint {
+                let host = host_key(&url);
+                let pin = match settings.pinned_certs().get(&host) {
+                    None => true,
+                    Some(pinned) if *pinned == fingerprint => false,
+                    Some(_) => confirm_certificate(
+                        &UnknownCertificate {
+                            host: host.clone(),
+                            fingerprint: fingerprint.clone(),
+                        },
+                        interactive,
+                    )?,
+                };
+                if pin {
+                    settings.pin_cert(host, fingerprint);
+                }
+            }
+            Ok((url, None, None))
+        }
+        (None, url) => match settings.default_printer() {
+            Some((found, printer)) => Ok((found, Some(printer.clone()), url)),
+            None => match url {
+                Some(url) => bail!(
+                    "invalid URL {:?}, and no default printer is set to send to instead",
+                    url
+                ),
+                None => bail!("no URL given, and no default printer is set"),
+            },
+        },
+    }
+}
+
+/// Printer to send to `url` with and how to connect, from `args` over the GUI's
+/// `configured` printer if there is one. Asks for the password if needed
+fn login(
+    args: ServerArgs,
+    configured: Option<Printer>,
+    url: &Url,
+    settings: &Settings,
+) -> Result<(Printer, ConnectOptions)> {
+    // Asked for up front so a retry after trusting a certificate doesn't ask again
+    let stored = configured.as_ref().map(|printer| printer.pass.as_str());
+    let pass = read_password(args.password_file.as_deref(), args.password_stdin, stored)?;
+    let printer = match configured {
+        // Its saved session only holds for the saved login
+        Some(printer) => Printer {
+            session: printer
+                .session
+                .filter(|_| args.user.is_none() && pass == printer.pass),
+            user: args.user.or(printer.user),
+            pass,
+            ..printer
+        },
+        None => Printer {
+            user: args.user,
+            ..Printer::new(pass, url.port().unwrap_or(DEFAULT_PORT))
+        },
+    };
+
+    let connect = ConnectOptions {
+        host: args.host.or_else(|| printer.cert_host.clone()),
+        ca: args.ca,
+        client_auth: args
+            .client_cert
+            .zip(args.client_key)
+            .map(|(cert, key)| ClientAuth { cert, key }),
+        timeout: Duration::from_secs(args.timeout),
+        retries: args.retries,
+        pinned_certs: settings.pinned_certs().clone(),
+        transport: args.transport,
+        rate_limit: args.rate_limit,
+        pool: None,
+    };
+    Ok((printer, connect))
+}
+
+/// [`printer_client::send_files`], first asking to trust the server's certificate if it isn't yet.
+/// Fails on an untrusted certificate unless `interactive`, see [`confirm_certificate`]
+fn send_trusting(
+    settings: &mut Settings,
+    url: &Url,
+    connect: &mut ConnectOptions,
+    documents: &[Document],
+    printer: &mut Printer,
+    options: &PrintOptions,
+    interactive: bool,
+) -> Result<Vec<Result<PrintOutcome, PrintError>>, PrintError> {
+    loop {
+        let result = printer_client::send_files(
+            url.clone(),
+            connect,
+            documents,
+            Some(printer),
+            options.clone(),
+            None,
+            None,
+        );
+        let cert = match result {
+            Err(PrintError::UntrustedCertificate(cert)) => cert,
+            result => return result,
+        };
+        if !confirm_certificate(&cert, interactive).map_err(PrintError::Other)? {
+            return Err(PrintError::UntrustedCertificate(cert));
+        }
+        settings.pin_cert(cert.host.clone(), cert.fingerprint.clone());
+        connect.pinned_certs = settings.pinned_certs().clone();
     }
-    Ok(())
 }
 
 fn run_gui() -> Result<()> {
@@ -80,4 +689,65 @@ fn run_gui() -> Result<()> {
     .unwrap_or_else(|e| error!("Failed to run GUI: {}", e));
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn parses_extensions() {
+        assert_eq!(parse_extension("pdf").unwrap(), "pdf");
+        assert_eq!(parse_extension(".txt").unwrap(), "txt");
+        assert_eq!(parse_extension("JPEG").unwrap(), "JPEG");
+        assert_eq!(parse_extension("mp3").unwrap(), "mp3");
+    }
+
+    #[test]
+    fn rejects_invalid_extensions() {
+        for ext in ["", ".", "tar.gz", "p df", "../pdf", "pdf/"] {
+            assert!(parse_extension(ext).is_err(), "{:?} was accepted", ext);
+        }
+    }
+
+    #[test]
+    fn password_files_come_before_stored_passwords() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "from file\nsecond line").unwrap();
+
+        let pass = read_password(Some(file.path()), false, Some("stored")).unwrap();
+        assert_eq!(pass, "from file");
+    }
+
+    #[test]
+    fn empty_password_files_fail() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), "\n").unwrap();
+
+        let err = read_password(Some(file.path()), false, None).unwrap_err();
+        assert_eq!(err.to_string(), "the password is empty");
+    }
+
+    #[test]
+    fn missing_password_files_fail() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("gone");
+
+        let err = read_password(Some(&path), false, Some("stored")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("failed to read password from {}", path.display())
+        );
+    }
+
+    #[test]
+    fn stored_passwords_are_used_without_a_file() {
+        // The environment variable comes first, only check the stored one without it
+        if std::env::var_os(PASSWORD_ENV).is_none() {
+            let pass = read_password(None, false, Some("stored\n")).unwrap();
+            assert_eq!(pass, "stored");
+        }
+    }
+}
@@ -1,205 +1,380 @@
 use std::{
     fs,
-    process::{self, Command, Stdio},
+    io::Read,
+    process::{Command, Stdio},
     time::Duration,
 };
 
-use anyhow::anyhow;
+use anyhow::{anyhow, bail, Context, Result};
 use semver::Version;
-use tracing::{debug, error};
-use update_informer::{registry, Check};
+use sha2::{Digest, Sha256};
+use tracing::debug;
 use uuid::Uuid;
 
 use crate::app::VersionStatus;
 
+/// Releases of the client are tagged `printer_client-v<version>`
+const TAG_PREFIX: &str = "printer_client-v";
+
+const RELEASES_URL: &str = "https://api.github.com/repos/CodedMasonry/remote_print/releases";
+
+/// Longest wait for the release list, so a hung GitHub doesn't stall the check
+const API_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest wait for an installer or checksum to download
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// Longest wait to connect to GitHub
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest release list accepted
+const MAX_API_RESPONSE: u64 = 4 * 1024 * 1024;
+/// Largest installer (or checksum) accepted
+const MAX_DOWNLOAD: u64 = 512 * 1024 * 1024;
+
 #[derive(Clone, Debug)]
 pub struct Release {
-    version: String,
-    assets: Vec<Asset>,
+    pub version: Version,
+    pub assets: Vec<Asset>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, serde::Deserialize)]
 pub struct Asset {
-    name: String,
-    download_url: String,
+    pub name: String,
+    #[serde(rename = "browser_download_url")]
+    pub download_url: String,
+}
+
+/// Release as returned by the GitHub API
+#[derive(serde::Deserialize)]
+struct GithubRelease {
+    tag_name: String,
+    assets: Vec<Asset>,
+    #[serde(default)]
+    draft: bool,
+    #[serde(default)]
+    prerelease: bool,
 }
 
-pub fn update() -> Result<(), Box<dyn std::error::Error>> {
-    // get the first available release
-    let release = get_latest_release("printer_client")?;
-    debug!("Got latest release");
+/// Result of [`perform_update`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpdateStatus {
+    UpToDate,
+    /// The installer for this version was started, the app should exit
+    Installing(Version),
+}
 
-    let mut installer = None;
+impl Release {
+    /// Installer for the current platform, `.msi` on Windows and `.sh` on Linux
+    pub fn installer(&self) -> Option<&Asset> {
+        let extension = installer_extension()?;
+        self.assets
+            .iter()
+            .find(|asset| asset.name.ends_with(extension))
+    }
 
-    let files = release
-        .assets
-        .into_iter()
-        .filter(|val| val.name.contains("msi") || val.name.contains("sh"))
-        .filter(|val| !val.name.contains("sha"));
-
-    // If the OS matches, installer will be set to it (Compiler flags will dictate this)
-    for file in files {
-        if cfg!(target_os = "windows") && file.name.contains("msi") {
-            installer = Some(file);
-        } else if cfg!(target_os = "linux") && file.name.contains("sh") {
-            installer = Some(file);
-        } else {
-            installer = None;
-        }
+    /// Checksum published for `asset`, named `<asset>.sha256`
+    pub fn checksum_for(&self, asset: &Asset) -> Option<&Asset> {
+        let name = format!("{}.sha256", asset.name);
+        self.assets.iter().find(|checksum| checksum.name == name)
     }
+}
+
+fn installer_extension() -> Option<&'static str> {
+    if cfg!(target_os = "windows") {
+        Some(".msi")
+    } else if cfg!(target_os = "linux") {
+        Some(".sh")
+    } else {
+        None
+    }
+}
+
+/// Whether this platform has an installer to update with
+pub fn is_supported() -> bool {
+    installer_extension().is_some()
+}
 
-    let installer: Asset = match installer {
-        Some(val) => val,
+/// Checks GitHub for a newer release than the running version
+pub fn check_outdated() -> Result<VersionStatus> {
+    match get_latest_release()? {
+        Some(release) => Ok(VersionStatus::OutDated(release.version.to_string())),
         None => {
-            return Err(anyhow!(
-            "No installer for supported OS; Check releases to see if your platform is supported"
-        )
-            .into())
+            debug!("Up To Date");
+            Ok(VersionStatus::UpToDate)
         }
+    }
+}
+
+/// Downloads and starts the installer of the newest release, if newer than the running version.
+/// `progress` is called with the bytes downloaded so far and the total
+pub fn perform_update(progress: Option<&dyn Fn(u64, u64)>) -> Result<UpdateStatus> {
+    let Some(release) = get_latest_release()? else {
+        return Ok(UpdateStatus::UpToDate);
     };
+    let installer = release.installer().ok_or_else(|| {
+        anyhow!(
+            "No installer for supported OS; Check releases to see if your platform is supported"
+        )
+    })?;
     println!("[Installer]: {}", installer.download_url);
 
-    let path = format!(
-        "{}{}-printer_client.msi",
-        std::env::temp_dir().to_string_lossy(),
-        Uuid::new_v4()
-    );
-    debug!("Temporary file created");
-
-    let response = reqwest::blocking::Client::new()
-        .get(installer.download_url)
-        .header("User-Agent", "remote_print")
-        .send()?;
-    let status = response.status();
-    debug!("Fetched response");
-
-    if !status.is_success() {
-        return Err(anyhow!("Failed to download update installer: {}", status.as_str()).into());
-    }
+    let checksum = release.checksum_for(installer).ok_or_else(|| {
+        anyhow!(
+            "No checksum published for {}, refusing to install",
+            installer.name
+        )
+    })?;
+
+    let bytes = download(&installer.download_url, progress)?;
+    let expected = String::from_utf8(download(&checksum.download_url, None)?)
+        .context("checksum file is not valid UTF-8")?;
+    verify_checksum(&bytes, &expected)
+        .with_context(|| format!("refusing to install {}", installer.name))?;
+    debug!("Installer checksum verified");
+    let path = std::env::temp_dir().join(format!("{}-{}", Uuid::new_v4(), installer.name));
+    fs::write(&path, bytes).context("failed to write installer")?;
+    debug!("Copied installer to {}", path.display());
 
-    // Handle the installer to run.
     if cfg!(target_os = "windows") {
-        println!("{:#?}", path);
-        fs::write(path.clone(), response.bytes()?)?;
-        println!("Copied installer to file");
-
-        Command::new("msiexec").arg("/i").arg(path).spawn()?;
-
-        process::exit(0);
-    } else if cfg!(target_os = "linux") {
-        fs::write(path.clone(), response.text()?)?;
-        println!("Copied installer to file");
-
+        Command::new("msiexec").arg("/i").arg(&path).spawn()?;
+    } else {
         Command::new("sh")
-            .arg(path)
+            .arg(&path)
             .stdout(Stdio::inherit())
             .stderr(Stdio::inherit())
             .spawn()?;
-
-        process::exit(0)
     }
 
-    Ok(())
+    Ok(UpdateStatus::Installing(release.version))
+}
+
+/// HTTP client giving up after `timeout`
+fn client(timeout: Duration) -> Result<reqwest::blocking::Client> {
+    Ok(reqwest::blocking::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(timeout)
+        .user_agent("remote_print")
+        .build()?)
 }
 
-pub fn get_latest_release(name: &str) -> Result<Release, Box<dyn std::error::Error>> {
-    let url = format!(
-        "https://api.github.com/repos/{}/{}/releases",
-        "CodedMasonry", "remote_print"
-    );
-    let resp = reqwest::blocking::Client::new()
-        .get(url)
+/// Newest release of the client, `None` if it isn't newer than the running version
+pub fn get_latest_release() -> Result<Option<Release>> {
+    let response = client(API_TIMEOUT)?
+        .get(RELEASES_URL)
         .header("Accept", "application/vnd.github.v3+json")
-        .header("User-Agent", "remote_print")
-        .send()?;
+        .send()?
+        .error_for_status()?;
+    let body = read_limited(response, MAX_API_RESPONSE, None)
+        .context("failed to read the release list")?;
+    let releases: Vec<GithubRelease> =
+        serde_json::from_slice(&body).context("invalid release list")?;
     debug!("Got update API response");
 
-    let binding: serde_json::Value = resp.json::<serde_json::Value>()?;
-    let releases = match binding.as_array() {
-        Some(val) => val,
-        None => return Err(anyhow!("No Releases found").into()),
-    };
+    let current = Version::parse(env!("CARGO_PKG_VERSION"))?;
+    Ok(newest_release(parse_releases(releases), &current))
+}
+
+/// Keeps published client releases with a valid version
+fn parse_releases(releases: Vec<GithubRelease>) -> Vec<Release> {
+    releases
+        .into_iter()
+        .filter(|rel| !rel.draft && !rel.prerelease)
+        .filter_map(|rel| match parse_tag(&rel.tag_name) {
+            Ok(version) => Some(Release {
+                version: version?,
+                assets: rel.assets,
+            }),
+            Err(e) => {
+                debug!("Skipping release: {:#}", e);
+                None
+            }
+        })
+        .collect()
+}
 
-    let mut formatted_releases = Vec::new();
-    for rel in releases {
-        if rel["tag_name"].to_string().contains(name) {
-            let version = rel["tag_name"].to_string();
-
-            formatted_releases.push(Release {
-                assets: parse_assets(rel["assets"].as_array().unwrap()),
-                version: version
-                    .replace("\"", "")
-                    .split("-v")
-                    .last()
-                    .unwrap()
-                    .to_owned(),
-            })
+/// Version of a client release tag: `printer_client-v1.2.3`, `v1.2.3` or `1.2.3`.
+/// `None` for tags of the other binaries (`printer_server-v1.2.3`)
+fn parse_tag(tag: &str) -> Result<Option<Version>> {
+    let version = match tag.strip_prefix(TAG_PREFIX) {
+        Some(version) => version,
+        None => {
+            // Other crates are tagged `<name>-v<version>`, a bare version starts with a digit
+            let bare = tag.strip_prefix('v').unwrap_or(tag);
+            if tag.contains("-v") && !bare.starts_with(|c: char| c.is_ascii_digit()) {
+                return Ok(None);
+            }
+            bare
         }
-    }
+    };
 
-    let newest = find_most_recent(&formatted_releases)?;
-    return Ok(newest.clone());
+    Version::parse(version)
+        .map(Some)
+        .with_context(|| format!("invalid version in release tag {:?}", tag))
 }
 
-fn parse_assets(assets: &[serde_json::Value]) -> Vec<Asset> {
-    let mut result = Vec::new();
-    for asset in assets {
-        result.push(Asset {
-            name: asset["name"].to_string().replace("\"", ""),
-            download_url: asset["browser_download_url"].to_string().replace("\"", ""),
-        })
-    }
+fn newest_release(releases: Vec<Release>, current: &Version) -> Option<Release> {
+    releases
+        .into_iter()
+        .max_by(|a, b| a.version.cmp(&b.version))
+        .filter(|rel| rel.version > *current)
+}
 
-    return result;
+/// Checks the SHA-256 of `bytes` against a `.sha256` file,
+/// either just the hex digest or `sha256sum` output (`<digest>  <file>`)
+pub fn verify_checksum(bytes: &[u8], checksum_file: &str) -> Result<()> {
+    let expected = checksum_file
+        .split_whitespace()
+        .next()
+        .ok_or_else(|| anyhow!("checksum file is empty"))?
+        .to_ascii_lowercase();
+    let actual: String = Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+
+    if actual != expected {
+        bail!("checksum mismatch: expected {}, got {}", expected, actual);
+    }
+    Ok(())
 }
 
-fn find_most_recent(items: &Vec<Release>) -> Result<&Release, Box<dyn std::error::Error>> {
-    let mut parsed_versions = Vec::new();
+fn download(url: &str, progress: Option<&dyn Fn(u64, u64)>) -> Result<Vec<u8>> {
+    let response = client(DOWNLOAD_TIMEOUT)?.get(url).send()?;
+    let status = response.status();
+    debug!("Fetched response");
 
-    for item in items {
-        match Version::parse(&item.version) {
-            Ok(parsed_version) => {
-                parsed_versions.push(parsed_version);
-            }
-            Err(e) => {
-                return Err(anyhow!("Failed to parse [{:#?}]: {:#?}", item.version, e).into());
-            }
-        }
+    if !status.is_success() {
+        bail!("Failed to download update installer: {}", status.as_str());
+    }
+
+    read_limited(response, MAX_DOWNLOAD, progress)
+}
+
+/// Reads the body of `response`, failing once it's over `limit` bytes
+fn read_limited(
+    mut response: reqwest::blocking::Response,
+    limit: u64,
+    progress: Option<&dyn Fn(u64, u64)>,
+) -> Result<Vec<u8>> {
+    let total = response.content_length().unwrap_or(0);
+    if total > limit {
+        bail!("response is too large ({} bytes, at most {})", total, limit);
     }
 
-    let most_recent = parsed_versions.iter().max();
+    let mut bytes = Vec::new();
+    let mut chunk = [0u8; 64 * 1024];
+    loop {
+        let read = response.read(&mut chunk)?;
+        if read == 0 {
+            break;
+        }
+        bytes.extend_from_slice(&chunk[..read]);
+        if bytes.len() as u64 > limit {
+            bail!("response is too large (over {} bytes)", limit);
+        }
 
-    match most_recent {
-        Some(most_recent) if most_recent > &Version::parse(env!("CARGO_PKG_VERSION")).unwrap() => {
-            Ok(&items[parsed_versions
-                .iter()
-                .position(|v| v == most_recent)
-                .unwrap()])
+        if let Some(progress) = progress {
+            progress(bytes.len() as u64, total.max(bytes.len() as u64));
         }
-        _ => Err(anyhow!("No version newer than current version").into()),
     }
+
+    Ok(bytes)
 }
 
-pub fn check_oudated() -> Result<VersionStatus, Box<dyn std::error::Error>> {
-    let informer = update_informer::new(
-        registry::Crates,
-        "printer_client",
-        env!("CARGO_PKG_VERSION"),
-    )
-    .interval(Duration::ZERO);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let status = match informer.check_version() {
-        Ok(ver) => ver,
-        Err(e) => {
-            error!("Failed to fetch version status: {:#?}", e);
-            return Err(e);
-        }
-    };
+    fn version(v: &str) -> Option<Version> {
+        Some(Version::parse(v).unwrap())
+    }
 
-    if let Some(ver) = status {
-        return Ok(VersionStatus::OutDated(ver.to_string()));
-    } else {
-        println!("Up To Date");
-        return Ok(VersionStatus::UpToDate);
+    /// Trimmed down `GET /releases` response
+    const RELEASES_JSON: &str = r#"[
+        {"tag_name": "printer_client-v0.4.0-rc1", "prerelease": true, "assets": []},
+        {"tag_name": "printer_client-v0.3.1", "draft": true, "assets": []},
+        {"tag_name": "printer_server-v0.9.0", "assets": []},
+        {"tag_name": "printer_client-vnext", "assets": []},
+        {
+            "tag_name": "printer_client-v0.3.0",
+            "assets": [{
+                "name": "printer_client-installer.sh",
+                "browser_download_url": "https://example.com/printer_client-installer.sh"
+            }]
+        },
+        {"tag_name": "printer_client-v0.2.5", "assets": []}
+    ]"#;
+
+    fn newest(current: &str) -> Option<Version> {
+        let releases: Vec<GithubRelease> = serde_json::from_str(RELEASES_JSON).unwrap();
+        let current = Version::parse(current).unwrap();
+        newest_release(parse_releases(releases), &current).map(|release| release.version)
+    }
+
+    #[test]
+    fn finds_the_newest_published_release() {
+        assert_eq!(newest("0.2.0"), version("0.3.0"));
+        assert_eq!(newest("0.2.9"), version("0.3.0"));
+        assert_eq!(newest("0.3.0-beta.1"), version("0.3.0"));
+    }
+
+    #[test]
+    fn up_to_date_without_a_newer_release() {
+        assert_eq!(newest("0.3.0"), None);
+        assert_eq!(newest("0.3.1"), None);
+    }
+
+    #[test]
+    fn verifies_checksums() {
+        // sha256 of "hello"
+        let digest = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        verify_checksum(b"hello", digest).unwrap();
+        verify_checksum(
+            b"hello",
+            &format!("{}  installer.sh\n", digest.to_uppercase()),
+        )
+        .unwrap();
+
+        let err = verify_checksum(b"hellO", digest).unwrap_err();
+        assert!(err.to_string().starts_with("checksum mismatch"), "{}", err);
+        let err = verify_checksum(b"hello", " \n").unwrap_err();
+        assert_eq!(err.to_string(), "checksum file is empty");
+    }
+
+    #[test]
+    fn parses_client_tags() {
+        assert_eq!(
+            parse_tag("printer_client-v1.2.3").unwrap(),
+            version("1.2.3")
+        );
+        assert_eq!(parse_tag("v1.2.3").unwrap(), version("1.2.3"));
+        assert_eq!(parse_tag("1.2.3").unwrap(), version("1.2.3"));
+    }
+
+    #[test]
+    fn parses_prereleases() {
+        assert_eq!(
+            parse_tag("printer_client-v1.2.3-rc1").unwrap(),
+            version("1.2.3-rc1")
+        );
+        assert_eq!(parse_tag("v0.2.0-beta.2").unwrap(), version("0.2.0-beta.2"));
+        assert_eq!(parse_tag("1.2.3-rc1").unwrap(), version("1.2.3-rc1"));
+    }
+
+    #[test]
+    fn skips_other_binaries() {
+        assert_eq!(parse_tag("printer_server-v0.1.23").unwrap(), None);
+        assert_eq!(parse_tag("printer_protocol-v0.1.0").unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_unparseable_tags() {
+        for tag in [
+            "",
+            "latest",
+            "v1.2",
+            "printer_client-v1",
+            "printer_client-vnext",
+        ] {
+            assert!(parse_tag(tag).is_err(), "{:?} parsed", tag);
+        }
     }
 }
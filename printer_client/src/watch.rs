@@ -0,0 +1,427 @@
+//! Printing files dropped into a folder, for `printer_client watch`
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, RecvTimeoutError},
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{bail, Context, Result};
+use notify::{RecursiveMode, Watcher};
+use tracing::{debug, warn};
+
+use crate::{Document, PrintError, PrintOutcome};
+
+/// Files are sent once their size and modification time stay the same this long,
+/// so ones still being written or copied aren't sent half done
+pub const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// The folder is also rescanned this often, in case the watcher misses a change
+const RESCAN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Wait before sending a file again after the server couldn't be reached or print it
+pub const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Endings of files browsers and copy tools are still writing
+const PARTIAL_SUFFIXES: [&str; 5] = [".part", ".partial", ".crdownload", ".tmp", "~"];
+
+/// What's done with a file once it printed
+#[derive(Debug, Clone)]
+pub enum AfterPrint {
+    /// Move it to this folder
+    Move(PathBuf),
+    Delete,
+}
+
+/// What [`watch`] did, for the caller to report
+#[derive(Debug)]
+pub enum Event {
+    /// The server couldn't be reached, the files are sent again after [`RETRY_DELAY`]
+    Unreachable(PrintError),
+    /// `file` printed, and was moved or deleted per [`AfterPrint`]
+    Printed {
+        file: PathBuf,
+        outcome: PrintOutcome,
+    },
+    /// `file` didn't print this time, it's sent again after [`RETRY_DELAY`]
+    Retrying { file: PathBuf, error: PrintError },
+    /// The server refused `file`. It was moved to `moved_to`, unless that failed
+    Failed {
+        file: PathBuf,
+        error: PrintError,
+        moved_to: Option<PathBuf>,
+    },
+}
+
+/// Files waiting in a watched folder
+pub struct Folder {
+    dir: PathBuf,
+    /// How long a file has to stay unchanged before it's sent
+    settle_time: Duration,
+    /// Size and modification time of each file last seen, and when it can be sent
+    files: HashMap<PathBuf, (u64, Option<SystemTime>, Instant)>,
+    /// Size and modification time of files already sent that are still in the folder,
+    /// because moving or deleting them failed
+    done: HashMap<PathBuf, (u64, Option<SystemTime>)>,
+}
+
+impl Folder {
+    /// Watches `dir`, sending files once unchanged for `settle_time`, usually [`SETTLE_TIME`]
+    pub fn new(dir: impl Into<PathBuf>, settle_time: Duration) -> Self {
+        Self {
+            dir: dir.into(),
+            settle_time,
+            files: HashMap::new(),
+            done: HashMap::new(),
+        }
+    }
+
+    /// Rescans the folder, returning the files ready to send, oldest first.
+    /// A file is ready once unchanged for the settle time, and never again once [`Folder::done`]
+    /// unless it changes
+    pub fn ready(&mut self) -> Result<Vec<PathBuf>> {
+        let now = Instant::now();
+        let mut present = HashMap::new();
+        let mut done = HashMap::new();
+        let entries = fs::read_dir(&self.dir)
+            .with_context(|| format!("failed to read {}", self.dir.display()))?;
+        for entry in entries {
+            let entry = entry?;
+            let Ok(meta) = entry.metadata() else { continue };
+            let path = entry.path();
+            if !meta.is_file() || is_ignored(&path) {
+                continue;
+            }
+            let (size, modified) = (meta.len(), meta.modified().ok());
+            if self.done.get(&path) == Some(&(size, modified)) {
+                done.insert(path, (size, modified));
+                continue;
+            }
+            let ready_at = match self.files.get(&path) {
+                Some((old_size, old_modified, ready_at))
+                    if *old_size == size && *old_modified == modified =>
+                {
+                    *ready_at
+                }
+                _ => now + self.settle_time,
+            };
+            present.insert(path, (size, modified, ready_at));
+        }
+        self.files = present;
+        // A file that's gone or was replaced is printed like any other next time
+        self.done = done;
+
+        let mut ready: Vec<_> = self
+            .files
+            .iter()
+            .filter(|(_, (_, _, ready_at))| *ready_at <= now)
+            .map(|(path, (_, modified, _))| (*modified, path.clone()))
+            .collect();
+        ready.sort();
+        Ok(ready.into_iter().map(|(_, path)| path).collect())
+    }
+
+    /// How long until the next file waiting to settle or held back can be sent
+    fn next_ready(&self) -> Option<Duration> {
+        let now = Instant::now();
+        self.files
+            .values()
+            .map(|(_, _, ready_at)| ready_at.saturating_duration_since(now))
+            .min()
+    }
+
+    /// Holds `path` back for `delay`
+    pub fn defer(&mut self, path: &Path, delay: Duration) {
+        if let Some((_, _, ready_at)) = self.files.get_mut(path) {
+            *ready_at = Instant::now() + delay;
+        }
+    }
+
+    /// Stops sending `path`. If it couldn't be moved or deleted it's skipped until it changes
+    pub fn done(&mut self, path: &Path) {
+        if let Some((size, modified, _)) = self.files.remove(path) {
+            self.done.insert(path.to_path_buf(), (size, modified));
+        }
+    }
+}
+
+/// Hidden files and ones still being downloaded
+fn is_ignored(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    name.starts_with('.') || PARTIAL_SUFFIXES.iter().any(|suffix| name.ends_with(suffix))
+}
+
+/// Moves `path` into `dir`, adding a number to the name if it's taken
+pub fn move_into(path: &Path, dir: &Path) -> io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let name = path.file_name().unwrap_or_default();
+    let mut target = dir.join(name);
+    let stem = Path::new(name)
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let extension = Path::new(name).extension().map(|e| e.to_string_lossy());
+    let mut n = 1;
+    while target.exists() {
+        target = dir.join(match &extension {
+            Some(extension) => format!("{} ({}).{}", stem, n, extension),
+            None => format!("{} ({})", stem, n),
+        });
+        n += 1;
+    }
+    // Falls back to copying when `dir` is on another filesystem
+    if fs::rename(path, &target).is_err() {
+        fs::copy(path, &target)?;
+        fs::remove_file(path)?;
+    }
+    Ok(target)
+}
+
+/// Whether sending again later might work: the server couldn't be reached, or couldn't print
+fn is_retryable(result: &Result<PrintOutcome, PrintError>) -> bool {
+    match result {
+        Ok(outcome) => outcome.is_retryable(),
+        Err(e) => matches!(e, PrintError::Connect(_) | PrintError::Timeout(_)),
+    }
+}
+
+/// Watches `dir` and prints files dropped into it with `send`, until `send` fails as a whole
+/// for any other reason than the server being unreachable.
+/// Printed files are moved or deleted per `after`, ones the server refused are moved to `failed`.
+/// What happens to each file is passed to `report`
+pub fn watch(
+    dir: &Path,
+    after: &AfterPrint,
+    failed: &Path,
+    send: impl FnMut(&[Document]) -> Result<Vec<Result<PrintOutcome, PrintError>>, PrintError>,
+    report: impl FnMut(Event),
+) -> Result<()> {
+    let folder = Folder::new(dir, SETTLE_TIME);
+    watch_folder(folder, RETRY_DELAY, after, failed, send, report)
+}
+
+/// [`watch`] for `folder`, sending files that didn't print again after `retry_delay`
+fn watch_folder(
+    mut folder: Folder,
+    retry_delay: Duration,
+    after: &AfterPrint,
+    failed: &Path,
+    mut send: impl FnMut(&[Document]) -> Result<Vec<Result<PrintOutcome, PrintError>>, PrintError>,
+    mut report: impl FnMut(Event),
+) -> Result<()> {
+    // Events only wake the loop up, the folder is rescanned either way
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        let _ = tx.send(event);
+    })
+    .context("failed to start watching for files")?;
+    watcher
+        .watch(&folder.dir, RecursiveMode::NonRecursive)
+        .with_context(|| format!("failed to watch {}", folder.dir.display()))?;
+    loop {
+        let files = folder.ready()?;
+        if !files.is_empty() {
+            let documents: Vec<Document> = files.iter().cloned().map(Document::from).collect();
+            let results = match send(&documents) {
+                Ok(results) => results,
+                Err(e @ (PrintError::Connect(_) | PrintError::Timeout(_))) => {
+                    report(Event::Unreachable(e));
+                    for file in &files {
+                        folder.defer(file, retry_delay);
+                    }
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            for (file, result) in files.into_iter().zip(results) {
+                let retry = is_retryable(&result);
+                match result.and_then(PrintOutcome::into_result) {
+                    Err(error) if retry => {
+                        folder.defer(&file, retry_delay);
+                        report(Event::Retrying { file, error });
+                    }
+                    Ok(outcome) => {
+                        let moved = match after {
+                            AfterPrint::Move(printed) => move_into(&file, printed).map(|_| ()),
+                            AfterPrint::Delete => fs::remove_file(&file),
+                        };
+                        if let Err(e) = moved {
+                            // Left where it is, but never printed twice
+                            warn!("failed to move {} out of the way: {}", file.display(), e);
+                        }
+                        folder.done(&file);
+                        report(Event::Printed { file, outcome });
+                    }
+                    Err(error) => {
+                        let moved_to = move_into(&file, failed)
+                            .inspect_err(|e| {
+                                warn!("failed to move {} out of the way: {}", file.display(), e)
+                            })
+                            .ok();
+                        folder.done(&file);
+                        report(Event::Failed {
+                            file,
+                            error,
+                            moved_to,
+                        });
+                    }
+                }
+            }
+        }
+
+        // Files held back are sent on time, not on the next rescan
+        let wait = folder
+            .next_ready()
+            .map_or(RESCAN_INTERVAL, |next| next.min(RESCAN_INTERVAL));
+        match rx.recv_timeout(wait) {
+            Ok(Ok(event)) => debug!("File event: {:?}", event),
+            Ok(Err(e)) => warn!("file watcher error: {}", e),
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => bail!("file watcher stopped"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_SETTLE_TIME: Duration = Duration::from_millis(200);
+    const TEST_RETRY_DELAY: Duration = Duration::from_millis(300);
+
+    #[test]
+    fn waits_for_files_to_settle() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut folder = Folder::new(dir.path(), TEST_SETTLE_TIME);
+        fs::write(dir.path().join("a.txt"), "hello").unwrap();
+        assert!(folder.ready().unwrap().is_empty());
+
+        // Still being written
+        std::thread::sleep(TEST_SETTLE_TIME / 2);
+        fs::write(dir.path().join("a.txt"), "hello world").unwrap();
+        std::thread::sleep(TEST_SETTLE_TIME / 2 + Duration::from_millis(50));
+        assert!(folder.ready().unwrap().is_empty());
+
+        std::thread::sleep(TEST_SETTLE_TIME);
+        assert_eq!(folder.ready().unwrap(), vec![dir.path().join("a.txt")]);
+    }
+
+    #[test]
+    fn done_files_left_behind_are_not_sent_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.txt");
+        let mut folder = Folder::new(dir.path(), Duration::ZERO);
+        fs::write(&file, "hello").unwrap();
+        assert_eq!(folder.ready().unwrap(), vec![file.clone()]);
+
+        // Moving it away failed
+        folder.done(&file);
+        assert!(folder.ready().unwrap().is_empty());
+        assert!(folder.ready().unwrap().is_empty());
+
+        // Replaced with another file of the same name
+        fs::write(&file, "hello again").unwrap();
+        assert_eq!(folder.ready().unwrap(), vec![file]);
+    }
+
+    #[test]
+    fn skips_hidden_and_partial_files() {
+        for name in [
+            ".hidden.pdf",
+            "report.pdf.part",
+            "photo.jpg.crdownload",
+            "notes.txt~",
+        ] {
+            assert!(is_ignored(Path::new(name)), "{}", name);
+        }
+        assert!(!is_ignored(Path::new("report.pdf")));
+    }
+
+    #[test]
+    fn moving_keeps_files_with_the_same_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let printed = dir.path().join("printed");
+        for _ in 0..2 {
+            fs::write(dir.path().join("a.txt"), "hello").unwrap();
+            move_into(&dir.path().join("a.txt"), &printed).unwrap();
+        }
+        assert!(printed.join("a.txt").exists());
+        assert!(printed.join("a (1).txt").exists());
+        assert!(!dir.path().join("a.txt").exists());
+    }
+
+    #[test]
+    fn watching_prints_moves_and_retries_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (printed, failed) = (dir.path().join("printed"), dir.path().join("failed"));
+        for name in ["good.pdf", "bad.pdf", "busy.pdf"] {
+            fs::write(dir.path().join(name), name).unwrap();
+        }
+
+        let start = Instant::now();
+        let mut sent = Vec::new();
+        let mut events = Vec::new();
+        let err = watch_folder(
+            Folder::new(dir.path(), Duration::ZERO),
+            TEST_RETRY_DELAY,
+            &AfterPrint::Move(printed.clone()),
+            &failed,
+            |documents| {
+                let mut names: Vec<String> = documents.iter().map(|d| d.name().unwrap()).collect();
+                names.sort();
+                sent.push(names.clone());
+                if sent.len() == 1 {
+                    return Err(PrintError::Connect(anyhow::anyhow!("server is down")));
+                }
+                if names == ["stop"] {
+                    return Err(PrintError::Other(anyhow::anyhow!("stopped")));
+                }
+                let busy = sent.len() == 2;
+                Ok(names
+                    .iter()
+                    .map(|name| match name.as_str() {
+                        "bad.pdf" => Ok(PrintOutcome::Rejected("not a PDF".into())),
+                        "busy.pdf" if busy => Ok(PrintOutcome::PrinterError("busy".into())),
+                        _ => Ok(PrintOutcome::Printed(None, Default::default())),
+                    })
+                    .collect())
+            },
+            |event| {
+                // Ends the watch once everything is through
+                if matches!(&event, Event::Printed { file, .. } if file.ends_with("busy.pdf")) {
+                    fs::write(dir.path().join("stop"), "").unwrap();
+                }
+                events.push(event);
+            },
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "stopped");
+
+        let all = ["bad.pdf", "busy.pdf", "good.pdf"];
+        assert_eq!(sent, [&all[..], &all, &["busy.pdf"], &["stop"]]);
+        // Sent again after the server was down, and again after the printer was busy
+        assert!(start.elapsed() >= TEST_RETRY_DELAY * 2);
+        assert!(matches!(
+            events[0],
+            Event::Unreachable(PrintError::Connect(_))
+        ));
+        assert!(events.iter().any(
+            |event| matches!(event, Event::Retrying { file, .. } if file.ends_with("busy.pdf"))
+        ));
+
+        assert!(printed.join("good.pdf").exists());
+        assert!(printed.join("busy.pdf").exists());
+        assert!(failed.join("bad.pdf").exists());
+        let left: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(left.len(), 3, "{:?}", left);
+    }
+}
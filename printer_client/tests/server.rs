@@ -0,0 +1,521 @@
+//! Runs the server in-process on an ephemeral port and drives it with the client
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use printer_client::{
+    ConnectOptions, ConnectionPool, Document, PrintError, PrintOptions, PrintOutcome, Printer,
+    Session, Transport,
+};
+use printer_server::{
+    connection::{self, Config},
+    Backend, HashCost, JobLimiter, Settings, DEFAULT_USER, MIN_HASH_ITERATIONS,
+    MIN_HASH_MEMORY_KIB,
+};
+use tempfile::TempDir;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use url::Url;
+use uuid::Uuid;
+
+const PASSWORD: &str = "correct horse battery staple";
+
+struct TestServer {
+    url: Url,
+    connect: ConnectOptions,
+    output_dir: TempDir,
+    /// QUIC connections accepted so far
+    connections: Arc<AtomicUsize>,
+    _temp_dir: TempDir,
+}
+
+/// Starts a server saving jobs to a temp dir, with [`DEFAULT_USER`] logging in with [`PASSWORD`]
+async fn start_server() -> TestServer {
+    start_server_over(true).await
+}
+
+/// [`start_server`], answering QUIC only if `quic` is set, like behind a firewall blocking UDP
+async fn start_server_over(quic: bool) -> TestServer {
+    start_server_with(quic, Duration::from_secs(10)).await
+}
+
+/// [`start_server_over`] on a runtime of its own, so it keeps going while the test blocks.
+/// QUIC connections idle for `idle_timeout` are dropped without telling the client
+fn start_server_in_background(idle_timeout: Duration) -> TestServer {
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            tx.send(start_server_with(true, idle_timeout).await)
+                .unwrap();
+            std::future::pending::<()>().await
+        })
+    });
+    rx.recv().unwrap()
+}
+
+async fn start_server_with(quic: bool, idle_timeout: Duration) -> TestServer {
+    let output_dir = TempDir::new().unwrap();
+    let temp_dir = TempDir::new().unwrap();
+
+    let backend = Backend::File {
+        output_dir: output_dir.path().to_path_buf(),
+    };
+    let mut settings = Settings::default();
+    settings.backend = backend.clone();
+    // Debug builds hash slowly
+    settings.hash_cost = HashCost {
+        iterations: MIN_HASH_ITERATIONS,
+        memory_kib: MIN_HASH_MEMORY_KIB,
+    };
+    settings.set_password(DEFAULT_USER, PASSWORD).unwrap();
+    let settings = Arc::new(settings);
+    let config = Arc::new(Config {
+        printer: None,
+        temp_dir: temp_dir.path().to_path_buf(),
+        backend,
+        jobs: JobLimiter::new(&settings),
+        idle_timeout: Duration::from_secs(10),
+    });
+
+    let (cert, key) = printer_server::self_signed(vec!["localhost".to_string()]).unwrap();
+    let fingerprint = printer_server::fingerprint(&cert);
+    let tls = connection::tls_config(
+        vec![rustls::Certificate(cert)],
+        rustls::PrivateKey(key),
+        None,
+    )
+    .unwrap();
+    let server_config = connection::server_config(tls.clone(), idle_timeout, None).unwrap();
+    let endpoint =
+        quinn::Endpoint::server(server_config, SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+    let addr = endpoint.local_addr().unwrap();
+    let url = Url::parse(&format!("https://{}", addr)).unwrap();
+
+    let (quic_config, quic_settings) = (config.clone(), settings.clone());
+    let connections = Arc::new(AtomicUsize::new(0));
+    let accepted = connections.clone();
+    if !quic {
+        // Keeps the port, ignoring new connections
+        endpoint.set_server_config(None);
+    }
+    tokio::spawn(async move {
+        while let Some(conn) = endpoint.accept().await {
+            accepted.fetch_add(1, Ordering::Relaxed);
+            tokio::spawn(connection::handle_connection(
+                quic_config.clone(),
+                quic_settings.clone(),
+                conn,
+            ));
+        }
+    });
+
+    // TCP on the same port, like the server binary
+    let listener = TcpListener::bind(addr).await.unwrap();
+    let acceptor = TlsAcceptor::from(tls);
+    tokio::spawn(async move {
+        while let Ok((stream, remote)) = listener.accept().await {
+            tokio::spawn(connection::handle_tcp_connection(
+                config.clone(),
+                settings.clone(),
+                acceptor.clone(),
+                stream,
+                remote,
+            ));
+        }
+    });
+
+    let mut connect = ConnectOptions {
+        host: Some("localhost".to_string()),
+        ..Default::default()
+    };
+    connect
+        .pinned_certs
+        .insert(printer_client::host_key(&url), fingerprint);
+
+    TestServer {
+        url,
+        connect,
+        output_dir,
+        connections,
+        _temp_dir: temp_dir,
+    }
+}
+
+fn document() -> Document {
+    Document::Bytes {
+        name: "hello.txt".to_string(),
+        bytes: Arc::from(&b"Hello from the integration test\n"[..]),
+    }
+}
+
+/// Prints [`document`] over `transport`, checking it's saved to the output dir
+async fn prints_over(transport: Transport) {
+    let mut server = start_server().await;
+    server.connect.transport = transport;
+    let mut printer = Printer::new(PASSWORD.to_string(), server.url.port().unwrap());
+
+    let outcome = printer_client::send_file_async(
+        server.url.clone(),
+        &server.connect,
+        document(),
+        Some(&mut printer),
+        PrintOptions::default(),
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    assert!(
+        matches!(outcome, PrintOutcome::Printed(..)),
+        "unexpected outcome: {}",
+        outcome
+    );
+    assert!(printer.session.is_some());
+
+    let printed: Vec<_> = std::fs::read_dir(server.output_dir.path())
+        .unwrap()
+        .map(|entry| std::fs::read(entry.unwrap().path()).unwrap())
+        .collect();
+    assert_eq!(printed, vec![b"Hello from the integration test\n".to_vec()]);
+}
+
+#[tokio::test]
+async fn prints_with_correct_password() {
+    prints_over(Transport::Quic).await;
+}
+
+#[tokio::test]
+async fn prints_over_tcp() {
+    prints_over(Transport::Tcp).await;
+}
+
+#[tokio::test]
+async fn streams_large_files_from_disk() {
+    let server = start_server().await;
+    let mut printer = Printer::new(PASSWORD.to_string(), server.url.port().unwrap());
+    // Over a MiB, large enough to be sent as a resumable upload
+    let line = b"A line of a long document\n";
+    let content = line.repeat(1024 * 1024 / line.len() + 1);
+    let files = TempDir::new().unwrap();
+    let path = files.path().join("long.txt");
+    std::fs::write(&path, &content).unwrap();
+
+    let outcome = printer_client::send_file_async(
+        server.url.clone(),
+        &server.connect,
+        Document::Path(path),
+        Some(&mut printer),
+        PrintOptions::default(),
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    assert!(
+        matches!(outcome, PrintOutcome::Printed(..)),
+        "unexpected outcome: {}",
+        outcome
+    );
+
+    let printed: Vec<_> = std::fs::read_dir(server.output_dir.path())
+        .unwrap()
+        .map(|entry| std::fs::read(entry.unwrap().path()).unwrap())
+        .collect();
+    assert_eq!(printed, vec![content]);
+}
+
+#[tokio::test]
+async fn auto_falls_back_to_tcp() {
+    let mut server = start_server_over(false).await;
+    server.connect.transport = Transport::Auto;
+    server.connect.timeout = Duration::from_millis(500);
+    server.connect.retries = 0;
+    let mut printer = Printer::new(PASSWORD.to_string(), server.url.port().unwrap());
+
+    let outcome = printer_client::send_file_async(
+        server.url.clone(),
+        &server.connect,
+        document(),
+        Some(&mut printer),
+        PrintOptions::default(),
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    assert!(
+        matches!(outcome, PrintOutcome::Printed(..)),
+        "unexpected outcome: {}",
+        outcome
+    );
+    assert_eq!(server.connections.load(Ordering::Relaxed), 0);
+    assert_eq!(
+        std::fs::read_dir(server.output_dir.path()).unwrap().count(),
+        1
+    );
+}
+
+#[tokio::test]
+async fn rejects_wrong_password() {
+    let server = start_server().await;
+
+    let err = printer_client::get_session(
+        server.url.clone(),
+        &server.connect,
+        None,
+        "wrong password".to_string(),
+    )
+    .await
+    .unwrap_err();
+    assert!(
+        matches!(err, PrintError::Auth(_)),
+        "unexpected error: {:#}",
+        err
+    );
+
+    let session = printer_client::get_session(
+        server.url.clone(),
+        &server.connect,
+        None,
+        PASSWORD.to_string(),
+    )
+    .await
+    .unwrap();
+    assert!(session.expiration > chrono::Utc::now());
+    assert_eq!(
+        std::fs::read_dir(server.output_dir.path()).unwrap().count(),
+        0
+    );
+}
+
+#[tokio::test]
+async fn logs_in_again_when_session_is_rejected() {
+    let server = start_server().await;
+    // Left over from before a server restart
+    let stale = Session {
+        id: Uuid::new_v4(),
+        expiration: chrono::Utc::now() + chrono::Duration::hours(1),
+    };
+    let mut printer = Printer {
+        session: Some(stale.clone()),
+        ..Printer::new(PASSWORD.to_string(), server.url.port().unwrap())
+    };
+
+    let outcome = printer_client::send_file_async(
+        server.url.clone(),
+        &server.connect,
+        document(),
+        Some(&mut printer),
+        PrintOptions::default(),
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    assert!(
+        matches!(outcome, PrintOutcome::Printed(..)),
+        "unexpected outcome: {}",
+        outcome
+    );
+    let session = printer.session.expect("a new session");
+    assert_ne!(session.id, stale.id);
+    assert_eq!(
+        std::fs::read_dir(server.output_dir.path()).unwrap().count(),
+        1
+    );
+}
+
+#[tokio::test]
+async fn pooled_connections_are_reused() {
+    let mut server = start_server().await;
+    server.connect.transport = Transport::Quic;
+    let pool = Arc::new(ConnectionPool::new(Duration::from_secs(60)));
+    server.connect.pool = Some(pool.clone());
+    let mut printer = Printer::new(PASSWORD.to_string(), server.url.port().unwrap());
+
+    for attempt in 0..3 {
+        // Connects again once the pooled connection is closed
+        if attempt == 2 {
+            assert_eq!(server.connections.load(Ordering::Relaxed), 1);
+            pool.close_all().await;
+        }
+        let outcome = printer_client::send_file_async(
+            server.url.clone(),
+            &server.connect,
+            document(),
+            Some(&mut printer),
+            PrintOptions::default(),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(
+            matches!(outcome, PrintOutcome::Printed(..)),
+            "unexpected outcome: {}",
+            outcome
+        );
+    }
+    assert_eq!(server.connections.load(Ordering::Relaxed), 2);
+    assert_eq!(
+        std::fs::read_dir(server.output_dir.path()).unwrap().count(),
+        3
+    );
+}
+
+#[tokio::test]
+async fn pooled_connections_dropped_by_the_server_are_replaced() {
+    let mut server = start_server_in_background(Duration::from_millis(500));
+    server.connect.transport = Transport::Quic;
+    server.connect.pool = Some(Arc::new(ConnectionPool::new(Duration::from_secs(60))));
+    let mut printer = Printer::new(PASSWORD.to_string(), server.url.port().unwrap());
+
+    for attempt in 0..2 {
+        if attempt == 1 {
+            // Blocks the test's runtime, so the pooled connection can't notice it's gone
+            std::thread::sleep(Duration::from_secs(1));
+        }
+        let outcome = printer_client::send_file_async(
+            server.url.clone(),
+            &server.connect,
+            document(),
+            Some(&mut printer),
+            PrintOptions::default(),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(
+            matches!(outcome, PrintOutcome::Printed(..)),
+            "unexpected outcome: {}",
+            outcome
+        );
+    }
+    assert_eq!(server.connections.load(Ordering::Relaxed), 2);
+    assert_eq!(
+        std::fs::read_dir(server.output_dir.path()).unwrap().count(),
+        2
+    );
+}
+
+#[tokio::test]
+async fn cancel_stops_waiting_for_the_response() {
+    // Takes requests and never answers them
+    let (cert, key) = printer_server::self_signed(vec!["localhost".to_string()]).unwrap();
+    let fingerprint = printer_server::fingerprint(&cert);
+    let tls = connection::tls_config(
+        vec![rustls::Certificate(cert)],
+        rustls::PrivateKey(key),
+        None,
+    )
+    .unwrap();
+    let server_config = connection::server_config(tls, Duration::from_secs(30), None).unwrap();
+    let endpoint =
+        quinn::Endpoint::server(server_config, SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+    let url = Url::parse(&format!("https://{}", endpoint.local_addr().unwrap())).unwrap();
+    tokio::spawn(async move {
+        let conn = endpoint.accept().await.unwrap().await.unwrap();
+        let (_send, mut recv) = conn.accept_bi().await.unwrap();
+        let _ = recv.read_to_end(usize::MAX).await;
+        std::future::pending::<()>().await
+    });
+
+    let mut connect = ConnectOptions {
+        host: Some("localhost".to_string()),
+        transport: Transport::Quic,
+        ..Default::default()
+    };
+    connect
+        .pinned_certs
+        .insert(printer_client::host_key(&url), fingerprint);
+    let mut printer = Printer {
+        session: Some(Session {
+            id: Uuid::new_v4(),
+            expiration: chrono::Utc::now() + chrono::Duration::hours(1),
+        }),
+        ..Printer::new(PASSWORD.to_string(), url.port().unwrap())
+    };
+
+    let cancel = Arc::new(AtomicBool::new(false));
+    let flag = cancel.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        flag.store(true, Ordering::Relaxed);
+    });
+    let result = tokio::time::timeout(
+        Duration::from_secs(5),
+        printer_client::send_file_async(
+            url,
+            &connect,
+            document(),
+            Some(&mut printer),
+            PrintOptions::default(),
+            None,
+            Some(&cancel),
+        ),
+    )
+    .await
+    .expect("cancel should end the wait");
+    assert!(matches!(result, Err(PrintError::Cancelled)), "{:?}", result);
+}
+
+/// Exit code of the client binary's `upload` of `file` to `url`, logging in with `password`.
+/// Runs with its own empty data dir so the user's settings aren't touched
+async fn upload_exit_code(url: &str, password: &str, file: &std::path::Path) -> i32 {
+    let data_dir = TempDir::new().unwrap();
+    let status = tokio::process::Command::new(env!("CARGO_BIN_EXE_printer_client"))
+        .args([
+            "upload",
+            "--host",
+            "localhost",
+            "--timeout",
+            "2",
+            "--retries",
+            "0",
+        ])
+        .arg(url)
+        .arg(file)
+        .env(printer_client::DATA_DIR_ENV, data_dir.path())
+        .env("REMOTE_PRINT_PASSWORD", password)
+        .status()
+        .await
+        .unwrap();
+    status.code().unwrap()
+}
+
+#[tokio::test]
+async fn upload_exit_codes_tell_failures_apart() {
+    let server = start_server().await;
+    let fingerprint = &server.connect.pinned_certs[&printer_client::host_key(&server.url)];
+    // Trusts the server's certificate like a URL it printed
+    let url = format!(
+        "remote-print://{}:{}?fingerprint={}",
+        server.url.host_str().unwrap(),
+        server.url.port().unwrap(),
+        fingerprint
+    );
+    let files = TempDir::new().unwrap();
+    let text = files.path().join("hello.txt");
+    std::fs::write(&text, "Hello from the integration test\n").unwrap();
+    let program = files.path().join("hello.exe");
+    std::fs::write(&program, "MZ").unwrap();
+
+    assert_eq!(upload_exit_code(&url, PASSWORD, &text).await, 0);
+    assert_eq!(upload_exit_code(&url, "wrong password", &text).await, 4);
+    assert_eq!(upload_exit_code(&url, PASSWORD, &program).await, 5);
+
+    // Nothing listens on a port just freed
+    let closed = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let unreachable = format!("remote-print://{}?fingerprint={}", closed, fingerprint);
+    assert_eq!(upload_exit_code(&unreachable, PASSWORD, &text).await, 3);
+}
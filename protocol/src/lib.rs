@@ -0,0 +1,1183 @@
+//! Wire format shared by the client and server.
+//!
+//! A request is a header block followed by the body. The header block is a request line
+//! (`POST "file.pdf"`, `GET printers`), `Name: value` headers and an empty line.
+//! What the response looks like depends on the request.
+
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+pub mod pdf;
+
+/// Version of the request protocol, bumped on incompatible changes.
+/// Must match the version in [`ALPN_PROTOCOL`]
+pub const PROTOCOL_VERSION: u32 = 1;
+/// ALPN identifier of [`PROTOCOL_VERSION`], negotiated during the TLS handshake
+pub const ALPN_PROTOCOL: &[u8] = b"remote-print/1";
+/// ALPN used before the protocol was versioned
+pub const LEGACY_ALPN: &[u8] = b"hq-29";
+
+/// Port servers listen on by default
+pub const DEFAULT_PORT: u16 = 4433;
+
+/// mDNS service type servers are advertised under
+pub const SERVICE_TYPE: &str = "_remoteprint._udp.local.";
+
+/// Maximum size of a request's header block, in bytes.
+/// Anything larger is rejected so a client can't stream headers forever.
+pub const MAX_HEADER_SIZE: usize = 8 * 1024;
+
+/// Most copies a single request may ask for
+pub const MAX_COPIES: u32 = 100;
+
+/// Values accepted for the `Sided` header, as understood by CUPS
+pub const SIDES: &[&str] = &["one-sided", "two-sided-long-edge", "two-sided-short-edge"];
+
+/// Values accepted for the `Position` header, where CUPS puts an image on the page
+pub const POSITIONS: &[&str] = &[
+    "center",
+    "top",
+    "bottom",
+    "left",
+    "right",
+    "top-left",
+    "top-right",
+    "bottom-left",
+    "bottom-right",
+];
+
+/// Largest `Scaling` percentage, over 100 enlarges the image
+pub const MAX_SCALING: u16 = 400;
+
+/// Extensions of images, the only files `Scaling` and `Position` apply to
+pub const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "tif", "tiff"];
+
+/// Longest accepted `Media` header value
+pub const MAX_MEDIA_LEN: usize = 64;
+
+/// Bytes [`sniff_extension`] needs to recognize a file
+pub const SNIFF_LEN: usize = 512;
+
+/// Scheme of the connection URLs a server prints, `remote-print://host:port?fingerprint=…`
+pub const URL_SCHEME: &str = "remote-print";
+
+/// Start of the plain text reply to a request that failed
+pub const ERROR_PREFIX: &str = "Failed to process request: ";
+
+/// Start of the line of an error reply giving its [`JobCode`], see [`error_response`]
+pub const ERROR_CODE_PREFIX: &str = "Code: ";
+
+/// Application close code for a peer done with the connection
+pub const CLOSE_DONE: u32 = 0;
+
+/// Application close code for a client speaking another protocol version
+pub const CLOSE_VERSION_MISMATCH: u32 = 1;
+
+/// Application close code for a client abandoning an upload
+pub const CLOSE_CANCELLED: u32 = 2;
+
+/// Application close code for a client giving up after an error, e.g. a failed login
+pub const CLOSE_ERROR: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Post,
+    Delete,
+}
+
+impl std::fmt::Display for Method {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Method::Get => "GET",
+            Method::Post => "POST",
+            Method::Delete => "DELETE",
+        })
+    }
+}
+
+/// Encoding of a request body
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ContentEncoding {
+    #[default]
+    Identity,
+    Zstd,
+}
+
+/// Print options requested by the client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrintOptions {
+    pub copies: u32,
+    /// CUPS `sides` value, printer default if `None`
+    pub sides: Option<String>,
+    /// CUPS `page-ranges` value (e.g. `3-7,10`), all pages if `None`
+    pub page_ranges: Option<String>,
+    /// CUPS `media` value (e.g. `A4`), printer default if `None`
+    pub media: Option<String>,
+    /// Rotate the page, portrait if `false`
+    pub landscape: bool,
+    /// Scale the document to the printable area
+    pub fit_to_page: bool,
+    /// CUPS `scaling` value, percent of the page an image covers. Printer default if `None`
+    pub scaling: Option<u16>,
+    /// CUPS `position` value, where an image goes on the page. Printer default if `None`
+    pub position: Option<String>,
+}
+
+impl Default for PrintOptions {
+    fn default() -> Self {
+        PrintOptions {
+            copies: 1,
+            sides: None,
+            page_ranges: None,
+            media: None,
+            landscape: false,
+            fit_to_page: false,
+            scaling: None,
+            position: None,
+        }
+    }
+}
+
+/// Header block of a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: Method,
+    /// Target in the request line: the resource for `GET` and `DELETE` (e.g. `authenticate`),
+    /// the filename for `POST` (percent-encoded, quoted by older clients).
+    /// Use [`Request::filename`] for the name of an uploaded file
+    pub target: String,
+    /// Name of the file being printed from the `Filename` header, sent percent-encoded.
+    /// See [`Request::filename`]
+    pub filename_header: Option<String>,
+    pub content_length: Option<u64>,
+    pub extension: Option<String>,
+    pub session_id: Option<Uuid>,
+    /// User authenticating, the server's default user if `None`
+    pub user: Option<String>,
+    /// [`PROTOCOL_VERSION`] the client speaks, `None` from older clients
+    pub protocol_version: Option<u32>,
+    /// Printer requested by the client, server default if `None`
+    pub printer: Option<String>,
+    pub options: PrintOptions,
+    pub encoding: ContentEncoding,
+    /// Validate and spool the file but don't print it
+    pub dry_run: bool,
+    /// [`content_hash`] of the whole body, makes the upload resumable
+    pub content_hash: Option<String>,
+    /// Bytes of the body sent in an earlier attempt, the body sent starts after them.
+    /// Only for resumable uploads
+    pub offset: u64,
+}
+
+impl Request {
+    fn new(method: Method, target: String) -> Self {
+        Request {
+            method,
+            target,
+            filename_header: None,
+            content_length: None,
+            extension: None,
+            session_id: None,
+            user: None,
+            protocol_version: None,
+            printer: None,
+            options: PrintOptions::default(),
+            encoding: ContentEncoding::Identity,
+            dry_run: false,
+            content_hash: None,
+            offset: 0,
+        }
+    }
+
+    /// `GET` of `resource`, e.g. `printers` or `job/<id>`
+    pub fn get(resource: impl Into<String>) -> Self {
+        Request::new(Method::Get, resource.into())
+    }
+
+    /// `DELETE` of `resource`, e.g. `session/<id>`
+    pub fn delete(resource: impl Into<String>) -> Self {
+        Request::new(Method::Delete, resource.into())
+    }
+
+    /// `POST` of a file called `filename`
+    pub fn post(filename: &str) -> Self {
+        Request {
+            filename_header: Some(filename.to_string()),
+            ..Request::new(Method::Post, percent_encode(filename))
+        }
+    }
+
+    /// Filename of a `POST`. Older clients only send it quoted in the request line
+    pub fn filename(&self) -> &str {
+        self.filename_header
+            .as_deref()
+            .unwrap_or_else(|| self.target.trim_matches('"'))
+    }
+
+    /// Header block as sent on the wire, ending with the empty line
+    pub fn serialize(&self) -> String {
+        let mut lines = vec![format!("{} {}", self.method, self.target)];
+
+        if let Some(filename) = &self.filename_header {
+            lines.push(format!("Filename: {}", percent_encode(filename)));
+        }
+        if let Some(length) = self.content_length {
+            lines.push(format!("Content-Length: {}", length));
+        }
+        if let Some(extension) = &self.extension {
+            lines.push(format!("Extension: {}", escape_header_value(extension)));
+        }
+        if let Some(id) = self.session_id {
+            lines.push(format!("Session: {}", id));
+        }
+        if let Some(user) = &self.user {
+            lines.push(format!("User: {}", escape_header_value(user)));
+        }
+        if let Some(version) = self.protocol_version {
+            lines.push(format!("Protocol-Version: {}", version));
+        }
+        if let Some(printer) = &self.printer {
+            lines.push(format!("Printer: {}", escape_header_value(printer)));
+        }
+        if self.options.copies != 1 {
+            lines.push(format!("Copies: {}", self.options.copies));
+        }
+        if let Some(sides) = &self.options.sides {
+            lines.push(format!("Sided: {}", escape_header_value(sides)));
+        }
+        if let Some(pages) = &self.options.page_ranges {
+            lines.push(format!("Pages: {}", escape_header_value(pages)));
+        }
+        if let Some(media) = &self.options.media {
+            lines.push(format!("Media: {}", escape_header_value(media)));
+        }
+        if self.options.landscape {
+            lines.push("Orientation: landscape".to_string());
+        }
+        if self.options.fit_to_page {
+            lines.push("Fit-To-Page: true".to_string());
+        }
+        if let Some(scaling) = self.options.scaling {
+            lines.push(format!("Scaling: {}", scaling));
+        }
+        if let Some(position) = &self.options.position {
+            lines.push(format!("Position: {}", escape_header_value(position)));
+        }
+        if self.encoding == ContentEncoding::Zstd {
+            lines.push("Content-Encoding: zstd".to_string());
+        }
+        if self.dry_run {
+            lines.push("Dry-Run: true".to_string());
+        }
+        if let Some(hash) = &self.content_hash {
+            lines.push(format!("Content-Hash: {}", hash));
+        }
+        if self.offset != 0 {
+            lines.push(format!("Offset: {}", self.offset));
+        }
+
+        lines.push("\r\n".to_string());
+        lines.join("\r\n")
+    }
+
+    /// Parses a header block.
+    /// Header names are case insensitive and may appear in any order.
+    pub fn parse(block: &str) -> Result<Request> {
+        if block.len() > MAX_HEADER_SIZE {
+            bail!("header block exceeds {} bytes", MAX_HEADER_SIZE);
+        }
+
+        let mut lines = block
+            .split('\n')
+            .map(|l| l.trim())
+            .skip_while(|l| l.is_empty());
+
+        // Request line (e.g. `POST file.pdf` or `GET authenticate`)
+        let request_line = lines.next().context("missing request line")?;
+        let (method, target) = request_line
+            .split_once(char::is_whitespace)
+            .map(|(m, t)| (m, t.trim()))
+            .unwrap_or((request_line, ""));
+        let method = match method {
+            "GET" => Method::Get,
+            "POST" => Method::Post,
+            "DELETE" => Method::Delete,
+            other => bail!("unknown request method: {:?}", other),
+        };
+
+        let mut request = Request::new(method, target.to_string());
+
+        let mut seen: Vec<String> = Vec::new();
+        for line in lines.take_while(|l| !l.is_empty()) {
+            let (key, value) = line
+                .split_once(':')
+                .with_context(|| format!("malformed header: {:?}", line))?;
+            let key = key.trim().to_ascii_lowercase();
+            let value = value.trim();
+
+            if seen.contains(&key) {
+                bail!("duplicate header: {}", key);
+            }
+
+            match key.as_str() {
+                "content-length" => {
+                    let len = value
+                        .parse()
+                        .with_context(|| format!("invalid Content-Length: {:?}", value))?;
+                    request.content_length = Some(len);
+                }
+                "filename" => {
+                    let filename = percent_decode(value)
+                        .with_context(|| format!("invalid Filename: {:?}", value))?;
+                    request.filename_header = Some(filename);
+                }
+                "extension" => request.extension = Some(header_value("Extension", value)?),
+                "content-encoding" => {
+                    request.encoding = match value {
+                        "identity" => ContentEncoding::Identity,
+                        "zstd" => ContentEncoding::Zstd,
+                        other => bail!("unsupported Content-Encoding: {:?}", other),
+                    }
+                }
+                "dry-run" => {
+                    request.dry_run = value
+                        .parse()
+                        .with_context(|| format!("invalid Dry-Run: {:?}", value))?;
+                }
+                "printer" => request.printer = Some(header_value("Printer", value)?),
+                "copies" => {
+                    let copies = value
+                        .parse()
+                        .with_context(|| format!("invalid Copies: {:?}", value))?;
+                    if !(1..=MAX_COPIES).contains(&copies) {
+                        bail!("Copies must be between 1 and {}", MAX_COPIES);
+                    }
+                    request.options.copies = copies;
+                }
+                "sided" => {
+                    let sides = header_value("Sided", value)?;
+                    if !SIDES.contains(&sides.as_str()) {
+                        bail!("invalid Sided: {:?}, expected one of {:?}", sides, SIDES);
+                    }
+                    request.options.sides = Some(sides);
+                }
+                "pages" => {
+                    let pages = header_value("Pages", value)?;
+                    if !is_valid_page_range(&pages) {
+                        bail!("invalid Pages: {:?}, expected e.g. 3-7,10", pages);
+                    }
+                    request.options.page_ranges = Some(pages);
+                }
+                "media" => {
+                    let media = header_value("Media", value)?;
+                    if !is_valid_media(&media) {
+                        bail!("invalid Media: {:?}", media);
+                    }
+                    request.options.media = Some(media);
+                }
+                "orientation" => {
+                    request.options.landscape = match value {
+                        "portrait" => false,
+                        "landscape" => true,
+                        other => bail!("invalid Orientation: {:?}", other),
+                    }
+                }
+                "fit-to-page" => {
+                    request.options.fit_to_page = value
+                        .parse()
+                        .with_context(|| format!("invalid Fit-To-Page: {:?}", value))?;
+                }
+                "scaling" => {
+                    let scaling = value
+                        .parse()
+                        .with_context(|| format!("invalid Scaling: {:?}", value))?;
+                    if !(1..=MAX_SCALING).contains(&scaling) {
+                        bail!("Scaling must be between 1 and {}", MAX_SCALING);
+                    }
+                    request.options.scaling = Some(scaling);
+                }
+                "position" => {
+                    let position = header_value("Position", value)?;
+                    if !POSITIONS.contains(&position.as_str()) {
+                        bail!(
+                            "invalid Position: {:?}, expected one of {:?}",
+                            position,
+                            POSITIONS
+                        );
+                    }
+                    request.options.position = Some(position);
+                }
+                "content-hash" => {
+                    if !is_valid_content_hash(value) {
+                        bail!("invalid Content-Hash: {:?}", value);
+                    }
+                    request.content_hash = Some(value.to_string());
+                }
+                "offset" => {
+                    request.offset = value
+                        .parse()
+                        .with_context(|| format!("invalid Offset: {:?}", value))?;
+                }
+                "user" => request.user = Some(header_value("User", value)?),
+                "protocol-version" => {
+                    let version = value
+                        .parse()
+                        .with_context(|| format!("invalid Protocol-Version: {:?}", value))?;
+                    request.protocol_version = Some(version);
+                }
+                "session" => {
+                    let id = Uuid::parse_str(value)
+                        .with_context(|| format!("invalid Session: {:?}", value))?;
+                    request.session_id = Some(id);
+                }
+                // Unknown headers are ignored to stay compatible with newer clients
+                _ => (),
+            }
+            seen.push(key);
+        }
+
+        if request.offset != 0 && request.content_hash.is_none() {
+            bail!("Offset without Content-Hash, only resumable uploads can be resumed");
+        }
+        if request.offset > request.content_length.unwrap_or(0) {
+            bail!("Offset is past the end of the body");
+        }
+
+        Ok(request)
+    }
+}
+
+/// Percent-encodes everything but unreserved URL characters (`A-Z a-z 0-9 - . _ ~`),
+/// so any name fits on a header line
+pub fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Percent-encodes `%` and control characters, so a value can't end its header line.
+/// Ordinary values are sent as they are, which older servers read unchanged
+pub fn escape_header_value(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '%' => "%25".to_string(),
+            c if c.is_ascii_control() => format!("%{:02X}", c as u8),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+/// Value of header `name` sent with [`escape_header_value`]
+fn header_value(name: &str, value: &str) -> Result<String> {
+    percent_decode(value).with_context(|| format!("invalid {}: {:?}", name, value))
+}
+
+/// Reverses [`percent_encode`] and [`escape_header_value`].
+/// Fails on malformed escapes or if the result isn't UTF-8
+pub fn percent_decode(value: &str) -> Result<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut iter = value.bytes();
+    while let Some(b) = iter.next() {
+        if b != b'%' {
+            bytes.push(b);
+            continue;
+        }
+        let hex = [iter.next(), iter.next()];
+        let byte = match hex {
+            [Some(hi), Some(lo)] => std::str::from_utf8(&[hi, lo])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        bytes.push(byte.context("malformed percent escape")?);
+    }
+
+    String::from_utf8(bytes).context("not valid UTF-8")
+}
+
+/// Guesses the extension of a file without one from its first [`SNIFF_LEN`] bytes.
+/// Knows the default allowed types, anything else that is UTF-8 is `txt`
+pub fn sniff_extension(head: &[u8]) -> Option<&'static str> {
+    const MAGIC: &[(&[u8], &str)] = &[
+        (b"%PDF-", "pdf"),
+        (b"%!PS", "ps"),
+        (b"\x89PNG\r\n\x1a\n", "png"),
+        (b"\xff\xd8\xff", "jpg"),
+        (b"GIF87a", "gif"),
+        (b"GIF89a", "gif"),
+        (b"II*\0", "tif"),
+        (b"MM\0*", "tif"),
+        (b"\xd0\xcf\x11\xe0\xa1\xb1\x1a\xe1", "doc"),
+    ];
+
+    if let Some((_, extension)) = MAGIC.iter().find(|(magic, _)| head.starts_with(magic)) {
+        return Some(extension);
+    }
+
+    // Office documents are zips, told apart by the name of their first entry
+    if head.starts_with(b"PK\x03\x04") {
+        let entry = head.get(30..).unwrap_or_default();
+        return if entry.starts_with(b"mimetypeapplication/vnd.oasis.opendocument.text") {
+            Some("odt")
+        } else if entry.starts_with(b"[Content_Types].xml") {
+            Some("docx")
+        } else {
+            None
+        };
+    }
+
+    // `head` may end in the middle of a character
+    let text = match std::str::from_utf8(head) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    };
+    (!head.is_empty() && text && !head.contains(&0)).then_some("txt")
+}
+
+/// Whether `extension` is an image, e.g. from [`sniff_extension`]
+pub fn is_image(extension: &str) -> bool {
+    IMAGE_EXTENSIONS
+        .iter()
+        .any(|image| image.eq_ignore_ascii_case(extension))
+}
+
+/// Checks a page range like `3-7,10`: comma separated pages or
+/// `first-last` ranges, pages starting at 1
+pub fn is_valid_page_range(range: &str) -> bool {
+    // Digits only, so signs and whitespace accepted by `parse` are rejected
+    let page = |p: &str| {
+        p.bytes()
+            .all(|b| b.is_ascii_digit())
+            .then(|| p.parse::<u32>().ok())
+            .flatten()
+            .filter(|&p| p > 0)
+    };
+
+    !range.is_empty()
+        && range.split(',').all(|part| match part.split_once('-') {
+            Some((first, last)) => {
+                matches!((page(first), page(last)), (Some(f), Some(l)) if f <= l)
+            }
+            None => page(part).is_some(),
+        })
+}
+
+/// Checks a media name looks like a CUPS keyword (`A4`, `na_letter_8.5x11in`).
+/// Whether the printer supports it is up to the server
+pub fn is_valid_media(media: &str) -> bool {
+    !media.is_empty()
+        && media.len() <= MAX_MEDIA_LEN
+        && media
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"_.-".contains(&b))
+}
+
+/// Successful reply to `GET authenticate`, `success&<session id>&<expiration>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthResponse {
+    pub session_id: Uuid,
+    pub expiration: DateTime<Utc>,
+}
+
+impl AuthResponse {
+    pub fn serialize(&self) -> String {
+        format!("success&{}&{}", self.session_id, self.expiration)
+    }
+
+    /// Parses a reply to `GET authenticate`, anything but a success is an error from the server
+    pub fn parse(resp: &str) -> Result<AuthResponse> {
+        let resp = resp.trim();
+        let fields: Vec<&str> = resp.split('&').collect();
+        if fields[0] != "success" {
+            bail!("Failed: {}", resp);
+        }
+
+        let [_, id, expiration] = fields[..] else {
+            bail!("malformed auth response: {:?}", resp);
+        };
+
+        Ok(AuthResponse {
+            session_id: Uuid::parse_str(id)
+                .with_context(|| format!("malformed auth response, invalid session id {:?}", id))?,
+            expiration: DateTime::from_str(expiration).with_context(|| {
+                format!(
+                    "malformed auth response, invalid expiration {:?}",
+                    expiration
+                )
+            })?,
+        })
+    }
+}
+
+/// Reply to a request that failed. The [`JobCode`] of a [`JobError`] follows on a line
+/// of its own, see [`error_code`]
+pub fn error_response(e: &anyhow::Error) -> Vec<u8> {
+    let code = e
+        .chain()
+        .find_map(|e| e.downcast_ref::<JobError>())
+        .and_then(|e| serde_json::to_value(e.code).ok());
+    match code.as_ref().and_then(|code| code.as_str()) {
+        Some(code) => format!("{}{}\n{}{}\n", ERROR_PREFIX, e, ERROR_CODE_PREFIX, code),
+        None => format!("{}{}\n", ERROR_PREFIX, e),
+    }
+    .into_bytes()
+}
+
+/// [`JobCode`] of an error reply, `None` if it has none (e.g. from older servers)
+pub fn error_code(resp: &str) -> Option<JobCode> {
+    let code = resp
+        .lines()
+        .find_map(|line| line.strip_prefix(ERROR_CODE_PREFIX))?;
+    serde_json::from_value(serde_json::Value::String(code.trim().to_string())).ok()
+}
+
+/// Message of an error reply, without [`ERROR_PREFIX`] or the code
+pub fn error_message(resp: &str) -> &str {
+    let message = resp.lines().next().unwrap_or_default();
+    message.strip_prefix(ERROR_PREFIX).unwrap_or(message).trim()
+}
+
+/// Whether a plain text reply is an error, see [`error_response`]
+pub fn is_error_response(resp: &str) -> bool {
+    // Older servers word some errors differently, they all start with `Failed`
+    resp.starts_with("Failed")
+}
+
+/// Result code of a print job, sent to the client as `code`
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobCode {
+    Done,
+    /// Dry run passed validation, nothing was printed
+    Validated,
+    AuthRequired,
+    SessionExpired,
+    UnknownPrinter,
+    InvalidRequest,
+    TooLarge,
+    /// The session, or the server, already has as many jobs in flight as it allows
+    TooManyJobs,
+    PrinterError,
+    Internal,
+    /// Code added by a newer server
+    #[serde(other)]
+    Unknown,
+}
+
+/// Error with a [`JobCode`]; any other error is reported as [`JobCode::Internal`]
+#[derive(Debug)]
+pub struct JobError {
+    pub code: JobCode,
+    pub message: String,
+}
+
+impl JobError {
+    pub fn new(code: JobCode, message: impl Into<String>) -> Self {
+        JobError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for JobError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for JobError {}
+
+/// Response to a `POST`, serialized as JSON
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq)]
+pub struct JobStatus {
+    /// `ok` or `error`
+    pub status: String,
+    pub code: JobCode,
+    pub message: String,
+    /// CUPS job ID, if the print command reported one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub job_id: Option<String>,
+    /// Pages the job prints, copies included. Unknown for anything but readable PDFs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pages: Option<u32>,
+    /// `pages` times the server's per-page cost, if it has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cost: Option<f64>,
+}
+
+impl JobStatus {
+    pub fn done(job_id: Option<String>) -> Self {
+        JobStatus {
+            status: "ok".to_string(),
+            code: JobCode::Done,
+            message: "done".to_string(),
+            job_id,
+            pages: None,
+            cost: None,
+        }
+    }
+
+    pub fn validated() -> Self {
+        JobStatus {
+            status: "ok".to_string(),
+            code: JobCode::Validated,
+            message: "dry run passed, nothing printed".to_string(),
+            job_id: None,
+            pages: None,
+            cost: None,
+        }
+    }
+
+    /// Adds the pages a job prints, and their cost at `page_cost` each
+    pub fn with_pages(self, pages: Option<u32>, page_cost: Option<f64>) -> Self {
+        JobStatus {
+            pages,
+            cost: pages
+                .zip(page_cost)
+                .map(|(pages, cost)| pages as f64 * cost),
+            ..self
+        }
+    }
+
+    pub fn from_error(e: &anyhow::Error) -> Self {
+        let code = e
+            .chain()
+            .find_map(|e| e.downcast_ref::<JobError>())
+            .map_or(JobCode::Internal, |e| e.code);
+
+        JobStatus {
+            status: "error".to_string(),
+            code,
+            message: format!("{:#}", e),
+            job_id: None,
+            pages: None,
+            cost: None,
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.status == "ok"
+    }
+}
+
+/// How far the server got with a resumable upload, reported for `GET upload/<content hash>`.
+/// Servers before [`UploadState::Received`] and [`UploadState::Finished`] reply with the
+/// bytes received alone
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadState {
+    /// Bytes received so far, a resumed upload starts after them
+    Partial(u64),
+    /// All of it arrived, the job is still being processed
+    Received,
+    /// The job is over, sending the upload again would print it twice
+    Finished(JobStatus),
+}
+
+/// State of a CUPS job, reported for `GET job/<id>`
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Printing,
+    Completed,
+    /// Canceled or aborted
+    Failed,
+    /// CUPS doesn't know the job (anymore)
+    Unknown,
+}
+
+impl JobState {
+    /// Whether the state won't change anymore
+    pub fn is_finished(self) -> bool {
+        !matches!(self, JobState::Queued | JobState::Printing)
+    }
+}
+
+impl std::fmt::Display for JobState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            JobState::Queued => "queued",
+            JobState::Printing => "printing",
+            JobState::Completed => "completed",
+            JobState::Failed => "failed",
+            JobState::Unknown => "unknown",
+        })
+    }
+}
+
+/// Options a printer supports, sent for `GET printer-options/<name>`
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct PrinterOptions {
+    /// Media sizes, a list of common ones if the printer doesn't report any
+    pub media: Vec<String>,
+    pub default_media: Option<String>,
+    /// Whether landscape printing is applied. Older servers don't send it and ignore it
+    #[serde(default)]
+    pub landscape: bool,
+    /// Whether fit-to-page is applied. Older servers don't send it and ignore it
+    #[serde(default)]
+    pub fit_to_page: bool,
+}
+
+/// SHA-256 fingerprint of a DER certificate, as colon separated hex (`AB:CD:...`).
+/// Printed by the server and shown by clients asked to trust it
+pub fn fingerprint(cert: &[u8]) -> String {
+    Sha256::digest(cert)
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// SHA-256 of a request body as lowercase hex, identifies resumable uploads
+pub fn content_hash(body: &[u8]) -> String {
+    hex(&Sha256::digest(body))
+}
+
+/// [`content_hash`] of everything read from `reader`, without holding it in memory
+pub fn content_hash_reader(mut reader: impl std::io::Read) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut reader, &mut hasher)?;
+    Ok(hex(&hasher.finalize()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Checks `hash` looks like a [`content_hash`], it ends up in file names
+pub fn is_valid_content_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(request: Request) {
+        let parsed = Request::parse(&request.serialize()).unwrap();
+        assert_eq!(parsed, request);
+    }
+
+    #[test]
+    fn print_request_round_trips() {
+        round_trip(Request {
+            content_length: Some(1234),
+            extension: Some("pdf".to_string()),
+            session_id: Some(Uuid::new_v4()),
+            printer: Some("Office_Laser".to_string()),
+            options: PrintOptions {
+                copies: 3,
+                sides: Some("two-sided-long-edge".to_string()),
+                page_ranges: Some("1-3,7".to_string()),
+                media: Some("A4".to_string()),
+                landscape: true,
+                fit_to_page: true,
+                scaling: Some(150),
+                position: Some("top-left".to_string()),
+            },
+            encoding: ContentEncoding::Zstd,
+            dry_run: true,
+            content_hash: Some(content_hash(b"report")),
+            offset: 1000,
+            ..Request::post("report final.pdf")
+        });
+    }
+
+    #[test]
+    fn minimal_print_request_round_trips() {
+        round_trip(Request {
+            content_length: Some(0),
+            extension: Some("txt".to_string()),
+            session_id: Some(Uuid::new_v4()),
+            ..Request::post("notes.txt")
+        });
+    }
+
+    #[test]
+    fn auth_request_round_trips() {
+        round_trip(Request::get("authenticate"));
+        round_trip(Request {
+            user: Some("alice".to_string()),
+            protocol_version: Some(PROTOCOL_VERSION),
+            ..Request::get("authenticate")
+        });
+    }
+
+    #[test]
+    fn get_requests_round_trip() {
+        for resource in [
+            "printers",
+            "history",
+            "status",
+            "job/Office_Laser-12",
+            "printer-options",
+            "printer-options/Office_Laser",
+        ] {
+            round_trip(Request {
+                session_id: Some(Uuid::new_v4()),
+                ..Request::get(resource)
+            });
+        }
+    }
+
+    #[test]
+    fn post_filename_round_trips() {
+        for name in [
+            "a b.pdf",
+            "r\u{e9}sum\u{e9}.pdf",
+            "100%\r\nSession: x.txt",
+            "\"quoted\".txt",
+        ] {
+            let request = Request::parse(&Request::post(name).serialize()).unwrap();
+            assert_eq!(request.filename(), name);
+        }
+    }
+
+    #[test]
+    fn filename_falls_back_to_the_quoted_request_line() {
+        let request = Request::parse("POST \"old client.pdf\"\r\n\r\n").unwrap();
+        assert_eq!(request.filename(), "old client.pdf");
+    }
+
+    #[test]
+    fn rejects_malformed_percent_encoding() {
+        for value in ["%", "%4", "%zz", "%FF"] {
+            assert!(percent_decode(value).is_err(), "{:?} decoded", value);
+        }
+    }
+
+    #[test]
+    fn parses_hand_written_headers() {
+        let block =
+            "POST \"file.pdf\"\r\ncontent-length: 5\r\nEXTENSION: pdf\r\nSided: one-sided\r\n\r\n";
+        let request = Request::parse(block).unwrap();
+        assert_eq!(request.content_length, Some(5));
+        assert_eq!(request.extension.as_deref(), Some("pdf"));
+        assert_eq!(request.options.sides.as_deref(), Some("one-sided"));
+    }
+
+    #[test]
+    fn parses_session_header() {
+        let id = Uuid::new_v4();
+        let request = Request::parse(&format!("GET printers\r\nSession: {}\r\n\r\n", id)).unwrap();
+        assert_eq!(request.session_id, Some(id));
+    }
+
+    #[test]
+    fn session_header_is_optional() {
+        let request = Request::parse("POST \"file.pdf\"\r\nExtension: pdf\r\n\r\n").unwrap();
+        assert_eq!(request.method, Method::Post);
+        assert_eq!(request.target, "\"file.pdf\"");
+        assert_eq!(request.filename(), "file.pdf");
+        assert_eq!(request.extension.as_deref(), Some("pdf"));
+        assert_eq!(request.content_length, None);
+        assert_eq!(request.session_id, None);
+    }
+
+    #[test]
+    fn rejects_duplicate_headers() {
+        let id = Uuid::new_v4();
+        let block = format!("GET printers\r\nSession: {}\r\nsession: {}\r\n\r\n", id, id);
+        let err = Request::parse(&block).unwrap_err();
+        assert_eq!(err.to_string(), "duplicate header: session");
+    }
+
+    #[test]
+    fn caps_the_header_block() {
+        let header = "Printer: x\r\n";
+        let fits = format!("GET printers\r\n{}", header);
+        let padding = " ".repeat(MAX_HEADER_SIZE - fits.len() - "\r\n".len());
+        Request::parse(&format!("{}{}\r\n", fits, padding)).unwrap();
+
+        let err = Request::parse(&format!("{}{} \r\n", fits, padding)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("header block exceeds {} bytes", MAX_HEADER_SIZE)
+        );
+    }
+
+    #[test]
+    fn colons_in_filenames_survive() {
+        // Only the first colon splits a header, the request line isn't split on them at all
+        let request =
+            Request::parse("POST \"10:30 notes.pdf\"\r\nExtension: pdf:x\r\n\r\n").unwrap();
+        assert_eq!(request.filename(), "10:30 notes.pdf");
+        assert_eq!(request.extension.as_deref(), Some("pdf:x"));
+    }
+
+    #[test]
+    fn lf_and_crlf_line_endings_parse_the_same() {
+        let crlf = "POST \"file.pdf\"\r\nContent-Length: 5\r\nExtension: pdf\r\n\r\n";
+        assert_eq!(
+            Request::parse(crlf).unwrap(),
+            Request::parse(&crlf.replace("\r\n", "\n")).unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_headers() {
+        for block in [
+            "PUT x\r\n\r\n",
+            "GET x\r\nCopies: 0\r\n\r\n",
+            "GET x\r\nSided: sideways\r\n\r\n",
+            "GET x\r\nPages: 5-2\r\n\r\n",
+            "GET x\r\nMedia: A4;rm\r\n\r\n",
+            "GET x\r\nOrientation: sideways\r\n\r\n",
+            "GET x\r\nScaling: 0\r\n\r\n",
+            "GET x\r\nScaling: 401\r\n\r\n",
+            "GET x\r\nPosition: middle\r\n\r\n",
+            "GET x\r\nSession: nope\r\n\r\n",
+            "GET x\r\nUser: a\r\nuser: b\r\n\r\n",
+            "GET x\r\nno colon\r\n\r\n",
+        ] {
+            assert!(Request::parse(block).is_err(), "{:?} parsed", block);
+        }
+    }
+
+    #[test]
+    fn header_values_with_line_breaks_round_trip() {
+        let request = Request {
+            extension: Some("pdf\r\nSession: x".to_string()),
+            user: Some("alice\nPrinter: other".to_string()),
+            printer: Some("Office\r\nDry-Run: true".to_string()),
+            ..Request::post("report.pdf")
+        };
+        let block = request.serialize();
+        // One line per header, none injected
+        let bare = Request::post("report.pdf").serialize();
+        assert_eq!(
+            block.matches("\r\n").count(),
+            bare.matches("\r\n").count() + 3
+        );
+        assert_eq!(Request::parse(&block).unwrap(), request);
+
+        // Stays one value, which isn't valid media
+        let request = Request {
+            options: PrintOptions {
+                media: Some("A4\nCopies: 99".to_string()),
+                ..Default::default()
+            },
+            ..Request::post("report.pdf")
+        };
+        let err = Request::parse(&request.serialize()).unwrap_err();
+        assert!(err.to_string().starts_with("invalid Media"), "{}", err);
+
+        // Ordinary values are sent unescaped
+        let request = Request {
+            printer: Some("Office Laser".to_string()),
+            user: Some("alice@example.com".to_string()),
+            ..Request::get("printers")
+        };
+        let block = request.serialize();
+        assert!(block.contains("Printer: Office Laser\r\n"), "{}", block);
+        assert!(block.contains("User: alice@example.com\r\n"), "{}", block);
+        assert_eq!(Request::parse(&block).unwrap(), request);
+    }
+
+    #[test]
+    fn sniffs_known_types() {
+        assert_eq!(sniff_extension(b"%PDF-1.7\n%\xe2\xe3"), Some("pdf"));
+        assert_eq!(
+            sniff_extension(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"),
+            Some("png")
+        );
+        assert_eq!(sniff_extension(b"\xff\xd8\xff\xe0\0\x10JFIF"), Some("jpg"));
+        assert_eq!(sniff_extension(b"plain text, caf\xc3"), Some("txt"));
+        assert_eq!(sniff_extension(b"\x7fELF\x02\x01\x01\0"), None);
+        assert_eq!(sniff_extension(b""), None);
+    }
+
+    #[test]
+    fn auth_response_round_trips() {
+        let resp = AuthResponse {
+            session_id: Uuid::new_v4(),
+            expiration: DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+        };
+        assert_eq!(AuthResponse::parse(&resp.serialize()).unwrap(), resp);
+    }
+
+    #[test]
+    fn auth_response_rejects_failures() {
+        let id = Uuid::nil();
+        for (resp, expected) in [
+            (
+                "Failed to process request: Invalid password\n",
+                "Failed: Failed to process request: Invalid password".to_string(),
+            ),
+            ("", "Failed: ".to_string()),
+            (
+                "success",
+                "malformed auth response: \"success\"".to_string(),
+            ),
+            (
+                "success&only-two",
+                "malformed auth response: \"success&only-two\"".to_string(),
+            ),
+            (
+                "success&a&b&c",
+                "malformed auth response: \"success&a&b&c\"".to_string(),
+            ),
+            (
+                "success&not-a-uuid&2024-01-01 00:00:00 UTC",
+                "malformed auth response, invalid session id \"not-a-uuid\"".to_string(),
+            ),
+            (
+                &format!("success&{}&tomorrow", id),
+                "malformed auth response, invalid expiration \"tomorrow\"".to_string(),
+            ),
+        ] {
+            let err = AuthResponse::parse(resp).unwrap_err();
+            assert_eq!(err.to_string(), expected, "{:?}", resp);
+        }
+    }
+
+    #[test]
+    fn job_status_round_trips() {
+        for status in [
+            JobStatus::done(Some("Office_Laser-12".to_string())),
+            JobStatus::done(None),
+            JobStatus::validated(),
+            JobStatus::from_error(&JobError::new(JobCode::TooLarge, "too big").into()),
+        ] {
+            let json = serde_json::to_string(&status).unwrap();
+            assert_eq!(serde_json::from_str::<JobStatus>(&json).unwrap(), status);
+        }
+    }
+
+    #[test]
+    fn error_responses_carry_the_job_code() {
+        let e = anyhow::Error::from(JobError::new(JobCode::SessionExpired, "Expired Session"));
+        let resp = String::from_utf8(error_response(&e.context("checking session"))).unwrap();
+        assert!(is_error_response(&resp));
+        assert_eq!(error_code(&resp), Some(JobCode::SessionExpired));
+        assert_eq!(error_message(&resp), "checking session");
+
+        // Errors without a code, and older servers
+        let resp = String::from_utf8(error_response(&anyhow::anyhow!("Invalid Request"))).unwrap();
+        assert_eq!(resp, format!("{}Invalid Request\n", ERROR_PREFIX));
+        assert_eq!(error_code(&resp), None);
+        assert_eq!(error_message(&resp), "Invalid Request");
+    }
+
+    #[test]
+    fn unknown_job_code_parses() {
+        let json = r#"{"status":"error","code":"out_of_paper","message":"no paper"}"#;
+        let status: JobStatus = serde_json::from_str(json).unwrap();
+        assert_eq!(status.code, JobCode::Unknown);
+    }
+}
@@ -0,0 +1,212 @@
+//! Just enough PDF parsing to count pages, without pulling in a full PDF library
+
+use std::io::Read;
+
+use flate2::read::ZlibDecoder;
+
+/// Most bytes inflated from the object streams of a PDF altogether, page trees are far smaller
+const MAX_INFLATED: u64 = 16 * 1024 * 1024;
+
+/// Object streams can't contain streams, so any nested deeper than this are skipped
+const MAX_OBJECT_STREAM_DEPTH: u32 = 1;
+
+/// Counts the pages of a PDF from the `/Count` of the root of its page tree.
+/// `None` if it can't tell, e.g. not a PDF, encrypted, or compressed with something
+/// other than Flate
+pub fn page_count(pdf: &[u8]) -> Option<u32> {
+    if !pdf.starts_with(b"%PDF-") || find(pdf, b"/Encrypt").is_some() {
+        return None;
+    }
+
+    // Every node of the page tree counts the pages below it, the root has the most
+    let mut scan = Scan::default();
+    page_tree_counts(pdf, 0, &mut scan);
+    scan.counts.into_iter().max()
+}
+
+/// What a scan through a PDF and its object streams found so far
+#[derive(Default)]
+struct Scan {
+    /// `/Count` of every page tree node
+    counts: Vec<u32>,
+    /// Bytes inflated from object streams, at most [`MAX_INFLATED`]
+    inflated: u64,
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn skip_whitespace(data: &[u8]) -> &[u8] {
+    let start = data
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(data.len());
+    &data[start..]
+}
+
+/// Whether `value` starts with the name `name`, and not a longer one like `/Pages` for `/Page`
+fn is_name(value: &[u8], name: &[u8]) -> bool {
+    value.starts_with(name) && !value.get(name.len()).is_some_and(u8::is_ascii_alphanumeric)
+}
+
+/// The value after `key` in `dict`
+fn value_of<'a>(dict: &'a [u8], key: &[u8]) -> Option<&'a [u8]> {
+    let mut rest = dict;
+    loop {
+        let at = find(rest, key)?;
+        rest = &rest[at + key.len()..];
+        // `/Type` shouldn't match `/TypeX`
+        if !rest.first().is_some_and(u8::is_ascii_alphanumeric) {
+            return Some(skip_whitespace(rest));
+        }
+    }
+}
+
+/// Collects the `/Count` of every page tree node in `data`, looking inside object streams
+/// down to [`MAX_OBJECT_STREAM_DEPTH`]
+fn page_tree_counts(data: &[u8], depth: u32, scan: &mut Scan) {
+    // Keys and values of the dictionaries still open, innermost last. A nested dictionary is
+    // left out of its parent, so every byte is copied at most once
+    let mut open: Vec<Vec<u8>> = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        match data.get(i..i + 2) {
+            Some(b"<<") => {
+                open.push(Vec::new());
+                i += 2;
+            }
+            Some(b">>") => {
+                i += 2;
+                let Some(dict) = open.pop() else {
+                    continue;
+                };
+
+                if value_of(&dict, b"/Type").is_some_and(|kind| is_name(kind, b"/Pages")) {
+                    if let Some(count) = value_of(&dict, b"/Count").and_then(parse_u32) {
+                        scan.counts.push(count);
+                    }
+                }
+
+                // Stream contents are binary, only object streams are worth looking into
+                let after = skip_whitespace(&data[i..]);
+                let Some(body) = after.strip_prefix(b"stream") else {
+                    continue;
+                };
+                let body = body.strip_prefix(b"\r").unwrap_or(body);
+                let body = body.strip_prefix(b"\n").unwrap_or(body);
+                let Some(end) = find(body, b"endstream") else {
+                    return;
+                };
+
+                let object_stream =
+                    value_of(&dict, b"/Type").is_some_and(|kind| is_name(kind, b"/ObjStm"));
+                let flate = value_of(&dict, b"/Filter").is_some_and(|filter| {
+                    let filter = skip_whitespace(filter.strip_prefix(b"[").unwrap_or(filter));
+                    is_name(filter, b"/FlateDecode")
+                });
+                let budget = MAX_INFLATED - scan.inflated;
+                if object_stream && flate && depth < MAX_OBJECT_STREAM_DEPTH && budget > 0 {
+                    let mut inflated = Vec::new();
+                    // Truncated streams still give the objects before the damage
+                    let _ = ZlibDecoder::new(&body[..end])
+                        .take(budget)
+                        .read_to_end(&mut inflated);
+                    scan.inflated += inflated.len() as u64;
+                    page_tree_counts(&inflated, depth + 1, scan);
+                }
+
+                open.clear();
+                i = data.len() - body[end..].len() + b"endstream".len();
+            }
+            _ => {
+                if let Some(dict) = open.last_mut() {
+                    dict.push(data[i]);
+                }
+                i += 1;
+            }
+        }
+    }
+}
+
+fn parse_u32(value: &[u8]) -> Option<u32> {
+    let digits = value.iter().take_while(|b| b.is_ascii_digit()).count();
+    std::str::from_utf8(&value[..digits]).ok()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::ZlibEncoder, Compression};
+
+    use super::*;
+
+    const PAGE_TREE: &[u8] = b"1 0 obj << /Type /Catalog /Pages 2 0 R >> endobj
+2 0 obj << /Type /Pages /Kids [3 0 R 4 0 R] /Count 3 >> endobj
+4 0 obj << /Type /Pages /Parent 2 0 R /Kids [5 0 R 6 0 R] /Count 2
+  /Resources << /Font << /F1 << /Type /Font /Count 99 >> >> >> >> endobj
+3 0 obj << /Type /Page /Parent 2 0 R >> endobj
+";
+
+    #[test]
+    fn counts_plain_page_tree() {
+        let pdf = [b"%PDF-1.4\n", PAGE_TREE, b"%%EOF\n"].concat();
+        assert_eq!(page_count(&pdf), Some(3));
+    }
+
+    /// `objects` as a Flate compressed object stream
+    fn object_stream(objects: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(objects).unwrap();
+        [
+            b"5 0 obj << /Type /ObjStm /N 2 /First 9 /Filter /FlateDecode >>\nstream\n".as_slice(),
+            &encoder.finish().unwrap(),
+            b"\nendstream\nendobj\n",
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn counts_page_tree_in_object_stream() {
+        let stream =
+            object_stream(b"2 0 4 50 << /Type /Pages /Kids [3 0 R] /Count 7 >> << /Type /Page >>");
+        let pdf = [b"%PDF-1.5\n".as_slice(), &stream, b"%%EOF\n"].concat();
+        assert_eq!(page_count(&pdf), Some(7));
+    }
+
+    #[test]
+    fn skips_object_streams_in_object_streams() {
+        let inner = object_stream(b"2 0 << /Type /Pages /Count 7 >>");
+        let pdf = [b"%PDF-1.5\n".as_slice(), &object_stream(&inner), b"%%EOF\n"].concat();
+        assert_eq!(page_count(&pdf), None);
+    }
+
+    #[test]
+    fn deeply_nested_dictionaries_are_read_in_one_pass() {
+        // Copying every dictionary as it closes takes minutes for this
+        let depth = 200_000;
+        let pdf = [
+            b"%PDF-1.4\n".as_slice(),
+            &b"<< /Type /Pages /Count 3 /A ".repeat(depth),
+            &b">> ".repeat(depth),
+        ]
+        .concat();
+        let start = std::time::Instant::now();
+        assert_eq!(page_count(&pdf), Some(3));
+        assert!(start.elapsed() < std::time::Duration::from_secs(10));
+    }
+
+    #[test]
+    fn unknown_without_page_tree() {
+        assert_eq!(page_count(b"not a pdf"), None);
+        assert_eq!(
+            page_count(b"%PDF-1.4\n1 0 obj << /Type /Catalog >> endobj"),
+            None
+        );
+        assert_eq!(page_count(b"%PDF-1.4\n2 0 obj << /Type /Pages /Cou"), None);
+
+        let encrypted = [b"%PDF-1.4\n", PAGE_TREE, b"trailer << /Encrypt 9 0 R >>"].concat();
+        assert_eq!(page_count(&encrypted), None);
+    }
+}
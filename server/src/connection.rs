@@ -0,0 +1,1208 @@
+//! Serving clients: reading requests off a connection and printing their files
+
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{self, Poll},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::Utc;
+use quinn::VarInt;
+use rustls::{Certificate, PrivateKey, RootCertStore};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader, ReadBuf},
+    net::TcpStream,
+    process::Command,
+    task::JoinSet,
+};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use uuid::Uuid;
+
+use crate::{
+    Backend, CupsArgs, JobCode, JobError, JobLimiter, JobRecord, JobStatus, Method, PrintOptions,
+    Request, SessionInfo, Settings, TempFile, ALPN_PROTOCOL, CLOSE_CANCELLED, CLOSE_DONE,
+    CLOSE_ERROR, CLOSE_VERSION_MISMATCH, LEGACY_ALPN, METRICS, PROTOCOL_VERSION, SNIFF_LEN,
+};
+
+/// Options shared by every connection
+pub struct Config {
+    /// Printer used when a request doesn't name one, system default if `None`
+    pub printer: Option<String>,
+    /// Where files are spooled while received
+    pub temp_dir: PathBuf,
+    pub backend: Backend,
+    /// Limits on jobs in flight
+    pub jobs: JobLimiter,
+    /// How long a TCP client can go without sending anything, QUIC has its own
+    pub idle_timeout: Duration,
+}
+
+/// Reader failing with [`io::ErrorKind::TimedOut`] when a read waits `timeout` for data.
+/// QUIC connections time out on their own, TCP ones would wait forever
+struct IdleTimeout<R> {
+    inner: R,
+    timeout: Duration,
+    deadline: Pin<Box<tokio::time::Sleep>>,
+    /// Whether a read is waiting, the deadline only runs while one is
+    waiting: bool,
+}
+
+impl<R> IdleTimeout<R> {
+    fn new(inner: R, timeout: Duration) -> Self {
+        IdleTimeout {
+            inner,
+            timeout,
+            deadline: Box::pin(tokio::time::sleep(timeout)),
+            waiting: false,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for IdleTimeout<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        // Time spent handling the request between reads doesn't count
+        if !this.waiting {
+            let deadline = tokio::time::Instant::now() + this.timeout;
+            this.deadline.as_mut().reset(deadline);
+        }
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                this.waiting = false;
+                Poll::Ready(result)
+            }
+            Poll::Pending => {
+                this.waiting = true;
+                match this.deadline.as_mut().poll(cx) {
+                    Poll::Ready(()) => Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("client sent nothing for {:?}", this.timeout),
+                    ))),
+                    Poll::Pending => Poll::Pending,
+                }
+            }
+        }
+    }
+}
+
+/// A job that printed, or passed a dry run
+struct PrintedJob {
+    /// CUPS or IPP job ID, if known
+    job_id: Option<String>,
+    /// Pages printed, copies included, if known
+    pages: Option<u32>,
+    /// Bytes of the body received by this request, as sent (compressed).
+    /// A resumed upload only counts the part sent after its offset
+    received: u64,
+    /// How long receiving the body took
+    transfer: Duration,
+}
+
+/// TLS config serving `cert`, requiring client certificates signed by `client_roots` if given.
+/// Shared by QUIC and TCP
+pub fn tls_config(
+    cert: Vec<Certificate>,
+    key: PrivateKey,
+    client_roots: Option<RootCertStore>,
+) -> Result<Arc<rustls::ServerConfig>> {
+    let server_crypto = rustls::ServerConfig::builder().with_safe_defaults();
+    let server_crypto = match client_roots {
+        Some(roots) => server_crypto.with_client_cert_verifier(
+            rustls::server::AllowAnyAuthenticatedClient::new(roots).boxed(),
+        ),
+        None => server_crypto.with_no_client_auth(),
+    };
+    let mut server_crypto = server_crypto.with_single_cert(cert, key)?;
+    server_crypto.alpn_protocols = vec![ALPN_PROTOCOL.to_vec(), LEGACY_ALPN.to_vec()];
+    Ok(Arc::new(server_crypto))
+}
+
+/// QUIC config using `tls`. Connections idle for `idle_timeout` are closed,
+/// `keep_alive_interval` pings keep them open
+pub fn server_config(
+    tls: Arc<rustls::ServerConfig>,
+    idle_timeout: Duration,
+    keep_alive_interval: Option<Duration>,
+) -> Result<quinn::ServerConfig> {
+    let mut server_config = quinn::ServerConfig::with_crypto(tls);
+    let transfer_config = Arc::get_mut(&mut server_config.transport).unwrap();
+    transfer_config.max_concurrent_uni_streams(8_u8.into());
+    transfer_config.max_idle_timeout(Some(
+        idle_timeout
+            .try_into()
+            .context("idle timeout is too long")?,
+    ));
+    transfer_config.keep_alive_interval(keep_alive_interval);
+    server_config.use_retry(true);
+
+    Ok(server_config)
+}
+
+/// Serves every request on `conn` until the client closes it
+pub async fn handle_connection(
+    config: Arc<Config>,
+    settings: Arc<Settings>,
+    conn: quinn::Connecting,
+) -> Result<()> {
+    // Clients sharing no ALPN protocol never get here, the handshake fails first
+    let connection = conn.await?;
+    METRICS.connection_opened();
+    let protocol = connection
+        .handshake_data()
+        .and_then(|data| data.downcast::<quinn::crypto::rustls::HandshakeData>().ok())
+        .and_then(|data| data.protocol);
+    let span = info_span!(
+        "connection",
+        remote = %connection.remote_address(),
+        protocol = %protocol
+            .as_deref()
+            .map_or_else(|| "<none>".into(), String::from_utf8_lossy)
+    );
+
+    async {
+        info!("established");
+
+        // Tell clients speaking something else why they're dropped
+        if let Some(reason) = unsupported_protocol(protocol.as_deref()) {
+            connection.close(VarInt::from_u32(CLOSE_VERSION_MISMATCH), reason.as_bytes());
+            bail!(reason);
+        }
+
+        // Requests are awaited before returning so shutdown can wait on them
+        let mut requests = JoinSet::new();
+
+        // Each stream initiated by the client constitutes a new request.
+        let result = loop {
+            let stream = connection.accept_bi().await;
+            let stream = match stream {
+                Err(quinn::ConnectionError::ApplicationClosed(close)) => {
+                    match describe_close(&close) {
+                        Some(reason) => info!("connection closed: {}", reason),
+                        None => warn!(
+                            "connection closed with unknown code {}: {}",
+                            close.error_code,
+                            String::from_utf8_lossy(&close.reason)
+                        ),
+                    }
+                    break Ok(());
+                }
+                Err(e) => {
+                    break Err(e);
+                }
+                Ok(s) => s,
+            };
+            let fut = handle_request(config.clone(), settings.clone(), connection.clone(), stream);
+            requests.spawn(
+                async move {
+                    if let Err(e) = fut.await {
+                        error!("failed: {reason}", reason = e.to_string());
+                    }
+                }
+                .instrument(info_span!("request")),
+            );
+        };
+
+        while requests.join_next().await.is_some() {}
+        result.map_err(anyhow::Error::from)
+    }
+    .instrument(span)
+    .await?;
+
+    Ok(())
+}
+
+/// Serves the one request a TCP connection carries, for clients that can't use QUIC.
+/// Clients not done with the TLS handshake after [`crate::TCP_HANDSHAKE_TIMEOUT`],
+/// or quiet for [`Config::idle_timeout`] after it, are dropped
+pub async fn handle_tcp_connection(
+    config: Arc<Config>,
+    settings: Arc<Settings>,
+    acceptor: TlsAcceptor,
+    stream: TcpStream,
+    remote: SocketAddr,
+) -> Result<()> {
+    let stream = tokio::time::timeout(crate::TCP_HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+        .await
+        .context("TLS handshake timed out")??;
+    METRICS.connection_opened();
+    let protocol = stream.get_ref().1.alpn_protocol().map(<[u8]>::to_vec);
+    let span = info_span!(
+        "tcp connection",
+        remote = %remote,
+        protocol = %protocol
+            .as_deref()
+            .map_or_else(|| "<none>".into(), String::from_utf8_lossy)
+    );
+
+    async {
+        info!("established");
+        if let Some(reason) = unsupported_protocol(protocol.as_deref()) {
+            bail!(reason);
+        }
+
+        let (recv, mut send) = tokio::io::split(stream);
+        let recv = IdleTimeout::new(recv, config.idle_timeout);
+        let resp = respond(&config, settings, remote, recv).await;
+        tokio::time::timeout(config.idle_timeout, send.write_all(&resp))
+            .await
+            .context("timed out sending response")?
+            .map_err(|e| anyhow!("failed to send response: {}", e))?;
+        send.shutdown()
+            .await
+            .map_err(|e| anyhow!("failed to shutdown stream: {}", e))?;
+        Ok(())
+    }
+    .instrument(span)
+    .await
+}
+
+/// Why a client that negotiated `protocol` can't be served, `None` if it can
+fn unsupported_protocol(protocol: Option<&[u8]>) -> Option<String> {
+    match protocol {
+        Some(ALPN_PROTOCOL) => None,
+        Some(LEGACY_ALPN) => {
+            debug!("client uses the legacy protocol");
+            None
+        }
+        other => Some(format!(
+            "unsupported protocol {:?}, this server speaks remote-print/{}",
+            other.map(String::from_utf8_lossy),
+            PROTOCOL_VERSION
+        )),
+    }
+}
+
+async fn handle_request(
+    config: Arc<Config>,
+    settings: Arc<Settings>,
+    connection: quinn::Connection,
+    (mut send, recv): (quinn::SendStream, quinn::RecvStream),
+) -> Result<()> {
+    let remote = connection.remote_address();
+    let resp = match process_request(&config, settings, remote, recv).await {
+        Ok(resp) => resp,
+        // There's no one to answer
+        Err(e) if abandoned(connection.close_reason().as_ref()) => {
+            info!("Abandoned by the client: {:#}", e);
+            return Ok(());
+        }
+        Err(e) => failure_response(e),
+    };
+
+    // Write result of handling and send finish
+    send.write_all(&resp)
+        .await
+        .map_err(|e| anyhow!("failed to send response: {}", e))?;
+    send.finish()
+        .await
+        .map_err(|e| anyhow!("failed to shutdown stream: {}", e))?;
+
+    Ok(())
+}
+
+/// Response to the request read from `recv`, errors included
+async fn respond<R: AsyncRead + Unpin>(
+    config: &Config,
+    settings: Arc<Settings>,
+    remote: SocketAddr,
+    recv: R,
+) -> Vec<u8> {
+    process_request(config, settings, remote, recv)
+        .await
+        .unwrap_or_else(failure_response)
+}
+
+/// Logs a failed request and makes the reply telling the client
+fn failure_response(e: anyhow::Error) -> Vec<u8> {
+    error!("Failed: {}", e);
+    METRICS.record_error(format!("{:#}", e));
+    crate::error_response(&e)
+}
+
+/// Whether the connection was closed by a client hanging up on purpose, cancelling or
+/// giving up after an error
+fn abandoned(reason: Option<&quinn::ConnectionError>) -> bool {
+    matches!(
+        reason,
+        Some(quinn::ConnectionError::ApplicationClosed(close))
+            if close.error_code == VarInt::from_u32(CLOSE_CANCELLED)
+                || close.error_code == VarInt::from_u32(CLOSE_ERROR)
+    )
+}
+
+/// What a client closing with `close` meant, `None` for codes it shouldn't send
+fn describe_close(close: &quinn::ApplicationClose) -> Option<String> {
+    let reason = String::from_utf8_lossy(&close.reason);
+    let code = u32::try_from(close.error_code.into_inner()).ok()?;
+    Some(match code {
+        CLOSE_DONE => "done".to_string(),
+        CLOSE_CANCELLED => format!("client cancelled ({})", reason),
+        CLOSE_ERROR => format!("client gave up after an error ({})", reason),
+        _ => return None,
+    })
+}
+
+/// `options` without the ones that don't apply to a document of type `extension`
+fn applicable_options(extension: &str, options: &PrintOptions) -> PrintOptions {
+    // Plain text has no page layout to rotate or scale
+    let mut options = options.clone();
+    if extension == "txt" && (options.landscape || options.fit_to_page) {
+        debug!("Ignoring orientation and scaling for plain text");
+        options.landscape = false;
+        options.fit_to_page = false;
+    }
+    if !crate::is_image(extension) && (options.scaling.is_some() || options.position.is_some()) {
+        debug!("Ignoring image scaling and position for {}", extension);
+        options.scaling = None;
+        options.position = None;
+    }
+    options
+}
+
+async fn process_request<R: AsyncRead + Unpin>(
+    config: &Config,
+    settings: Arc<Settings>,
+    remote: SocketAddr,
+    recv: R,
+) -> Result<Vec<u8>> {
+    let mut reader = BufReader::new(recv);
+    let block = crate::read_headers(&mut reader).await?;
+    let headers = Request::parse(&block)?;
+    debug!("Headers: {:?}", headers);
+    // ALPN already turned away clients of other versions, except over the legacy ALPN which
+    // names none. Clients that don't send a version predate the header and speak version 1
+    if let Some(version) = headers.protocol_version {
+        if version != PROTOCOL_VERSION {
+            bail!(
+                "Unsupported protocol version {}, this server speaks version {}",
+                version,
+                PROTOCOL_VERSION
+            );
+        }
+    }
+
+    match (&headers.method, headers.target.as_str()) {
+        (Method::Post, _) => {
+            let status = match print_job(config, &settings, reader, &headers).await {
+                Ok(job) if headers.dry_run => {
+                    JobStatus::validated().with_pages(job.pages, settings.page_cost)
+                }
+                Ok(job) => JobStatus::done(job.job_id).with_pages(job.pages, settings.page_cost),
+                Err(e) => {
+                    error!("Print job failed: {:#}", e);
+                    JobStatus::from_error(&e)
+                }
+            };
+            if let Some(hash) = &headers.content_hash {
+                let session = headers.session_id.unwrap_or_default();
+                crate::upload_finished(session, hash, &status).await;
+            }
+            Ok(serde_json::to_vec(&status)?)
+        }
+        (Method::Get, "status") => {
+            check_session(headers.session_id).await?;
+            Ok(serde_json::to_vec(&METRICS.status().await)?)
+        }
+        (Method::Get, "history") => {
+            check_session(headers.session_id).await?;
+            let history = crate::JOB_HISTORY.lock().await;
+            Ok(serde_json::to_vec(&*history)?)
+        }
+        (Method::Get, target) if target.starts_with("job/") => {
+            check_session(headers.session_id).await?;
+            let id = &target["job/".len()..];
+            let state = match &config.backend {
+                Backend::Ipp { uri } => crate::ipp::job_state(uri, id).await?,
+                _ => crate::job_state(id).await?,
+            };
+            debug!("Job {} is {:?}", id, state);
+            Ok(serde_json::to_vec(&state)?)
+        }
+        (Method::Get, target) if target.starts_with("upload/") => {
+            check_session(headers.session_id).await?;
+            // `upload/<content hash>`, how much of it a resumed upload can skip
+            let hash = &target["upload/".len()..];
+            if !crate::is_valid_content_hash(hash) {
+                bail!("Invalid content hash {:?}", hash);
+            }
+            let session = headers.session_id.unwrap_or_default();
+            let state = crate::upload_state(&config.temp_dir, session, hash).await;
+            debug!("Upload {} is {:?}", hash, state);
+            Ok(serde_json::to_vec(&state)?)
+        }
+        (Method::Get, target)
+            if target == "printer-options" || target.starts_with("printer-options/") =>
+        {
+            check_session(headers.session_id).await?;
+            // `printer-options/<name>`, or the default printer without a name
+            let printer = match target.strip_prefix("printer-options/") {
+                Some(name) => {
+                    if !crate::list_printers().await?.iter().any(|p| p == name) {
+                        bail!("Unknown printer {:?}", name);
+                    }
+                    Some(name)
+                }
+                None => None,
+            };
+            let options = crate::printer_options(printer).await;
+            Ok(serde_json::to_vec(&options)?)
+        }
+        (Method::Get, "printers") => {
+            check_session(headers.session_id).await?;
+            let printers = crate::list_printers().await?;
+            Ok(printers.join("\n").into_bytes())
+        }
+        (Method::Get, "sessions") => {
+            check_admin(&settings, headers.session_id).await?;
+            Ok(serde_json::to_vec(&crate::active_sessions().await)?)
+        }
+        (Method::Delete, target) if target.starts_with("session/") => {
+            let admin = check_admin(&settings, headers.session_id).await?;
+            let id = &target["session/".len()..];
+            let id = Uuid::parse_str(id).with_context(|| format!("Invalid session {:?}", id))?;
+            let Some(session) = crate::revoke_session(id).await else {
+                bail!("Unknown session {}", id);
+            };
+            info!(
+                "{:?} revoked session {} of user {:?}",
+                admin, id, session.user
+            );
+            Ok(serde_json::to_vec(&SessionInfo {
+                id,
+                user: session.user,
+                expiration: session.expiration,
+            })?)
+        }
+        (Method::Get, target) if target.contains("auth") => {
+            crate::init_session(&settings, headers.user.as_deref(), reader, remote).await
+        }
+        _ => bail!("Invalid Request"),
+    }
+}
+
+/// Authenticates and prints a `POST`
+async fn print_job<R: AsyncRead + Unpin>(
+    config: &Config,
+    settings: &Settings,
+    reader: BufReader<R>,
+    headers: &Request,
+) -> Result<PrintedJob> {
+    let user = check_session(headers.session_id).await?;
+    // Held until the job is done
+    let _permit = config
+        .jobs
+        .try_acquire(headers.session_id.unwrap_or_default())
+        .await?;
+    let filename = crate::sanitize_filename(headers.filename());
+    info!("Print job {:?} from user {:?}", filename, user);
+
+    let result = async {
+        // Requested printer must exist, otherwise use the server's.
+        // Only CUPS has printers to check against
+        let printer = match &headers.printer {
+            Some(name) if config.backend != Backend::Cups => Some(name.clone()),
+            Some(name) => {
+                let available = crate::list_printers().await?;
+                if !available.contains(name) {
+                    return Err(JobError::new(
+                        JobCode::UnknownPrinter,
+                        format!(
+                            "Unknown printer {:?}, available printers: {}",
+                            name,
+                            available.join(", ")
+                        ),
+                    )
+                    .into());
+                }
+                Some(name.clone())
+            }
+            None => config.printer.clone(),
+        };
+
+        // Unsupported media is rejected rather than silently ignored by CUPS
+        let mut options = headers.options.clone();
+        // IPP printers reject it themselves
+        if let Some(media) = &options.media {
+            if !matches!(config.backend, Backend::Ipp { .. }) {
+                options.media = Some(crate::check_media(printer.as_deref(), media).await?);
+            }
+        }
+
+        // Dropped rather than rejected where the printer wouldn't apply them
+        if config.backend == Backend::Cups && (options.landscape || options.fit_to_page) {
+            let supported = crate::printer_options(printer.as_deref()).await;
+            if options.landscape && !supported.landscape {
+                info!("Printer doesn't support landscape, ignoring it");
+                options.landscape = false;
+            }
+            if options.fit_to_page && !supported.fit_to_page {
+                info!("Printer doesn't support fit-to-page, ignoring it");
+                options.fit_to_page = false;
+            }
+        }
+
+        let job = print_file(config, &printer, settings, reader, headers, &options).await?;
+        let kib_per_sec = job.received as f64 / 1024.0 / job.transfer.as_secs_f64().max(0.001);
+        info!(
+            "{} {:?}: {} bytes in {:.2?} ({:.0} KiB/s), printer {}",
+            if headers.dry_run {
+                "Validated"
+            } else {
+                "Printed"
+            },
+            filename,
+            job.received,
+            job.transfer,
+            kib_per_sec,
+            printer.as_deref().unwrap_or("default")
+        );
+        Ok(job)
+    }
+    .await;
+
+    if let Err(e) = &result {
+        METRICS.job_failed();
+        METRICS.record_error(format!("{:#}", e));
+    }
+
+    // Every authenticated job is recorded, whether it printed or not
+    let code = match &result {
+        Ok(_) if headers.dry_run => JobCode::Validated,
+        Ok(_) => JobCode::Done,
+        Err(e) => JobStatus::from_error(e).code,
+    };
+    crate::record_job(JobRecord {
+        time: Utc::now(),
+        session: headers.session_id.unwrap_or_default(),
+        user,
+        filename,
+        extension: headers.extension.clone(),
+        size: headers.content_length,
+        success: result.is_ok(),
+        code,
+        job_id: result.as_ref().ok().and_then(|job| job.job_id.clone()),
+        pages: result.as_ref().ok().and_then(|job| job.pages),
+    })
+    .await;
+
+    result
+}
+
+/// Fails unless `id` belongs to an existing, unexpired session.
+/// Returns the user the session belongs to
+async fn check_session(id: Option<Uuid>) -> Result<String> {
+    let id = id.ok_or_else(|| JobError::new(JobCode::AuthRequired, "missing Session header"))?;
+    let lock = crate::SESSION_STORAGE.lock().await;
+
+    // Checks if session exists
+    if let Some(session) = lock.get(&id) {
+        if session.expiration < Utc::now() {
+            return Err(JobError::new(JobCode::SessionExpired, "Expired Session").into());
+        }
+        Ok(session.user.clone())
+    } else {
+        Err(JobError::new(JobCode::AuthRequired, "Authentication Required").into())
+    }
+}
+
+/// [`check_session`], also failing unless the session's user is one of [`Settings::admins`]
+async fn check_admin(settings: &Settings, id: Option<Uuid>) -> Result<String> {
+    let user = check_session(id).await?;
+    if !settings.admins.contains(&user) {
+        bail!("Admin access required");
+    }
+    Ok(user)
+}
+
+async fn print_file<R: AsyncRead + Unpin>(
+    config: &Config,
+    printer: &Option<String>,
+    settings: &Settings,
+    mut reader: BufReader<R>,
+    headers: &Request,
+    options: &PrintOptions,
+) -> Result<PrintedJob> {
+    let invalid = |e: anyhow::Error| JobError::new(JobCode::InvalidRequest, format!("{:#}", e));
+
+    // Files without an extension are identified by their content once received
+    let extension = match headers.extension.as_deref() {
+        Some(extension) if !extension.is_empty() => {
+            settings.check_extension(extension).map_err(invalid)?
+        }
+        _ => String::new(),
+    };
+    let length = headers
+        .content_length
+        .ok_or_else(|| JobError::new(JobCode::InvalidRequest, "missing Content-Length header"))?;
+    settings.check_file_size(length)?;
+    debug!("Extension: {:?}", extension);
+
+    // Create temp file, removed once this function returns
+    let temp = TempFile::new(&config.temp_dir, &extension);
+    let dir = temp.path();
+    let mut file = File::create(dir).await?;
+    debug!(file = %dir.display());
+
+    // Resumable uploads are kept as sent until complete, then decoded like any other
+    let started = Instant::now();
+    let upload = match &headers.content_hash {
+        Some(hash) => {
+            let session = headers.session_id.unwrap_or_default();
+            crate::forget_upload(session, hash).await;
+            let upload = crate::receive_upload(
+                &config.temp_dir,
+                session,
+                hash,
+                headers.offset,
+                length,
+                &mut reader,
+            )
+            .await?;
+            crate::upload_received(session, hash).await;
+            Some(upload)
+        }
+        None => None,
+    };
+
+    // Exactly the advertised length, a truncated upload must not print
+    let written = match &upload {
+        Some(upload) => {
+            let mut body = BufReader::new(File::open(upload.path()).await?);
+            crate::copy_body(
+                &mut body,
+                &mut file,
+                length,
+                headers.encoding,
+                settings.max_file_size,
+            )
+            .await?
+        }
+        None => {
+            crate::copy_body(
+                &mut reader,
+                &mut file,
+                length,
+                headers.encoding,
+                settings.max_file_size,
+            )
+            .await?
+        }
+    };
+    let transfer = started.elapsed();
+    let received = length.saturating_sub(headers.offset);
+    debug!("Successfully copied {} bytes to file", written);
+
+    let mut head = Vec::new();
+    File::open(dir)
+        .await?
+        .take(SNIFF_LEN as u64)
+        .read_to_end(&mut head)
+        .await?;
+    let extension = if extension.is_empty() {
+        let sniffed = crate::sniff_extension(&head).ok_or_else(|| {
+            JobError::new(
+                JobCode::InvalidRequest,
+                "file has no extension and its type wasn't recognized",
+            )
+        })?;
+        debug!("Sniffed extension: {}", sniffed);
+        settings.check_extension(sniffed).map_err(invalid)?
+    } else {
+        settings.check_content(&extension, &head).map_err(invalid)?;
+        extension
+    };
+
+    let options = applicable_options(&extension, options);
+
+    // Only PDFs are counted, a PDF that can't be read prints an unknown number of pages
+    let pages = if extension == "pdf" {
+        let pdf = tokio::fs::read(dir).await?;
+        tokio::task::spawn_blocking(move || crate::pdf::page_count(&pdf))
+            .await?
+            .map(|pages| crate::printed_pages(pages, &options))
+    } else {
+        None
+    };
+    debug!("Pages: {:?}", pages);
+
+    if headers.dry_run {
+        info!("Dry run, not printing {}", dir.display());
+        return Ok(PrintedJob {
+            job_id: None,
+            pages,
+            received,
+            transfer,
+        });
+    }
+
+    let job_id = submit(
+        config,
+        printer,
+        dir,
+        &extension,
+        headers.filename(),
+        &options,
+    )
+    .await?;
+
+    // Only accepted jobs are archived. A failed copy doesn't fail the job,
+    // e.g. when the archive's disk is full
+    if let Some(archive_dir) = &settings.archive_dir {
+        let session = headers.session_id.unwrap_or_default();
+        match crate::archive_document(archive_dir, session, headers.filename(), &extension, dir)
+            .await
+        {
+            Ok(archived) => debug!("Archived to {}", archived.display()),
+            Err(e) => {
+                error!("Failed to archive document: {:#}", e);
+                METRICS.record_error(format!("failed to archive document: {:#}", e));
+            }
+        }
+    }
+
+    Ok(PrintedJob {
+        job_id,
+        pages,
+        received,
+        transfer,
+    })
+}
+
+/// Hands the received file at `dir` to the backend, returning the job ID if it reports one
+async fn submit(
+    config: &Config,
+    printer: &Option<String>,
+    dir: &Path,
+    extension: &str,
+    filename: &str,
+    options: &PrintOptions,
+) -> Result<Option<String>> {
+    match &config.backend {
+        Backend::File { output_dir } => {
+            let output = output_dir.join(format!(
+                "{}-{}.{}",
+                Utc::now().format("%Y%m%d-%H%M%S"),
+                Uuid::new_v4().simple(),
+                extension
+            ));
+            // Copied rather than moved, the output dir may be on another filesystem
+            tokio::fs::copy(dir, &output).await.map_err(|e| {
+                JobError::new(
+                    JobCode::PrinterError,
+                    format!("failed to save to {}: {}", output.display(), e),
+                )
+            })?;
+            info!("Saved job to {}", output.display());
+            METRICS.job_printed();
+            return Ok(None);
+        }
+        Backend::Ipp { uri } => {
+            let job_name = crate::sanitize_filename(filename);
+            let job_id = crate::ipp::print(uri, dir, extension, &job_name, options).await?;
+            info!("Printed over IPP, job {}", job_id);
+            METRICS.job_printed();
+            return Ok(Some(job_id));
+        }
+        Backend::Cups => {}
+    }
+
+    // Print
+    debug!(printer = printer);
+    debug!("Options: {:?}", options);
+    // `lp` reports the job ID, `lpr` prints nothing. Only fall back to it if `lp` is missing
+    let mut lp = Command::new("lp");
+    lp.args(options.lp_args());
+    if let Some(printer) = printer {
+        lp.arg("-d").arg(printer);
+    }
+    let result = match lp.arg(dir).output().await {
+        Ok(output) => output,
+        Err(e) => {
+            debug!("Failed to run lp ({}), trying lpr", e);
+            let mut lpr = Command::new("lpr");
+            lpr.args(options.lpr_args());
+            if let Some(printer) = printer {
+                lpr.arg("-P").arg(printer);
+            }
+            lpr.arg(dir).output().await?
+        }
+    };
+
+    // If success, return done, else, return output.
+    if result.status.success() {
+        let job_id = crate::parse_job_id(&String::from_utf8_lossy(&result.stdout));
+        info!("Printed, job {:?}", job_id);
+        METRICS.job_printed();
+        Ok(job_id)
+    } else {
+        let err = String::from_utf8(result.stderr)?;
+        // If no printer was found, notify User
+        if err.contains("not exist") {
+            let printers = crate::list_printers().await?;
+            error!(
+                "Please specify a printer or set a default printer, Here are available printers: \n{:#?}",
+                printers
+            );
+            return Err(JobError::new(JobCode::UnknownPrinter, err.trim()).into());
+        }
+
+        let state = crate::printer_state(printer.as_deref()).await;
+        debug!("Printer state: {:?}", state);
+        Err(JobError::new(
+            JobCode::PrinterError,
+            crate::describe_print_error(err.trim(), state),
+        )
+        .into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Session, UploadState, SESSION_STORAGE};
+
+    /// Response of [`respond`] to `request` with a short body, as if from localhost
+    async fn request(config: &Config, settings: &Arc<Settings>, request: Request) -> Vec<u8> {
+        let mut bytes = request.serialize().into_bytes();
+        bytes.extend(b"hello");
+        let remote = SocketAddr::from(([127, 0, 0, 1], 0));
+        respond(config, settings.clone(), remote, bytes.as_slice()).await
+    }
+
+    /// Config saving jobs to the temp dir
+    fn test_config(settings: &Settings) -> Config {
+        Config {
+            printer: None,
+            temp_dir: std::env::temp_dir(),
+            backend: Backend::File {
+                output_dir: std::env::temp_dir(),
+            },
+            jobs: JobLimiter::new(settings),
+            idle_timeout: crate::DEFAULT_IDLE_TIMEOUT,
+        }
+    }
+
+    async fn login(user: &str) -> Uuid {
+        let id = Uuid::new_v4();
+        SESSION_STORAGE.lock().await.insert(id, Session::new(user));
+        id
+    }
+
+    #[tokio::test]
+    async fn revoked_sessions_cannot_print() {
+        let mut settings = Settings::default();
+        settings.admins.insert("admin".to_string());
+        let settings = Arc::new(settings);
+        let config = test_config(&settings);
+        let admin = login("admin").await;
+        let user = login("alice").await;
+
+        // Only admins can see sessions
+        let listing = Request {
+            session_id: Some(user),
+            ..Request::get("sessions")
+        };
+        let resp = request(&config, &settings, listing.clone()).await;
+        assert!(String::from_utf8_lossy(&resp).contains("Admin access required"));
+        let resp = request(
+            &config,
+            &settings,
+            Request {
+                session_id: Some(admin),
+                ..listing
+            },
+        )
+        .await;
+        let sessions: Vec<SessionInfo> = serde_json::from_slice(&resp).unwrap();
+        assert!(sessions.iter().any(|s| s.id == user && s.user == "alice"));
+
+        let revoke = Request {
+            session_id: Some(admin),
+            ..Request::delete(format!("session/{}", user))
+        };
+        let resp = request(&config, &settings, revoke).await;
+        let revoked: SessionInfo = serde_json::from_slice(&resp).unwrap();
+        assert_eq!(revoked.id, user);
+
+        let print = Request {
+            session_id: Some(user),
+            content_length: Some(5),
+            extension: Some("txt".to_string()),
+            ..Request::post("notes.txt")
+        };
+        let resp = request(&config, &settings, print).await;
+        let status: JobStatus = serde_json::from_slice(&resp).unwrap();
+        assert_eq!(status.code, JobCode::AuthRequired);
+        assert_eq!(status.message, "Authentication Required");
+    }
+
+    #[tokio::test]
+    async fn printer_options_path_must_match_exactly() {
+        let settings = Arc::new(Settings::default());
+        let config = test_config(&settings);
+        let options = Request {
+            session_id: Some(login("alice").await),
+            ..Request::get("printer-optionsX")
+        };
+        let resp = request(&config, &settings, options).await;
+        assert!(String::from_utf8_lossy(&resp).contains("Invalid Request"));
+    }
+
+    #[tokio::test]
+    async fn uploads_without_an_extension_are_sniffed() {
+        let settings = Arc::new(Settings::default());
+        let config = test_config(&settings);
+        // `request` sends "hello", plain text
+        let print = Request {
+            session_id: Some(login("alice").await),
+            content_length: Some(5),
+            dry_run: true,
+            ..Request::post("notes")
+        };
+        let resp = request(&config, &settings, print.clone()).await;
+        let status: JobStatus = serde_json::from_slice(&resp).unwrap();
+        assert_eq!(status.code, JobCode::Validated, "{}", status.message);
+
+        // Bytes of no known type are refused
+        let mut bytes = print.serialize().into_bytes();
+        bytes.extend(b"\x7fELF\0");
+        let remote = SocketAddr::from(([127, 0, 0, 1], 0));
+        let resp = respond(&config, settings.clone(), remote, bytes.as_slice()).await;
+        let status: JobStatus = serde_json::from_slice(&resp).unwrap();
+        assert_eq!(status.code, JobCode::InvalidRequest);
+        assert_eq!(
+            status.message,
+            "file has no extension and its type wasn't recognized"
+        );
+    }
+
+    #[tokio::test]
+    async fn finished_uploads_report_their_status() {
+        let settings = Arc::new(Settings::default());
+        let config = test_config(&settings);
+        let session = login("alice").await;
+        // `request` sends "hello"
+        let hash = crate::content_hash(b"hello");
+        let state = Request {
+            session_id: Some(session),
+            ..Request::get(format!("upload/{}", hash))
+        };
+        let resp = request(&config, &settings, state.clone()).await;
+        let state_of = |resp: &[u8]| serde_json::from_slice::<UploadState>(resp).unwrap();
+        assert_eq!(state_of(&resp), UploadState::Partial(0));
+
+        let print = Request {
+            session_id: Some(session),
+            content_length: Some(5),
+            extension: Some("txt".to_string()),
+            content_hash: Some(hash),
+            dry_run: true,
+            ..Request::post("notes.txt")
+        };
+        let resp = request(&config, &settings, print).await;
+        let status: JobStatus = serde_json::from_slice(&resp).unwrap();
+        assert_eq!(status.code, JobCode::Validated, "{}", status.message);
+
+        // A client that lost the response learns the job is over instead of sending it again
+        let resp = request(&config, &settings, state).await;
+        assert_eq!(state_of(&resp), UploadState::Finished(status));
+    }
+
+    #[tokio::test]
+    async fn resumed_uploads_count_only_the_resent_bytes() {
+        let settings = Settings::default();
+        let config = test_config(&settings);
+        let print = Request {
+            session_id: Some(login("alice").await),
+            content_length: Some(5),
+            extension: Some("txt".to_string()),
+            content_hash: Some(crate::content_hash(b"hello")),
+            dry_run: true,
+            ..Request::post("notes.txt")
+        };
+        let options = PrintOptions::default();
+        let stopped = print_file(
+            &config,
+            &None,
+            &settings,
+            BufReader::new(&b"he"[..]),
+            &print,
+            &options,
+        )
+        .await;
+        assert!(stopped.is_err());
+
+        let resumed = Request { offset: 2, ..print };
+        let job = print_file(
+            &config,
+            &None,
+            &settings,
+            BufReader::new(&b"llo"[..]),
+            &resumed,
+            &options,
+        )
+        .await
+        .unwrap();
+        assert_eq!(job.received, 3);
+    }
+
+    #[tokio::test]
+    async fn only_accepted_jobs_are_archived() {
+        let archive_dir = std::env::temp_dir().join(format!("archive-{}", Uuid::new_v4()));
+        let settings = Settings {
+            archive_dir: Some(archive_dir.clone()),
+            ..Settings::default()
+        };
+        let print = Request {
+            session_id: Some(login("alice").await),
+            content_length: Some(5),
+            extension: Some("txt".to_string()),
+            ..Request::post("notes.txt")
+        };
+        let options = PrintOptions::default();
+        let print_to = |config| {
+            print_file(
+                config,
+                &None,
+                &settings,
+                BufReader::new(&b"hello"[..]),
+                &print,
+                &options,
+            )
+        };
+
+        // The output dir doesn't exist, so the job fails
+        let failing = Config {
+            backend: Backend::File {
+                output_dir: archive_dir.join("missing"),
+            },
+            ..test_config(&settings)
+        };
+        assert!(print_to(&failing).await.is_err());
+        assert!(!archive_dir.exists());
+
+        let output_dir = std::env::temp_dir().join(format!("output-{}", Uuid::new_v4()));
+        std::fs::create_dir(&output_dir).unwrap();
+        let working = Config {
+            backend: Backend::File {
+                output_dir: output_dir.clone(),
+            },
+            ..test_config(&settings)
+        };
+        print_to(&working).await.unwrap();
+        let day = std::fs::read_dir(&archive_dir)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(std::fs::read_dir(day.path()).unwrap().count(), 1);
+
+        std::fs::remove_dir_all(&archive_dir).unwrap();
+        std::fs::remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[test]
+    fn image_options_only_apply_to_images() {
+        let options = PrintOptions {
+            landscape: true,
+            fit_to_page: true,
+            scaling: Some(150),
+            position: Some("top-left".to_string()),
+            ..PrintOptions::default()
+        };
+        for extension in ["png", "jpg"] {
+            assert_eq!(applicable_options(extension, &options), options);
+        }
+
+        let pdf = applicable_options("pdf", &options);
+        assert_eq!((pdf.scaling, pdf.position), (None, None));
+        assert!(pdf.landscape && pdf.fit_to_page);
+
+        let txt = applicable_options("txt", &options);
+        assert_eq!((txt.scaling, txt.position), (None, None));
+        assert!(!txt.landscape && !txt.fit_to_page);
+    }
+
+    fn close(code: u64, reason: &'static [u8]) -> quinn::ApplicationClose {
+        quinn::ApplicationClose {
+            error_code: VarInt::from_u64(code).unwrap(),
+            reason: reason.into(),
+        }
+    }
+
+    #[test]
+    fn describes_client_close_codes() {
+        let described = |code| describe_close(&close(code, b"disk full"));
+        assert_eq!(described(CLOSE_DONE.into()).unwrap(), "done");
+        assert_eq!(
+            described(CLOSE_CANCELLED.into()).unwrap(),
+            "client cancelled (disk full)"
+        );
+        assert_eq!(
+            described(CLOSE_ERROR.into()).unwrap(),
+            "client gave up after an error (disk full)"
+        );
+        assert_eq!(described(CLOSE_VERSION_MISMATCH.into()), None);
+        assert_eq!(described(u64::from(u32::MAX) + 1), None);
+    }
+
+    #[test]
+    fn only_cancelled_and_failed_clients_abandon_requests() {
+        let closed = |code: u32| {
+            abandoned(Some(&quinn::ConnectionError::ApplicationClosed(close(
+                code.into(),
+                b"",
+            ))))
+        };
+        assert!(closed(CLOSE_CANCELLED));
+        assert!(closed(CLOSE_ERROR));
+        assert!(!closed(CLOSE_DONE));
+        assert!(!closed(CLOSE_VERSION_MISMATCH));
+        assert!(!abandoned(Some(&quinn::ConnectionError::TimedOut)));
+        assert!(!abandoned(None));
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_protocol_versions() {
+        let settings = Arc::new(Settings::default());
+        let config = test_config(&settings);
+        let auth = Request {
+            user: Some("alice".to_string()),
+            protocol_version: Some(PROTOCOL_VERSION + 1),
+            ..Request::get("authenticate")
+        };
+        let resp = request(&config, &settings, auth).await;
+        assert!(String::from_utf8_lossy(&resp).contains(&format!(
+            "Unsupported protocol version {}",
+            PROTOCOL_VERSION + 1
+        )));
+    }
+
+    #[tokio::test]
+    async fn quiet_clients_time_out() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut reader = IdleTimeout::new(server, Duration::from_millis(100));
+
+        // Slow but steady is fine
+        for _ in 0..3 {
+            tokio::time::sleep(Duration::from_millis(60)).await;
+            client.write_all(b"x").await.unwrap();
+            reader.read_exact(&mut [0u8; 1]).await.unwrap();
+        }
+
+        let err = reader.read(&mut [0u8; 1]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,328 @@
+//! Printing straight to network printers over IPP, for machines without CUPS
+
+use std::{path::Path, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use ipp::prelude::*;
+
+use crate::{JobCode, JobError, JobState, PrintOptions};
+
+/// How long a printer gets to answer a single request
+const IPP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Checks `uri` is a printer the IPP backend can reach.
+/// Only plain `ipp://`, printers are expected on the local network
+pub fn parse_uri(uri: &str) -> Result<Uri> {
+    let parsed: Uri = uri
+        .parse()
+        .with_context(|| format!("invalid printer URI {:?}", uri))?;
+    match parsed.scheme_str() {
+        Some("ipp" | "http") => Ok(parsed),
+        Some("ipps" | "https") => bail!("ipps:// is not supported, use the printer's ipp:// URI"),
+        _ => bail!(
+            "invalid printer URI {:?}, expected e.g. ipp://printer.local/ipp/print",
+            uri
+        ),
+    }
+}
+
+/// MIME type sent as `document-format`, printers detect `application/octet-stream` themselves
+fn document_format(extension: &str) -> &'static str {
+    match extension {
+        "pdf" => "application/pdf",
+        "ps" => "application/postscript",
+        "txt" => "text/plain",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "tif" | "tiff" => "image/tiff",
+        _ => "application/octet-stream",
+    }
+}
+
+/// PWG name for the CUPS media names in [`crate::KNOWN_MEDIA`], anything else is sent as is
+fn media_keyword(media: &str) -> &str {
+    match media.to_ascii_lowercase().as_str() {
+        "letter" => "na_letter_8.5x11in",
+        "legal" => "na_legal_8.5x14in",
+        "executive" => "na_executive_7.25x10.5in",
+        "tabloid" => "na_ledger_11x17in",
+        "a3" => "iso_a3_297x420mm",
+        "a4" => "iso_a4_210x297mm",
+        "a5" => "iso_a5_148x210mm",
+        "b5" => "iso_b5_176x250mm",
+        "env10" => "na_number-10_4.125x9.5in",
+        "envdl" => "iso_dl_110x220mm",
+        _ => media,
+    }
+}
+
+/// Job template attributes for `options`, named like the CUPS options they replace
+fn job_attributes(options: &PrintOptions) -> Vec<IppAttribute> {
+    let mut attributes = vec![IppAttribute::new(
+        IppAttribute::COPIES,
+        IppValue::Integer(options.copies as i32),
+    )];
+    if let Some(sides) = &options.sides {
+        attributes.push(IppAttribute::new(
+            IppAttribute::SIDES,
+            IppValue::Keyword(sides.clone()),
+        ));
+    }
+    if let Some(media) = &options.media {
+        attributes.push(IppAttribute::new(
+            "media",
+            IppValue::Keyword(media_keyword(media).to_string()),
+        ));
+    }
+    if let Some(pages) = &options.page_ranges {
+        // Already checked by `is_valid_page_range`
+        let ranges = pages
+            .split(',')
+            .filter_map(|part| {
+                let (first, last) = part.split_once('-').unwrap_or((part, part));
+                Some(IppValue::RangeOfInteger {
+                    min: first.parse().ok()?,
+                    max: last.parse().ok()?,
+                })
+            })
+            .collect();
+        attributes.push(IppAttribute::new("page-ranges", IppValue::Array(ranges)));
+    }
+    if options.landscape {
+        // `orientation-requested` enum value for landscape
+        attributes.push(IppAttribute::new(
+            IppAttribute::ORIENTATION_REQUESTED,
+            IppValue::Enum(4),
+        ));
+    }
+    if options.fit_to_page {
+        attributes.push(IppAttribute::new(
+            "print-scaling",
+            IppValue::Keyword("fit".to_string()),
+        ));
+    }
+    // CUPS extensions, other printers ignore them
+    if let Some(scaling) = options.scaling {
+        attributes.push(IppAttribute::new(
+            "scaling",
+            IppValue::Integer(scaling.into()),
+        ));
+    }
+    if let Some(position) = &options.position {
+        attributes.push(IppAttribute::new(
+            "position",
+            IppValue::Keyword(position.clone()),
+        ));
+    }
+    attributes
+}
+
+/// Why the printer refused a request, from `status-message` if it sent one
+fn status_error(response: &IppRequestResponse) -> JobError {
+    let message = response
+        .attributes()
+        .groups_of(DelimiterTag::OperationAttributes)
+        .find_map(|group| group.attributes().get("status-message"))
+        .map(|attr| attr.value().to_string())
+        .unwrap_or_else(|| format!("{:?}", response.header().status_code()));
+    JobError::new(
+        JobCode::PrinterError,
+        format!("printer refused the job: {}", message),
+    )
+}
+
+/// Prints `path` on the printer at `uri`, returning the printer's job ID
+pub async fn print(
+    uri: &str,
+    path: &Path,
+    extension: &str,
+    job_name: &str,
+    options: &PrintOptions,
+) -> Result<String> {
+    let uri = parse_uri(uri)?;
+    // Files are small enough (`max_file_size`) to hand over in one piece
+    let document = tokio::fs::read(path).await?;
+
+    let operation = IppOperationBuilder::print_job(
+        uri.clone(),
+        IppPayload::new(std::io::Cursor::new(document)),
+    )
+    .job_title(job_name)
+    .document_format(document_format(extension))
+    .attributes(job_attributes(options))
+    .build();
+
+    let client = AsyncIppClient::builder(uri)
+        .request_timeout(IPP_TIMEOUT)
+        .build();
+    let response = client.send(operation).await.map_err(|e| {
+        JobError::new(
+            JobCode::PrinterError,
+            format!("failed to reach printer: {}", e),
+        )
+    })?;
+    if !response.header().status_code().is_success() {
+        return Err(status_error(&response).into());
+    }
+
+    let job_id = response
+        .attributes()
+        .groups_of(DelimiterTag::JobAttributes)
+        .find_map(|group| group.attributes().get(IppAttribute::JOB_ID))
+        .map(|attr| attr.value().to_string())
+        .context("printer accepted the job without a job-id")?;
+    Ok(job_id)
+}
+
+/// Asks the printer at `uri` what state job `id` is in
+pub async fn job_state(uri: &str, id: &str) -> Result<JobState> {
+    let uri = parse_uri(uri)?;
+    let Ok(id) = id.parse::<i32>() else {
+        return Ok(JobState::Unknown);
+    };
+
+    let operation = IppOperationBuilder::get_job_attributes(uri.clone(), id).build();
+    let client = AsyncIppClient::builder(uri)
+        .request_timeout(IPP_TIMEOUT)
+        .build();
+    let response = client.send(operation).await?;
+    if !response.header().status_code().is_success() {
+        return Ok(JobState::Unknown);
+    }
+
+    let state = response
+        .attributes()
+        .groups_of(DelimiterTag::JobAttributes)
+        .find_map(|group| group.attributes().get(IppAttribute::JOB_STATE))
+        .map(|attr| attr.value());
+    Ok(state.map_or(JobState::Unknown, job_state_of))
+}
+
+/// [`JobState`] of a `job-state` value, as defined in RFC 8011
+fn job_state_of(state: &IppValue) -> JobState {
+    match state {
+        // pending, pending-held
+        IppValue::Enum(3 | 4) => JobState::Queued,
+        // processing, processing-stopped: the job resumes once the printer does
+        IppValue::Enum(5 | 6) => JobState::Printing,
+        // canceled, aborted
+        IppValue::Enum(7 | 8) => JobState::Failed,
+        IppValue::Enum(9) => JobState::Completed,
+        _ => JobState::Unknown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Value of the attribute called `name`
+    fn value<'a>(attributes: &'a [IppAttribute], name: &str) -> Option<&'a IppValue> {
+        attributes
+            .iter()
+            .find(|attr| attr.name() == name)
+            .map(|attr| attr.value())
+    }
+
+    #[test]
+    fn default_options_only_set_copies() {
+        let attributes = job_attributes(&PrintOptions::default());
+        assert_eq!(attributes.len(), 1);
+        assert_eq!(
+            value(&attributes, IppAttribute::COPIES),
+            Some(&IppValue::Integer(1))
+        );
+    }
+
+    #[test]
+    fn maps_options_to_job_attributes() {
+        let options = PrintOptions {
+            copies: 3,
+            sides: Some("two-sided-long-edge".to_string()),
+            media: Some("A4".to_string()),
+            landscape: true,
+            fit_to_page: true,
+            ..PrintOptions::default()
+        };
+        let attributes = job_attributes(&options);
+        assert_eq!(
+            value(&attributes, IppAttribute::COPIES),
+            Some(&IppValue::Integer(3))
+        );
+        assert_eq!(
+            value(&attributes, IppAttribute::SIDES),
+            Some(&IppValue::Keyword("two-sided-long-edge".to_string()))
+        );
+        assert_eq!(
+            value(&attributes, "media"),
+            Some(&IppValue::Keyword("iso_a4_210x297mm".to_string()))
+        );
+        assert_eq!(
+            value(&attributes, IppAttribute::ORIENTATION_REQUESTED),
+            Some(&IppValue::Enum(4))
+        );
+        assert_eq!(
+            value(&attributes, "print-scaling"),
+            Some(&IppValue::Keyword("fit".to_string()))
+        );
+    }
+
+    #[test]
+    fn maps_image_options() {
+        let options = PrintOptions {
+            scaling: Some(150),
+            position: Some("top-left".to_string()),
+            ..PrintOptions::default()
+        };
+        let attributes = job_attributes(&options);
+        assert_eq!(value(&attributes, "scaling"), Some(&IppValue::Integer(150)));
+        assert_eq!(
+            value(&attributes, "position"),
+            Some(&IppValue::Keyword("top-left".to_string()))
+        );
+    }
+
+    #[test]
+    fn maps_media_keywords() {
+        assert_eq!(media_keyword("Letter"), "na_letter_8.5x11in");
+        assert_eq!(media_keyword("a4"), "iso_a4_210x297mm");
+        assert_eq!(media_keyword("EnvDL"), "iso_dl_110x220mm");
+        // Already PWG names, or unknown to us
+        assert_eq!(media_keyword("iso_a6_105x148mm"), "iso_a6_105x148mm");
+        assert_eq!(media_keyword("Custom.4x6in"), "Custom.4x6in");
+    }
+
+    #[test]
+    fn maps_page_ranges() {
+        let options = PrintOptions {
+            page_ranges: Some("1-3,5,8-10".to_string()),
+            ..PrintOptions::default()
+        };
+        assert_eq!(
+            value(&job_attributes(&options), "page-ranges"),
+            Some(&IppValue::Array(vec![
+                IppValue::RangeOfInteger { min: 1, max: 3 },
+                IppValue::RangeOfInteger { min: 5, max: 5 },
+                IppValue::RangeOfInteger { min: 8, max: 10 },
+            ]))
+        );
+    }
+
+    #[test]
+    fn maps_job_states() {
+        for (value, state) in [
+            (3, JobState::Queued),
+            (4, JobState::Queued),
+            (5, JobState::Printing),
+            (6, JobState::Printing),
+            (7, JobState::Failed),
+            (8, JobState::Failed),
+            (9, JobState::Completed),
+            (42, JobState::Unknown),
+        ] {
+            assert_eq!(job_state_of(&IppValue::Enum(value)), state, "{}", value);
+        }
+        assert_eq!(job_state_of(&IppValue::Integer(9)), JobState::Unknown);
+    }
+}
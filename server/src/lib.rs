@@ -1,29 +1,444 @@
-use anyhow::{bail, Context, Result};
-use quinn::RecvStream;
+use anyhow::{anyhow, bail, Context, Result};
+use async_compression::tokio::bufread::ZstdDecoder;
 use rustls::{self, Certificate, PrivateKey};
-use std::{collections::HashMap, path::PathBuf, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+};
 use uuid::Uuid;
 
 use chrono::prelude::*;
 use chrono::Duration;
 use lazy_static::lazy_static;
+use mdns_sd::{ServiceDaemon, ServiceInfo};
 use orion::{self, pwhash};
-use serde;
+use rand::distributions::{Alphanumeric, DistString};
 use tokio::{
     fs,
-    io::{self, AsyncReadExt, BufReader},
-    sync::Mutex,
+    io::{
+        self, AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite,
+        AsyncWriteExt,
+    },
+    process::Command,
+    sync::{Mutex, OwnedSemaphorePermit, Semaphore},
 };
 use tracing::{error, info};
 
+pub mod connection;
+pub mod ipp;
+
+pub use printer_protocol::{
+    content_hash, error_response, fingerprint, is_image, is_valid_content_hash, is_valid_media,
+    is_valid_page_range, pdf, sniff_extension, AuthResponse, ContentEncoding, JobCode, JobError,
+    JobState, JobStatus, Method, PrintOptions, PrinterOptions, Request, UploadState, ALPN_PROTOCOL,
+    CLOSE_CANCELLED, CLOSE_DONE, CLOSE_ERROR, CLOSE_VERSION_MISMATCH, LEGACY_ALPN, MAX_COPIES,
+    MAX_HEADER_SIZE, MAX_MEDIA_LEN, PROTOCOL_VERSION, SERVICE_TYPE, SIDES, SNIFF_LEN, URL_SCHEME,
+};
+
+/// Longest password accepted, logins sending more are rejected unread
+pub const MAX_PASSWORD_LEN: usize = 1024;
+
+/// Failed password attempts allowed from one address before it gets locked out
+pub const MAX_AUTH_FAILURES: u32 = 5;
+/// Lockout applied on reaching [`MAX_AUTH_FAILURES`], doubled for every failure after
+pub const AUTH_LOCKOUT_SECS: i64 = 30;
+/// Upper bound for a single lockout
+pub const MAX_AUTH_LOCKOUT_SECS: i64 = 60 * 60;
+/// Failures of an address that isn't locked out are forgotten after this long without another
+pub const AUTH_FAILURE_MEMORY_SECS: i64 = 60 * 60;
+
+/// Most print jobs kept in [`JOB_HISTORY`]
+pub const MAX_JOB_HISTORY: usize = 200;
+
+/// How long a TCP client gets to finish the TLS handshake
+pub const TCP_HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// How long shutdown waits for active connections to finish
+pub const SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// How often expired sessions are removed from [`SESSION_STORAGE`]
+pub const SESSION_PURGE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+
+/// How long `lpoptions -l` output is reused before asking CUPS again
+pub const PRINTER_OPTIONS_TTL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// How often `--cert`/`--key` are checked for changes
+pub const CERT_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// How long a connection can go without any traffic before it's dropped
+pub const DEFAULT_IDLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// How often idle connections are pinged, well within the UDP timeouts of common NATs
+pub const DEFAULT_KEEP_ALIVE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(15);
+
+/// Longest accepted `Extension` header value
+pub const MAX_EXTENSION_LEN: usize = 8;
+
+/// Filenames are cut to this many bytes before being logged or recorded, most file systems' limit
+pub const MAX_FILENAME_LEN: usize = 255;
+
+/// Largest file accepted when settings don't set one (50 MiB)
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 50 * 1024 * 1024;
+
+/// Jobs a single session can have in flight at once when settings don't say
+pub const DEFAULT_MAX_JOBS_PER_SESSION: usize = 4;
+
+/// File types accepted when settings don't list any
+pub const DEFAULT_EXTENSIONS: &[&str] = &[
+    "pdf", "txt", "ps", "png", "jpg", "jpeg", "gif", "tif", "tiff", "doc", "docx", "odt",
+];
+
+/// Media accepted when the printer doesn't report its own (`lpoptions -l`)
+pub const KNOWN_MEDIA: &[&str] = &[
+    "Letter",
+    "Legal",
+    "Executive",
+    "Tabloid",
+    "A3",
+    "A4",
+    "A5",
+    "B5",
+    "Env10",
+    "EnvDL",
+];
+
+/// User clients authenticate as when they don't send a `User` header
+pub const DEFAULT_USER: &str = "default";
+
+/// Shorter passwords are only accepted after confirming
+pub const MIN_PASSWORD_LENGTH: usize = 12;
+
+/// Lowest zxcvbn strength estimate accepted without confirming
+const MIN_PASSWORD_SCORE: zxcvbn::Score = zxcvbn::Score::Three;
+
+/// Fewest Argon2 passes orion accepts
+pub const MIN_HASH_ITERATIONS: u32 = 3;
+/// Least Argon2 memory orion accepts, in KiB
+pub const MIN_HASH_MEMORY_KIB: u32 = 8;
+
+/// Argon2 cost of new password hashes. Each hash stores its own cost,
+/// so changing this only affects passwords set afterwards
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HashCost {
+    /// Passes over the memory, at least [`MIN_HASH_ITERATIONS`]
+    pub iterations: u32,
+    /// Memory used per hash in KiB, at least [`MIN_HASH_MEMORY_KIB`]
+    pub memory_kib: u32,
+}
+
+impl Default for HashCost {
+    fn default() -> Self {
+        Self {
+            iterations: 3,
+            memory_kib: 1 << 16,
+        }
+    }
+}
+
+impl HashCost {
+    /// Fails for costs orion refuses
+    pub fn validate(&self) -> Result<()> {
+        if self.iterations < MIN_HASH_ITERATIONS {
+            bail!(
+                "hash_cost.iterations must be at least {}, got {}",
+                MIN_HASH_ITERATIONS,
+                self.iterations
+            );
+        }
+        if self.memory_kib < MIN_HASH_MEMORY_KIB {
+            bail!(
+                "hash_cost.memory_kib must be at least {}, got {}",
+                MIN_HASH_MEMORY_KIB,
+                self.memory_kib
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Where print jobs go
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Backend {
+    /// Print with `lpr`/`lp`
+    #[default]
+    Cups,
+    /// Save jobs to `output_dir` without printing, for testing without a printer
+    File { output_dir: PathBuf },
+    /// Send jobs straight to the printer at `uri` over IPP, without CUPS
+    Ipp { uri: String },
+}
+
 #[derive(serde::Deserialize, serde::Serialize, Debug)]
 pub struct Settings {
-    pub hash: pwhash::PasswordHash,
+    /// Password hashes by username
+    #[serde(default, deserialize_with = "deserialize_users")]
+    pub users: HashMap<String, UserHash>,
+    /// Single shared password of older settings files, moved to [`DEFAULT_USER`] on load
+    #[serde(
+        default,
+        skip_serializing,
+        deserialize_with = "deserialize_shared_hash"
+    )]
+    hash: Option<UserHash>,
+    /// Extensions clients are allowed to print, lowercase
+    #[serde(default = "default_extensions")]
+    pub allowed_extensions: Vec<String>,
+    /// Largest file accepted, in bytes
+    #[serde(default = "default_max_file_size")]
+    pub max_file_size: u64,
+    /// Reject files whose content doesn't match their extension, see [`Settings::check_content`]
+    #[serde(default = "default_check_file_type")]
+    pub check_file_type: bool,
+    /// Used when `--printer-backend` isn't given
+    #[serde(default)]
+    pub backend: Backend,
+    /// Cost of hashing new passwords
+    #[serde(default)]
+    pub hash_cost: HashCost,
+    /// Cost of a printed page, clients get an estimate for jobs with a known page count
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page_cost: Option<f64>,
+    /// Jobs a single session can have in flight at once, more are rejected
+    #[serde(default = "default_max_jobs_per_session")]
+    pub max_jobs_per_session: usize,
+    /// Jobs in flight at once across all sessions, unlimited if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_concurrent_jobs: Option<usize>,
+    /// Keep a copy of every printed document here, see [`archive_document`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archive_dir: Option<PathBuf>,
+    /// Users allowed to list and revoke sessions
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub admins: HashSet<String>,
+    /// Checked against for unknown users, see [`Settings::dummy_hash`]
+    #[serde(skip)]
+    dummy_hash: OnceLock<pwhash::PasswordHash>,
+    /// Set when the settings file couldn't be read, so it isn't overwritten
+    #[serde(skip)]
+    unsaved: bool,
+}
+
+fn default_max_file_size() -> u64 {
+    DEFAULT_MAX_FILE_SIZE
+}
+
+fn default_max_jobs_per_session() -> usize {
+    DEFAULT_MAX_JOBS_PER_SESSION
+}
+
+fn default_check_file_type() -> bool {
+    true
+}
+
+fn default_extensions() -> Vec<String> {
+    DEFAULT_EXTENSIONS.iter().map(|x| x.to_string()).collect()
+}
+
+/// Password hash of a user, saved as its encoding
+#[derive(serde::Serialize, Debug)]
+#[serde(untagged)]
+pub enum UserHash {
+    Valid(pwhash::PasswordHash),
+    /// Saved hash that can't be parsed, kept as is so saving the settings doesn't drop the user.
+    /// Logins as the user fail until they're added again with `--add-user`
+    Invalid(String),
+}
+
+impl From<pwhash::PasswordHash> for UserHash {
+    fn from(hash: pwhash::PasswordHash) -> Self {
+        UserHash::Valid(hash)
+    }
+}
+
+/// Password hash of `user` as saved, [`UserHash::Invalid`] (logged) if it's broken,
+/// so one bad entry only locks out that user instead of stopping the server
+fn parse_hash(user: &str, encoded: String) -> UserHash {
+    match pwhash::PasswordHash::from_encoded(&encoded) {
+        Ok(hash) => UserHash::Valid(hash),
+        Err(_) => {
+            error!(
+                "password hash of user {:?} is invalid, they can't log in until \
+                 added again with --add-user",
+                user
+            );
+            UserHash::Invalid(encoded)
+        }
+    }
+}
+
+fn deserialize_users<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<String, UserHash>, D::Error> {
+    let users: HashMap<String, String> = serde::Deserialize::deserialize(deserializer)?;
+    Ok(users
+        .into_iter()
+        .map(|(user, encoded)| {
+            let hash = parse_hash(&user, encoded);
+            (user, hash)
+        })
+        .collect())
+}
+
+fn deserialize_shared_hash<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<UserHash>, D::Error> {
+    let encoded: Option<String> = serde::Deserialize::deserialize(deserializer)?;
+    Ok(encoded.map(|encoded| parse_hash(DEFAULT_USER, encoded)))
+}
+
+/// Print command arguments for [`PrintOptions`]
+pub trait CupsArgs {
+    /// Arguments for `lpr`
+    fn lpr_args(&self) -> Vec<String>;
+    /// Arguments for `lp`, after its color and layout defaults so the options override them
+    fn lp_args(&self) -> Vec<String>;
+}
+
+impl CupsArgs for PrintOptions {
+    fn lpr_args(&self) -> Vec<String> {
+        let mut args = vec!["-#".to_string(), self.copies.to_string()];
+        args.extend(cups_options(self));
+        args
+    }
+
+    fn lp_args(&self) -> Vec<String> {
+        let mut args: Vec<String> = ["-o", "ColorModel=cym", "-o", "number-up=1", "-n"]
+            .map(String::from)
+            .into();
+        args.push(self.copies.to_string());
+        args.extend(cups_options(self));
+        args
+    }
+}
+
+/// `-o` options understood by both `lpr` and `lp`
+fn cups_options(options: &PrintOptions) -> Vec<String> {
+    let mut args = Vec::new();
+    if let Some(sides) = &options.sides {
+        args.extend(["-o".to_string(), format!("sides={}", sides)]);
+    }
+    if let Some(pages) = &options.page_ranges {
+        args.extend(["-o".to_string(), format!("page-ranges={}", pages)]);
+    }
+    if let Some(media) = &options.media {
+        args.extend(["-o".to_string(), format!("media={}", media)]);
+    }
+    if options.landscape {
+        args.extend(["-o".to_string(), "landscape".to_string()]);
+    }
+    if options.fit_to_page {
+        args.extend(["-o".to_string(), "fit-to-page".to_string()]);
+    }
+    if let Some(scaling) = options.scaling {
+        args.extend(["-o".to_string(), format!("scaling={}", scaling)]);
+    }
+    if let Some(position) = &options.position {
+        args.extend(["-o".to_string(), format!("position={}", position)]);
+    }
+    args
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
 pub struct Session {
-    pub expiratrion: DateTime<Utc>,
+    /// User the session was created for
+    pub user: String,
+    #[serde(alias = "expiratrion")]
+    pub expiration: DateTime<Utc>,
+}
+
+/// A print job kept in [`JOB_HISTORY`]
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct JobRecord {
+    pub time: DateTime<Utc>,
+    pub session: Uuid,
+    pub user: String,
+    pub filename: String,
+    pub extension: Option<String>,
+    /// Bytes received, compressed size if the body was compressed
+    pub size: Option<u64>,
+    pub success: bool,
+    pub code: JobCode,
+    pub job_id: Option<String>,
+    /// Pages printed, copies included, if known
+    pub pages: Option<u32>,
+}
+
+/// Limits how many jobs are in flight at once, per session and across the server
+pub struct JobLimiter {
+    per_session: usize,
+    total: Option<Arc<Semaphore>>,
+    sessions: Mutex<HashMap<Uuid, Arc<Semaphore>>>,
+}
+
+/// A job's slot in a [`JobLimiter`], freed when dropped
+pub struct JobPermit {
+    _session: OwnedSemaphorePermit,
+    _total: Option<OwnedSemaphorePermit>,
+}
+
+impl JobLimiter {
+    /// Limits from `max_jobs_per_session` and `max_concurrent_jobs` of `settings`
+    pub fn new(settings: &Settings) -> Self {
+        JobLimiter {
+            per_session: settings.max_jobs_per_session,
+            total: settings
+                .max_concurrent_jobs
+                .map(|total| Arc::new(Semaphore::new(total))),
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a slot for a job of `session`. Fails with [`JobCode::TooManyJobs`]
+    /// rather than waiting if the session or the server is at its limit
+    pub async fn try_acquire(&self, session: Uuid) -> Result<JobPermit, JobError> {
+        let session = {
+            let mut sessions = self.sessions.lock().await;
+            // Forget idle sessions so the map doesn't grow forever. The permit is taken before
+            // the lock is released, otherwise another call could forget the session in between
+            sessions.retain(|_, jobs| jobs.available_permits() < self.per_session);
+            sessions
+                .entry(session)
+                .or_insert_with(|| Arc::new(Semaphore::new(self.per_session)))
+                .clone()
+                .try_acquire_owned()
+        }
+        .map_err(|_| {
+            JobError::new(
+                JobCode::TooManyJobs,
+                format!(
+                    "too many concurrent jobs, at most {} per session",
+                    self.per_session
+                ),
+            )
+        })?;
+        let total = match &self.total {
+            Some(total) => Some(total.clone().try_acquire_owned().map_err(|_| {
+                JobError::new(
+                    JobCode::TooManyJobs,
+                    "too many concurrent jobs on the server, try again later",
+                )
+            })?),
+            None => None,
+        };
+        Ok(JobPermit {
+            _session: session,
+            _total: total,
+        })
+    }
+}
+
+/// Failed authentication attempts of a single address
+#[derive(Debug, Clone, Default)]
+pub struct AuthFailures {
+    pub count: u32,
+    pub locked_until: Option<DateTime<Utc>>,
+    pub last_failure: Option<DateTime<Utc>>,
 }
 
 lazy_static! {
@@ -31,28 +446,574 @@ lazy_static! {
     // Sessions should only last a few hours at maximum
     pub static ref SESSION_STORAGE: Arc<Mutex<HashMap<Uuid, Session>>> =
         Arc::new(Mutex::from(HashMap::new()));
+
+    // Cleared on successful authentication
+    pub static ref AUTH_FAILURES: Arc<Mutex<HashMap<IpAddr, AuthFailures>>> =
+        Arc::new(Mutex::from(HashMap::new()));
+
+    // Most recent jobs last, never longer than MAX_JOB_HISTORY
+    pub static ref JOB_HISTORY: Arc<Mutex<VecDeque<JobRecord>>> =
+        Arc::new(Mutex::from(VecDeque::new()));
+
+    // Resumable uploads received in full by session and content hash, with the status of their
+    // job once it's over. Most recent last, never longer than MAX_JOB_HISTORY
+    static ref RECEIVED_UPLOADS: Mutex<VecDeque<(Uuid, String, Option<JobStatus>)>> =
+        Mutex::from(VecDeque::new());
+
+    // Reported by `GET status`, started when first accessed
+    pub static ref METRICS: Metrics = Metrics::new();
+
+    // Options per printer (`None` for the default) and when they were read
+    static ref PRINTER_OPTIONS: Mutex<HashMap<Option<String>, (std::time::Instant, PrinterOptions)>> =
+        Mutex::from(HashMap::new());
+}
+
+/// Counters for `GET status`. Atomics so the print path never waits on them
+pub struct Metrics {
+    pub started: DateTime<Utc>,
+    connections: AtomicU64,
+    sessions_created: AtomicU64,
+    auth_failures: AtomicU64,
+    jobs_printed: AtomicU64,
+    jobs_failed: AtomicU64,
+    last_error: std::sync::Mutex<Option<LastError>>,
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct LastError {
+    pub time: DateTime<Utc>,
+    pub message: String,
+}
+
+/// Response to `GET status`, serialized as JSON
+#[derive(serde::Serialize, Debug)]
+pub struct ServerStatus {
+    pub version: &'static str,
+    pub started: DateTime<Utc>,
+    pub uptime_secs: i64,
+    pub connections: u64,
+    pub active_sessions: usize,
+    pub sessions_created: u64,
+    pub auth_failures: u64,
+    pub jobs_printed: u64,
+    pub jobs_failed: u64,
+    pub last_error: Option<LastError>,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Metrics {
+            started: Utc::now(),
+            connections: AtomicU64::new(0),
+            sessions_created: AtomicU64::new(0),
+            auth_failures: AtomicU64::new(0),
+            jobs_printed: AtomicU64::new(0),
+            jobs_failed: AtomicU64::new(0),
+            last_error: std::sync::Mutex::new(None),
+        }
+    }
+
+    pub fn connection_opened(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn session_created(&self) {
+        self.sessions_created.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn auth_failed(&self) {
+        self.auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn job_printed(&self) {
+        self.jobs_printed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn job_failed(&self) {
+        self.jobs_failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_error(&self, message: impl Into<String>) {
+        let error = LastError {
+            time: Utc::now(),
+            message: message.into(),
+        };
+        *self.last_error.lock().unwrap() = Some(error);
+    }
+
+    /// Snapshot of the counters, counting unexpired sessions
+    pub async fn status(&self) -> ServerStatus {
+        let now = Utc::now();
+        let active_sessions = SESSION_STORAGE
+            .lock()
+            .await
+            .values()
+            .filter(|session| session.expiration >= now)
+            .count();
+
+        ServerStatus {
+            version: env!("CARGO_PKG_VERSION"),
+            started: self.started,
+            uptime_secs: (now - self.started).num_seconds(),
+            connections: self.connections.load(Ordering::Relaxed),
+            active_sessions,
+            sessions_created: self.sessions_created.load(Ordering::Relaxed),
+            auth_failures: self.auth_failures.load(Ordering::Relaxed),
+            jobs_printed: self.jobs_printed.load(Ordering::Relaxed),
+            jobs_failed: self.jobs_failed.load(Ordering::Relaxed),
+            last_error: self.last_error.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// Adds `record` to [`JOB_HISTORY`], dropping the oldest jobs past [`MAX_JOB_HISTORY`]
+pub async fn record_job(record: JobRecord) {
+    push_capped(&mut *JOB_HISTORY.lock().await, record);
+}
+
+/// Adds `item` to the end of `list`, dropping the oldest items past [`MAX_JOB_HISTORY`]
+fn push_capped<T>(list: &mut VecDeque<T>, item: T) {
+    list.push_back(item);
+    while list.len() > MAX_JOB_HISTORY {
+        list.pop_front();
+    }
+}
+
+/// A session as listed by `GET sessions`
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SessionInfo {
+    pub id: Uuid,
+    pub user: String,
+    pub expiration: DateTime<Utc>,
+}
+
+/// Unexpired sessions, soonest to expire first
+pub async fn active_sessions() -> Vec<SessionInfo> {
+    let now = Utc::now();
+    let mut sessions: Vec<SessionInfo> = SESSION_STORAGE
+        .lock()
+        .await
+        .iter()
+        .filter(|(_, session)| session.expiration >= now)
+        .map(|(id, session)| SessionInfo {
+            id: *id,
+            user: session.user.clone(),
+            expiration: session.expiration,
+        })
+        .collect();
+    sessions.sort_by_key(|session| session.expiration);
+    sessions
+}
+
+/// Removes session `id`, returning it if it existed
+pub async fn revoke_session(id: Uuid) -> Option<Session> {
+    SESSION_STORAGE.lock().await.remove(&id)
 }
 
 impl Session {
-    pub fn new() -> Self {
+    pub fn new(user: &str) -> Self {
         Session {
-            expiratrion: Utc::now() + Duration::hours(4),
+            user: user.to_string(),
+            expiration: Utc::now() + Duration::hours(4),
+        }
+    }
+}
+
+/// Pages a job prints of a document with `document_pages` pages, given its page range
+/// and copies. Ranges past the end of the document print nothing, overlaps print once
+pub fn printed_pages(document_pages: u32, options: &PrintOptions) -> u32 {
+    let Some(ranges) = &options.page_ranges else {
+        return document_pages.saturating_mul(options.copies);
+    };
+
+    // Already checked by `is_valid_page_range`
+    let mut ranges: Vec<(u32, u32)> = ranges
+        .split(',')
+        .filter_map(|part| {
+            let (first, last) = part.split_once('-').unwrap_or((part, part));
+            Some((
+                first.parse().ok()?,
+                last.parse::<u32>().ok()?.min(document_pages),
+            ))
+        })
+        .filter(|(first, last)| first <= last)
+        .collect();
+    ranges.sort_unstable();
+
+    let mut selected = 0u64;
+    let mut next = 1u64;
+    for (first, last) in ranges {
+        let (first, last) = (u64::from(first).max(next), u64::from(last));
+        if first <= last {
+            selected += last - first + 1;
+            next = last + 1;
+        }
+    }
+    u32::try_from(selected * u64::from(options.copies)).unwrap_or(u32::MAX)
+}
+
+/// Start of the names of partial uploads in the temp dir, see [`partial_upload_path`]
+const PARTIAL_UPLOAD_PREFIX: &str = "remoteprint-partial-";
+
+/// Randomly named file that is deleted when dropped
+pub struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    /// Picks a random name in `dir`, without extension if `extension` is empty.
+    /// The file itself is not created
+    pub fn new(dir: &Path, extension: &str) -> Self {
+        let mut name = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
+        if !extension.is_empty() {
+            name = format!("{}.{}", name, extension);
+        }
+        TempFile {
+            path: dir.join(name),
+        }
+    }
+
+    /// Takes over the existing file at `path`
+    pub fn at(path: PathBuf) -> Self {
+        TempFile { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Where the body of a resumable upload is kept until it's complete.
+/// Keyed by session too, so nobody else can resume it
+pub fn partial_upload_path(dir: &Path, session: Uuid, hash: &str) -> PathBuf {
+    dir.join(format!(
+        "{}{}-{}",
+        PARTIAL_UPLOAD_PREFIX,
+        session.simple(),
+        hash
+    ))
+}
+
+/// Removes the partial uploads of `session` other than the one with `hash`, so a session
+/// keeps at most one partial upload in the temp dir
+async fn remove_other_partial_uploads(dir: &Path, session: Uuid, hash: &str) -> Result<()> {
+    let prefix = format!("{}{}-", PARTIAL_UPLOAD_PREFIX, session.simple());
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        let Some(other) = name.to_str().and_then(|n| n.strip_prefix(&prefix)) else {
+            continue;
+        };
+        if other != hash {
+            fs::remove_file(entry.path()).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Bytes of the upload with `hash` received so far, where a resumed upload starts
+pub async fn partial_upload_len(dir: &Path, session: Uuid, hash: &str) -> u64 {
+    fs::metadata(partial_upload_path(dir, session, hash))
+        .await
+        .map_or(0, |meta| meta.len())
+}
+
+/// How far the upload with `hash` got, for a client that lost its connection while sending it.
+/// Once received in full, sending it again would print it twice
+pub async fn upload_state(dir: &Path, session: Uuid, hash: &str) -> UploadState {
+    let received = RECEIVED_UPLOADS
+        .lock()
+        .await
+        .iter()
+        .find(|(s, h, _)| *s == session && h == hash)
+        .map(|(_, _, status)| status.clone());
+    match received {
+        Some(Some(status)) => UploadState::Finished(status),
+        Some(None) => UploadState::Received,
+        None => UploadState::Partial(partial_upload_len(dir, session, hash).await),
+    }
+}
+
+/// Marks the upload with `hash` as received in full, see [`upload_state`]
+pub async fn upload_received(session: Uuid, hash: &str) {
+    let mut received = RECEIVED_UPLOADS.lock().await;
+    received.retain(|(s, h, _)| !(*s == session && h == hash));
+    push_capped(&mut received, (session, hash.to_string(), None));
+}
+
+/// Keeps `status` as the outcome of the upload with `hash`, if it was received in full
+pub async fn upload_finished(session: Uuid, hash: &str, status: &JobStatus) {
+    let mut received = RECEIVED_UPLOADS.lock().await;
+    if let Some((_, _, outcome)) = received
+        .iter_mut()
+        .find(|(s, h, _)| *s == session && h == hash)
+    {
+        *outcome = Some(status.clone());
+    }
+}
+
+/// Forgets an earlier upload with `hash`, as sending the same file again prints it again
+pub async fn forget_upload(session: Uuid, hash: &str) {
+    RECEIVED_UPLOADS
+        .lock()
+        .await
+        .retain(|(s, h, _)| !(*s == session && h == hash));
+}
+
+/// Adds `body`, starting at `offset` of a body of `length` bytes, to the partial upload with
+/// `hash`. Returns the complete body once all of it arrived and matches `hash`.
+/// An interrupted upload is kept so the client can resume it
+pub async fn receive_upload<R: AsyncRead + Unpin>(
+    dir: &Path,
+    session: Uuid,
+    hash: &str,
+    offset: u64,
+    length: u64,
+    mut body: R,
+) -> Result<TempFile> {
+    let path = partial_upload_path(dir, session, hash);
+    if fs::metadata(&path).await.is_err() {
+        remove_other_partial_uploads(dir, session, hash).await?;
+    }
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .await?;
+    let have = file.metadata().await?.len();
+    if offset > have {
+        return Err(JobError::new(
+            JobCode::InvalidRequest,
+            format!(
+                "can't resume at byte {}, only {} were received",
+                offset, have
+            ),
+        )
+        .into());
+    }
+
+    // Anything past `offset` is sent again
+    file.set_len(offset).await?;
+    file.seek(io::SeekFrom::Start(offset)).await?;
+    let copied = io::copy(&mut (&mut body).take(length - offset), &mut file).await;
+    file.flush().await?;
+    let received = offset + copied?;
+    if received < length {
+        return Err(JobError::new(
+            JobCode::InvalidRequest,
+            format!("upload stopped after {} of {} bytes", received, length),
+        )
+        .into());
+    }
+
+    // Removed from here on, a complete upload is never resumed
+    let upload = TempFile::at(path);
+    if body.read(&mut [0u8; 1]).await? != 0 {
+        return Err(body_too_long(length).into());
+    }
+    let complete = upload.path().to_owned();
+    let received_hash = tokio::task::spawn_blocking(move || {
+        std::fs::File::open(complete).and_then(printer_protocol::content_hash_reader)
+    })
+    .await??;
+    if received_hash != hash {
+        return Err(JobError::new(
+            JobCode::InvalidRequest,
+            "body doesn't match its Content-Hash",
+        )
+        .into());
+    }
+    Ok(upload)
+}
+
+/// Copies a received document to `archive_dir/<date>/<time>-<session>-<filename>`,
+/// adding `extension` if the filename has none. Long filenames are cut so the name fits in
+/// [`MAX_FILENAME_LEN`] bytes, keeping `extension`. Returns where it was copied to
+pub async fn archive_document(
+    archive_dir: &Path,
+    session: Uuid,
+    filename: &str,
+    extension: &str,
+    document: &Path,
+) -> Result<PathBuf> {
+    let now = Local::now();
+    let dir = archive_dir.join(now.format("%Y-%m-%d").to_string());
+    fs::create_dir_all(&dir)
+        .await
+        .with_context(|| format!("failed to create {}", dir.display()))?;
+
+    let prefix = format!("{}-{}-", now.format("%H%M%S%.3f"), session.simple());
+    let filename = sanitize_filename(filename);
+    let suffix = match extension {
+        "" => String::new(),
+        extension => format!(".{}", extension),
+    };
+    let name = if Path::new(&filename).extension().is_some() {
+        format!("{}{}", prefix, filename)
+    } else {
+        format!("{}{}{}", prefix, filename, suffix)
+    };
+    let name = if name.len() > MAX_FILENAME_LEN {
+        let fits = MAX_FILENAME_LEN - prefix.len() - suffix.len();
+        format!("{}{}{}", prefix, truncate_bytes(&filename, fits), suffix)
+    } else {
+        name
+    };
+
+    let archived = dir.join(name);
+    if let Err(e) = fs::copy(document, &archived).await {
+        // Don't leave half a copy behind
+        let _ = fs::remove_file(&archived).await;
+        return Err(e).with_context(|| format!("failed to copy to {}", archived.display()));
+    }
+    Ok(archived)
+}
+
+/// Longest prefix of `s` at most `max` bytes long, not splitting a character
+fn truncate_bytes(s: &str, max: usize) -> &str {
+    let mut end = max.min(s.len());
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// Removes partial uploads of sessions that no longer exist, returning how many were removed
+pub async fn purge_partial_uploads(dir: &Path) -> usize {
+    let Ok(mut entries) = fs::read_dir(dir).await else {
+        return 0;
+    };
+    let sessions = SESSION_STORAGE
+        .lock()
+        .await
+        .keys()
+        .copied()
+        .collect::<HashSet<_>>();
+
+    let mut purged = 0;
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name = entry.file_name();
+        let Some(rest) = name
+            .to_str()
+            .and_then(|n| n.strip_prefix(PARTIAL_UPLOAD_PREFIX))
+        else {
+            continue;
+        };
+        let session = rest
+            .split_once('-')
+            .and_then(|(session, _)| Uuid::parse_str(session).ok());
+        if session.is_some_and(|session| sessions.contains(&session)) {
+            continue;
+        }
+        match fs::remove_file(entry.path()).await {
+            Ok(_) => purged += 1,
+            Err(e) => error!("failed to remove {}: {}", entry.path().display(), e),
+        }
+    }
+    purged
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        match std::fs::remove_file(&self.path) {
+            Ok(_) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => error!("failed to remove {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+impl AuthFailures {
+    /// Records a failed attempt, locking out the address once past the limit
+    pub fn record(&mut self) {
+        self.count += 1;
+        self.last_failure = Some(Utc::now());
+
+        if self.count >= MAX_AUTH_FAILURES {
+            let exponent = (self.count - MAX_AUTH_FAILURES).min(16);
+            let secs = (AUTH_LOCKOUT_SECS << exponent).min(MAX_AUTH_LOCKOUT_SECS);
+            self.locked_until = Some(Utc::now() + Duration::seconds(secs));
+        }
+    }
+
+    /// Takes back an attempt counted by [`AuthFailures::record`] that wasn't the client's fault
+    pub fn forgive(&mut self) {
+        self.count = self.count.saturating_sub(1);
+        if self.count < MAX_AUTH_FAILURES {
+            self.locked_until = None;
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked_until.is_some_and(|until| until > Utc::now())
+    }
+
+    /// Whether the address can be forgotten: not locked out, and no failure for a while
+    fn is_stale(&self) -> bool {
+        let forget_after = Duration::seconds(AUTH_FAILURE_MEMORY_SECS);
+        !self.is_locked()
+            && self
+                .last_failure
+                .is_none_or(|last| last + forget_after < Utc::now())
+    }
+}
+
+/// No users, so nobody can log in until one is added
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            users: HashMap::new(),
+            hash: None,
+            allowed_extensions: default_extensions(),
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            check_file_type: true,
+            backend: Backend::Cups,
+            hash_cost: HashCost::default(),
+            page_cost: None,
+            max_jobs_per_session: DEFAULT_MAX_JOBS_PER_SESSION,
+            max_concurrent_jobs: None,
+            archive_dir: None,
+            admins: HashSet::new(),
+            dummy_hash: OnceLock::new(),
+            unsaved: false,
         }
     }
 }
 
 impl Settings {
+    /// Loads the settings file, creating it with [`Settings::build`] on first run
     pub async fn get_settings() -> Result<Settings> {
-        let dirs = directories::ProjectDirs::from("com", "Coded Masonry", "Remote Print").unwrap();
+        Self::get_settings_or(Settings::build).await
+    }
 
-        let settings = match fs::read(dirs.data_local_dir().join("server_settings.json")).await {
+    /// Loads the settings file, creating it with `first_run` if there's none yet
+    pub async fn get_settings_or(first_run: impl FnOnce() -> Result<Settings>) -> Result<Settings> {
+        let settings = match fs::read(data_dir()?.join("server_settings.json")).await {
             Ok(file) => {
-                let settings: Settings = serde_json::from_slice(&file)?;
+                let mut settings: Settings = serde_json::from_slice(&file)?;
+                settings.hash_cost.validate()?;
+                if let Some(cost) = settings.page_cost {
+                    if !cost.is_finite() || cost < 0.0 {
+                        bail!("page_cost can't be negative, got {}", cost);
+                    }
+                }
+                if settings.max_jobs_per_session == 0 || settings.max_concurrent_jobs == Some(0) {
+                    bail!("max_jobs_per_session and max_concurrent_jobs must be at least 1");
+                }
+                if let Some(hash) = settings.hash.take() {
+                    info!("migrating shared password to user {:?}", DEFAULT_USER);
+                    settings
+                        .users
+                        .entry(DEFAULT_USER.to_string())
+                        .or_insert(hash);
+                    Settings::save_settings(&settings).await?;
+                }
                 settings
             }
             Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
                 info!("local Settings not found, returning default");
-                let settings = Settings::build()?;
+                let settings = first_run()?;
 
                 Settings::save_settings(&settings).await?;
                 settings
@@ -62,52 +1023,347 @@ impl Settings {
                     "failed to open settings: {}\nUsing default settings (Saving disabled)",
                     e
                 );
-                Settings::build()?
+                Settings {
+                    unsaved: true,
+                    ..first_run()?
+                }
             }
         };
 
         Ok(settings)
     }
+
+    /// Writes `settings` to the settings file, unless it couldn't be read
     pub async fn save_settings(settings: &Settings) -> Result<()> {
-        let dirs = directories::ProjectDirs::from("com", "Coded Masonry", "Remote Print").unwrap();
+        if settings.unsaved {
+            bail!("not saving settings, the settings file couldn't be read");
+        }
+        let dir = data_dir()?;
         let json = serde_json::to_string(&settings)?;
 
         // Make sure directories exist
-        fs::create_dir_all(dirs.data_local_dir()).await?;
+        fs::create_dir_all(&dir).await?;
         // Write the json
-        fs::write(dirs.data_local_dir().join("server_settings.json"), json).await?;
+        fs::write(dir.join("server_settings.json"), json).await?;
 
         Ok(())
     }
 
     pub fn build() -> Result<Self> {
         println!("A password is needed for clients to connect");
-        let pass = inquire::Password::new("Please enter a password:")
-            .with_display_toggle_enabled()
-            .with_display_mode(inquire::PasswordDisplayMode::Hidden)
-            .with_custom_confirmation_message("Confirm Password:")
-            .with_custom_confirmation_error_message("Passwords do not match")
-            .prompt()?;
+        let mut settings = Self::default();
+        let hash = prompt_password(DEFAULT_USER, settings.hash_cost)?;
+        settings.users.insert(DEFAULT_USER.to_string(), hash.into());
+        Ok(settings)
+    }
 
-        let password = pwhash::Password::from_slice(pass.as_bytes())?;
-        drop(pass); // Want the raw password in memory for as little time as possible
+    /// Prompts for a password and adds (or replaces) `user`
+    pub fn add_user(&mut self, user: &str) -> Result<()> {
+        println!("Setting password for user {:?}", user);
+        let hash = prompt_password(user, self.hash_cost)?;
+        self.users.insert(user.to_string(), hash.into());
+        Ok(())
+    }
 
-        let hash = pwhash::hash_password(&password, 3, 1 << 16)?;
+    /// Adds (or replaces) `user` with `password` without prompting, for tests and scripts
+    pub fn set_password(&mut self, user: &str, password: &str) -> Result<()> {
+        let hash = hash_password(password, self.hash_cost)?;
+        self.users.insert(user.to_string(), hash.into());
+        Ok(())
+    }
 
-        Ok(Self { hash })
+    /// Hash of a random password at [`Settings::hash_cost`], made on first use.
+    /// Logins as unknown users are checked against it, so they take as long as
+    /// a wrong password and don't reveal which users exist
+    fn dummy_hash(&self) -> Result<&pwhash::PasswordHash> {
+        if let Some(hash) = self.dummy_hash.get() {
+            return Ok(hash);
+        }
+        let password = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+        let hash = hash_password(&password, self.hash_cost)?;
+        Ok(self.dummy_hash.get_or_init(|| hash))
     }
-}
 
-// Parse cert and keys
-pub async fn parse_tls_cert(
-    key: Option<PathBuf>,
-    cert: Option<PathBuf>,
-) -> Result<(Vec<Certificate>, PrivateKey)> {
-    if let (Some(key_path), Some(cert_path)) = (key, cert) {
-        let key = fs::read(key_path.clone())
-            .await
-            .context("failed to read private key")?;
-        let key = if key_path.extension().map_or(false, |x| x == "der") {
+    /// Removes `user`, failing if it doesn't exist
+    pub fn remove_user(&mut self, user: &str) -> Result<()> {
+        if self.users.remove(user).is_none() {
+            bail!("no user named {:?}", user);
+        }
+        self.admins.remove(user);
+        Ok(())
+    }
+
+    /// Fails with [`too_large`] if a body of `length` bytes is over [`Settings::max_file_size`]
+    pub fn check_file_size(&self, length: u64) -> Result<(), JobError> {
+        if length > self.max_file_size {
+            return Err(too_large(self.max_file_size));
+        }
+        Ok(())
+    }
+
+    /// Sanitizes a client supplied extension and checks it against the allow-list.
+    /// Returns the lowercased extension safe to use in a file name
+    pub fn check_extension(&self, extension: &str) -> Result<String> {
+        if extension.is_empty() {
+            bail!("empty file extension");
+        }
+        if extension.len() > MAX_EXTENSION_LEN
+            || !extension.chars().all(|c| c.is_ascii_alphanumeric())
+        {
+            bail!("invalid file extension {:?}", extension);
+        }
+
+        let extension = extension.to_ascii_lowercase();
+        if !self
+            .allowed_extensions
+            .iter()
+            .any(|x| x.eq_ignore_ascii_case(&extension))
+        {
+            bail!(
+                "file type {:?} not allowed, allowed types: {}",
+                extension,
+                self.allowed_extensions.join(", ")
+            );
+        }
+
+        Ok(extension)
+    }
+
+    /// Checks the first [`SNIFF_LEN`] bytes of a file look like its (checked) `extension`,
+    /// so e.g. an executable can't be sent as a PDF. Plain text passes as any type,
+    /// types [`sniff_extension`] doesn't know pass unless they look like one it does
+    pub fn check_content(&self, extension: &str, head: &[u8]) -> Result<()> {
+        if !self.check_file_type {
+            return Ok(());
+        }
+
+        let declared = match extension {
+            "jpeg" => "jpg",
+            "tiff" => "tif",
+            other => other,
+        };
+        let sniffed = sniff_extension(head);
+        let matches = match sniffed {
+            Some(sniffed) => sniffed == declared || sniffed == "txt",
+            // Office documents don't always start with the entry they're told apart by
+            None if head.starts_with(b"PK\x03\x04") => {
+                !KNOWN_TYPES.contains(&declared) || matches!(declared, "docx" | "odt")
+            }
+            None => !KNOWN_TYPES.contains(&declared),
+        };
+        if !matches {
+            bail!(
+                "file content doesn't match its type {:?}{}",
+                extension,
+                sniffed.map_or(String::new(), |x| format!(", looks like {}", x))
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Types [`sniff_extension`] recognizes, the content of files declaring them is checked
+const KNOWN_TYPES: &[&str] = &[
+    "pdf", "ps", "png", "jpg", "gif", "tif", "doc", "docx", "odt", "txt",
+];
+
+/// Why `pass` is too weak for a server on the internet, `None` if it's fine
+fn password_weakness(pass: &str, user: &str) -> Option<String> {
+    if pass.chars().count() < MIN_PASSWORD_LENGTH {
+        return Some(format!("shorter than {} characters", MIN_PASSWORD_LENGTH));
+    }
+
+    let estimate = zxcvbn::zxcvbn(pass, &[user]);
+    if estimate.score() < MIN_PASSWORD_SCORE {
+        let reason = estimate
+            .feedback()
+            .and_then(|feedback| feedback.warning())
+            .map(|warning| warning.to_string())
+            .unwrap_or_else(|| "easy to guess".to_string());
+        return Some(reason);
+    }
+    None
+}
+
+/// Asks for a new password for `user` (with confirmation) and hashes it at `cost`.
+/// Weak passwords are asked again unless the user insists
+fn prompt_password(user: &str, cost: HashCost) -> Result<pwhash::PasswordHash> {
+    let pass = loop {
+        let pass = inquire::Password::new("Please enter a password:")
+            .with_display_toggle_enabled()
+            .with_display_mode(inquire::PasswordDisplayMode::Hidden)
+            .with_custom_confirmation_message("Confirm Password:")
+            .with_custom_confirmation_error_message("Passwords do not match")
+            .prompt()?;
+
+        // Couldn't be used to log in, and isn't worth scoring
+        if pass.len() > MAX_PASSWORD_LEN {
+            println!("Password is longer than {} bytes", MAX_PASSWORD_LEN);
+            continue;
+        }
+        let Some(reason) = password_weakness(&pass, user) else {
+            break pass;
+        };
+        println!("Weak password: {}", reason);
+        if inquire::Confirm::new("Use it anyway?")
+            .with_default(false)
+            .prompt()?
+        {
+            break pass;
+        }
+    };
+
+    hash_password(&pass, cost)
+}
+
+fn hash_password(pass: &str, cost: HashCost) -> Result<pwhash::PasswordHash> {
+    let password = to_password(pass.as_bytes())?;
+    // orion's errors don't say what's wrong, so check what it would refuse first
+    cost.validate()?;
+    pwhash::hash_password(&password, cost.iterations, cost.memory_kib).map_err(|_| {
+        anyhow!(
+            "failed to hash password with {} iterations and {} KiB of memory, \
+             try a lower hash_cost in the settings file",
+            cost.iterations,
+            cost.memory_kib
+        )
+    })
+}
+
+/// `pass` as an orion password, which can't be empty
+fn to_password(pass: &[u8]) -> Result<pwhash::Password> {
+    if pass.is_empty() {
+        bail!("password is empty");
+    }
+    if pass.len() > MAX_PASSWORD_LEN {
+        bail!("password is longer than {} bytes", MAX_PASSWORD_LEN);
+    }
+    pwhash::Password::from_slice(pass).map_err(|_| anyhow!("password is too long"))
+}
+
+/// Whether `password` matches `hash`. orion fails the same way on a mismatch and on a hash
+/// it can't check, so the cost stored in the hash is checked first and is an error if orion
+/// would refuse it, leaving a failure to verify as a wrong password
+fn verify_password(hash: &pwhash::PasswordHash, password: &pwhash::Password) -> Result<bool> {
+    stored_cost(hash)?
+        .validate()
+        .context("stored password hash can't be checked")?;
+    Ok(pwhash::hash_password_verify(hash, password).is_ok())
+}
+
+/// Cost `hash` was made with, from its encoding: `$argon2i$v=19$m=<memory>,t=<iterations>,p=1$...`
+fn stored_cost(hash: &pwhash::PasswordHash) -> Result<HashCost> {
+    let encoded = hash.unprotected_as_encoded();
+    let params = encoded
+        .split('$')
+        .nth(3)
+        .context("stored password hash has no parameters")?;
+    let mut cost = HashCost {
+        iterations: 0,
+        memory_kib: 0,
+    };
+    for param in params.split(',') {
+        match param.split_once('=') {
+            Some(("m", value)) => cost.memory_kib = value.parse()?,
+            Some(("t", value)) => cost.iterations = value.parse()?,
+            _ => (),
+        }
+    }
+    Ok(cost)
+}
+
+/// Environment variable pointing the client and server at another data directory
+pub const DATA_DIR_ENV: &str = "REMOTE_PRINT_DATA_DIR";
+
+/// Where settings and certificates are kept: [`DATA_DIR_ENV`] if set, the platform's
+/// local data directory otherwise. Fails without a home directory, e.g. in some containers.
+/// The client finds the same directory, it trusts the certificate the server keeps there
+pub fn data_dir() -> Result<PathBuf> {
+    data_dir_from(std::env::var_os(DATA_DIR_ENV))
+}
+
+/// [`data_dir`] with `var` as the value of [`DATA_DIR_ENV`]
+fn data_dir_from(var: Option<std::ffi::OsString>) -> Result<PathBuf> {
+    if let Some(dir) = var.filter(|dir| !dir.is_empty()) {
+        return Ok(PathBuf::from(dir));
+    }
+    let dirs = directories::ProjectDirs::from("com", "Coded Masonry", "Remote Print")
+        .with_context(|| {
+            format!(
+                "couldn't find a home directory to keep data in, set {} to a directory",
+                DATA_DIR_ENV
+            )
+        })?;
+    Ok(dirs.data_local_dir().to_path_buf())
+}
+
+/// Where the self-signed certificate and its key are kept
+fn self_signed_paths() -> Result<(PathBuf, PathBuf)> {
+    let path = data_dir()?;
+    Ok((path.join("cert.der"), path.join("key.der")))
+}
+
+/// Names the server is likely reached by: localhost, the host name, its mDNS `.local` name
+/// and the address of every network interface
+pub fn default_sans() -> Vec<String> {
+    let mut sans = vec!["localhost".to_string()];
+    match hostname::get() {
+        Ok(host) => {
+            let host = host.to_string_lossy();
+            sans.push(host.to_string());
+            sans.push(format!("{}.local", host));
+        }
+        Err(e) => error!("failed to get hostname: {}", e),
+    }
+    match if_addrs::get_if_addrs() {
+        Ok(interfaces) => sans.extend(interfaces.iter().map(|i| i.ip().to_string())),
+        Err(e) => error!("failed to list network interfaces: {}", e),
+    }
+
+    let mut seen = HashSet::new();
+    sans.retain(|san| seen.insert(san.clone()));
+    sans
+}
+
+/// Generates a self-signed certificate for `sans` without saving it,
+/// returns the DER certificate and key
+pub fn self_signed(sans: Vec<String>) -> Result<(Vec<u8>, Vec<u8>)> {
+    let cert =
+        rcgen::generate_simple_self_signed(sans).context("failed to generate certificate")?;
+    let key = cert.serialize_private_key_der();
+    Ok((cert.serialize_der()?, key))
+}
+
+/// Generates the self-signed certificate used without `--cert`, valid for `sans` (host names or IPs).
+/// Replaces the current one, returns the DER certificate and key
+pub async fn generate_self_signed(sans: Vec<String>) -> Result<(Vec<u8>, Vec<u8>)> {
+    let (cert_path, key_path) = self_signed_paths()?;
+    let (cert, key) = self_signed(sans)?;
+
+    if let Some(dir) = cert_path.parent() {
+        fs::create_dir_all(dir)
+            .await
+            .context("failed to create certificate directory")?;
+    }
+    fs::write(&cert_path, &cert)
+        .await
+        .context("failed to write certificate")?;
+    fs::write(&key_path, &key)
+        .await
+        .context("failed to write private key")?;
+    Ok((cert, key))
+}
+
+// Parse cert and keys
+pub async fn parse_tls_cert(
+    key: Option<PathBuf>,
+    cert: Option<PathBuf>,
+) -> Result<(Vec<Certificate>, PrivateKey)> {
+    if let (Some(key_path), Some(cert_path)) = (key, cert) {
+        let key = fs::read(key_path.clone())
+            .await
+            .context("failed to read private key")?;
+        let key = if key_path.extension().is_some_and(|x| x == "der") {
             rustls::PrivateKey(key)
         } else {
             let pkcs8 = rustls_pemfile::pkcs8_private_keys(&mut &*key)
@@ -129,7 +1385,7 @@ pub async fn parse_tls_cert(
         let cert_chain = fs::read(cert_path.clone())
             .await
             .context("failed to read certificate chain")?;
-        let cert_chain = if cert_path.extension().map_or(false, |x| x == "der") {
+        let cert_chain = if cert_path.extension().is_some_and(|x| x == "der") {
             vec![rustls::Certificate(cert_chain)]
         } else {
             rustls_pemfile::certs(&mut &*cert_chain)
@@ -138,13 +1394,13 @@ pub async fn parse_tls_cert(
                 .map(rustls::Certificate)
                 .collect()
         };
+        if cert_chain.is_empty() {
+            bail!("no certificates found in {}", cert_path.display());
+        }
 
         Ok((cert_chain, key))
     } else {
-        let dirs = directories::ProjectDirs::from("com", "Coded Masonry", "Remote Print").unwrap();
-        let path = dirs.data_local_dir();
-        let cert_path = path.join("cert.der");
-        let key_path = path.join("key.der");
+        let (cert_path, key_path) = self_signed_paths()?;
         let (cert, key) = match fs::read(&cert_path)
             .await
             .and_then(|x| Ok((x, std::fs::read(&key_path)?)))
@@ -152,19 +1408,7 @@ pub async fn parse_tls_cert(
             Ok(x) => x,
             Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
                 info!("generating self-signed certificate");
-                let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
-                let key = cert.serialize_private_key_der();
-                let cert = cert.serialize_der().unwrap();
-                fs::create_dir_all(path)
-                    .await
-                    .context("failed to create certificate directory")?;
-                fs::write(&cert_path, &cert)
-                    .await
-                    .context("failed to write certificate")?;
-                fs::write(&key_path, &key)
-                    .await
-                    .context("failed to write private key")?;
-                (cert, key)
+                generate_self_signed(default_sans()).await?
             }
             Err(e) => {
                 bail!("failed to read certificate: {}", e);
@@ -177,39 +1421,1700 @@ pub async fn parse_tls_cert(
     }
 }
 
+/// Forgets failed logins of addresses that stopped trying, returns how many were dropped
+pub async fn purge_auth_failures() -> usize {
+    let mut lock = AUTH_FAILURES.lock().await;
+    let before = lock.len();
+    lock.retain(|_, failures| !failures.is_stale());
+    before - lock.len()
+}
+
+/// Loads the CA(s) client certificates must be signed by, DER or PEM
+pub async fn parse_client_ca(path: PathBuf) -> Result<rustls::RootCertStore> {
+    let ca = fs::read(&path)
+        .await
+        .context("failed to read client CA certificate")?;
+    let certs = if path.extension().is_some_and(|x| x == "der") {
+        vec![ca]
+    } else {
+        rustls_pemfile::certs(&mut &*ca).context("invalid PEM-encoded client CA certificate")?
+    };
+
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in certs {
+        roots.add(&rustls::Certificate(cert))?;
+    }
+    if roots.is_empty() {
+        bail!("no certificates found in {}", path.display());
+    }
+
+    Ok(roots)
+}
+
+/// Makes a client supplied filename safe to log and record: keeps the last path
+/// component, drops control characters and cuts it to [`MAX_FILENAME_LEN`] bytes.
+/// Received files get random names, only [`archive_document`] puts it in one
+pub fn sanitize_filename(name: &str) -> String {
+    let name: String = name
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect();
+
+    match truncate_bytes(&name, MAX_FILENAME_LEN).trim() {
+        "" | "." | ".." => "unnamed".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// Whether `program` is an executable in one of the `PATH` directories
+pub fn in_path(program: &str) -> bool {
+    let name = format!("{}{}", program, std::env::consts::EXE_SUFFIX);
+    std::env::var_os("PATH")
+        .is_some_and(|path| std::env::split_paths(&path).any(|dir| dir.join(&name).is_file()))
+}
+
+/// Lists the printers known to CUPS (`lpstat -p`)
+pub async fn list_printers() -> Result<Vec<String>> {
+    let output = Command::new("lpstat")
+        .arg("-p")
+        .output()
+        .await
+        .context("failed to run lpstat")?;
+    let output = String::from_utf8(output.stdout)?;
+
+    // Lines look like `printer <name> is idle.  enabled since ...`
+    let printers = output
+        .lines()
+        .filter_map(|l| l.strip_prefix("printer "))
+        .filter_map(|l| l.split_whitespace().next())
+        .map(String::from)
+        .collect();
+
+    Ok(printers)
+}
+
+/// Options of `printer` (the default printer if `None`), read at most once per
+/// [`PRINTER_OPTIONS_TTL`]
+pub async fn printer_options(printer: Option<&str>) -> PrinterOptions {
+    let key = printer.map(String::from);
+    if let Some((read, options)) = PRINTER_OPTIONS.lock().await.get(&key) {
+        if read.elapsed() < PRINTER_OPTIONS_TTL {
+            return options.clone();
+        }
+    }
+
+    // Failures aren't kept, CUPS may be back by the next request
+    let Some(mut options) = read_printer_options(printer).await else {
+        return PrinterOptions {
+            media: known_media(),
+            ..Default::default()
+        };
+    };
+    if options.media.is_empty() {
+        options.media = known_media();
+    }
+    PRINTER_OPTIONS
+        .lock()
+        .await
+        .insert(key, (std::time::Instant::now(), options.clone()));
+    options
+}
+
+fn known_media() -> Vec<String> {
+    KNOWN_MEDIA.iter().map(|m| m.to_string()).collect()
+}
+
+/// Reads the options of `printer` with `lpoptions -l`, `None` if it can't be run or fails
+async fn read_printer_options(printer: Option<&str>) -> Option<PrinterOptions> {
+    let mut command = Command::new("lpoptions");
+    if let Some(printer) = printer {
+        command.arg("-p").arg(printer);
+    }
+    match command.arg("-l").output().await {
+        Ok(output) if output.status.success() => Some(parse_printer_options(
+            &String::from_utf8_lossy(&output.stdout),
+        )),
+        Ok(output) => {
+            error!(
+                "lpoptions failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            None
+        }
+        Err(e) => {
+            error!("failed to run lpoptions: {}", e);
+            None
+        }
+    }
+}
+
+/// Parses `lpoptions -l` output, where media sizes are listed as
+/// `PageSize/Media Size: Letter *A4 Legal` with the default starred
+fn parse_printer_options(output: &str) -> PrinterOptions {
+    let mut options = PrinterOptions::default();
+    for line in output.lines() {
+        let Some((key, values)) = line.split_once(':') else {
+            continue;
+        };
+        let name = key.split('/').next().unwrap_or_default().trim();
+        // Orientation and scaling are done by the CUPS filters, which queues with a driver run
+        // and raw queues (no options listed) skip. A driver always lists a page size,
+        // some queues list the options themselves
+        match name.to_ascii_lowercase().as_str() {
+            "pagesize" | "media" => {
+                options.landscape = true;
+                options.fit_to_page = true;
+            }
+            "orientation-requested" | "landscape" => {
+                options.landscape = true;
+                continue;
+            }
+            "fit-to-page" | "print-scaling" => {
+                options.fit_to_page = true;
+                continue;
+            }
+            _ => continue,
+        }
+
+        for value in values.split_whitespace() {
+            let media = value.trim_start_matches('*');
+            if value.starts_with('*') {
+                options.default_media = Some(media.to_string());
+            }
+            if !options.media.iter().any(|m| m == media) {
+                options.media.push(media.to_string());
+            }
+        }
+    }
+    options
+}
+
+/// Checks `media` is supported by `printer`, returning the printer's spelling of it
+pub async fn check_media(printer: Option<&str>, media: &str) -> Result<String> {
+    let supported = printer_options(printer).await.media;
+    match supported.iter().find(|m| m.eq_ignore_ascii_case(media)) {
+        Some(media) => Ok(media.clone()),
+        None => Err(JobError::new(
+            JobCode::InvalidRequest,
+            format!(
+                "Unsupported media {:?}, supported media: {}",
+                media,
+                supported.join(", ")
+            ),
+        )
+        .into()),
+    }
+}
+
+/// Whether a CUPS printer is ready, from `lpstat -p`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrinterState {
+    Idle,
+    Processing,
+    /// Stopped by `cupsdisable`, or by CUPS after an error. Jobs queue but don't print
+    Disabled,
+}
+
+impl std::fmt::Display for PrinterState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PrinterState::Idle => "idle",
+            PrinterState::Processing => "printing",
+            PrinterState::Disabled => "disabled",
+        })
+    }
+}
+
+/// Parses `lpstat -p` output for a single printer, e.g.
+/// `printer office is idle.  enabled since ...` or `printer office disabled since ...`
+pub fn parse_printer_state(output: &str) -> Option<PrinterState> {
+    let line = output.lines().find(|l| l.starts_with("printer "))?;
+    let (_, rest) = line["printer ".len()..].split_once(' ')?;
+    if rest.starts_with("disabled") {
+        Some(PrinterState::Disabled)
+    } else if rest.starts_with("is idle") {
+        Some(PrinterState::Idle)
+    } else if rest.starts_with("now printing") {
+        Some(PrinterState::Processing)
+    } else {
+        None
+    }
+}
+
+/// State of `printer`, or of the default printer. `None` if lpstat doesn't know it
+pub async fn printer_state(printer: Option<&str>) -> Option<PrinterState> {
+    let printer = match printer {
+        Some(printer) => printer.to_string(),
+        None => {
+            let output = Command::new("lpstat").arg("-d").output().await.ok()?;
+            // `system default destination: <name>`
+            let output = String::from_utf8_lossy(&output.stdout);
+            output.split_once(": ")?.1.trim().to_string()
+        }
+    };
+    let output = Command::new("lpstat")
+        .arg("-p")
+        .arg(&printer)
+        .output()
+        .await
+        .ok()?;
+    parse_printer_state(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Message for a failed `lp`/`lpr`: what went wrong and what to do about it for errors CUPS
+/// commonly reports, `stderr` as is otherwise. Mentions `state` if known and not idle
+pub fn describe_print_error(stderr: &str, state: Option<PrinterState>) -> String {
+    let lower = stderr.to_lowercase();
+    let has = |words: &[&str]| words.iter().any(|word| lower.contains(word));
+
+    let hint = if has(&["not accepting jobs"]) {
+        Some("Printer isn't accepting jobs; run `cupsaccept <printer>` on the server")
+    } else if state == Some(PrinterState::Disabled) || has(&["disabled", "paused"]) {
+        Some("Printer is disabled; run `cupsenable <printer>` on the server")
+    } else if has(&["media-empty", "out of paper", "paper out", "load paper"]) {
+        Some("Printer is out of paper; load paper and try again")
+    } else if has(&["media-jam", "paper jam", "jammed"]) {
+        Some("Printer has a paper jam; clear it and try again")
+    } else if has(&[
+        "marker-supply-empty",
+        "toner empty",
+        "out of toner",
+        "out of ink",
+    ]) {
+        Some("Printer is out of toner or ink")
+    } else if has(&[
+        "offline",
+        "unable to connect",
+        "not connected",
+        "not responding",
+    ]) {
+        Some("Printer is offline; check it's turned on and connected")
+    } else {
+        None
+    };
+
+    let message = match hint {
+        Some(hint) => format!("{} ({})", hint, stderr),
+        None => stderr.to_string(),
+    };
+    match state {
+        Some(state) if state != PrinterState::Idle && state != PrinterState::Disabled => {
+            format!("{}, printer is {}", message, state)
+        }
+        _ => message,
+    }
+}
+
+/// Finds the job ID in `lp` output, `request id is <id> (1 file(s))`.
+/// CUPS' `lpr` prints nothing, so this is `None` for jobs it sent
+pub fn parse_job_id(output: &str) -> Option<String> {
+    let (_, rest) = output.split_once("request id is ")?;
+    rest.split_whitespace().next().map(String::from)
+}
+
+/// Looks up the state of job `id` with `lpstat`
+pub async fn job_state(id: &str) -> Result<JobState> {
+    for which in ["not-completed", "completed"] {
+        let output = Command::new("lpstat")
+            .args(["-l", "-W", which, "-o"])
+            .output()
+            .await
+            .context("failed to run lpstat")?;
+        let output = String::from_utf8(output.stdout)?;
+
+        let Some(alerts) = find_job(&output, id) else {
+            continue;
+        };
+        return Ok(lpstat_job_state(which == "completed", &alerts));
+    }
+
+    Ok(JobState::Unknown)
+}
+
+/// State of a job listed by `lpstat -W completed` if `completed`, `-W not-completed` otherwise,
+/// with the `Alerts:` found by [`find_job`]. A stopped job isn't failed, it resumes with the printer
+fn lpstat_job_state(completed: bool, alerts: &str) -> JobState {
+    let failed = ["canceled", "aborted", "completed-with-errors"]
+        .iter()
+        .any(|reason| alerts.contains(reason));
+    match completed {
+        _ if failed => JobState::Failed,
+        true => JobState::Completed,
+        false if alerts.contains("job-printing") || alerts.contains("job-stopped") => {
+            JobState::Printing
+        }
+        false => JobState::Queued,
+    }
+}
+
+/// Finds job `id` in `lpstat -l -o` output, returning its `Alerts:` (job state reasons).
+/// Jobs start with an unindented `<id>  <user>  <size>  <date>` line, details follow indented
+fn find_job(listing: &str, id: &str) -> Option<String> {
+    let mut lines = listing
+        .lines()
+        .skip_while(|l| l.split_whitespace().next() != Some(id));
+    lines.next()?;
+
+    let alerts = lines
+        .take_while(|l| l.starts_with(char::is_whitespace))
+        .filter_map(|l| l.trim().strip_prefix("Alerts:"))
+        .map(str::trim)
+        .collect::<Vec<_>>()
+        .join(" ");
+    Some(alerts)
+}
+
+/// Advertises the server on the local network over mDNS.
+/// The advertisement lasts as long as the returned daemon is kept alive
+pub fn advertise(port: u16) -> Result<ServiceDaemon> {
+    let host = hostname::get()
+        .context("failed to get hostname")?
+        .to_string_lossy()
+        .into_owned();
+
+    let daemon = ServiceDaemon::new()?;
+    let service = ServiceInfo::new(
+        SERVICE_TYPE,
+        &host,
+        &format!("{}.local.", host),
+        "",
+        port,
+        None,
+    )?
+    .enable_addr_auto();
+    daemon.register(service)?;
+
+    Ok(daemon)
+}
+
+/// Addresses clients on the network can reach a server listening on `listen` by.
+/// Listening on all interfaces, that's every non-loopback interface address of the same IP version
+pub fn reachable_addrs(listen: SocketAddr) -> Vec<SocketAddr> {
+    if !listen.ip().is_unspecified() {
+        return vec![listen];
+    }
+    match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces
+            .iter()
+            .map(|i| i.ip())
+            .filter(|ip| !ip.is_loopback() && ip.is_ipv4() == listen.is_ipv4())
+            .map(|ip| SocketAddr::new(ip, listen.port()))
+            .collect(),
+        Err(e) => {
+            error!("failed to list network interfaces: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// URL clients connect to `addr` with, carrying the certificate `fingerprint` if given
+/// so clients trusting on first use can check it
+pub fn connection_url(addr: SocketAddr, fingerprint: Option<&str>) -> String {
+    match fingerprint {
+        Some(fingerprint) => format!("{}://{}?fingerprint={}", URL_SCHEME, addr, fingerprint),
+        None => format!("{}://{}", URL_SCHEME, addr),
+    }
+}
+
+/// `text` as a QR code drawn with block characters, light on dark so it scans in dark terminals
+pub fn terminal_qr(text: &str) -> Result<String> {
+    use qrcode::render::unicode::Dense1x2;
+
+    let code = qrcode::QrCode::new(text).context("failed to make QR code")?;
+    Ok(code
+        .render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .build())
+}
+
+/// Removes expired sessions, returning how many were removed
+pub async fn purge_expired_sessions() -> usize {
+    let mut lock = SESSION_STORAGE.lock().await;
+    let before = lock.len();
+    let now = Utc::now();
+
+    lock.retain(|_, session| session.expiration >= now);
+    before - lock.len()
+}
+
 /// Attempts to create a session.
-/// Fails if password doesn't match
-pub async fn init_session(
-    hash: &pwhash::PasswordHash,
-    mut reader: BufReader<RecvStream>,
+/// Fails if password doesn't match or `remote` is locked out
+pub async fn init_session<R: AsyncRead + Unpin>(
+    settings: &Settings,
+    user: Option<&str>,
+    reader: R,
+    remote: SocketAddr,
 ) -> Result<Vec<u8>> {
+    // Bounded, anyone can send this before authenticating
     let mut pass = Vec::new();
-    reader.read_to_end(&mut pass).await?;
+    reader
+        .take(MAX_PASSWORD_LEN as u64 + 1)
+        .read_to_end(&mut pass)
+        .await?;
+    if pass.len() > MAX_PASSWORD_LEN {
+        bail!("Password exceeds {} bytes", MAX_PASSWORD_LEN);
+    }
 
-    let password = pwhash::Password::from_slice(&pass)?;
+    // Reject locked out addresses before doing any hashing. The attempt is counted as failed
+    // under the same lock, so parallel guesses can't all pass the check before any is recorded.
+    // Cleared again on success
+    let attempts = {
+        let mut lock = AUTH_FAILURES.lock().await;
+        let failures = lock.entry(remote.ip()).or_default();
+        if failures.is_locked() {
+            bail!("Too many failed attempts, try again later");
+        }
+        failures.record();
+        failures.count
+    };
+
+    let user = user.unwrap_or(DEFAULT_USER);
+
+    // Unknown users and empty passwords count as a failed attempt, same as a wrong password.
+    // Unknown users are still hashed, so the time taken doesn't tell whether a user exists
+    let verified = match (settings.users.get(user), to_password(&pass)) {
+        (Some(UserHash::Valid(hash)), Ok(password)) => verify_password(hash, &password),
+        (Some(UserHash::Invalid(_)), Ok(_)) => Err(anyhow!("stored password hash is invalid")),
+        (None, Ok(password)) => settings
+            .dummy_hash()
+            .and_then(|hash| verify_password(hash, &password))
+            .map(|_| false),
+        _ => Ok(false),
+    };
+    // The server's fault, not a failed attempt of the client
+    let verified = match verified {
+        Ok(verified) => verified,
+        Err(e) => {
+            if let Some(failures) = AUTH_FAILURES.lock().await.get_mut(&remote.ip()) {
+                failures.forgive();
+            }
+            error!("failed to check the password of user {:?}: {:#}", user, e);
+            METRICS.record_error(format!("failed to check a password: {:#}", e));
+            bail!("Server failed to check the password");
+        }
+    };
 
-    // Implement fail timeout later
     // Register session if success, return result of verification
-    match pwhash::hash_password_verify(hash, &password) {
-        Ok(_) => {
-            // Initialize new connection
-            // Generate UUID on server because you should never trust the client
-            let mut lock = SESSION_STORAGE.lock().await;
-            let session_id = Uuid::new_v4();
-            let session = Session::new();
+    if verified {
+        AUTH_FAILURES.lock().await.remove(&remote.ip());
+        info!("{} authenticated as {:?}", remote.ip(), user);
 
-            lock.insert(session_id, session.clone());
-            drop(lock); // Explicit release
+        // Initialize new connection
+        // Generate UUID on server because you should never trust the client
+        let mut lock = SESSION_STORAGE.lock().await;
+        let session_id = Uuid::new_v4();
+        let session = Session::new(user);
 
-            // Success & Id & Expiratrion
-            // Designed for client handling
-            let result = format!("success&{}&{}", session_id, session.expiratrion)
-                .as_bytes()
-                .to_vec();
-            return Ok(result);
-        }
-        Err(_) => {
-            bail!("Invalid Password");
+        lock.insert(session_id, session.clone());
+        drop(lock); // Explicit release
+        METRICS.session_created();
+
+        let result = AuthResponse {
+            session_id,
+            expiration: session.expiration,
+        };
+        Ok(result.serialize().into_bytes())
+    } else {
+        info!(
+            "failed authentication from {} ({} attempts)",
+            remote.ip(),
+            attempts
+        );
+        METRICS.auth_failed();
+
+        bail!("Invalid username or password");
+    }
+}
+
+/// Error for a file over the server's `max` file size
+pub fn too_large(max: u64) -> JobError {
+    JobError::new(
+        JobCode::TooLarge,
+        format!("File exceeds the server's limit of {} bytes", max),
+    )
+}
+
+/// Copies exactly `length` bytes of body into `writer`, decompressing it if `encoding` says so.
+/// Fails if the stream ends early or carries more, so a client can't write past its
+/// Content-Length, or if the decompressed body is over `max_size`. Returns the decoded size
+pub async fn copy_body<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    length: u64,
+    encoding: ContentEncoding,
+    max_size: u64,
+) -> Result<u64>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut body = (&mut *reader).take(length);
+    let written = match encoding {
+        ContentEncoding::Identity => io::copy(&mut body, writer).await?,
+        // Decompressed size is checked too, a small body can expand a lot
+        ContentEncoding::Zstd => {
+            let mut decoder = ZstdDecoder::new(&mut body).take(max_size + 1);
+            let written = io::copy(&mut decoder, writer).await.map_err(|e| {
+                JobError::new(
+                    JobCode::InvalidRequest,
+                    format!("failed to decompress body: {}", e),
+                )
+            })?;
+            if written > max_size {
+                return Err(too_large(max_size).into());
+            }
+            written
         }
     };
+    writer.flush().await?;
+
+    // The decoder stops at the end of its frame, anything after it is left in `body`
+    if body.limit() != 0 {
+        let received = length - body.limit();
+        let message = if body.read(&mut [0u8; 1]).await? == 0 {
+            format!("Body ended after {} of {} bytes", received, length)
+        } else {
+            format!(
+                "Body has data after its compressed content at byte {}",
+                received
+            )
+        };
+        return Err(JobError::new(JobCode::InvalidRequest, message).into());
+    }
+    if reader.read(&mut [0u8; 1]).await? != 0 {
+        return Err(body_too_long(length).into());
+    }
+    Ok(written)
+}
+
+fn body_too_long(length: u64) -> JobError {
+    JobError::new(
+        JobCode::InvalidRequest,
+        format!("Body exceeds Content-Length of {} bytes", length),
+    )
+}
+
+/// Reads the header block (everything up to the first empty line).
+/// Fails if the block exceeds [`MAX_HEADER_SIZE`] or the stream ends early.
+pub async fn read_headers<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<String> {
+    let mut block = String::new();
+    let mut limited = reader.take(MAX_HEADER_SIZE as u64);
+
+    loop {
+        let start = block.len();
+        let read = limited.read_line(&mut block).await?;
+        if read == 0 {
+            if limited.limit() == 0 {
+                bail!("header block exceeds {} bytes", MAX_HEADER_SIZE);
+            }
+            bail!("stream ended before end of headers");
+        }
+
+        // Empty line terminates the headers
+        if block[start..].trim().is_empty() {
+            return Ok(block);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_option_is_passed_to_cups() {
+        let default = PrintOptions::default;
+        for (options, arg) in [
+            (
+                PrintOptions {
+                    sides: Some("two-sided-long-edge".to_string()),
+                    ..default()
+                },
+                "sides=two-sided-long-edge",
+            ),
+            (
+                PrintOptions {
+                    page_ranges: Some("1-3,5".to_string()),
+                    ..default()
+                },
+                "page-ranges=1-3,5",
+            ),
+            (
+                PrintOptions {
+                    media: Some("A4".to_string()),
+                    ..default()
+                },
+                "media=A4",
+            ),
+            (
+                PrintOptions {
+                    landscape: true,
+                    ..default()
+                },
+                "landscape",
+            ),
+            (
+                PrintOptions {
+                    fit_to_page: true,
+                    ..default()
+                },
+                "fit-to-page",
+            ),
+            (
+                PrintOptions {
+                    scaling: Some(80),
+                    ..default()
+                },
+                "scaling=80",
+            ),
+            (
+                PrintOptions {
+                    position: Some("center".to_string()),
+                    ..default()
+                },
+                "position=center",
+            ),
+        ] {
+            let copies = options.copies.to_string();
+            assert_eq!(options.lpr_args(), ["-#", &copies, "-o", arg]);
+            assert_eq!(
+                options.lp_args(),
+                [
+                    "-o",
+                    "ColorModel=cym",
+                    "-o",
+                    "number-up=1",
+                    "-n",
+                    &copies,
+                    "-o",
+                    arg
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn copies_are_passed_to_cups() {
+        let options = PrintOptions {
+            copies: 3,
+            ..PrintOptions::default()
+        };
+        assert_eq!(options.lpr_args(), ["-#", "3"]);
+        assert_eq!(options.lp_args()[4..], ["-n", "3"]);
+    }
+
+    #[test]
+    fn passes_image_options_to_cups() {
+        let options = PrintOptions {
+            scaling: Some(150),
+            position: Some("top-left".to_string()),
+            ..PrintOptions::default()
+        };
+        let expected = ["-o", "scaling=150", "-o", "position=top-left"];
+        assert!(options.lpr_args().ends_with(&expected.map(String::from)));
+        assert!(options.lp_args().ends_with(&expected.map(String::from)));
+    }
+
+    #[test]
+    fn lp_defaults_come_before_the_options() {
+        let options = PrintOptions {
+            copies: 2,
+            ..PrintOptions::default()
+        };
+        assert_eq!(
+            options.lp_args(),
+            ["-o", "ColorModel=cym", "-o", "number-up=1", "-n", "2"]
+        );
+    }
+
+    #[test]
+    fn parses_printer_state() {
+        for (output, state) in [
+            (
+                "printer office is idle.  enabled since Mon 01 Jan 2024\n",
+                Some(PrinterState::Idle),
+            ),
+            (
+                "printer office now printing office-12.  enabled since Mon 01 Jan 2024\n",
+                Some(PrinterState::Processing),
+            ),
+            (
+                "printer office disabled since Mon 01 Jan 2024 -\n\tPaused\n",
+                Some(PrinterState::Disabled),
+            ),
+            (
+                "lpstat: Invalid destination name in list \"office\".\n",
+                None,
+            ),
+            ("", None),
+        ] {
+            assert_eq!(parse_printer_state(output), state, "{:?}", output);
+        }
+    }
+
+    #[test]
+    fn describes_print_errors() {
+        for (stderr, state, expected) in [
+            (
+                "lp: Destination \"office\" is not accepting jobs.",
+                None,
+                "Printer isn't accepting jobs",
+            ),
+            (
+                "lpr: Error - The printer or class is not available.",
+                Some(PrinterState::Disabled),
+                "Printer is disabled",
+            ),
+            (
+                "lpr: Error - printer-state-reasons media-empty-error",
+                None,
+                "Printer is out of paper",
+            ),
+            (
+                "lpr: Error - Paper jam in tray 2",
+                None,
+                "Printer has a paper jam",
+            ),
+            (
+                "lpr: Error - Printer is offline",
+                Some(PrinterState::Idle),
+                "Printer is offline",
+            ),
+        ] {
+            let message = describe_print_error(stderr, state);
+            assert!(
+                message.starts_with(expected),
+                "{:?} gave {:?}",
+                stderr,
+                message
+            );
+            assert!(
+                message.contains(stderr),
+                "{:?} dropped from {:?}",
+                stderr,
+                message
+            );
+        }
+
+        // Unrecognized errors are kept as they are, with the state if it's worth mentioning
+        assert_eq!(describe_print_error("lpr: oops", None), "lpr: oops");
+        assert_eq!(
+            describe_print_error("lpr: oops", Some(PrinterState::Processing)),
+            "lpr: oops, printer is printing"
+        );
+    }
+
+    #[tokio::test]
+    async fn limits_concurrent_jobs() {
+        let settings = Settings {
+            max_jobs_per_session: 2,
+            max_concurrent_jobs: Some(3),
+            ..Default::default()
+        };
+        let limiter = JobLimiter::new(&settings);
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let held = [
+            limiter.try_acquire(first).await.unwrap(),
+            limiter.try_acquire(first).await.unwrap(),
+        ];
+        let err = limiter.try_acquire(first).await.err().unwrap();
+        assert_eq!(err.code, JobCode::TooManyJobs);
+
+        // Other sessions have their own limit, until the server's is reached
+        let other = limiter.try_acquire(second).await.unwrap();
+        let err = limiter.try_acquire(second).await.err().unwrap();
+        assert_eq!(err.code, JobCode::TooManyJobs);
+
+        // Finished jobs free their slot
+        drop(held);
+        drop(other);
+        limiter.try_acquire(first).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn session_limits_hold_under_concurrency() {
+        let settings = Settings {
+            max_jobs_per_session: 1,
+            ..Default::default()
+        };
+        let limiter = Arc::new(JobLimiter::new(&settings));
+        let session = Uuid::new_v4();
+
+        for _ in 0..1000 {
+            // Other sessions forget idle ones while this one takes its slot
+            let start = Arc::new(tokio::sync::Barrier::new(8));
+            let tasks: Vec<_> = (0..8)
+                .map(|i| {
+                    let (limiter, start) = (limiter.clone(), start.clone());
+                    let id = if i % 2 == 0 { session } else { Uuid::new_v4() };
+                    tokio::spawn(async move {
+                        start.wait().await;
+                        (id, limiter.try_acquire(id).await.ok())
+                    })
+                })
+                .collect();
+            let mut held = Vec::new();
+            for task in tasks {
+                held.push(task.await.unwrap());
+            }
+            let taken = held
+                .iter()
+                .filter(|(id, permit)| *id == session && permit.is_some())
+                .count();
+            assert_eq!(taken, 1);
+        }
+    }
+
+    /// Settings where `alice` logs in with `password`, hashed cheaply since tests run unoptimized
+    fn settings_with_alice(password: &str) -> Settings {
+        let password = pwhash::Password::from_slice(password.as_bytes()).unwrap();
+        let hash = pwhash::hash_password(&password, 3, 8).unwrap();
+        Settings {
+            users: HashMap::from([("alice".to_string(), hash.into())]),
+            hash_cost: HashCost {
+                iterations: MIN_HASH_ITERATIONS,
+                memory_kib: MIN_HASH_MEMORY_KIB,
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn hashes_with_a_custom_cost() {
+        let cost = HashCost {
+            iterations: MIN_HASH_ITERATIONS + 1,
+            memory_kib: MIN_HASH_MEMORY_KIB * 2,
+        };
+        let hash = hash_password("hunter22", cost).unwrap();
+        assert!(
+            hash.unprotected_as_encoded()
+                .contains(&format!("m={},t={}", cost.memory_kib, cost.iterations)),
+            "{}",
+            hash.unprotected_as_encoded()
+        );
+
+        let right = to_password(b"hunter22").unwrap();
+        let wrong = to_password(b"hunter23").unwrap();
+        assert!(verify_password(&hash, &right).unwrap());
+        assert!(!verify_password(&hash, &wrong).unwrap());
+
+        let too_cheap = HashCost {
+            iterations: MIN_HASH_ITERATIONS - 1,
+            ..cost
+        };
+        let err = hash_password("hunter22", too_cheap).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("hash_cost.iterations must be at least"));
+    }
+
+    #[test]
+    fn empty_passwords_are_refused() {
+        let err = to_password(b"").unwrap_err();
+        assert_eq!(err.to_string(), "password is empty");
+        let err = hash_password("", HashCost::default()).unwrap_err();
+        assert_eq!(err.to_string(), "password is empty");
+    }
+
+    #[test]
+    fn weak_passwords_are_explained() {
+        assert_eq!(
+            password_weakness("hunter22", "alice").as_deref(),
+            Some("shorter than 12 characters")
+        );
+        // Fine on its own, but guessable when it's the username
+        assert_eq!(password_weakness("quietmaple7Vx", "bob"), None);
+        assert_eq!(
+            password_weakness("quietmaple7Vx", "quietmaple7Vx").as_deref(),
+            Some("easy to guess")
+        );
+        assert_eq!(
+            password_weakness("correct horse battery staple", "alice"),
+            None
+        );
+    }
+
+    #[test]
+    fn broken_password_hashes_are_kept() {
+        let hash = hash_password("hunter22", HashCost::default()).unwrap();
+        let json = serde_json::json!({
+            "users": { "alice": hash.unprotected_as_encoded(), "bob": "not a hash" },
+        });
+        let settings: Settings = serde_json::from_value(json).unwrap();
+        assert!(matches!(&settings.users["bob"], UserHash::Invalid(raw) if raw == "not a hash"));
+        let UserHash::Valid(hash) = &settings.users["alice"] else {
+            panic!("alice's hash wasn't parsed");
+        };
+        let password = to_password(b"hunter22").unwrap();
+        assert!(verify_password(hash, &password).unwrap());
+
+        // Saved back as they were
+        let saved = serde_json::to_value(&settings).unwrap();
+        assert_eq!(saved["users"]["bob"], "not a hash");
+        assert_eq!(saved["users"]["alice"], hash.unprotected_as_encoded());
+
+        let settings = serde_json::from_str::<Settings>(r#"{"hash": "not a hash"}"#).unwrap();
+        assert!(matches!(settings.hash, Some(UserHash::Invalid(_))));
+    }
+
+    #[tokio::test]
+    async fn users_with_a_broken_hash_cant_log_in() {
+        let mut settings = settings_with_alice("hunter22");
+        settings.users.insert(
+            "bob".to_string(),
+            UserHash::Invalid("not a hash".to_string()),
+        );
+        let remote = SocketAddr::from(([10, 0, 0, 11], 1234));
+        let err = init_session(&settings, Some("bob"), &b"hunter22"[..], remote)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Server failed to check the password");
+
+        // Not held against the client
+        let failures = AUTH_FAILURES.lock().await.get(&remote.ip()).cloned();
+        assert_eq!(failures.map_or(0, |failures| failures.count), 0);
+    }
+
+    #[test]
+    fn reads_the_cost_stored_in_a_hash() {
+        let cost = HashCost {
+            iterations: MIN_HASH_ITERATIONS,
+            memory_kib: MIN_HASH_MEMORY_KIB,
+        };
+        let hash = hash_password("hunter22", cost).unwrap();
+        assert_eq!(stored_cost(&hash).unwrap(), cost);
+    }
+
+    #[test]
+    fn parses_printer_options() {
+        let options = parse_printer_options(
+            "PageSize/Media Size: Letter *A4 Legal\nColorModel/Color Mode: *Gray RGB\n",
+        );
+        assert_eq!(options.media, ["Letter", "A4", "Legal"]);
+        assert_eq!(options.default_media.as_deref(), Some("A4"));
+        assert!(options.landscape && options.fit_to_page);
+
+        // Raw queue
+        assert_eq!(parse_printer_options(""), PrinterOptions::default());
+        let options = parse_printer_options("ColorModel/Color Mode: *Gray RGB\n");
+        assert!(!options.landscape && !options.fit_to_page);
+
+        let options = parse_printer_options("print-scaling/Scaling: *auto fit none\n");
+        assert!(!options.landscape && options.fit_to_page);
+        assert!(options.media.is_empty());
+    }
+
+    #[test]
+    fn data_dir_can_be_overridden() {
+        let dir = data_dir_from(Some("/var/lib/remote-print".into())).unwrap();
+        assert_eq!(dir, PathBuf::from("/var/lib/remote-print"));
+
+        // Set but empty is the same as unset
+        if let Ok(default) = data_dir_from(None) {
+            assert_eq!(data_dir_from(Some("".into())).unwrap(), default);
+        }
+    }
+
+    #[tokio::test]
+    async fn metrics_count_each_event() {
+        let metrics = Metrics::new();
+        metrics.connection_opened();
+        metrics.connection_opened();
+        metrics.session_created();
+        metrics.auth_failed();
+        metrics.job_printed();
+        metrics.job_failed();
+        metrics.record_error("printer on fire");
+
+        let status = metrics.status().await;
+        assert_eq!(status.connections, 2);
+        assert_eq!(status.sessions_created, 1);
+        assert_eq!(status.auth_failures, 1);
+        assert_eq!(status.jobs_printed, 1);
+        assert_eq!(status.jobs_failed, 1);
+        assert_eq!(status.last_error.unwrap().message, "printer on fire");
+    }
+
+    #[tokio::test]
+    async fn unreadable_settings_arent_overwritten() {
+        let settings = Settings {
+            unsaved: true,
+            ..Settings::default()
+        };
+        let err = Settings::save_settings(&settings).await.unwrap_err();
+        assert!(err.to_string().contains("couldn't be read"), "{:#}", err);
+    }
+
+    #[test]
+    fn history_keeps_the_most_recent_jobs() {
+        let mut history = VecDeque::new();
+        for job in 0..MAX_JOB_HISTORY + 10 {
+            push_capped(&mut history, job);
+        }
+        assert_eq!(history.len(), MAX_JOB_HISTORY);
+        assert_eq!(history.front(), Some(&10));
+        assert_eq!(history.back(), Some(&(MAX_JOB_HISTORY + 9)));
+    }
+
+    #[test]
+    fn sanitizes_filenames() {
+        assert_eq!(sanitize_filename("report.pdf"), "report.pdf");
+        assert_eq!(sanitize_filename("../../etc/passwd"), "passwd");
+        assert_eq!(sanitize_filename("C:\\Users\\me\\report.pdf"), "report.pdf");
+        assert_eq!(sanitize_filename("dir/"), "unnamed");
+        assert_eq!(
+            sanitize_filename("bad\u{1b}[2J\nname\t.pdf"),
+            "bad[2Jname.pdf"
+        );
+        // Only ever a name to log or archive under a prefix, never a path of its own
+        assert_eq!(sanitize_filename(".profile"), ".profile");
+        for name in ["", "  ", ".", "..", "a/..", "\u{7}"] {
+            assert_eq!(sanitize_filename(name), "unnamed", "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn sanitized_filenames_fit_in_255_bytes() {
+        assert_eq!(sanitize_filename(&"a".repeat(300)).len(), MAX_FILENAME_LEN);
+
+        // Cut on a character boundary, not after 255 characters
+        let name = sanitize_filename(&"\u{e9}".repeat(200));
+        assert_eq!(name.len(), 254);
+        assert!(name.chars().all(|c| c == '\u{e9}'));
+    }
+
+    #[tokio::test]
+    async fn failed_printer_options_arent_cached() {
+        // lpoptions isn't installed or doesn't know the printer
+        let printer = format!("missing-{}", Uuid::new_v4());
+        let options = printer_options(Some(&printer)).await;
+        assert_eq!(options.media, KNOWN_MEDIA);
+        assert!(!PRINTER_OPTIONS.lock().await.contains_key(&Some(printer)));
+    }
+
+    #[test]
+    fn reachable_addrs_of_a_specific_address_is_that_address() {
+        let listen: SocketAddr = "192.168.1.20:4433".parse().unwrap();
+        assert_eq!(reachable_addrs(listen), vec![listen]);
+    }
+
+    #[test]
+    fn reachable_addrs_of_all_interfaces_skip_loopback() {
+        for listen in ["0.0.0.0:4433", "[::]:4433"] {
+            let listen: SocketAddr = listen.parse().unwrap();
+            for addr in reachable_addrs(listen) {
+                assert!(!addr.ip().is_loopback(), "{}", addr);
+                assert_eq!(addr.is_ipv4(), listen.is_ipv4(), "{}", addr);
+                assert_eq!(addr.port(), 4433);
+            }
+        }
+    }
+
+    #[test]
+    fn makes_connection_urls() {
+        let v4: SocketAddr = "192.168.1.20:4433".parse().unwrap();
+        let v6: SocketAddr = "[fe80::1]:4433".parse().unwrap();
+        assert_eq!(connection_url(v4, None), "remote-print://192.168.1.20:4433");
+        assert_eq!(connection_url(v6, None), "remote-print://[fe80::1]:4433");
+        assert_eq!(
+            connection_url(v4, Some("AB:CD")),
+            "remote-print://192.168.1.20:4433?fingerprint=AB:CD"
+        );
+    }
+
+    #[test]
+    fn finds_jobs_in_lpstat_listings() {
+        let listing = "\
+office-41    alice     1024   Mon 12 Oct 2026 10:00:00
+\tStatus: the printer is out of paper
+\tAlerts: job-stopped
+\tqueued for office
+office-42    bob       2048   Mon 12 Oct 2026 10:01:00
+\tAlerts: job-incoming
+";
+        assert_eq!(
+            find_job(listing, "office-41").as_deref(),
+            Some("job-stopped")
+        );
+        assert_eq!(
+            find_job(listing, "office-42").as_deref(),
+            Some("job-incoming")
+        );
+        // Not a prefix of another job's ID
+        assert_eq!(find_job(listing, "office-4"), None);
+        assert_eq!(find_job(listing, "office-43"), None);
+    }
+
+    #[test]
+    fn maps_lpstat_alerts_to_job_states() {
+        assert_eq!(lpstat_job_state(false, "job-incoming"), JobState::Queued);
+        assert_eq!(lpstat_job_state(false, "job-printing"), JobState::Printing);
+        assert_eq!(lpstat_job_state(false, "job-stopped"), JobState::Printing);
+        assert_eq!(
+            lpstat_job_state(true, "job-completed-successfully"),
+            JobState::Completed
+        );
+        assert_eq!(
+            lpstat_job_state(true, "job-canceled-by-user"),
+            JobState::Failed
+        );
+        assert_eq!(
+            lpstat_job_state(true, "aborted-by-system"),
+            JobState::Failed
+        );
+    }
+
+    #[test]
+    fn parses_job_id() {
+        assert_eq!(
+            parse_job_id("request id is office-42 (1 file(s))\n").as_deref(),
+            Some("office-42")
+        );
+        // `lpr` output
+        assert_eq!(parse_job_id(""), None);
+    }
+
+    #[test]
+    fn checks_extensions() {
+        let settings = settings_with_alice("hunter22");
+        assert_eq!(settings.check_extension("txt").unwrap(), "txt");
+        assert_eq!(settings.check_extension("PDF").unwrap(), "pdf");
+
+        for (extension, error) in [
+            ("", "empty file extension"),
+            ("../evil", "invalid file extension \"../evil\""),
+            ("pdf.exe", "invalid file extension \"pdf.exe\""),
+            ("waytoolong", "invalid file extension \"waytoolong\""),
+        ] {
+            let err = settings.check_extension(extension).unwrap_err();
+            assert_eq!(err.to_string(), error, "extension {:?}", extension);
+        }
+
+        let err = settings.check_extension("exe").unwrap_err();
+        assert!(
+            err.to_string().starts_with("file type \"exe\" not allowed"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn files_over_the_size_limit_are_rejected() {
+        let settings = Settings {
+            max_file_size: 5,
+            ..settings_with_alice("hunter22")
+        };
+        settings.check_file_size(5).unwrap();
+        let err = settings.check_file_size(6).unwrap_err();
+        assert_eq!(err.code, JobCode::TooLarge);
+    }
+
+    #[test]
+    fn checks_file_content() {
+        let settings = Settings::default();
+        let elf = b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0";
+        let err = settings.check_content("pdf", elf).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "file content doesn't match its type \"pdf\""
+        );
+        let err = settings
+            .check_content("pdf", b"\x89PNG\r\n\x1a\n")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "file content doesn't match its type \"pdf\", looks like png"
+        );
+
+        // Text passes as any type, it may be PostScript without a header
+        settings.check_content("txt", b"hello\n").unwrap();
+        settings
+            .check_content("ps", b"newpath 0 0 moveto\n")
+            .unwrap();
+
+        let mut docx = b"PK\x03\x04".to_vec();
+        docx.resize(30, 0);
+        docx.extend_from_slice(b"[Content_Types].xml");
+        settings.check_content("docx", &docx).unwrap();
+        settings.check_content("jpeg", b"\xff\xd8\xff\xe0").unwrap();
+        // Word may write another entry first
+        settings.check_content("docx", b"PK\x03\x04\x14\0").unwrap();
+        settings.check_content("pdf", &docx).unwrap_err();
+
+        let unchecked = Settings {
+            check_file_type: false,
+            ..Default::default()
+        };
+        unchecked.check_content("pdf", elf).unwrap();
+    }
+
+    #[tokio::test]
+    async fn decompressed_size_is_limited() {
+        let body = b"All work and no play makes Jack a dull boy\n".repeat(100);
+        let mut compressed = Vec::new();
+        async_compression::tokio::bufread::ZstdEncoder::new(body.as_slice())
+            .read_to_end(&mut compressed)
+            .await
+            .unwrap();
+        let length = compressed.len() as u64;
+        let receive = |max_size| {
+            let mut sent = compressed.as_slice();
+            async move {
+                let mut file = Vec::new();
+                copy_body(
+                    &mut sent,
+                    &mut file,
+                    length,
+                    ContentEncoding::Zstd,
+                    max_size,
+                )
+                .await
+                .map(|_| file)
+            }
+        };
+
+        assert_eq!(receive(body.len() as u64).await.unwrap(), body);
+        let err = receive(body.len() as u64 - 1).await.unwrap_err();
+        let err = err.downcast::<JobError>().unwrap();
+        assert_eq!(err.code, JobCode::TooLarge);
+    }
+
+    #[tokio::test]
+    async fn repeated_bad_passwords_lock_the_address_out() {
+        let settings = settings_with_alice("hunter22");
+        let remote = SocketAddr::from(([10, 0, 0, 5], 1234));
+        for _ in 0..MAX_AUTH_FAILURES {
+            let err = init_session(&settings, Some("alice"), &b"wrong"[..], remote)
+                .await
+                .unwrap_err();
+            assert_eq!(err.to_string(), "Invalid username or password");
+        }
+
+        // Even the right password is refused while locked out
+        let err = init_session(&settings, Some("alice"), &b"hunter22"[..], remote)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Too many failed attempts, try again later");
+
+        // Other addresses aren't affected
+        let other = SocketAddr::from(([10, 0, 0, 6], 1234));
+        init_session(&settings, Some("alice"), &b"hunter22"[..], other)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn parallel_bad_passwords_cant_pass_the_lockout() {
+        let settings = Arc::new(settings_with_alice("hunter22"));
+        let remote = SocketAddr::from(([10, 0, 0, 9], 1234));
+        let mut attempts = tokio::task::JoinSet::new();
+        for _ in 0..MAX_AUTH_FAILURES * 2 {
+            let settings = settings.clone();
+            attempts.spawn(async move {
+                init_session(&settings, Some("alice"), &b"wrong"[..], remote)
+                    .await
+                    .unwrap_err()
+                    .to_string()
+            });
+        }
+
+        // Only as many guesses as the limit get their password checked
+        let mut checked = 0;
+        while let Some(err) = attempts.join_next().await {
+            if err.unwrap() == "Invalid username or password" {
+                checked += 1;
+            }
+        }
+        assert_eq!(checked, MAX_AUTH_FAILURES);
+    }
+
+    #[tokio::test]
+    async fn unknown_users_are_checked_against_a_dummy_hash() {
+        let settings = settings_with_alice("hunter22");
+        let remote = SocketAddr::from(([10, 0, 0, 8], 1234));
+        let err = init_session(&settings, Some("bob"), &b"hunter22"[..], remote)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Invalid username or password");
+        assert!(settings.dummy_hash.get().is_some());
+    }
+
+    #[test]
+    fn lockouts_double_up_to_the_limit() {
+        // Rounded up, the lockout was set a moment ago
+        let lockout = |failures: &AuthFailures| {
+            (failures.locked_until.unwrap() - Utc::now()).num_seconds() + 1
+        };
+
+        let mut failures = AuthFailures::default();
+        for _ in 1..MAX_AUTH_FAILURES {
+            failures.record();
+        }
+        assert!(!failures.is_locked());
+
+        failures.record();
+        assert!(failures.is_locked());
+        assert_eq!(lockout(&failures), AUTH_LOCKOUT_SECS);
+        failures.record();
+        assert_eq!(lockout(&failures), AUTH_LOCKOUT_SECS * 2);
+        for _ in 0..20 {
+            failures.record();
+        }
+        assert_eq!(lockout(&failures), MAX_AUTH_LOCKOUT_SECS);
+    }
+
+    #[tokio::test]
+    async fn addresses_that_stopped_failing_are_forgotten() {
+        let stale: IpAddr = [10, 0, 1, 1].into();
+        let recent: IpAddr = [10, 0, 1, 2].into();
+        {
+            let mut lock = AUTH_FAILURES.lock().await;
+            let long_ago = Utc::now() - Duration::seconds(AUTH_FAILURE_MEMORY_SECS + 1);
+            let failures = AuthFailures {
+                count: 2,
+                locked_until: None,
+                last_failure: Some(long_ago),
+            };
+            lock.insert(stale, failures);
+
+            let mut failures = AuthFailures::default();
+            failures.record();
+            lock.insert(recent, failures);
+        }
+
+        assert!(purge_auth_failures().await >= 1);
+        let lock = AUTH_FAILURES.lock().await;
+        assert!(!lock.contains_key(&stale));
+        assert!(lock.contains_key(&recent));
+    }
+
+    #[tokio::test]
+    async fn expired_sessions_are_purged() {
+        let expired = Uuid::new_v4();
+        let valid = Uuid::new_v4();
+        {
+            let mut lock = SESSION_STORAGE.lock().await;
+            let mut session = Session::new("alice");
+            session.expiration = Utc::now() - Duration::seconds(1);
+            lock.insert(expired, session);
+            lock.insert(valid, Session::new("alice"));
+        }
+
+        assert!(purge_expired_sessions().await >= 1);
+        let lock = SESSION_STORAGE.lock().await;
+        assert!(!lock.contains_key(&expired));
+        assert!(lock.contains_key(&valid));
+    }
+
+    #[tokio::test]
+    async fn oversized_passwords_are_rejected() {
+        let longest = "a".repeat(MAX_PASSWORD_LEN);
+        let settings = settings_with_alice(&longest);
+        let remote = SocketAddr::from(([10, 0, 0, 7], 1234));
+
+        let err = init_session(
+            &settings,
+            Some("alice"),
+            format!("{}a", longest).as_bytes(),
+            remote,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("Password exceeds {} bytes", MAX_PASSWORD_LEN)
+        );
+
+        // Anything past the limit is left unread
+        assert!(
+            init_session(&settings, Some("alice"), tokio::io::repeat(b'a'), remote)
+                .await
+                .is_err()
+        );
+
+        init_session(&settings, Some("alice"), longest.as_bytes(), remote)
+            .await
+            .unwrap();
+
+        let mut settings = settings;
+        assert!(settings
+            .set_password("alice", &format!("{}a", longest))
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn bodies_longer_than_advertised_are_rejected() {
+        let mut file = Vec::new();
+        copy_body(
+            &mut &b"hello"[..],
+            &mut file,
+            5,
+            ContentEncoding::Identity,
+            DEFAULT_MAX_FILE_SIZE,
+        )
+        .await
+        .unwrap();
+        assert_eq!(file, b"hello");
+
+        let err = copy_body(
+            &mut &b"hello"[..],
+            &mut Vec::new(),
+            2,
+            ContentEncoding::Identity,
+            DEFAULT_MAX_FILE_SIZE,
+        )
+        .await
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("exceeds Content-Length"),
+            "{}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn compressed_bodies_are_decoded_into_the_spool_file() {
+        let text = "All work and no play makes Jack a dull boy\n".repeat(1000);
+        let mut compressed = Vec::new();
+        async_compression::tokio::bufread::ZstdEncoder::new(text.as_bytes())
+            .read_to_end(&mut compressed)
+            .await
+            .unwrap();
+        assert!(compressed.len() < text.len());
+
+        let temp = TempFile::new(&std::env::temp_dir(), "txt");
+        let mut file = fs::File::create(temp.path()).await.unwrap();
+        let length = compressed.len() as u64;
+        copy_body(
+            &mut compressed.as_slice(),
+            &mut file,
+            length,
+            ContentEncoding::Zstd,
+            DEFAULT_MAX_FILE_SIZE,
+        )
+        .await
+        .unwrap();
+        drop(file);
+        assert_eq!(fs::read(temp.path()).await.unwrap(), text.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn headers_end_at_an_empty_line_with_either_line_ending() {
+        for sent in [
+            &b"GET printers\r\nSession: x\r\n\r\nbody"[..],
+            &b"GET printers\nSession: x\n\nbody"[..],
+        ] {
+            let mut reader = io::BufReader::new(sent);
+            let block = read_headers(&mut reader).await.unwrap();
+            assert!(block.starts_with("GET printers"), "{:?}", block);
+            let mut rest = String::new();
+            reader.read_to_string(&mut rest).await.unwrap();
+            assert_eq!(rest, "body");
+        }
+    }
+
+    fn body() -> Vec<u8> {
+        (0..100_000u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn archives_fit_long_filenames() {
+        let archive_dir = std::env::temp_dir().join(format!("archive-{}", Uuid::new_v4()));
+        let document = TempFile::new(&std::env::temp_dir(), "pdf");
+        fs::write(document.path(), b"%PDF").await.unwrap();
+
+        // Multibyte characters, so the cut falls inside one
+        let filename = format!("{}.pdf", "\u{e9}".repeat(200));
+        let archived = archive_document(
+            &archive_dir,
+            Uuid::new_v4(),
+            &filename,
+            "pdf",
+            document.path(),
+        )
+        .await
+        .unwrap();
+        let name = archived.file_name().unwrap().to_str().unwrap();
+        assert!(name.len() <= MAX_FILENAME_LEN, "{} bytes", name.len());
+        assert!(name.ends_with("\u{e9}.pdf"), "{}", name);
+        assert_eq!(fs::read(&archived).await.unwrap(), b"%PDF");
+
+        // Names without an extension get the document's
+        let archived = archive_document(
+            &archive_dir,
+            Uuid::new_v4(),
+            "notes",
+            "txt",
+            document.path(),
+        )
+        .await
+        .unwrap();
+        assert!(archived.to_str().unwrap().ends_with("-notes.txt"));
+
+        fs::remove_dir_all(&archive_dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_archives_leave_nothing_behind() {
+        let archive_dir = std::env::temp_dir().join(format!("archive-{}", Uuid::new_v4()));
+        let missing = archive_dir.join("missing.pdf");
+        archive_document(&archive_dir, Uuid::new_v4(), "a.pdf", "pdf", &missing)
+            .await
+            .unwrap_err();
+
+        let day = std::fs::read_dir(&archive_dir)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(std::fs::read_dir(day.path()).unwrap().count(), 0);
+        fs::remove_dir_all(&archive_dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn upload_resumes_from_offset() {
+        let dir = std::env::temp_dir();
+        let session = Uuid::new_v4();
+        let body = body();
+        let hash = content_hash(&body);
+        let length = body.len() as u64;
+
+        // Connection lost after 40000 bytes
+        let first = receive_upload(&dir, session, &hash, 0, length, &body[..40_000]).await;
+        assert!(first.is_err());
+        assert_eq!(partial_upload_len(&dir, session, &hash).await, 40_000);
+
+        // Can't skip bytes the server never got
+        let ahead = receive_upload(&dir, session, &hash, 50_000, length, &body[50_000..]).await;
+        assert!(ahead.is_err());
+
+        let upload = receive_upload(&dir, session, &hash, 40_000, length, &body[40_000..])
+            .await
+            .unwrap();
+        assert_eq!(fs::read(upload.path()).await.unwrap(), body);
+
+        drop(upload);
+        assert_eq!(partial_upload_len(&dir, session, &hash).await, 0);
+    }
+
+    #[test]
+    fn printed_pages_follow_range_and_copies() {
+        let options = |range: Option<&str>, copies| PrintOptions {
+            page_ranges: range.map(String::from),
+            copies,
+            ..Default::default()
+        };
+
+        assert_eq!(printed_pages(10, &options(None, 1)), 10);
+        assert_eq!(printed_pages(10, &options(None, 3)), 30);
+        assert_eq!(printed_pages(10, &options(Some("3-7,10"), 2)), 12);
+        // Overlapping and past the end
+        assert_eq!(printed_pages(10, &options(Some("1-4,3-6,9-20"), 1)), 8);
+        assert_eq!(printed_pages(10, &options(Some("11-12"), 1)), 0);
+        assert_eq!(
+            printed_pages(u32::MAX, &options(Some("1-4294967295"), 2)),
+            u32::MAX
+        );
+    }
+
+    /// Receives `sent` as a body advertised as `length` bytes long
+    async fn receive(mut sent: &[u8], length: u64) -> Result<Vec<u8>> {
+        let mut file = Vec::new();
+        copy_body(
+            &mut sent,
+            &mut file,
+            length,
+            ContentEncoding::Identity,
+            DEFAULT_MAX_FILE_SIZE,
+        )
+        .await?;
+        Ok(file)
+    }
+
+    #[tokio::test]
+    async fn body_must_match_content_length() {
+        let body = body();
+        let length = body.len() as u64;
+        assert_eq!(receive(&body, length).await.unwrap(), body);
+
+        // Stream ended early
+        assert!(receive(&body[..50_000], length).await.is_err());
+        assert!(receive(&[], length).await.is_err());
+
+        // Stream carries more than advertised
+        assert!(receive(&body, length - 1).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn new_upload_replaces_partial_upload_of_session() {
+        let dir = std::env::temp_dir();
+        let session = Uuid::new_v4();
+        let other_session = Uuid::new_v4();
+        let body = body();
+        let hash = content_hash(&body);
+        let length = body.len() as u64;
+
+        let first = receive_upload(&dir, session, &hash, 0, length, &body[..40_000]).await;
+        assert!(first.is_err());
+        let other = receive_upload(&dir, other_session, &hash, 0, length, &body[..10_000]).await;
+        assert!(other.is_err());
+
+        // Starting another upload drops the session's earlier one
+        let second_body = &body[..60_000];
+        let second_hash = content_hash(second_body);
+        let second = receive_upload(
+            &dir,
+            session,
+            &second_hash,
+            0,
+            60_000,
+            &second_body[..20_000],
+        )
+        .await;
+        assert!(second.is_err());
+        assert_eq!(partial_upload_len(&dir, session, &hash).await, 0);
+        assert_eq!(
+            partial_upload_len(&dir, session, &second_hash).await,
+            20_000
+        );
+
+        // Other sessions keep theirs
+        assert_eq!(partial_upload_len(&dir, other_session, &hash).await, 10_000);
+
+        // Resuming doesn't drop the upload being resumed
+        let upload = receive_upload(
+            &dir,
+            session,
+            &second_hash,
+            20_000,
+            60_000,
+            &second_body[20_000..],
+        )
+        .await
+        .unwrap();
+        assert_eq!(fs::read(upload.path()).await.unwrap(), second_body);
+        drop(upload);
+
+        fs::remove_file(partial_upload_path(&dir, other_session, &hash))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn upload_not_matching_hash_is_dropped() {
+        let dir = std::env::temp_dir();
+        let session = Uuid::new_v4();
+        let body = body();
+        let hash = content_hash(b"something else");
+
+        let upload =
+            receive_upload(&dir, session, &hash, 0, body.len() as u64, body.as_slice()).await;
+        assert!(upload.is_err());
+        assert_eq!(partial_upload_len(&dir, session, &hash).await, 0);
+    }
 }
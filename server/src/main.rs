@@ -1,34 +1,86 @@
-use std::{net::SocketAddr, path::PathBuf, sync::Arc};
-
-use anyhow::{anyhow, bail, Result};
-use chrono::Utc;
-use clap::Parser;
-use printer_server::Settings;
-use quinn::RecvStream;
-use rand::distributions::{Alphanumeric, DistString};
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use clap::{parser::ValueSource, ArgMatches, CommandFactory, FromArgMatches, Parser};
+use printer_server::{connection, Backend, Settings, METRICS};
 use tokio::{
-    fs::File,
-    io::{AsyncBufReadExt, BufReader},
-    process::Command,
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task::JoinSet,
 };
+use tokio_rustls::TlsAcceptor;
+
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::EnvFilter;
+
+/// Where print jobs go
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum PrinterBackend {
+    /// Print with `lpr`/`lp`
+    Cups,
+    /// Save to `--output-dir` without printing, for testing without a printer
+    File,
+    /// Send straight to the printer at `--ipp-uri`, without CUPS
+    Ipp,
+}
 
-use printer_server;
-use tracing::{debug, error, info, info_span, Instrument};
-use tracing_subscriber;
-use uuid::Uuid;
+/// How log lines are written
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum LogFormat {
+    /// Human readable, for running in a terminal
+    Pretty,
+    /// One JSON object per line with the fields of its spans, for log aggregators
+    Json,
+}
 
-const ALPN_QUIC_HTTP: &[&[u8]] = &[b"hq-29"];
+/// Where the TLS config comes from, kept to rebuild it when the certificate changes
+struct TlsSource {
+    key: Option<PathBuf>,
+    cert: Option<PathBuf>,
+    client_roots: Option<rustls::RootCertStore>,
+    idle_timeout: Duration,
+    /// `None` to not send keep-alives
+    keep_alive_interval: Option<Duration>,
+}
 
 #[derive(Parser, Debug)]
 struct Args {
+    /// JSON file with any of the options below that are kept running the server,
+    /// named like the flags with underscores, e.g. "output_dir". Flags override it
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
     /// TLS private key in PEM format
-    #[clap(short = 'k', long = "key", requires = "cert")]
+    #[clap(short = 'k', long = "key")]
     key: Option<PathBuf>,
 
-    /// TLS certificate in PEM format
-    #[clap(short = 'c', long = "cert", requires = "key")]
+    /// TLS certificate in PEM format.
+    /// Reloaded along with the key when either file changes, or on SIGHUP
+    #[clap(short = 'c', long = "cert")]
     cert: Option<PathBuf>,
 
+    /// Generate a new self-signed certificate for these host names and IPs, replacing the current one.
+    /// Without any, uses localhost, the host name and the addresses of the network interfaces.
+    /// Clients that trusted the old certificate have to trust it again. Exits once done
+    #[arg(long, value_name = "SAN", num_args = 0.., conflicts_with = "cert")]
+    generate_cert: Option<Vec<String>>,
+
+    /// Write the server's certificate to this path in DER format, for clients' `--ca`, and exit
+    #[arg(long, value_name = "PATH")]
+    export_cert: Option<PathBuf>,
+
+    /// Require clients to present a certificate signed by this CA (DER or PEM).
+    /// Clients without a valid certificate fail the TLS handshake before any request is read
+    #[clap(long = "client-ca")]
+    client_ca: Option<PathBuf>,
+
     /// Address to listen on
     #[clap(short, long = "listen", default_value = "0.0.0.0:4433")]
     listen: SocketAddr,
@@ -37,21 +89,238 @@ struct Args {
     #[arg(short, long)]
     printer: Option<String>,
 
+    /// Directory received files are spooled to; defaults to the system temp dir
+    #[arg(long)]
+    temp_dir: Option<PathBuf>,
+
+    /// Where print jobs go; defaults to the `backend` in the settings file, which defaults to cups
+    #[arg(long, value_enum)]
+    printer_backend: Option<PrinterBackend>,
+
+    /// Directory the `file` backend saves jobs to
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+
+    /// Printer the `ipp` backend sends jobs to, e.g. ipp://printer.local/ipp/print
+    #[arg(long)]
+    ipp_uri: Option<String>,
+
+    /// Refuse to start if `lpr`/`lp` can't be found, instead of just warning
+    #[arg(long)]
+    require_printer: bool,
+
+    /// Advertise the server on the local network (mDNS)
+    #[arg(long)]
+    advertise: bool,
+
+    /// Also print a QR code of each connection URL and the certificate fingerprint,
+    /// for setting up clients by scanning it
+    #[arg(long)]
+    qr: bool,
+
+    /// Only listen for QUIC. By default the server also accepts TLS over TCP on the same port,
+    /// for clients on networks that block UDP
+    #[arg(long)]
+    no_tcp: bool,
+
     /// Reset password.
     #[arg(long)]
     reset_password: bool,
+
+    /// Add a user (prompts for their password) or change an existing user's password
+    #[arg(long, value_name = "NAME")]
+    add_user: Option<String>,
+
+    /// Make the user added with --add-user an admin, able to list and revoke sessions
+    #[arg(long, requires = "add_user")]
+    admin: bool,
+
+    /// Remove a user, revoking their access
+    #[arg(long, value_name = "NAME")]
+    remove_user: Option<String>,
+
+    /// Log more, repeat for even more (-vv)
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Only log errors
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Seconds a connection can be idle before it's dropped, freeing half-dead connections
+    #[arg(
+        long,
+        default_value_t = printer_server::DEFAULT_IDLE_TIMEOUT.as_secs(),
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    idle_timeout: u64,
+
+    /// Seconds between keep-alive pings on idle connections, so NATs don't drop them.
+    /// Must be less than --idle-timeout, 0 to disable
+    #[arg(long, default_value_t = printer_server::DEFAULT_KEEP_ALIVE_INTERVAL.as_secs())]
+    keep_alive_interval: u64,
+
+    /// Log format; json suits systemd and containers shipping logs to an aggregator
+    #[arg(long, value_enum, default_value_t = LogFormat::Pretty)]
+    log_format: LogFormat,
+}
+
+/// Options read from `--config`, see [`Args::load`].
+/// One-off actions like `--add-user` are left to the command line
+#[derive(serde::Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    key: Option<PathBuf>,
+    cert: Option<PathBuf>,
+    client_ca: Option<PathBuf>,
+    listen: Option<SocketAddr>,
+    printer: Option<String>,
+    temp_dir: Option<PathBuf>,
+    printer_backend: Option<PrinterBackend>,
+    output_dir: Option<PathBuf>,
+    ipp_uri: Option<String>,
+    require_printer: Option<bool>,
+    advertise: Option<bool>,
+    qr: Option<bool>,
+    no_tcp: Option<bool>,
+    verbose: Option<u8>,
+    quiet: Option<bool>,
+    idle_timeout: Option<u64>,
+    keep_alive_interval: Option<u64>,
+    log_format: Option<LogFormat>,
+}
+
+impl Args {
+    /// Parses the command line, filling in options it doesn't set from `--config`.
+    /// Flags take precedence over the file, the file over the defaults
+    fn load() -> Result<Self> {
+        Args::from_matches(&Args::command().get_matches())
+    }
+
+    /// [`Args::load`] for already parsed arguments
+    fn from_matches(matches: &ArgMatches) -> Result<Self> {
+        let mut args = Args::from_arg_matches(matches)?;
+        if let Some(path) = args.config.clone() {
+            args.merge_config(&path, matches)?;
+        }
+
+        // Checked here rather than by clap, flags can rely on the file for the rest
+        if args.key.is_some() != args.cert.is_some() {
+            bail!("--key and --cert have to be set together");
+        }
+        if args.cert.is_some() && args.generate_cert.is_some() {
+            bail!("--generate-cert can't be used with --cert");
+        }
+        if args.quiet && args.verbose > 0 {
+            bail!("--quiet can't be used with --verbose");
+        }
+        if args.idle_timeout == 0 {
+            bail!("--idle-timeout has to be at least 1");
+        }
+        match args.printer_backend {
+            Some(PrinterBackend::File) if args.output_dir.is_none() => {
+                bail!("--printer-backend file needs --output-dir")
+            }
+            Some(PrinterBackend::Ipp) if args.ipp_uri.is_none() => {
+                bail!("--printer-backend ipp needs --ipp-uri")
+            }
+            _ => (),
+        }
+        Ok(args)
+    }
+
+    /// Sets the options in the config file at `path` that weren't given as flags
+    fn merge_config(&mut self, path: &Path, matches: &ArgMatches) -> Result<()> {
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config {}", path.display()))?;
+        let file: ConfigFile = serde_json::from_str(&json)
+            .with_context(|| format!("invalid config {}", path.display()))?;
+        let from_flags = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+        macro_rules! merge {
+            ($($field:ident),*) => {$(
+                if let Some(value) = file.$field {
+                    if !from_flags(stringify!($field)) {
+                        self.$field = value.into();
+                    }
+                }
+            )*};
+        }
+        merge!(
+            key,
+            cert,
+            client_ca,
+            listen,
+            printer,
+            temp_dir,
+            printer_backend,
+            output_dir,
+            ipp_uri,
+            require_printer,
+            advertise,
+            qr,
+            no_tcp,
+            idle_timeout,
+            keep_alive_interval,
+            log_format
+        );
+        // Both set the log level, so either flag overrides the file's level
+        if !from_flags("verbose") && !from_flags("quiet") {
+            merge!(verbose, quiet);
+        }
+        Ok(())
+    }
+}
+
+/// Level to log at: warnings and errors by default, more with each `-v`,
+/// only errors with `-q`. `RUST_LOG` overrides it
+fn log_level(verbose: u8, quiet: bool) -> &'static str {
+    match (quiet, verbose) {
+        (true, _) => "error",
+        (false, 0) => "warn",
+        (false, 1) => "info",
+        (false, 2) => "debug",
+        (false, _) => "trace",
+    }
+}
+
+/// Logs at [`log_level`] in `format`
+fn init_tracing(verbose: u8, quiet: bool, format: LogFormat) {
+    let level = log_level(verbose, quiet);
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(level));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match format {
+        LogFormat::Pretty => builder.init(),
+        // Lines logged in a request carry the fields of its spans, like the remote address
+        LogFormat::Json => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .init(),
+    }
 }
 
 // Init tracing
 fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-
-    let args = Args::parse();
+    // Read before logging starts, the file can set how to log
+    let args = match Args::load() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("ERROR: {e:#}");
+            std::process::exit(2);
+        }
+    };
+    init_tracing(args.verbose, args.quiet, args.log_format);
 
     if args.reset_password {
-        let dirs =
-            directories::ProjectDirs::from("com", "Coded Masonry", "Remote Print").unwrap();
-        match std::fs::remove_file(dirs.data_local_dir().join("server_settings.json")) {
+        let dir = match printer_server::data_dir() {
+            Ok(dir) => dir,
+            Err(e) => {
+                eprintln!("ERROR: {e:#}");
+                std::process::exit(1);
+            }
+        };
+        match std::fs::remove_file(dir.join("server_settings.json")) {
             Ok(_) => println!("Password reset"),
             Err(_) => println!("No password was saved"),
         };
@@ -69,263 +338,435 @@ fn main() -> Result<()> {
     std::process::exit(code);
 }
 
+/// Warns about missing CUPS commands now rather than on the first job.
+/// Fails instead if `require` is set and nothing can print
+fn check_print_tools(require: bool) -> Result<()> {
+    if !printer_server::in_path("lpr") && !printer_server::in_path("lp") {
+        if require {
+            bail!("neither lpr nor lp found in PATH, install CUPS to print");
+        }
+        warn!("neither lpr nor lp found in PATH, print jobs will fail until CUPS is installed");
+    }
+    if !printer_server::in_path("lpstat") {
+        warn!("lpstat not found in PATH, printers can't be listed or checked");
+    }
+    Ok(())
+}
+
 // main func
 #[tokio::main]
 async fn run(args: Args) -> Result<()> {
-    let (cert, key) = printer_server::parse_tls_cert(args.key, args.cert).await?;
+    // Pings any less often and the connection times out between them
+    if args.keep_alive_interval >= args.idle_timeout {
+        bail!(
+            "--keep-alive-interval ({}s) must be less than --idle-timeout ({}s)",
+            args.keep_alive_interval,
+            args.idle_timeout
+        );
+    }
+
+    if let Some(mut sans) = args.generate_cert.clone() {
+        if sans.is_empty() {
+            sans = printer_server::default_sans();
+        }
+        printer_server::generate_self_signed(sans.clone()).await?;
+        println!("Generated a certificate for {}", sans.join(", "));
+    }
+    if let Some(path) = &args.export_cert {
+        let (chain, _) =
+            printer_server::parse_tls_cert(args.key.clone(), args.cert.clone()).await?;
+        tokio::fs::write(path, &chain[0].0)
+            .await
+            .with_context(|| format!("failed to write certificate to {}", path.display()))?;
+        println!("Certificate written to {}", path.display());
+    }
+    if args.generate_cert.is_some() || args.export_cert.is_some() {
+        return Ok(());
+    }
+
+    if args.add_user.is_some() || args.remove_user.is_some() {
+        return manage_users(&args).await;
+    }
+
+    let client_roots = match args.client_ca {
+        Some(path) => {
+            info!("Requiring client certificates");
+            Some(printer_server::parse_client_ca(path).await?)
+        }
+        None => None,
+    };
+    let tls = TlsSource {
+        key: args.key,
+        cert: args.cert,
+        client_roots,
+        idle_timeout: Duration::from_secs(args.idle_timeout),
+        keep_alive_interval: Some(Duration::from_secs(args.keep_alive_interval))
+            .filter(|interval| !interval.is_zero()),
+    };
+    let (tcp_config, server_config, fingerprint) = tls.server_config().await?;
     debug!("Certificate and Key Parsed Successfully");
 
-    let settings = Arc::new(printer_server::Settings::get_settings().await?);
+    let settings = Arc::new(Settings::get_settings().await?);
     debug!("Settings parsed successfully");
 
-    let printer = Arc::new(args.printer.clone());
-
-    let mut server_crypto = rustls::ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(cert, key)?;
-    server_crypto.alpn_protocols = ALPN_QUIC_HTTP.iter().map(|&x| x.into()).collect();
+    let temp_dir = args.temp_dir.unwrap_or_else(std::env::temp_dir);
+    tokio::fs::create_dir_all(&temp_dir)
+        .await
+        .with_context(|| format!("failed to create temp dir {}", temp_dir.display()))?;
+    debug!("Spooling files to {}", temp_dir.display());
+
+    // Checked by Args::load, which needs the path or URI of the chosen backend
+    let backend = match args.printer_backend {
+        Some(PrinterBackend::Cups) => Backend::Cups,
+        Some(PrinterBackend::File) => Backend::File {
+            output_dir: args.output_dir.unwrap(),
+        },
+        Some(PrinterBackend::Ipp) => Backend::Ipp {
+            uri: args.ipp_uri.unwrap(),
+        },
+        None => settings.backend.clone(),
+    };
+    match &backend {
+        Backend::Cups => check_print_tools(args.require_printer)?,
+        Backend::File { output_dir } => {
+            tokio::fs::create_dir_all(output_dir)
+                .await
+                .with_context(|| format!("failed to create output dir {}", output_dir.display()))?;
+            eprintln!(
+                "Saving jobs to {} instead of printing",
+                output_dir.display()
+            );
+        }
+        Backend::Ipp { uri } => {
+            printer_server::ipp::parse_uri(uri)?;
+            eprintln!("Printing to {} over IPP", uri);
+        }
+    }
 
-    let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(server_crypto));
-    let transfer_config = Arc::get_mut(&mut server_config.transport).unwrap();
-    transfer_config.max_concurrent_uni_streams(8_u8.into());
-    server_config.use_retry(true);
+    let config = Arc::new(connection::Config {
+        printer: args.printer,
+        temp_dir,
+        backend,
+        jobs: printer_server::JobLimiter::new(&settings),
+        idle_timeout: Duration::from_secs(args.idle_timeout),
+    });
+
+    // Sessions are only checked on use, so sweep expired ones periodically
+    let temp_dir = config.temp_dir.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(printer_server::SESSION_PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let reaped = printer_server::purge_expired_sessions().await;
+            debug!("Purged {} expired sessions", reaped);
+            let reaped = printer_server::purge_auth_failures().await;
+            debug!("Forgot failed logins of {} addresses", reaped);
+            // Uploads can only be resumed within their session
+            let reaped = printer_server::purge_partial_uploads(&temp_dir).await;
+            debug!("Purged {} partial uploads", reaped);
+        }
+    });
 
     let endpoint = quinn::Endpoint::server(server_config, args.listen)?;
     eprintln!("Listening on {}", endpoint.local_addr()?);
-
-    while let Some(conn) = endpoint.accept().await {
-        info!("connection incoming");
-        let handle = handle_connection(printer.clone(), settings.clone(), conn);
-        tokio::spawn(async move {
-            if let Err(e) = handle.await {
-                error!("connection failed: {reason}", reason = e.to_string())
+    // Same port as QUIC, so clients falling back don't need to know another one
+    let tcp = if args.no_tcp {
+        None
+    } else {
+        let listener = TcpListener::bind(endpoint.local_addr()?)
+            .await
+            .context("failed to listen on TCP, --no-tcp disables it")?;
+        eprintln!("Listening on {} (TCP)", listener.local_addr()?);
+        Some(listener)
+    };
+    for addr in printer_server::reachable_addrs(endpoint.local_addr()?) {
+        eprintln!("Connect to {}", printer_server::connection_url(addr, None));
+        if args.qr {
+            let url = printer_server::connection_url(addr, Some(&fingerprint));
+            match printer_server::terminal_qr(&url) {
+                Ok(qr) => eprintln!("{}", qr),
+                Err(e) => error!("failed to show {} as a QR code: {:#}", url, e),
             }
-        });
+        }
     }
+    let mut acceptor = TlsAcceptor::from(tcp_config);
+    // Uptime counts from here
+    lazy_static::initialize(&METRICS);
+
+    // Kept alive for as long as the server runs
+    let _mdns = if args.advertise {
+        match printer_server::advertise(endpoint.local_addr()?.port()) {
+            Ok(daemon) => {
+                info!("advertising as {}", printer_server::SERVICE_TYPE);
+                Some(daemon)
+            }
+            Err(e) => {
+                error!("failed to advertise server: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
 
-    Ok(())
-}
-
-async fn handle_connection(
-    printer: Arc<Option<String>>,
-    settings: Arc<Settings>,
-    conn: quinn::Connecting,
-) -> Result<()> {
-    let connection = conn.await?;
-    let span = info_span!(
-        "connection",
-        remote = %connection.remote_address(),
-        protocol = %connection
-            .handshake_data()
-            .unwrap()
-            .downcast::<quinn::crypto::rustls::HandshakeData>().unwrap()
-            .protocol
-            .map_or_else(|| "<none>".into(), |x| String::from_utf8_lossy(&x).into_owned())
-    );
-
-    async {
-        info!("established");
+    let mut connections = JoinSet::new();
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let mut reload = tls.watch();
 
-        // Each stream initiated by the client constitutes a new request.
-        loop {
-            let stream = connection.accept_bi().await;
-            let stream = match stream {
-                Err(quinn::ConnectionError::ApplicationClosed { .. }) => {
-                    info!("connection closed");
-                    return Ok(());
-                }
-                Err(e) => {
-                    return Err(e);
-                }
-                Ok(s) => s,
-            };
-            let fut = handle_request(printer.clone(), settings.clone(), stream);
-            tokio::spawn(
-                async move {
-                    if let Err(e) = fut.await {
-                        error!("failed: {reason}", reason = e.to_string());
+    loop {
+        tokio::select! {
+            conn = endpoint.accept() => {
+                let Some(conn) = conn else { break };
+                info!("connection incoming");
+                let handle = connection::handle_connection(config.clone(), settings.clone(), conn);
+                connections.spawn(async move {
+                    if let Err(e) = handle.await {
+                        error!("connection failed: {reason}", reason = e.to_string())
                     }
-                }
-                .instrument(info_span!("request")),
-            );
+                });
+            }
+            accepted = accept_tcp(tcp.as_ref()) => {
+                let (stream, remote) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        error!("failed to accept TCP connection: {}", e);
+                        continue;
+                    }
+                };
+                info!("TCP connection incoming");
+                let handle = connection::handle_tcp_connection(
+                    config.clone(),
+                    settings.clone(),
+                    acceptor.clone(),
+                    stream,
+                    remote,
+                );
+                connections.spawn(async move {
+                    if let Err(e) = handle.await {
+                        error!("connection failed: {reason}", reason = e.to_string())
+                    }
+                });
+            }
+            // Reap finished connections so the set doesn't grow forever
+            Some(_) = connections.join_next(), if !connections.is_empty() => (),
+            Some(()) = reload.recv() => tls.reload(&endpoint, &mut acceptor).await,
+            _ = &mut shutdown => break,
         }
     }
-    .instrument(span)
-    .await?;
+
+    // Refuse new connections, let the current ones finish
+    endpoint.set_server_config(None);
+    drop(tcp);
+    info!(
+        "Shutting down, waiting for {} active connections",
+        connections.len()
+    );
+    let drained = tokio::time::timeout(printer_server::SHUTDOWN_TIMEOUT, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        error!(
+            "{} connections still active after {:?}, closing them",
+            connections.len(),
+            printer_server::SHUTDOWN_TIMEOUT
+        );
+        connections.shutdown().await;
+    }
+
+    endpoint.close(printer_server::CLOSE_DONE.into(), b"server shutting down");
+    endpoint.wait_idle().await;
+    info!("Shut down");
 
     Ok(())
 }
 
-async fn handle_request(
-    printer: Arc<Option<String>>,
-    settings: Arc<Settings>,
-    (mut send, recv): (quinn::SendStream, quinn::RecvStream),
-) -> Result<()> {
-    let resp = process_request(printer.as_ref(), settings, recv)
-        .await
-        .unwrap_or_else(|e| {
-            error!("Failed: {}", e);
-            format!("Failed to process request: {e}\n").into_bytes()
-        });
-
-    // Write result of handling and send finish
-    send.write_all(&resp)
-        .await
-        .map_err(|e| anyhow!("failed to send response: {}", e))?;
-    send.finish()
-        .await
-        .map_err(|e| anyhow!("failed to shutdown stream: {}", e))?;
+/// Runs `--add-user`/`--remove-user` and exits, without starting the server
+async fn manage_users(args: &Args) -> Result<()> {
+    // The user being added can be the first one, no need to ask for the default user's password
+    let mut settings = if args.add_user.is_some() {
+        Settings::get_settings_or(|| Ok(Settings::default())).await?
+    } else {
+        Settings::get_settings().await?
+    };
 
+    if let Some(user) = &args.add_user {
+        settings.add_user(user)?;
+        if args.admin {
+            settings.admins.insert(user.clone());
+        }
+        Settings::save_settings(&settings).await?;
+        println!("User {:?} saved", user);
+    }
+    if let Some(user) = &args.remove_user {
+        settings.remove_user(user)?;
+        Settings::save_settings(&settings).await?;
+        println!("User {:?} removed", user);
+    }
     Ok(())
 }
 
-async fn process_request(
-    printer: &Option<String>,
-    settings: Arc<Settings>,
-    recv: RecvStream,
-) -> Result<Vec<u8>> {
-    let mut reader = BufReader::new(recv);
-    let mut name = String::new();
-    loop {
-        let r = reader.read_line(&mut name).await.unwrap();
-        if r < 3 {
-            break;
-        }
+impl TlsSource {
+    /// TLS config for TCP, the QUIC config using it and the certificate's fingerprint
+    async fn server_config(
+        &self,
+    ) -> Result<(Arc<rustls::ServerConfig>, quinn::ServerConfig, String)> {
+        let (cert, key) =
+            printer_server::parse_tls_cert(self.key.clone(), self.cert.clone()).await?;
+        let fingerprint = printer_server::fingerprint(&cert[0].0);
+        eprintln!("Certificate fingerprint (SHA-256): {}", fingerprint);
+
+        let tls = connection::tls_config(cert, key, self.client_roots.clone())
+            .context("failed to build server config")?;
+        let quic =
+            connection::server_config(tls.clone(), self.idle_timeout, self.keep_alive_interval)?;
+        Ok((tls, quic, fingerprint))
     }
 
-    let mut extension = String::new();
-    let mut session_id = String::new();
-    let mut request_context = String::new();
-    let linesplit = name.split("\n");
-    // Parse some headers
-    for l in linesplit {
-        if l.starts_with("Extension") {
-            // Extension Header
-            let sizeplit = l.split(":");
-            for s in sizeplit {
-                if !(s.starts_with("Extension")) {
-                    extension = s.trim().parse::<String>().unwrap();
-                }
+    /// Swaps in a config built from the current files. New connections use it,
+    /// existing ones keep the old certificate. Keeps the old config if the files don't parse
+    async fn reload(&self, endpoint: &quinn::Endpoint, tcp: &mut TlsAcceptor) {
+        match self.server_config().await {
+            Ok((tls, quic, _)) => {
+                endpoint.set_server_config(Some(quic));
+                *tcp = TlsAcceptor::from(tls);
+                info!("Reloaded TLS certificate");
             }
-        } else if l.starts_with("Session") {
-            // Session Header
-            let sizeplit = l.split(":");
-            for s in sizeplit {
-                if !(s.starts_with("Session")) {
-                    session_id = s.trim().parse::<String>().unwrap();
-                }
+            Err(e) => {
+                error!(
+                    "FAILED TO RELOAD TLS CERTIFICATE, still using the old one: {:#}",
+                    e
+                );
+                METRICS.record_error(format!("failed to reload TLS certificate: {:#}", e));
             }
-        } else if l.starts_with("POST") {
-            // if POST
-            request_context = String::from("print")
-        } else if l.starts_with("GET") && l.contains("auth") {
-            // if AUTH
-            request_context = String::from("auth")
         }
     }
 
-    if request_context == String::from("print") {
-        let lock = printer_server::SESSION_STORAGE.lock().await;
-        let id = Uuid::parse_str(&session_id)?;
+    /// Notifies on SIGHUP (unix), or when `--cert`/`--key` are modified,
+    /// checked every [`printer_server::CERT_CHECK_INTERVAL`]
+    fn watch(&self) -> mpsc::Receiver<()> {
+        let (tx, rx) = mpsc::channel(1);
+        let files: Vec<PathBuf> = self.key.iter().chain(&self.cert).cloned().collect();
+
+        #[cfg(unix)]
+        {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                use tokio::signal::unix::{signal, SignalKind};
+                match signal(SignalKind::hangup()) {
+                    Ok(mut hangup) => {
+                        while hangup.recv().await.is_some() {
+                            info!("SIGHUP received, reloading TLS certificate");
+                            let _ = tx.try_send(());
+                        }
+                    }
+                    Err(e) => error!("failed to listen for SIGHUP: {}", e),
+                }
+            });
+        }
 
-        // Checks if session exists
-        if let Some(session) = lock.get(&id) {
-            if session.expiratrion < Utc::now() {
-                bail!("Expired Session")
-            }
-        } else {
-            bail!("Authentication Required")
+        if !files.is_empty() {
+            tokio::spawn(async move {
+                let modified = || async {
+                    let mut times = Vec::new();
+                    for file in &files {
+                        times.push(
+                            tokio::fs::metadata(file)
+                                .await
+                                .and_then(|m| m.modified())
+                                .ok(),
+                        );
+                    }
+                    times
+                };
+
+                let mut last = modified().await;
+                let mut interval = tokio::time::interval(printer_server::CERT_CHECK_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    let current = modified().await;
+                    if current != last {
+                        info!("TLS certificate files changed, reloading");
+                        last = current;
+                        let _ = tx.try_send(());
+                    }
+                }
+            });
         }
 
-        drop(lock); // Explicit release
-        print_file(printer, reader, extension).await
-    } else if request_context == String::from("auth") {
-        printer_server::init_session(&settings.hash, reader).await
-    } else {
-        bail!("Invalid Request")
+        rx
     }
 }
 
-async fn print_file(
-    printer: &Option<String>,
-    mut reader: BufReader<RecvStream>,
-    extension: String,
-) -> Result<Vec<u8>> {
-    debug!("Entension: {}", extension);
-
-    // Create temp file
-    let temp_name = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
-    let dir = format!("/tmp/{}.{}", temp_name, extension);
-    let mut file = File::create(dir.clone()).await?;
-    debug!(file = dir);
-
-    // Copy body to file
-    tokio::io::copy(&mut reader, &mut file).await?;
-    debug!("Successfully copied to file");
-
-    // Print
-    debug!(printer = printer);
-    let result = if printer.is_some() {
-        let temp = Command::new("lpr")
-            .arg(dir.clone())
-            .arg("-P")
-            .arg(printer.as_ref().unwrap())
-            .output()
-            .await;
-
-        // If it failed, try using lp instead
-        // Printing flags can be removed if compiling locally.
-        match temp {
-            Ok(output) => output,
-            Err(_) => {
-                Command::new("lp")
-                    .arg(dir.clone())
-                    .arg("-d")
-                    .arg(printer.as_ref().unwrap())
-                    .arg("-oColorModel=cym")
-                    .arg("-o number-up=1")
-                    .output()
-                    .await?
+/// Next connection on `listener`, never without one
+async fn accept_tcp(listener: Option<&TcpListener>) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Resolves on Ctrl-C, or SIGTERM on unix (e.g. `systemctl stop`)
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
             }
-        }
-    } else {
-        // Use Default (only works if lpr exists)
-        let temp = Command::new("lpr").arg(dir.clone()).output().await;
-        match temp {
-            Ok(o) => o,
-            Err(_) => {
-                Command::new("lp")
-                    .arg(dir.clone())
-                    .arg("-oColorModel=cym")
-                    .arg("-o number-up=1")
-                    .output()
-                    .await?
+            Err(e) => {
+                error!("failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await
             }
         }
     };
-
-    // If success, return done, else, return output.
-    if result.status.success() {
-        tokio::fs::remove_file(dir).await?;
-        Ok(b"done".to_vec())
-    } else {
-        tokio::fs::remove_file(dir).await?;
-        let err = String::from_utf8(result.stderr)?;
-        // If no printer was found, notify User
-        if err.contains("not exist") {
-            let printers =
-                String::from_utf8(Command::new("lpstat").arg("-p").output().await?.stdout)?;
-            let printers: Vec<&str> = printers
-                .lines()
-                .map(|e| e.split(" ").nth(1).unwrap())
-                .collect();
-            error!(
-                "Please specify a printer or set a default printer, Here are available printers: \n{:#?}",
-                printers
-            );
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => {
+            if let Err(e) = result {
+                error!("failed to listen for Ctrl-C: {}", e);
+                std::future::pending::<()>().await
+            }
         }
+        _ = terminate => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Loads `flags` with a config file containing `json`
+    fn load(json: &str, flags: &[&str]) -> Result<Args> {
+        let path = std::env::temp_dir().join(format!("config-{}.json", uuid::Uuid::new_v4()));
+        std::fs::write(&path, json).unwrap();
+        let config = path.to_str().unwrap();
+        let matches = Args::command()
+            .get_matches_from(["printer_server", "--config", config].iter().chain(flags));
+        let args = Args::from_matches(&matches);
+        std::fs::remove_file(&path).unwrap();
+        args
+    }
 
-        bail!("{:?}", err)
+    #[test]
+    fn flags_override_the_config_file() {
+        let json = r#"{"listen": "127.0.0.1:5000", "idle_timeout": 30, "quiet": true}"#;
+        let args = load(json, &[]).unwrap();
+        assert_eq!(args.listen, "127.0.0.1:5000".parse().unwrap());
+        assert_eq!(args.idle_timeout, 30);
+        assert!(args.quiet);
+
+        let args = load(json, &["--listen", "0.0.0.0:6000", "--verbose"]).unwrap();
+        assert_eq!(args.listen, "0.0.0.0:6000".parse().unwrap());
+        assert_eq!(args.idle_timeout, 30);
+        assert!(!args.quiet);
+        assert_eq!(args.verbose, 1);
+
+        let args = load(r#"{"verbose": 2}"#, &["--quiet"]).unwrap();
+        assert!(args.quiet);
+        assert_eq!(args.verbose, 0);
     }
 }